@@ -47,13 +47,24 @@ impl<T> SpinLock<T> {
         }
     }
 
-    pub fn lock(&self) -> Guard<T> {
+    pub fn lock(&self) -> Guard<'_, T> {
         while self.locked.swap(true, Acquire) {
             std::hint::spin_loop();
         }
         Guard { lock: self }
     }
 
+    // ロックされていればスピンせずにNoneを返す
+    pub fn try_lock(&self) -> Option<Guard<'_, T>> {
+        if self.locked.swap(true, Acquire) {
+            None
+        } else {
+            Some(Guard { lock: self })
+        }
+    }
+
+    /// # Safety
+    /// ロックを保持しているスレッドだけが呼び出せる
     pub unsafe fn unlock(&self) {
         self.locked.store(false, Release);
     }
@@ -72,3 +83,29 @@ fn main() {
     let g = x.lock();
     assert!(g.as_slice() == [1, 2, 2] || g.as_slice() == [2, 2, 1]);
 }
+
+#[test]
+fn test_spin_lock() {
+    let x = SpinLock::new(0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    *x.lock() += 1;
+                }
+            });
+        }
+    });
+    assert_eq!(*x.lock(), 4000);
+}
+
+#[test]
+fn test_try_lock() {
+    let x = SpinLock::new(0);
+    let mut g = x.try_lock().unwrap();
+    // ロックを保持している間は取得できない
+    assert!(x.try_lock().is_none());
+    *g += 1;
+    drop(g);
+    assert_eq!(*x.try_lock().unwrap(), 1);
+}
@@ -0,0 +1,169 @@
+#![allow(dead_code)]
+
+// mpsc.rsとring_channel.rsで、1つずつ送受信する場合とまとめて送受信する場合のスループットを比べる
+// cargo run --release --bin channel_batch_bench -- --senders 1,2,4 --messages 1000000 --batch 64
+//
+// --senders   mpscのSenderのスレッド数(カンマ区切りで複数指定できる)。ring_channelは常に1
+// --messages  Senderごとに送るメッセージの数
+// --batch     send_all()とreceive_many()でまとめる数
+//
+// まとめて送ると、mpscはtailのswapとReceiverを起こす確認がバッチごとに1回になり、
+// ring_channelはtailとheadの書き込みがバッチごとに1回になるので、
+// SenderとReceiverの間でキャッシュラインが行き来する回数が減る
+// ring_channelはスピンして待つので、CPUが1つしかないと相手に切り替わるまで進まず、差が出ない
+
+#[path = "../mpsc.rs"]
+mod mpsc;
+#[path = "../ring_channel.rs"]
+mod ring_channel;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Config {
+    senders: Vec<usize>,
+    messages: u64,
+    batch: usize,
+}
+
+fn parse_args() -> Config {
+    let mut config = Config {
+        senders: vec![1, 2, 4],
+        messages: 1_000_000,
+        batch: 64,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .unwrap_or_else(|| panic!("missing value for {arg}"));
+        match arg.as_str() {
+            "--senders" => {
+                config.senders = value
+                    .split(',')
+                    .map(|n| n.parse().expect("invalid --senders"))
+                    .collect()
+            }
+            "--messages" => config.messages = value.parse().expect("invalid --messages"),
+            "--batch" => config.batch = value.parse().expect("invalid --batch"),
+            _ => panic!("unknown option: {arg}"),
+        }
+    }
+    assert!(config.batch > 0, "--batch must be at least 1");
+    config
+}
+
+// batchが1なら1つずつ、そうでなければbatch個ずつまとめて送受信する
+fn run_mpsc(senders: usize, config: &Config, batch: usize) -> Duration {
+    let (sender, mut receiver) = mpsc::channel();
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..senders {
+            let sender = sender.clone();
+            s.spawn(move || {
+                if batch == 1 {
+                    for i in 0..config.messages {
+                        sender.send(i).unwrap();
+                    }
+                } else {
+                    let mut i = 0;
+                    while i < config.messages {
+                        let end = (i + batch as u64).min(config.messages);
+                        sender.send_all(i..end).unwrap();
+                        i = end;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let mut received = 0;
+        if batch == 1 {
+            while receiver.receive().is_some() {
+                received += 1;
+            }
+        } else {
+            let mut buf = Vec::with_capacity(batch);
+            loop {
+                let n = receiver.receive_many(&mut buf, batch);
+                if n == 0 {
+                    break;
+                }
+                received += n as u64;
+                buf.clear();
+            }
+        }
+        assert_eq!(received, config.messages * senders as u64);
+    });
+    start.elapsed()
+}
+
+fn run_ring(config: &Config, batch: usize) -> Duration {
+    let mut channel = ring_channel::Channel::<u64, 1024>::new();
+    let start = Instant::now();
+    thread::scope(|s| {
+        let (mut sender, mut receiver) = channel.split();
+        s.spawn(move || {
+            if batch == 1 {
+                for i in 0..config.messages {
+                    sender.send(i);
+                }
+            } else {
+                sender.send_all(0..config.messages);
+            }
+        });
+
+        let mut received = 0;
+        if batch == 1 {
+            while received < config.messages {
+                receiver.receive();
+                received += 1;
+            }
+        } else {
+            let mut buf = Vec::with_capacity(batch);
+            while received < config.messages {
+                received += receiver.receive_many(&mut buf, batch) as u64;
+                buf.clear();
+            }
+        }
+    });
+    start.elapsed()
+}
+
+fn print_row(variant: &str, senders: usize, total: u64, single: Duration, batched: Duration) {
+    let rate = |d: Duration| total as f64 / d.as_secs_f64() / 1e6;
+    println!(
+        "{:<8}{:>8}{:>14.2}{:>14.2}{:>10.2}",
+        variant,
+        senders,
+        rate(single),
+        rate(batched),
+        single.as_secs_f64() / batched.as_secs_f64()
+    );
+}
+
+fn main() {
+    let config = parse_args();
+    println!(
+        "messages per sender: {}, batch: {}",
+        config.messages, config.batch
+    );
+    println!(
+        "{:<8}{:>8}{:>14}{:>14}{:>10}",
+        "variant", "senders", "single(M/s)", "batch(M/s)", "speedup"
+    );
+    for &senders in &config.senders {
+        let single = run_mpsc(senders, &config, 1);
+        let batched = run_mpsc(senders, &config, config.batch);
+        print_row(
+            "mpsc",
+            senders,
+            config.messages * senders as u64,
+            single,
+            batched,
+        );
+    }
+    let single = run_ring(&config, 1);
+    let batched = run_ring(&config, config.batch);
+    print_row("ring", 1, config.messages, single, batched);
+}
@@ -0,0 +1,420 @@
+#![allow(dead_code)]
+
+// このクレートのチャネルとstd::sync::mpscを、スループットとレイテンシで比べる
+// cargo run --release --bin channel_bench -- --senders 1,2,4 --messages 1000000 --round-trips 100000
+//
+// --senders      スループットを測るSenderのスレッド数(カンマ区切りで複数指定できる)
+// --messages     Senderごとに送るメッセージの数
+// --round-trips  レイテンシを測るときに2つのスレッドの間で往復させる回数(oneshotで受け渡す回数も同じ)
+// --capacity     mpmc.rsの容量
+//
+// throughput: 複数のSenderから1つのReceiverに送り切るまでの、1秒あたりのメッセージ数
+//             mpsc.rsとmpmc.rsとstd::sync::mpsc::channel()を比べる
+// latency:    2つのチャネルで1つのメッセージを往復させたときの、1往復あたりの時間
+//             spsc_channel.rsとmpsc.rsとmpmc.rsとstd::sync::mpsc::channel()を比べる
+//             受け取る側は毎回parkしてunparkされるので、起床の速さもここに現れる
+// handoff:    oneshotの各実装で、送ってから別のスレッドが受け取るまでの時間
+//             1回きりのチャネルは往復させられないので、送った時刻をメッセージにして片道を測る
+
+#[path = "../cancellation_token.rs"]
+mod cancellation_token;
+#[path = "../mpmc.rs"]
+mod mpmc;
+#[path = "../mpsc.rs"]
+mod mpsc;
+#[path = "../oneshot_channel.rs"]
+mod oneshot_channel;
+#[path = "../oneshot_channel_ack.rs"]
+mod oneshot_channel_ack;
+#[path = "../oneshot_channel_arc.rs"]
+mod oneshot_channel_arc;
+#[path = "../oneshot_channel_async.rs"]
+mod oneshot_channel_async;
+#[path = "../oneshot_channel_lifetime.rs"]
+mod oneshot_channel_lifetime;
+#[path = "../oneshot_channel_multi_sender.rs"]
+mod oneshot_channel_multi_sender;
+#[path = "../oneshot_channel_no_std.rs"]
+mod oneshot_channel_no_std;
+#[path = "../oneshot_channel_nonblocking.rs"]
+mod oneshot_channel_nonblocking;
+#[path = "../oneshot_channel_static.rs"]
+mod oneshot_channel_static;
+#[path = "../spsc_channel.rs"]
+mod spsc_channel;
+#[path = "../waiter.rs"]
+mod waiter;
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Config {
+    senders: Vec<usize>,
+    messages: u64,
+    round_trips: u64,
+    capacity: usize,
+}
+
+fn parse_args() -> Config {
+    let mut config = Config {
+        senders: vec![1, 2, 4],
+        messages: 1_000_000,
+        round_trips: 100_000,
+        capacity: 1024,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .unwrap_or_else(|| panic!("missing value for {arg}"));
+        match arg.as_str() {
+            "--senders" => {
+                config.senders = value
+                    .split(',')
+                    .map(|n| n.parse().expect("invalid --senders"))
+                    .collect()
+            }
+            "--messages" => config.messages = value.parse().expect("invalid --messages"),
+            "--round-trips" => config.round_trips = value.parse().expect("invalid --round-trips"),
+            "--capacity" => config.capacity = value.parse().expect("invalid --capacity"),
+            _ => panic!("unknown option: {arg}"),
+        }
+    }
+    config
+}
+
+// 比べるチャネルの送受信だけをそろえる
+// spsc_channelのsend()とreceive()は&mut selfなので、どちらも&mut selfで呼ぶ
+trait BenchChannel {
+    const NAME: &'static str;
+    type Sender: Send;
+    type Receiver: Send;
+    fn channel(config: &Config) -> (Self::Sender, Self::Receiver);
+    fn send(sender: &mut Self::Sender, value: u64);
+    fn receive(receiver: &mut Self::Receiver) -> Option<u64>;
+}
+
+// Senderを複製できるチャネル
+trait BenchMpsc: BenchChannel {
+    fn clone_sender(sender: &Self::Sender) -> Self::Sender;
+}
+
+struct Mpsc;
+
+impl BenchChannel for Mpsc {
+    const NAME: &'static str = "mpsc";
+    type Sender = mpsc::Sender<u64>;
+    type Receiver = mpsc::Receiver<u64>;
+    fn channel(_: &Config) -> (Self::Sender, Self::Receiver) {
+        mpsc::channel()
+    }
+    fn send(sender: &mut Self::Sender, value: u64) {
+        sender.send(value).unwrap();
+    }
+    fn receive(receiver: &mut Self::Receiver) -> Option<u64> {
+        receiver.receive()
+    }
+}
+
+impl BenchMpsc for Mpsc {
+    fn clone_sender(sender: &Self::Sender) -> Self::Sender {
+        sender.clone()
+    }
+}
+
+struct Mpmc;
+
+impl BenchChannel for Mpmc {
+    const NAME: &'static str = "mpmc";
+    type Sender = mpmc::Sender<u64>;
+    type Receiver = mpmc::Receiver<u64>;
+    fn channel(config: &Config) -> (Self::Sender, Self::Receiver) {
+        mpmc::channel(config.capacity)
+    }
+    fn send(sender: &mut Self::Sender, value: u64) {
+        sender.send(value).unwrap();
+    }
+    fn receive(receiver: &mut Self::Receiver) -> Option<u64> {
+        receiver.receive()
+    }
+}
+
+impl BenchMpsc for Mpmc {
+    fn clone_sender(sender: &Self::Sender) -> Self::Sender {
+        sender.clone()
+    }
+}
+
+struct Spsc;
+
+impl BenchChannel for Spsc {
+    const NAME: &'static str = "spsc";
+    type Sender = spsc_channel::Sender<u64>;
+    type Receiver = spsc_channel::Receiver<u64>;
+    fn channel(_: &Config) -> (Self::Sender, Self::Receiver) {
+        spsc_channel::channel()
+    }
+    fn send(sender: &mut Self::Sender, value: u64) {
+        sender.send(value).unwrap();
+    }
+    fn receive(receiver: &mut Self::Receiver) -> Option<u64> {
+        receiver.receive()
+    }
+}
+
+struct Std;
+
+impl BenchChannel for Std {
+    const NAME: &'static str = "std";
+    type Sender = std_mpsc::Sender<u64>;
+    type Receiver = std_mpsc::Receiver<u64>;
+    fn channel(_: &Config) -> (Self::Sender, Self::Receiver) {
+        std_mpsc::channel()
+    }
+    fn send(sender: &mut Self::Sender, value: u64) {
+        sender.send(value).unwrap();
+    }
+    fn receive(receiver: &mut Self::Receiver) -> Option<u64> {
+        receiver.recv().ok()
+    }
+}
+
+impl BenchMpsc for Std {
+    fn clone_sender(sender: &Self::Sender) -> Self::Sender {
+        sender.clone()
+    }
+}
+
+fn throughput<C: BenchMpsc>(senders: usize, config: &Config) -> f64 {
+    let (sender, mut receiver) = C::channel(config);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..senders {
+            let mut sender = C::clone_sender(&sender);
+            s.spawn(move || {
+                for i in 0..config.messages {
+                    C::send(&mut sender, i);
+                }
+            });
+        }
+        drop(sender);
+        let mut received = 0;
+        while C::receive(&mut receiver).is_some() {
+            received += 1;
+        }
+        assert_eq!(received, config.messages * senders as u64);
+    });
+    let total = config.messages * senders as u64;
+    total as f64 / start.elapsed().as_secs_f64() / 1e6
+}
+
+fn latency<C: BenchChannel>(config: &Config) -> Duration {
+    let (mut ping, mut ping_receiver) = C::channel(config);
+    let (mut pong, mut pong_receiver) = C::channel(config);
+    let start = Instant::now();
+    thread::scope(|s| {
+        s.spawn(move || {
+            while let Some(i) = C::receive(&mut ping_receiver) {
+                C::send(&mut pong, i);
+            }
+        });
+        for i in 0..config.round_trips {
+            C::send(&mut ping, i);
+            assert_eq!(C::receive(&mut pong_receiver), Some(i));
+        }
+        // Senderをドロップして相手のスレッドを終わらせる
+        drop(ping);
+    });
+    start.elapsed() / config.round_trips as u32
+}
+
+// 1回きりのチャネルは使い回せないので、計測の前にすべて作っておく
+// Senderはまとめて別のスレッドに渡し、Receiverはチャネルを作ったこのスレッドで受け取る
+// (Receiverをほかのスレッドに渡せない実装があり、parkして待つのもsplit()したスレッドだけになる)
+// 送る側は前のメッセージが受け取られるまで待ってから、送った時刻をメッセージにして送る
+fn handoff<S: Send, R>(
+    pairs: Vec<(S, R)>,
+    send: impl Fn(S, Instant) + Sync,
+    receive: impl Fn(R) -> Instant,
+) -> Duration {
+    let rounds = pairs.len() as u32;
+    let (senders, receivers): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+    let received = AtomicUsize::new(0);
+    let mut total = Duration::ZERO;
+    thread::scope(|s| {
+        s.spawn(|| {
+            for (i, sender) in senders.into_iter().enumerate() {
+                while received.load(Acquire) < i {
+                    thread::yield_now();
+                }
+                send(sender, Instant::now());
+            }
+        });
+        for receiver in receivers {
+            total += receive(receiver).elapsed();
+            received.fetch_add(1, Release);
+        }
+    });
+    total / rounds
+}
+
+// 待たずに受け取る実装は、届くまでスレッドを譲りながら待つ
+fn wait_ready(is_ready: impl Fn() -> bool) {
+    while !is_ready() {
+        thread::yield_now();
+    }
+}
+
+fn handoff_all(rounds: usize) -> Vec<(&'static str, Duration)> {
+    let mut results = Vec::new();
+
+    let channels: Vec<_> = (0..rounds)
+        .map(|_| oneshot_channel::Channel::new())
+        .collect();
+    let pairs = channels.iter().map(|c| (c, c)).collect();
+    results.push((
+        "oneshot",
+        handoff(
+            pairs,
+            // チャネルごとに1回しか送らない
+            |c, t| unsafe { c.send(t) },
+            |c| {
+                wait_ready(|| c.is_ready());
+                c.receive()
+            },
+        ),
+    ));
+
+    let pairs = (0..rounds)
+        .map(|_| oneshot_channel_multi_sender::channel())
+        .collect();
+    results.push((
+        "multi_sender",
+        handoff(
+            pairs,
+            |s, t| s.send(t).ok().unwrap(),
+            |r| r.receive().unwrap(),
+        ),
+    ));
+
+    let pairs = (0..rounds)
+        .map(|_| oneshot_channel_arc::channel())
+        .collect();
+    results.push((
+        "arc",
+        handoff(
+            pairs,
+            |s, t| s.send(t).unwrap(),
+            |r| {
+                wait_ready(|| r.is_ready());
+                r.receive().unwrap()
+            },
+        ),
+    ));
+
+    let pairs = (0..rounds)
+        .map(|_| oneshot_channel_async::channel())
+        .collect();
+    results.push(("async", handoff(pairs, |s, t| s.send(t), |r| r.receive())));
+
+    let mut channels: Vec<_> = (0..rounds)
+        .map(|_| oneshot_channel_ack::Channel::new())
+        .collect();
+    let pairs = channels.iter_mut().map(|c| c.split()).collect();
+    results.push((
+        "ack",
+        handoff(
+            pairs,
+            // Ackは待たずに捨てる
+            |s, t| drop(s.send(t)),
+            |r| r.receive().unwrap(),
+        ),
+    ));
+
+    // split()に&'staticが要るので、チャネルはリークさせる
+    let pairs = (0..rounds)
+        .map(|_| Box::leak(Box::new(oneshot_channel_static::Channel::new())).split())
+        .collect();
+    results.push((
+        "static",
+        handoff(pairs, |s, t| s.send(t), |r| r.receive().unwrap()),
+    ));
+
+    let mut channels: Vec<_> = (0..rounds)
+        .map(|_| oneshot_channel_lifetime::Channel::new())
+        .collect();
+    let pairs = channels.iter_mut().map(|c| c.split()).collect();
+    results.push((
+        "lifetime",
+        handoff(
+            pairs,
+            |s, t| s.send(t),
+            |r| {
+                wait_ready(|| r.is_ready());
+                r.receive().unwrap()
+            },
+        ),
+    ));
+
+    let mut channels: Vec<_> = (0..rounds)
+        .map(|_| oneshot_channel_nonblocking::Channel::new())
+        .collect();
+    let pairs = channels.iter_mut().map(|c| c.split()).collect();
+    results.push((
+        "nonblocking",
+        handoff(pairs, |s, t| s.send(t), |r| r.receive().unwrap()),
+    ));
+
+    // 既定のSpinは空いているCPUがないと進まないので、譲りながら待つ
+    let mut channels: Vec<_> = (0..rounds)
+        .map(|_| {
+            oneshot_channel_no_std::Channel::with_hook(oneshot_channel_no_std::FnHook {
+                wait: thread::yield_now,
+                wake: || {},
+            })
+        })
+        .collect();
+    let pairs = channels.iter_mut().map(|c| c.split()).collect();
+    results.push((
+        "no_std",
+        handoff(pairs, |s, t| s.send(t), |r| r.receive().unwrap()),
+    ));
+
+    results
+}
+
+fn main() {
+    let config = parse_args();
+    println!("messages per sender: {}", config.messages);
+    println!("{:<8}{:>8}{:>14}", "variant", "senders", "M msg/s");
+    for &senders in &config.senders {
+        for (name, rate) in [
+            (Mpsc::NAME, throughput::<Mpsc>(senders, &config)),
+            (Mpmc::NAME, throughput::<Mpmc>(senders, &config)),
+            (Std::NAME, throughput::<Std>(senders, &config)),
+        ] {
+            println!("{name:<8}{senders:>8}{rate:>14.2}");
+        }
+    }
+
+    println!();
+    println!("round trips: {}", config.round_trips);
+    println!("{:<8}{:>16}", "variant", "round trip(ns)");
+    for (name, d) in [
+        (Spsc::NAME, latency::<Spsc>(&config)),
+        (Mpsc::NAME, latency::<Mpsc>(&config)),
+        (Mpmc::NAME, latency::<Mpmc>(&config)),
+        (Std::NAME, latency::<Std>(&config)),
+    ] {
+        println!("{name:<8}{:>16}", d.as_nanos());
+    }
+
+    println!();
+    println!("{:<14}{:>14}", "oneshot", "handoff(ns)");
+    for (name, d) in handoff_all(config.round_trips as usize) {
+        println!("{name:<14}{:>14}", d.as_nanos());
+    }
+}
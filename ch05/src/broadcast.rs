@@ -0,0 +1,388 @@
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+
+// 送ったメッセージをすべてのReceiverが受け取るチャネル
+// メッセージは容量capacityのリングバッファに書き込み、Receiverはそれぞれ次に読む位置を持つ
+// 遅いReceiverを待たずに古いメッセージを上書きするので、送る側がブロックされることはない
+// 上書きされて読めなかったReceiverには、読み飛ばした数をErr(Lagged)で知らせる
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "capacity must be at least 1");
+    let a = Arc::new(Channel {
+        buffer: (0..capacity)
+            .map(|_| {
+                RwLock::new(Slot {
+                    pos: 0,
+                    value: None,
+                })
+            })
+            .collect(),
+        state: Mutex::new(State {
+            tail: 0,
+            senders: 1,
+            receivers: 1,
+            closed: false,
+        }),
+        sent: Condvar::new(),
+    });
+    (
+        Sender { channel: a.clone() },
+        Receiver {
+            channel: a,
+            next: 0,
+        },
+    )
+}
+
+struct Channel<T> {
+    // pos番目のメッセージはbuffer[pos % capacity]に書き込む
+    buffer: Box<[RwLock<Slot<T>>]>,
+    state: Mutex<State>,
+    // メッセージが送られたか、最後のSenderがドロップされた
+    sent: Condvar,
+}
+
+struct Slot<T> {
+    // 書き込まれているメッセージの番号
+    pos: u64,
+    value: Option<T>,
+}
+
+struct State {
+    // 次に送るメッセージの番号
+    tail: u64,
+    senders: usize,
+    receivers: usize,
+    // Sender::close()で閉じられた
+    closed: bool,
+}
+
+impl State {
+    // これ以上メッセージが送られてこない
+    fn is_disconnected(&self) -> bool {
+        self.senders == 0 || self.closed
+    }
+}
+
+impl<T> Channel<T> {
+    fn slot(&self, pos: u64) -> &RwLock<Slot<T>> {
+        &self.buffer[(pos % self.buffer.len() as u64) as usize]
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReceiveError {
+    // 上書きされて読めなかったメッセージの数
+    // 次のreceive()はバッファに残っている一番古いメッセージから受け取る
+    Lagged(u64),
+    // すべてのSenderがドロップされるかclose()され、残りのメッセージもすべて受け取った
+    Disconnected,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryReceiveError {
+    Empty,
+    Lagged(u64),
+    Disconnected,
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    // Receiverが1つもなければ、誰も読まないのでメッセージを返す
+    // close()された後も送らずに返す
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let mut state = self.channel.state.lock().unwrap();
+        if state.receivers == 0 || state.closed {
+            return Err(value);
+        }
+        // stateをロックしている間に書き込むので、メッセージの番号は送った順に並ぶ
+        let pos = state.tail;
+        *self.channel.slot(pos).write().unwrap() = Slot {
+            pos,
+            value: Some(value),
+        };
+        state.tail += 1;
+        drop(state);
+        self.channel.sent.notify_all();
+        Ok(())
+    }
+
+    // チャネルを閉じる。すべてのSenderをドロップしなくても、Receiverに終わりを伝えられる
+    // Receiverはバッファに残っているメッセージを受け取り終えてからErr(Disconnected)を返す
+    pub fn close(&self) {
+        self.channel.state.lock().unwrap().closed = true;
+        self.channel.sent.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.channel.state.lock().unwrap().closed
+    }
+
+    // これから送るメッセージを受け取るReceiverを作る
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut state = self.channel.state.lock().unwrap();
+        state.receivers += 1;
+        Receiver {
+            channel: self.channel.clone(),
+            next: state.tail,
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.state.lock().unwrap().senders += 1;
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.channel.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            // 待っているReceiverを起こしてErr(Disconnected)を返させる
+            self.channel.sent.notify_all();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+    // 次に受け取るメッセージの番号
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    // メッセージが届くまで待つ
+    pub fn receive(&mut self) -> Result<T, ReceiveError> {
+        loop {
+            match self.try_receive() {
+                Ok(value) => return Ok(value),
+                Err(TryReceiveError::Lagged(n)) => return Err(ReceiveError::Lagged(n)),
+                Err(TryReceiveError::Disconnected) => return Err(ReceiveError::Disconnected),
+                Err(TryReceiveError::Empty) => {
+                    let state = self.channel.state.lock().unwrap();
+                    // try_receive()の後で送られていれば待たずに読み直す
+                    let next = self.next;
+                    drop(
+                        self.channel
+                            .sent
+                            .wait_while(state, |s| s.tail == next && !s.is_disconnected())
+                            .unwrap(),
+                    );
+                }
+            }
+        }
+    }
+
+    // 待たずに受け取る
+    pub fn try_receive(&mut self) -> Result<T, TryReceiveError> {
+        loop {
+            let slot = self.channel.slot(self.next).read().unwrap();
+            if slot.value.is_some() && slot.pos == self.next {
+                self.next += 1;
+                return Ok(slot.value.clone().unwrap());
+            }
+            // 同じ場所に新しいメッセージが書き込まれていれば追い越されている
+            let overtaken = slot.value.is_some() && slot.pos > self.next;
+            // send()はstate、スロットの順にロックするので、スロットを手放してからstateをロックする
+            drop(slot);
+            let state = self.channel.state.lock().unwrap();
+            if !overtaken {
+                if state.tail == self.next {
+                    return Err(if state.is_disconnected() {
+                        TryReceiveError::Disconnected
+                    } else {
+                        TryReceiveError::Empty
+                    });
+                }
+                // スロットを読んだ後でstateをロックするまでに送られたので、読み直す
+                continue;
+            }
+            // バッファに残っている一番古いメッセージまで進める
+            let oldest = state.tail.saturating_sub(self.channel.buffer.len() as u64);
+            let missed = oldest - self.next;
+            self.next = oldest;
+            return Err(TryReceiveError::Lagged(missed));
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    // 複製したReceiverは、元のReceiverがまだ受け取っていないメッセージから受け取る
+    fn clone(&self) -> Self {
+        self.channel.state.lock().unwrap().receivers += 1;
+        Self {
+            channel: self.channel.clone(),
+            next: self.next,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.state.lock().unwrap().receivers -= 1;
+    }
+}
+
+// receive()を繰り返す。Laggedは飛ばすので、受け取れたメッセージだけが順に返る
+// 最後のSenderがドロップされるかclose()され、残りのメッセージをすべて受け取ったら終わる
+pub struct Iter<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+pub struct IntoIter<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T: Clone> Receiver<T> {
+    pub fn iter(&mut self) -> Iter<'_, T> {
+        Iter { receiver: self }
+    }
+}
+
+impl<T: Clone> Iterator for Iter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        // 追い越されて読めなかったメッセージは飛ばして続ける
+        loop {
+            match self.receiver.receive() {
+                Ok(value) => return Some(value),
+                Err(ReceiveError::Lagged(_)) => continue,
+                Err(ReceiveError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+impl<T: Clone> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        // 追い越されて読めなかったメッセージは飛ばして続ける
+        loop {
+            match self.receiver.receive() {
+                Ok(value) => return Some(value),
+                Err(ReceiveError::Lagged(_)) => continue,
+                Err(ReceiveError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+impl<T: Clone> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { receiver: self }
+    }
+}
+
+impl<'a, T: Clone> IntoIterator for &'a mut Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+#[test]
+fn test_broadcast() {
+    use std::thread;
+
+    let (sender, receiver) = channel(16);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let mut receiver = receiver.clone();
+            s.spawn(move || {
+                // 追い越されなければ、すべてのReceiverがすべてのメッセージを順に受け取る
+                let mut expected = 0;
+                loop {
+                    match receiver.receive() {
+                        Ok(i) => {
+                            assert_eq!(i, expected);
+                            expected += 1;
+                        }
+                        Err(ReceiveError::Lagged(n)) => expected += n,
+                        Err(ReceiveError::Disconnected) => break,
+                    }
+                }
+                assert_eq!(expected, 1000);
+            });
+        }
+        drop(receiver);
+        s.spawn(move || {
+            for i in 0..1000 {
+                sender.send(i).unwrap();
+            }
+        });
+    });
+}
+
+#[test]
+fn test_lagged() {
+    let (sender, mut receiver) = channel(4);
+    for i in 0..10 {
+        sender.send(i).unwrap();
+    }
+    // 0から5は上書きされた
+    assert_eq!(receiver.try_receive(), Err(TryReceiveError::Lagged(6)));
+    for i in 6..10 {
+        assert_eq!(receiver.try_receive(), Ok(i));
+    }
+    assert_eq!(receiver.try_receive(), Err(TryReceiveError::Empty));
+
+    let mut late = sender.subscribe();
+    sender.send(10).unwrap();
+    assert_eq!(late.receive(), Ok(10));
+    drop(sender);
+    assert_eq!(receiver.receive(), Ok(10));
+    assert_eq!(receiver.receive(), Err(ReceiveError::Disconnected));
+
+    // 追い越された分を飛ばして、残っているメッセージを最後まで受け取る
+    let (sender, receiver) = channel(2);
+    for i in 0..5 {
+        sender.send(i).unwrap();
+    }
+    drop(sender);
+    assert_eq!(receiver.into_iter().collect::<Vec<_>>(), [3, 4]);
+
+    // Receiverがいなければメッセージは返される
+    let (sender, receiver) = channel(1);
+    drop(receiver);
+    assert_eq!(sender.send(1), Err(1));
+}
+
+#[test]
+fn test_close() {
+    use std::thread;
+
+    let (sender, mut receiver) = channel(16);
+    let other = sender.clone();
+    thread::scope(|s| {
+        s.spawn(|| {
+            for i in 0..10 {
+                sender.send(i).unwrap();
+            }
+            // ほかのSenderが残っていても終わりを伝えられる
+            sender.close();
+            assert_eq!(sender.send(10), Err(10));
+        });
+        // 閉じる前に送られたメッセージはすべて受け取れる
+        assert_eq!(
+            receiver.iter().collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+    });
+    assert!(other.is_closed());
+    assert_eq!(other.send(20), Err(20));
+    assert_eq!(receiver.try_receive(), Err(TryReceiveError::Disconnected));
+}
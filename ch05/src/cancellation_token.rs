@@ -0,0 +1,89 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread, ThreadId};
+
+// 待っているスレッドにキャンセルを伝えるためのトークン
+// 複製したトークンは同じ状態を共有するので、どれか1つでcancel()すればすべてに伝わる
+// parkして待つ側はregister()で自分を登録しておき、cancel()でunparkしてもらう
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    cancelled: AtomicBool,
+    // cancel()されたら起こすスレッド
+    waiters: Mutex<Vec<Thread>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                cancelled: AtomicBool::new(false),
+                waiters: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Release);
+        for t in self.inner.waiters.lock().unwrap().drain(..) {
+            t.unpark();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Acquire)
+    }
+
+    // 今のスレッドを、cancel()されたらunparkされるように登録する
+    // 登録した後でis_cancelled()を確認すれば、その間にcancel()されても取りこぼさない
+    // (cancel()はフラグを立ててからwaitersをロックするので、ロックの後ならフラグが見える)
+    pub fn register(&self) -> Registration<'_> {
+        let thread = thread::current();
+        let id = thread.id();
+        self.inner.waiters.lock().unwrap().push(thread);
+        Registration { token: self, id }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ドロップすると登録を解除する
+pub struct Registration<'a> {
+    token: &'a CancellationToken,
+    id: ThreadId,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut waiters = self.token.inner.waiters.lock().unwrap();
+        if let Some(i) = waiters.iter().position(|t| t.id() == self.id) {
+            waiters.swap_remove(i);
+        }
+    }
+}
+
+#[test]
+fn test_cancellation_token() {
+    let token = CancellationToken::new();
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let token = token.clone();
+            s.spawn(move || {
+                let _registration = token.register();
+                while !token.is_cancelled() {
+                    thread::park();
+                }
+            });
+        }
+        token.cancel();
+    });
+    assert!(token.inner.waiters.lock().unwrap().is_empty());
+}
@@ -0,0 +1,245 @@
+use crate::waiter::{AsyncWaker, BlockingWaiter, Park, Waiter};
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicPtr, AtomicUsize};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// 最新の値だけを残すチャネル
+// send()はまだ受け取られていない前の値を新しい値で置き換えてドロップする
+// センサーの値や状態のように、途中の古い値を受け取っても意味がないときに使う
+//
+// 値はBoxに入れて、ポインタをswapするだけで置き換える
+// 置き換えた側が古いBoxを持つことになるので、同じ値を2回ドロップしたり読んだりすることはない
+//
+// Receiverの待ち方はWで選ぶ(waiter.rs)。channel()はスレッドをparkする
+// channel_with::<T, Spin>()ならスピンし、AsyncWakerならpoll_receive()で非同期に待つ
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    channel_with()
+}
+
+pub fn channel_with<T, W: Waiter>() -> (Sender<T, W>, Receiver<T, W>) {
+    let a = Arc::new(Channel {
+        slot: AtomicPtr::new(ptr::null_mut()),
+        senders: AtomicUsize::new(1),
+        waiter: W::default(),
+        _owns: PhantomData,
+    });
+    (Sender { channel: a.clone() }, Receiver { channel: a })
+}
+
+struct Channel<T, W> {
+    // 受け取られていない値。なければnull
+    slot: AtomicPtr<T>,
+    // 生きているSenderの数
+    senders: AtomicUsize,
+    // slotやsendersを書き換えたら起こす
+    waiter: W,
+    // AtomicPtrはTに関わらずSendとSyncになるので、自動の実装を止めて下で条件を付け直す
+    _owns: PhantomData<*mut T>,
+}
+
+// 値はSenderのスレッドからReceiverのスレッドに移るので、TがSendであればよい
+unsafe impl<T, W> Send for Channel<T, W>
+where
+    T: Send,
+    W: Send,
+{
+}
+unsafe impl<T, W> Sync for Channel<T, W>
+where
+    T: Send,
+    W: Sync,
+{
+}
+
+impl<T, W> Channel<T, W> {
+    // 値が届いているか、すべてのSenderがドロップされていればtrue
+    fn ready(&self) -> bool {
+        !self.slot.load(Acquire).is_null() || self.senders.load(Acquire) == 0
+    }
+
+    // 取り出すものがなくなるまで待った後で呼ぶ
+    // ドロップされる前に送られた値が残っているかもしれないので、もう一度取り出してみる
+    fn take_or_disconnected(&self) -> Poll<Option<T>> {
+        if let Some(value) = self.take() {
+            return Poll::Ready(Some(value));
+        }
+        if self.senders.load(Acquire) == 0 {
+            return Poll::Ready(self.take());
+        }
+        Poll::Pending
+    }
+
+    fn take(&self) -> Option<T> {
+        let p = self.slot.swap(ptr::null_mut(), Acquire);
+        if p.is_null() {
+            return None;
+        }
+        Some(*unsafe { Box::from_raw(p) })
+    }
+}
+
+impl<T, W> Drop for Channel<T, W> {
+    fn drop(&mut self) {
+        let p = *self.slot.get_mut();
+        if !p.is_null() {
+            drop(unsafe { Box::from_raw(p) });
+        }
+    }
+}
+
+pub struct Sender<T, W: Waiter = Park> {
+    channel: Arc<Channel<T, W>>,
+}
+
+impl<T, W: Waiter> Sender<T, W> {
+    // 受け取られていない値があれば、それを置き換えてドロップする
+    pub fn send(&self, value: T) {
+        let new = Box::into_raw(Box::new(value));
+        // Releaseで新しい値をReceiverに見せ、Acquireで置き換えた値を読めるようにする
+        let old = self.channel.slot.swap(new, AcqRel);
+        if !old.is_null() {
+            drop(unsafe { Box::from_raw(old) });
+        }
+        self.channel.waiter.wake();
+    }
+}
+
+impl<T, W: Waiter> Clone for Sender<T, W> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Relaxed);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T, W: Waiter> Drop for Sender<T, W> {
+    fn drop(&mut self) {
+        if self.channel.senders.fetch_sub(1, Release) == 1 {
+            self.channel.waiter.wake();
+        }
+    }
+}
+
+pub struct Receiver<T, W: Waiter = Park> {
+    channel: Arc<Channel<T, W>>,
+}
+
+impl<T, W: Waiter> Receiver<T, W> {
+    // 待たずに最新の値を取り出す。前回から送られていなければNone
+    pub fn try_receive(&mut self) -> Option<T> {
+        self.channel.take()
+    }
+}
+
+impl<T, W: BlockingWaiter> Receiver<T, W> {
+    // 値が送られるまで待ち、最新の値を取り出す
+    // すべてのSenderがドロップされ、値も残っていなければNoneを返す
+    pub fn receive(&mut self) -> Option<T> {
+        let channel = &*self.channel;
+        loop {
+            if let Poll::Ready(value) = channel.take_or_disconnected() {
+                return value;
+            }
+            channel.waiter.wait_until(|| channel.ready());
+        }
+    }
+}
+
+impl<T> Receiver<T, AsyncWaker> {
+    // receive()の非同期版。値がなければcxのWakerを登録してPendingを返す
+    pub fn poll_receive(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let channel = &*self.channel;
+        loop {
+            if let Poll::Ready(value) = channel.take_or_disconnected() {
+                return Poll::Ready(value);
+            }
+            if channel
+                .waiter
+                .poll_until(cx, || channel.ready())
+                .is_pending()
+            {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_overwrite() {
+    use std::sync::atomic::AtomicUsize;
+
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+    #[derive(Debug)]
+    struct DetectDrop(i32);
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    let (sender, mut receiver) = channel();
+    sender.send(DetectDrop(1));
+    sender.send(DetectDrop(2));
+    // 受け取られなかった1は置き換えられた時点でドロップされる
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    sender.send(DetectDrop(3));
+    assert_eq!(NUM_DROPS.load(Relaxed), 2);
+    assert_eq!(receiver.try_receive().map(|d| d.0), Some(3));
+    assert_eq!(NUM_DROPS.load(Relaxed), 3);
+    assert!(receiver.try_receive().is_none());
+
+    // 受け取られずに残った値はチャネルと一緒にドロップされる
+    sender.send(DetectDrop(4));
+    drop(sender);
+    drop(receiver);
+    assert_eq!(NUM_DROPS.load(Relaxed), 4);
+}
+
+#[test]
+fn test_receive() {
+    use std::thread;
+
+    let (sender, mut receiver) = channel();
+    thread::scope(|s| {
+        s.spawn(move || {
+            for i in 1..=100 {
+                sender.send(i);
+            }
+        });
+        // 途中の値は飛ばされることがあるが、順番が戻ることはなく、最後の値は必ず受け取れる
+        let mut last = 0;
+        while let Some(i) = receiver.receive() {
+            assert!(i > last);
+            last = i;
+        }
+        assert_eq!(last, 100);
+    });
+}
+
+#[test]
+fn test_waiters() {
+    use crate::waiter::Spin;
+    use std::task::Waker;
+    use std::thread;
+
+    // 同じ手順のまま、スピンして待つ
+    let (sender, mut receiver) = channel_with::<i32, Spin>();
+    thread::scope(|s| {
+        s.spawn(move || sender.send(1));
+        assert_eq!(receiver.receive(), Some(1));
+        assert_eq!(receiver.receive(), None);
+    });
+
+    // 非同期に待つ。何もしないWakerでも、届いた後にもう一度pollすれば受け取れる
+    let (sender, mut receiver) = channel_with::<i32, AsyncWaker>();
+    let mut cx = Context::from_waker(Waker::noop());
+    assert_eq!(receiver.poll_receive(&mut cx), Poll::Pending);
+    sender.send(2);
+    assert_eq!(receiver.poll_receive(&mut cx), Poll::Ready(Some(2)));
+    drop(sender);
+    assert_eq!(receiver.poll_receive(&mut cx), Poll::Ready(None));
+}
@@ -1,21 +1,44 @@
+#![allow(dead_code)]
+
 use crate::oneshot_channel_nonblocking::Channel;
+use crate::scoped_channel::scoped_channel;
 use std::thread;
 
 //
+mod broadcast;
+mod cancellation_token;
+mod mailbox;
+mod mpmc;
+mod mpsc;
 // mod oneshot_channel;
-// mod oneshot_channel_arc;
-// mod oneshot_channel_lifetime;
+mod oneshot_channel_ack;
+mod oneshot_channel_arc;
+mod oneshot_channel_async;
+mod oneshot_channel_lifetime;
+mod oneshot_channel_multi_sender;
+mod oneshot_channel_no_std;
 mod oneshot_channel_nonblocking;
+mod oneshot_channel_static;
+#[cfg(unix)]
+mod pollable;
+mod request_response;
+mod ring_channel;
+mod scoped_channel;
+mod select;
 // mod simple_channel;
+mod spsc_channel;
+#[cfg(test)]
+mod stress;
+mod waiter;
+mod watch;
 
 fn main() {
     println!("Hello, world!");
     let mut channel = Channel::new();
     thread::scope(|s| {
-        let (sender, receiver) = channel.split();
-        s.spawn(move || {
+        let (_, receiver) = scoped_channel(s, &mut channel).spawn_sender(|sender| {
             sender.send("hello world!!");
         });
-        assert_eq!(receiver.receive(), "hello world!!");
+        assert_eq!(receiver.receive(), Ok("hello world!!"));
     });
 }
@@ -0,0 +1,566 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{fence, AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+// 複数のSenderから複数のReceiverに送る、容量に上限のあるチャネル
+// 固定長の配列をリングバッファとして使い、各スロットのseqで空か埋まっているかを表す(Dmitry Vyukovの有界MPMCキュー)
+// SenderどうしとReceiverどうしはtailとheadのCASで位置を取り合うだけなのでロックしない
+// 満杯ならsend()が、空ならreceive()がスレッドをparkして待つ
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0);
+    let a = Arc::new(Channel {
+        buffer: (0..capacity)
+            .map(|i| Slot {
+                seq: AtomicUsize::new(i.wrapping_mul(2)),
+                message: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect(),
+        tail: AtomicUsize::new(0),
+        head: AtomicUsize::new(0),
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+        send_waiters: Waiters::new(),
+        receive_waiters: Waiters::new(),
+    });
+    (Sender { channel: a.clone() }, Receiver { channel: a })
+}
+
+struct Slot<T> {
+    // 位置posに対して
+    // 2 * pos: 空いていて、posへの書き込みを待っている
+    // 2 * pos + 1: posのメッセージが入っている
+    // 読み出したら2 * (pos + capacity)にして次の周回の書き込みを待つ
+    // 2倍しておくことで、容量が1でも前の周回の「埋まっている」と次の周回の「空いている」が重ならない
+    seq: AtomicUsize,
+    message: UnsafeCell<MaybeUninit<T>>,
+}
+
+struct Channel<T> {
+    buffer: Box<[Slot<T>]>,
+    // 次に書き込む位置
+    tail: AtomicUsize,
+    // 次に読み出す位置
+    head: AtomicUsize,
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+    // Sender::close()で閉じられた
+    closed: AtomicBool,
+    // 空きを待つSender
+    send_waiters: Waiters,
+    // メッセージを待つReceiver
+    receive_waiters: Waiters,
+}
+
+// スロットへの書き込みと読み出しはseqで順序づけられている
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+// 待機しているスレッドの一覧
+// 起こすときは全員を起こし、それぞれが状態を確かめ直す
+// 1人だけ起こすと、登録したままparkせずに戻ったスレッドを選んでしまい、ほかの待機者が眠り続けることがある
+struct Waiters {
+    // falseなら、相手はロックせずに起こすのを省略する
+    parked: AtomicBool,
+    threads: Mutex<Vec<Thread>>,
+}
+
+impl Waiters {
+    const fn new() -> Self {
+        Self {
+            parked: AtomicBool::new(false),
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    // 登録してからreadyを確かめ直し、まだならparkする
+    // deadlineがあれば、それまでしかparkしない。戻った後は呼び出し側がreadyと時刻を確かめ直す
+    fn wait(&self, ready: impl Fn() -> bool, deadline: Option<Instant>) {
+        self.threads.lock().unwrap().push(thread::current());
+        self.parked.store(true, Relaxed);
+        // wake()のfenceと対になる
+        // どちらかが必ず相手の書き込みを見るので、起こされずに眠り続けることはない
+        fence(SeqCst);
+        if ready() {
+            return;
+        }
+        match deadline {
+            None => thread::park(),
+            // 登録は残るが、次のwake()で余分にunparkされるだけなので問題ない
+            Some(deadline) => {
+                thread::park_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+        }
+    }
+
+    fn wake(&self) {
+        fence(SeqCst);
+        if self.parked.swap(false, Relaxed) {
+            for t in self.threads.lock().unwrap().drain(..) {
+                t.unpark();
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    // 空きがない
+    Full(T),
+    // Receiverがすべてドロップされたか、close()された
+    Disconnected(T),
+}
+
+// どちらの場合も送れなかったメッセージを返す
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendTimeoutError<T> {
+    // 時間内に空きができなかった
+    Timeout(T),
+    // Receiverがすべてドロップされたか、close()された
+    Disconnected(T),
+}
+
+impl<T> Channel<T> {
+    fn slot(&self, pos: usize) -> &Slot<T> {
+        &self.buffer[pos % self.buffer.len()]
+    }
+
+    // 満杯ならメッセージを返す
+    fn push(&self, message: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Relaxed);
+        loop {
+            let slot = self.slot(pos);
+            // AcquireはReceiverが読み出した後のReleaseと対応する
+            let seq = slot.seq.load(Acquire);
+            let diff = seq.wrapping_sub(pos.wrapping_mul(2)) as isize;
+            if diff == 0 {
+                // 空いているので位置を取り合う
+                match self
+                    .tail
+                    .compare_exchange_weak(pos, pos.wrapping_add(1), Relaxed, Relaxed)
+                {
+                    Ok(_) => {
+                        unsafe { (*slot.message.get()).write(message) };
+                        slot.seq.store(pos.wrapping_mul(2) + 1, Release);
+                        return Ok(());
+                    }
+                    Err(p) => pos = p,
+                }
+            } else if diff < 0 {
+                // 前の周回のメッセージがまだ読み出されていない
+                return Err(message);
+            } else {
+                // ほかのSenderが先に書き込んだ
+                pos = self.tail.load(Relaxed);
+            }
+        }
+    }
+
+    // 空ならNoneを返す
+    fn pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Relaxed);
+        loop {
+            let slot = self.slot(pos);
+            // AcquireはSenderが書き込んだ後のReleaseと対応する
+            let seq = slot.seq.load(Acquire);
+            let diff = seq.wrapping_sub(pos.wrapping_mul(2) + 1) as isize;
+            if diff == 0 {
+                match self
+                    .head
+                    .compare_exchange_weak(pos, pos.wrapping_add(1), Relaxed, Relaxed)
+                {
+                    Ok(_) => {
+                        let message = unsafe { (*slot.message.get()).assume_init_read() };
+                        let next = pos.wrapping_add(self.buffer.len()).wrapping_mul(2);
+                        slot.seq.store(next, Release);
+                        return Some(message);
+                    }
+                    Err(p) => pos = p,
+                }
+            } else if diff < 0 {
+                // まだ書き込まれていない
+                return None;
+            } else {
+                // ほかのReceiverが先に読み出した
+                pos = self.head.load(Relaxed);
+            }
+        }
+    }
+
+    // parkする前に確かめ直す
+    fn can_push(&self) -> bool {
+        let pos = self.tail.load(Relaxed);
+        let seq = self.slot(pos).seq.load(Acquire);
+        seq.wrapping_sub(pos.wrapping_mul(2)) as isize >= 0
+    }
+
+    // 受け取られていないメッセージのおおよその数
+    // tailを進めてから書き込むので、書き込み中のメッセージも数える
+    // headとtailは別々に読むので、その間にほかのスレッドが進めると一時的に範囲を外れることがある
+    // 負になったら0に、容量を超えたら容量にそろえる
+    fn len(&self) -> usize {
+        let head = self.head.load(Relaxed);
+        let tail = self.tail.load(Relaxed);
+        (tail.wrapping_sub(head) as isize).clamp(0, self.buffer.len() as isize) as usize
+    }
+
+    // これ以上メッセージが送られてこない
+    // 届いているメッセージを受け取り終えたら、receive()はNoneを返す
+    fn is_disconnected(&self) -> bool {
+        self.senders.load(Acquire) == 0 || self.closed.load(Acquire)
+    }
+
+    fn can_pop(&self) -> bool {
+        let pos = self.head.load(Relaxed);
+        let seq = self.slot(pos).seq.load(Acquire);
+        seq.wrapping_sub(pos.wrapping_mul(2) + 1) as isize >= 0
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        // 受け取られなかったメッセージをドロップする
+        while self.pop().is_some() {}
+    }
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    // 空きができるまで待つ
+    // Receiverがすべてドロップされていれば送らずにメッセージを返す
+    pub fn send(&self, message: T) -> Result<(), T> {
+        self.send_until(message, None).map_err(|e| match e {
+            SendTimeoutError::Disconnected(m) | SendTimeoutError::Timeout(m) => m,
+        })
+    }
+
+    // 空きができるのをtimeoutの間だけ待つ
+    // Receiverが遅れていると送る側も止まるので、待ち続けずに諦めたいときに使う
+    pub fn send_timeout(&self, message: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.send_until(message, Instant::now().checked_add(timeout))
+    }
+
+    // send_timeout()と同じだが、期限を時刻で指定する
+    // 複数のメッセージを送るときに、全体で同じ期限を使える
+    pub fn send_deadline(&self, message: T, deadline: Instant) -> Result<(), SendTimeoutError<T>> {
+        self.send_until(message, Some(deadline))
+    }
+
+    // deadlineがNoneならタイムアウトしない
+    fn send_until(
+        &self,
+        mut message: T,
+        deadline: Option<Instant>,
+    ) -> Result<(), SendTimeoutError<T>> {
+        let channel = &*self.channel;
+        loop {
+            match self.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(m)) => {
+                    return Err(SendTimeoutError::Disconnected(m))
+                }
+                Err(TrySendError::Full(m)) => message = m,
+            }
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return Err(SendTimeoutError::Timeout(message));
+            }
+            // wait()はparkする前にcan_push()で確かめ直す
+            channel.send_waiters.wait(
+                || {
+                    channel.can_push()
+                        || channel.receivers.load(Relaxed) == 0
+                        || channel.closed.load(Relaxed)
+                },
+                deadline,
+            );
+        }
+    }
+
+    // 待たずに送る
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        let channel = &*self.channel;
+        if channel.receivers.load(Relaxed) == 0 || self.is_closed() {
+            return Err(TrySendError::Disconnected(message));
+        }
+        channel.push(message).map_err(TrySendError::Full)?;
+        channel.receive_waiters.wake();
+        Ok(())
+    }
+}
+
+impl<T> Sender<T> {
+    // チャネルを閉じる。すべてのSenderをドロップしなくても、Receiverに終わりを伝えられる
+    // Receiverは残っているメッセージを受け取り終えてからNoneを返す
+    // 閉じた後のsend()は、空きを待っている途中のものも含めてErrを返す
+    // close()と同時に送ったメッセージは、Okでも受け取られないことがある
+    pub fn close(&self) {
+        self.channel.closed.store(true, Release);
+        self.channel.receive_waiters.wake();
+        self.channel.send_waiters.wake();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.channel.closed.load(Acquire)
+    }
+}
+
+// len()とis_empty()はSenderとReceiverのどちらからも呼べる
+impl<T> Sender<T> {
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        Some(self.channel.buffer.len())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Relaxed);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // 最後のSenderなら、待っているReceiverを起こしてNoneを返させる
+        if self.channel.senders.fetch_sub(1, Release) == 1 {
+            self.channel.receive_waiters.wake();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    // メッセージが届くまで待つ
+    // すべてのSenderがドロップされるかclose()され、残っているメッセージもなければNoneを返す
+    pub fn receive(&self) -> Option<T> {
+        let channel = &*self.channel;
+        loop {
+            if let Some(message) = self.try_receive() {
+                return Some(message);
+            }
+            if channel.is_disconnected() {
+                // 最後のSenderがドロップされる前に送ったメッセージが残っているかもしれない
+                return self.try_receive();
+            }
+            channel
+                .receive_waiters
+                .wait(|| channel.can_pop() || channel.is_disconnected(), None);
+        }
+    }
+
+    // 待たずに受け取る
+    pub fn try_receive(&self) -> Option<T> {
+        let message = self.channel.pop()?;
+        self.channel.send_waiters.wake();
+        Some(message)
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        Some(self.channel.buffer.len())
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receivers.fetch_add(1, Relaxed);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // 最後のReceiverなら、空きを待っているSenderを起こしてErrを返させる
+        if self.channel.receivers.fetch_sub(1, Relaxed) == 1 {
+            self.channel.send_waiters.wake();
+        }
+    }
+}
+
+// receive()を繰り返す。すべてのSenderがドロップされるかclose()され、受け取るメッセージがなくなったら終わる
+// receive()は&selfで呼べるので、&Receiverからも繰り返せる
+// 複数のReceiverで繰り返すと、メッセージはどれか1つのReceiverにだけ渡る
+pub struct Iter<'a, T> {
+    receiver: &'a Receiver<T>,
+}
+
+pub struct IntoIter<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> Receiver<T> {
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { receiver: self }
+    }
+}
+
+impl<T> Iterator for Iter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.receive()
+    }
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.receive()
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { receiver: self }
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+#[test]
+fn test_mpmc() {
+    let (sender, receiver) = channel(4);
+    let total = thread::scope(|s| {
+        for t in 0..4 {
+            let sender = sender.clone();
+            s.spawn(move || {
+                for i in 0..1000 {
+                    sender.send(t * 1000 + i).unwrap();
+                }
+            });
+        }
+        drop(sender);
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let receiver = receiver.clone();
+                s.spawn(move || receiver.into_iter().sum::<usize>())
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .sum::<usize>()
+    });
+    // すべてのメッセージがちょうど1度ずつ受け取られた
+    assert_eq!(total, (0..4000).sum());
+}
+
+#[test]
+fn test_try_send() {
+    let (sender, receiver) = channel(1);
+    assert!(receiver.is_empty());
+    assert_eq!(sender.try_send(1), Ok(()));
+    assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+    assert_eq!((sender.len(), receiver.capacity()), (1, Some(1)));
+    assert_eq!(receiver.try_receive(), Some(1));
+    assert_eq!(receiver.try_receive(), None);
+    assert!(sender.is_empty());
+
+    // 受け取られなかったメッセージはチャネルと一緒にドロップされる
+    let (sender, receiver) = channel(1);
+    let message = Arc::new(());
+    sender.send(message.clone()).unwrap();
+    drop(receiver);
+    assert!(matches!(
+        sender.try_send(Arc::new(())),
+        Err(TrySendError::Disconnected(_))
+    ));
+    drop(sender);
+    assert_eq!(Arc::strong_count(&message), 1);
+}
+
+#[test]
+fn test_send_timeout() {
+    let (sender, receiver) = channel(1);
+    sender.send(1).unwrap();
+    // 受け取られるまで空きがない
+    let timeout = Duration::from_millis(20);
+    let start = Instant::now();
+    assert_eq!(
+        sender.send_timeout(2, timeout),
+        Err(SendTimeoutError::Timeout(2))
+    );
+    assert!(start.elapsed() >= timeout);
+    assert_eq!(
+        sender.send_deadline(2, Instant::now()),
+        Err(SendTimeoutError::Timeout(2))
+    );
+
+    // 待っている間に受け取られれば送れる
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(receiver.receive(), Some(1));
+        });
+        assert_eq!(sender.send_timeout(2, Duration::from_secs(10)), Ok(()));
+    });
+    drop(receiver);
+    assert_eq!(
+        sender.send_timeout(3, Duration::from_secs(10)),
+        Err(SendTimeoutError::Disconnected(3))
+    );
+}
+
+#[test]
+fn test_close() {
+    let (sender, receiver) = channel(4);
+    let other = sender.clone();
+    thread::scope(|s| {
+        s.spawn(move || {
+            for i in 0..10 {
+                sender.send(i).unwrap();
+            }
+            // ほかのSenderが残っていても終わりを伝えられる
+            sender.close();
+            assert_eq!(sender.send(10), Err(10));
+        });
+        // 閉じる前に送られたメッセージはすべて受け取れる
+        assert_eq!(
+            (&receiver).into_iter().collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+    });
+    assert!(other.is_closed());
+    assert_eq!(other.try_send(20), Err(TrySendError::Disconnected(20)));
+}
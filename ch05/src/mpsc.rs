@@ -0,0 +1,605 @@
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, Thread};
+
+// 複数のSenderから1つのReceiverに送る、上限のないチャネル
+// メッセージは連結リストでつなぐ(Dmitry VyukovのMPSCキュー)
+// send()はtailをswapしてつなぐだけなのでロックも待機もしない
+// receive()はリストが空ならスレッドをparkして待つ
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    // headは常に受け取り済みのノード(最初はダミー)を指し、次のノードから受け取る
+    let stub = Box::into_raw(Box::new(Node {
+        next: AtomicPtr::new(ptr::null_mut()),
+        message: None,
+    }));
+    let a = Arc::new(Channel {
+        head: UnsafeCell::new(stub),
+        tail: AtomicPtr::new(stub),
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+        sent: AtomicUsize::new(0),
+        received: AtomicUsize::new(0),
+        receiver_parked: AtomicBool::new(false),
+        receiver_wakeup: Mutex::new(None),
+    });
+    (Sender { channel: a.clone() }, Receiver { channel: a })
+}
+
+struct Node<T> {
+    next: AtomicPtr<Node<T>>,
+    message: Option<T>,
+}
+
+struct Channel<T> {
+    // Receiverだけが読み書きする
+    head: UnsafeCell<*mut Node<T>>,
+    // 最後に追加されたノード
+    tail: AtomicPtr<Node<T>>,
+    // 生きているSenderの数。0になったらreceive()は待たずに返る
+    senders: AtomicUsize,
+    // どれかのSenderがclose()した。Senderが残っていても、0になったときと同じように扱う
+    closed: AtomicBool,
+    // len()のための、送った数と受け取った数
+    // sentはつなぐ前に増やし、receivedは外した後でReleaseで増やすので、receivedを先に読めばsent以下になる
+    sent: AtomicUsize,
+    received: AtomicUsize,
+    // Receiverがparkしようとしている
+    // falseならsend()はロックせずにunparkを省略する
+    receiver_parked: AtomicBool,
+    receiver_wakeup: Mutex<Option<Wakeup>>,
+}
+
+// 待っているReceiverの起こし方
+// receive()はスレッドをparkし、poll_receive()はタスクのWakerを登録して戻る
+enum Wakeup {
+    Thread(Thread),
+    Waker(Waker),
+}
+
+impl Wakeup {
+    fn wake(self) {
+        match self {
+            Wakeup::Thread(t) => t.unpark(),
+            Wakeup::Waker(w) => w.wake(),
+        }
+    }
+}
+
+// ノードは生ポインタでつなぐので自動では実装されない
+// TがSendであれば、どのスレッドから送っても受け取ってもよい
+unsafe impl<T> Send for Channel<T> where T: Send {}
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+enum Pop<T> {
+    Message(T),
+    Empty,
+    // Senderがtailをswapしてから前のノードのnextをつなぐまでの間
+    // すぐにつながるので、待機せずにもう一度試す
+    Inconsistent,
+}
+
+impl<T> Channel<T> {
+    fn new_node(message: T) -> *mut Node<T> {
+        Box::into_raw(Box::new(Node {
+            next: AtomicPtr::new(ptr::null_mut()),
+            message: Some(message),
+        }))
+    }
+
+    // firstからlastまでつないだノードを、まとめてリストの末尾に追加する
+    fn push(&self, first: *mut Node<T>, last: *mut Node<T>) {
+        let prev = self.tail.swap(last, AcqRel);
+        // prevはまだReceiverに受け取られていない(nextがnullの間は解放されない)
+        // Releaseで書き込むので、first以降のノードをつないだ書き込みもReceiverに見える
+        unsafe { (*prev).next.store(first, Release) };
+    }
+
+    /// # Safety
+    /// Receiverだけが呼び出せる
+    unsafe fn pop(&self) -> Pop<T> {
+        let head = *self.head.get();
+        let next = (*head).next.load(Acquire);
+        if next.is_null() {
+            return if self.tail.load(Acquire) == head {
+                Pop::Empty
+            } else {
+                Pop::Inconsistent
+            };
+        }
+        *self.head.get() = next;
+        // nextが新しいダミーになるので、メッセージだけを取り出して古いダミーを解放する
+        let message = (*next).message.take().unwrap();
+        drop(Box::from_raw(head));
+        // 書き込むのはReceiverだけなのでfetch_addにしなくてよい
+        self.received
+            .store(self.received.load(Relaxed).wrapping_add(1), Release);
+        Pop::Message(message)
+    }
+
+    // 受け取られていないメッセージのおおよその数
+    // つなぎ終えていないメッセージも数えるので、try_receive()で受け取れる数より多いことがある
+    fn len(&self) -> usize {
+        let received = self.received.load(Acquire);
+        self.sent.load(Relaxed).wrapping_sub(received)
+    }
+
+    // これ以上メッセージが送られてこない
+    // 届いているメッセージを受け取り終えたら、receive()はNoneを返す
+    fn is_disconnected(&self) -> bool {
+        self.senders.load(Acquire) == 0 || self.closed.load(Acquire)
+    }
+
+    fn wake_receiver(&self) {
+        // リストへの追加やSenderの数の変更を、receive()がparkする前に確認できるようにする
+        fence(SeqCst);
+        if self.receiver_parked.swap(false, Relaxed) {
+            if let Some(w) = self.receiver_wakeup.lock().unwrap().take() {
+                w.wake();
+            }
+        }
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        // 受け取られなかったメッセージごとノードを解放する
+        let mut node = *self.head.get_mut();
+        while !node.is_null() {
+            let b = unsafe { Box::from_raw(node) };
+            node = b.next.load(Relaxed);
+        }
+    }
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    // Receiverがドロップされていても送れるが、受け取られずにチャネルと一緒にドロップされる
+    // close()された後は送らずにメッセージを返す
+    pub fn send(&self, message: T) -> Result<(), T> {
+        if self.is_closed() {
+            return Err(message);
+        }
+        let node = Channel::new_node(message);
+        self.channel.sent.fetch_add(1, Relaxed);
+        self.channel.push(node, node);
+        self.channel.wake_receiver();
+        Ok(())
+    }
+
+    // まとめて送る
+    // ノードは他のスレッドから見えないうちにつないでおくので、tailのswapとwake_receiver()は1回で済む
+    // ほかのSenderのメッセージが途中に割り込むことはない
+    // close()された後は、messagesを1つも取り出さずにそのまま返す
+    pub fn send_all<I: IntoIterator<Item = T>>(&self, messages: I) -> Result<(), I> {
+        if self.is_closed() {
+            return Err(messages);
+        }
+        let mut messages = messages.into_iter();
+        let Some(first) = messages.next() else {
+            return Ok(());
+        };
+        let first = Channel::new_node(first);
+        let mut last = first;
+        let mut n = 1;
+        for message in messages {
+            let node = Channel::new_node(message);
+            unsafe { (*last).next.store(node, Relaxed) };
+            last = node;
+            n += 1;
+        }
+        self.channel.sent.fetch_add(n, Relaxed);
+        self.channel.push(first, last);
+        self.channel.wake_receiver();
+        Ok(())
+    }
+
+    // チャネルを閉じる。すべてのSenderをドロップしなくても、Receiverに終わりを伝えられる
+    // Receiverはそれまでに届いたメッセージを受け取り終えてからNoneを返す
+    // 閉じた後のsend()はErrを返す。close()と同時に送ったメッセージは、Okでも受け取られないことがある
+    pub fn close(&self) {
+        self.channel.closed.store(true, Release);
+        self.channel.wake_receiver();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.channel.closed.load(Acquire)
+    }
+}
+
+// len()とis_empty()はSenderとReceiverのどちらからも呼べる
+// 上限がないので、capacity()は常にNoneを返す
+impl<T> Sender<T> {
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Relaxed);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // 最後のSenderなら、待っているReceiverを起こしてNoneを返させる
+        if self.channel.senders.fetch_sub(1, Release) == 1 {
+            self.channel.wake_receiver();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    // メッセージが届くまで待つ
+    // すべてのSenderがドロップされ、受け取っていないメッセージもなければNoneを返す
+    // &mut selfにすることで、同時に受け取るのは1スレッドだけになる
+    pub fn receive(&mut self) -> Option<T> {
+        loop {
+            if let Poll::Ready(message) = self.poll(|| Wakeup::Thread(thread::current())) {
+                return message;
+            }
+            thread::park();
+        }
+    }
+
+    // receive()の非同期版。スレッドをparkせずに、空ならcxのWakerを登録してPendingを返す
+    // futuresのStream::poll_next()と同じ形なので、そのまま包んでStreamにできる
+    pub fn poll_receive(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.poll(|| Wakeup::Waker(cx.waker().clone()))
+    }
+
+    // asyncブロックの中で.awaitして受け取る
+    pub fn receive_async(&mut self) -> ReceiveFuture<'_, T> {
+        ReceiveFuture { receiver: self }
+    }
+
+    // 受け取れるか、最後のSenderがドロップされていればReady
+    // そうでなければwakeupを登録してPendingを返すので、呼び出し側は起こされるまで待つ
+    fn poll(&mut self, wakeup: impl FnOnce() -> Wakeup) -> Poll<Option<T>> {
+        let channel = &*self.channel;
+        let mut wakeup = Some(wakeup);
+        loop {
+            match unsafe { channel.pop() } {
+                Pop::Message(message) => return Poll::Ready(Some(message)),
+                Pop::Inconsistent => thread::yield_now(),
+                Pop::Empty => {
+                    if channel.is_disconnected() {
+                        // 最後のSenderがドロップされる前に送ったメッセージが残っているかもしれない
+                        return Poll::Ready(match unsafe { channel.pop() } {
+                            Pop::Message(message) => Some(message),
+                            _ => None,
+                        });
+                    }
+                    if let Some(wakeup) = wakeup.take() {
+                        *channel.receiver_wakeup.lock().unwrap() = Some(wakeup());
+                    }
+                    channel.receiver_parked.store(true, Relaxed);
+                    // wake_receiver()のfenceと対になる
+                    // どちらかが必ず相手の書き込みを見るので、起こされずに眠り続けることはない
+                    fence(SeqCst);
+                    let head = unsafe { *channel.head.get() };
+                    if channel.tail.load(Acquire) == head && !channel.is_disconnected() {
+                        return Poll::Pending;
+                    }
+                }
+            }
+        }
+    }
+
+    // 取り出さずに、receive()がすぐに返るかを調べる(select.rsから使う)
+    // 返らなければthreadを登録してfalseを返すので、呼び出し側はparkして待てる
+    // 登録はreceive()と同じ場所を使うので、&selfでも同時に待てるのは1スレッドだけ
+    pub(crate) fn poll_ready(&self, thread: &Thread) -> bool {
+        let channel = &*self.channel;
+        let ready = || {
+            let head = unsafe { *channel.head.get() };
+            // tailがheadと違えば、Senderがつなぎ終えていなくても届いている
+            channel.tail.load(Acquire) != head || channel.is_disconnected()
+        };
+        if ready() {
+            return true;
+        }
+        *channel.receiver_wakeup.lock().unwrap() = Some(Wakeup::Thread(thread.clone()));
+        channel.receiver_parked.store(true, Relaxed);
+        // poll()と同じく、wake_receiver()のfenceと対になる
+        fence(SeqCst);
+        ready()
+    }
+}
+
+// ReceiverはSyncなので&Receiverを共有した複数のスレッドから同じ&Tが見える
+// そのためpeek()はTがSyncのときだけ使える
+impl<T: Sync> Receiver<T> {
+    // 次に受け取るメッセージを取り出さずに見る
+    // ノードを外して解放するのはReceiverだけなので、&selfを借用している間は残る
+    // Senderがつなぎ終えていなければ、まだ届いていないものとしてNoneを返す
+    pub fn peek(&self) -> Option<&T> {
+        unsafe {
+            let head = *self.channel.head.get();
+            let next = (*head).next.load(Acquire);
+            next.as_ref()?.message.as_ref()
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    // 1つ以上届くまで待ち、届いているメッセージをlimit個までbufに追加する
+    // 待つのは最初の1つだけなので、1つずつreceive()するよりparkやunparkの回数が減る
+    // 追加した数を返す。すべてのSenderがドロップされ、メッセージもなければ0を返す
+    pub fn receive_many(&mut self, buf: &mut Vec<T>, limit: usize) -> usize {
+        if limit == 0 {
+            return 0;
+        }
+        let Some(message) = self.receive() else {
+            return 0;
+        };
+        buf.push(message);
+        let mut n = 1;
+        while n < limit {
+            // Senderがつないでいる途中なら待たずに返す
+            let Pop::Message(message) = (unsafe { self.channel.pop() }) else {
+                break;
+            };
+            buf.push(message);
+            n += 1;
+        }
+        n
+    }
+
+    // 待たずに受け取る
+    pub fn try_receive(&mut self) -> Option<T> {
+        loop {
+            match unsafe { self.channel.pop() } {
+                Pop::Message(message) => return Some(message),
+                Pop::Empty => return None,
+                Pop::Inconsistent => thread::yield_now(),
+            }
+        }
+    }
+}
+
+pub struct ReceiveFuture<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for ReceiveFuture<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.receiver.poll_receive(cx)
+    }
+}
+
+// receive()を繰り返す。すべてのSenderがドロップされ、受け取るメッセージがなくなったら終わる
+pub struct Iter<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+pub struct IntoIter<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> Receiver<T> {
+    pub fn iter(&mut self) -> Iter<'_, T> {
+        Iter { receiver: self }
+    }
+}
+
+impl<T> Iterator for Iter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.receive()
+    }
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.receive()
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { receiver: self }
+    }
+}
+
+impl<'a, T> IntoIterator for &'a mut Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+#[test]
+fn test_mpsc() {
+    let (sender, mut receiver) = channel();
+    thread::scope(|s| {
+        for t in 0..4 {
+            let sender = sender.clone();
+            s.spawn(move || {
+                for i in 0..1000 {
+                    sender.send((t, i)).unwrap();
+                }
+            });
+        }
+        drop(sender);
+
+        // 同じSenderから送ったメッセージは送った順に届く
+        let mut next = [0; 4];
+        for (t, i) in &mut receiver {
+            assert_eq!(next[t], i);
+            next[t] += 1;
+        }
+        assert_eq!(next, [1000; 4]);
+        assert!(receiver.is_empty());
+    });
+
+    // 受け取らなかったメッセージはチャネルと一緒にドロップされる
+    let (sender, receiver) = channel();
+    assert!(receiver.peek().is_none());
+    let message = Arc::new(());
+    sender.send(message.clone()).unwrap();
+    assert!(Arc::ptr_eq(receiver.peek().unwrap(), &message));
+    drop(receiver);
+    drop(sender);
+    assert_eq!(Arc::strong_count(&message), 1);
+
+    // TがSyncでなければpeek()はなく、同じ名前のトレイトのメソッドが呼ばれる
+    // peek()が&Cell<u32>を返せばこの型の比較はコンパイルエラーになる
+    trait NotSync {
+        fn peek(&self) -> &'static str {
+            "not Sync"
+        }
+    }
+    impl<T> NotSync for Receiver<T> {}
+    let (sender, receiver) = channel();
+    sender.send(std::cell::Cell::new(1)).unwrap();
+    assert_eq!(receiver.peek(), "not Sync");
+}
+
+#[test]
+fn test_batch() {
+    let (sender, mut receiver) = channel();
+    let mut received = Vec::new();
+    thread::scope(|s| {
+        for t in 0..4 {
+            let sender = sender.clone();
+            s.spawn(move || {
+                for i in 0..100 {
+                    sender.send_all((0..10).map(|j| (t, i * 10 + j))).unwrap();
+                }
+            });
+        }
+        drop(sender);
+
+        let mut buf = Vec::new();
+        while receiver.receive_many(&mut buf, 32) > 0 {
+            assert!(buf.len() <= 32);
+            received.append(&mut buf);
+            // まだ届いていない分を数えていても、全体を超えることはない
+            assert!(received.len() + receiver.len() <= 4000);
+        }
+    });
+    assert_eq!(received.len(), 4000);
+    assert_eq!(receiver.len(), 0);
+    // 1回のsend_all()で送ったメッセージの間に、ほかのSenderのメッセージは割り込まない
+    for w in received.windows(2) {
+        if w[1].1 % 10 != 0 {
+            assert_eq!(w[1], (w[0].0, w[0].1 + 1));
+        }
+    }
+}
+
+#[test]
+fn test_close() {
+    let (sender, mut receiver) = channel();
+    let other = sender.clone();
+    thread::scope(|s| {
+        s.spawn(move || {
+            for i in 0..10 {
+                sender.send(i).unwrap();
+            }
+            // ほかのSenderが残っていても終わりを伝えられる
+            sender.close();
+            // 閉じた後は送れずに返される
+            assert_eq!(sender.send(10), Err(10));
+            assert!(sender.send_all(11..20).is_err());
+        });
+        // 閉じる前に送られたメッセージはすべて受け取れる
+        assert_eq!(
+            receiver.iter().collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+    });
+    assert!(other.is_closed());
+    assert_eq!(other.send(20), Err(20));
+}
+
+#[test]
+fn test_receive_async() {
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    // Wakerで起こされるとunparkするだけの最小のエグゼキュータ
+    struct ThreadWaker(Thread);
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    let (sender, mut receiver) = channel();
+    thread::scope(|s| {
+        s.spawn(move || {
+            for i in 0..1000 {
+                sender.send(i).unwrap();
+            }
+        });
+        let sum = block_on(async {
+            let mut sum = 0;
+            while let Some(i) = receiver.receive_async().await {
+                sum += i;
+            }
+            sum
+        });
+        assert_eq!(sum, (0..1000).sum::<i32>());
+    });
+}
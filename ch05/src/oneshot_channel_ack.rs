@@ -0,0 +1,208 @@
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{AtomicBool, AtomicU8};
+use std::thread;
+use std::thread::Thread;
+
+// 受け取られたことを送った側で確認できるoneshot_channel_nonblocking
+// send()はAckを返し、Ack::wait()はReceiverがメッセージを取り出すまでparkして待つ
+// readyと逆向きのフラグ(receipt)をReceiverが立てて、送った側のスレッドをunparkする
+pub struct Channel<T> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    ready: AtomicBool,
+    // Senderがドロップされた(send()した後も含む)
+    disconnected: AtomicBool,
+    // send()したスレッド。readyをtrueにする前に書き込み、Receiverが受け取った後で起こす
+    sending_thread: UnsafeCell<Option<Thread>>,
+    // PENDING -> RECEIVEDかDROPPED。Receiverだけが書き込む
+    receipt: AtomicU8,
+}
+
+const PENDING: u8 = 0;
+const RECEIVED: u8 = 1;
+// Receiverがメッセージを受け取らずにドロップされた
+const DROPPED: u8 = 2;
+
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+impl<T> Channel<T> {
+    pub const fn new() -> Self {
+        Self {
+            message: UnsafeCell::new(MaybeUninit::uninit()),
+            ready: AtomicBool::new(false),
+            disconnected: AtomicBool::new(false),
+            sending_thread: UnsafeCell::new(None),
+            receipt: AtomicU8::new(PENDING),
+        }
+    }
+
+    pub fn split(&mut self) -> (Sender<'_, T>, Receiver<'_, T>) {
+        // 上書きすることで古い*selfのDropが実行される
+        *self = Self::new();
+        (
+            Sender {
+                channel: self,
+                receiving_thread: thread::current(),
+            },
+            Receiver {
+                channel: self,
+                _no_send: PhantomData,
+            },
+        )
+    }
+
+    // readyがtrueになった後でだけ呼べる
+    unsafe fn wake_sender(&self) {
+        if let Some(t) = &*self.sending_thread.get() {
+            t.unpark();
+        }
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        if *self.ready.get_mut() {
+            unsafe { self.message.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+pub struct Sender<'a, T> {
+    channel: &'a Channel<T>,
+    receiving_thread: Thread,
+}
+
+impl<'a, T> Sender<'a, T> {
+    // 値渡しにより1度しか呼ばれないことが保証されているのでパニックしない
+    pub fn send(self, message: T) -> Ack<'a, T> {
+        let channel = self.channel;
+        unsafe {
+            (*channel.message.get()).write(message);
+            *channel.sending_thread.get() = Some(thread::current());
+        }
+        // Receiverのドロップと同時に起きても、どちらかが必ず相手の書き込みを見るようにSeqCstにする
+        channel.ready.store(true, SeqCst);
+        self.receiving_thread.unpark();
+        Ack {
+            channel,
+            _no_send: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for Sender<'_, T> {
+    fn drop(&mut self) {
+        self.channel.disconnected.store(true, Release);
+        self.receiving_thread.unpark();
+    }
+}
+
+// Receiverがメッセージを受け取らずにドロップされた
+#[derive(Debug, PartialEq, Eq)]
+pub struct NotReceived;
+
+// send()したスレッドがunparkされるので、ほかのスレッドに渡せない
+#[must_use = "call wait() to block until the message is received"]
+pub struct Ack<'a, T> {
+    channel: &'a Channel<T>,
+    _no_send: PhantomData<*const ()>,
+}
+
+impl<T> Ack<'_, T> {
+    pub fn is_received(&self) -> bool {
+        self.channel.receipt.load(Acquire) == RECEIVED
+    }
+
+    // Receiverがメッセージを取り出すまで待つ
+    pub fn wait(self) -> Result<(), NotReceived> {
+        loop {
+            match self.channel.receipt.load(SeqCst) {
+                RECEIVED => return Ok(()),
+                DROPPED => return Err(NotReceived),
+                _ => thread::park(),
+            }
+        }
+    }
+}
+
+// Senderがメッセージを送らずにドロップされた
+#[derive(Debug, PartialEq, Eq)]
+pub struct Disconnected;
+
+pub struct Receiver<'a, T> {
+    channel: &'a Channel<T>,
+    _no_send: PhantomData<*const ()>,
+}
+
+impl<T> Receiver<'_, T> {
+    pub fn receive(self) -> Result<T, Disconnected> {
+        loop {
+            // falseに戻すことで値がないことをドロップに伝えられる
+            if self.channel.ready.swap(false, Acquire) {
+                return Ok(self.take());
+            }
+            if self.channel.disconnected.load(Acquire) {
+                // ドロップする前にsend()していれば受け取れる
+                if self.channel.ready.swap(false, Acquire) {
+                    return Ok(self.take());
+                }
+                return Err(Disconnected);
+            }
+            thread::park();
+        }
+    }
+
+    // readyをfalseに戻した後で呼ぶ
+    fn take(&self) -> T {
+        let message = unsafe { (*self.channel.message.get()).assume_init_read() };
+        // 取り出した後で知らせるので、Ack::wait()から戻った時点でメッセージはもう受け取られている
+        self.channel.receipt.store(RECEIVED, SeqCst);
+        unsafe { self.channel.wake_sender() };
+        message
+    }
+}
+
+impl<T> Drop for Receiver<'_, T> {
+    fn drop(&mut self) {
+        if self.channel.receipt.load(Relaxed) != PENDING {
+            return;
+        }
+        // 受け取らずにドロップされた。send()済みなら待っているスレッドを起こす
+        // send()がまだなら、これから待つスレッドはwait()でDROPPEDを見る
+        self.channel.receipt.store(DROPPED, SeqCst);
+        if self.channel.ready.load(SeqCst) {
+            unsafe { self.channel.wake_sender() };
+        }
+    }
+}
+
+#[test]
+fn test_ack() {
+    use std::time::Duration;
+
+    let mut channel = Channel::new();
+    thread::scope(|s| {
+        let (sender, receiver) = channel.split();
+        s.spawn(move || {
+            let ack = sender.send(String::from("hello"));
+            // Receiverが取り出すまで戻らない
+            assert_eq!(ack.wait(), Ok(()));
+        });
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(receiver.receive().as_deref(), Ok("hello"));
+    });
+
+    // 受け取られずにReceiverがドロップされたら、待っている側はErrで戻る
+    thread::scope(|s| {
+        let (sender, receiver) = channel.split();
+        s.spawn(move || {
+            let ack = sender.send(String::from("unread"));
+            assert!(!ack.is_received());
+            assert_eq!(ack.wait(), Err(NotReceived));
+        });
+        thread::sleep(Duration::from_millis(10));
+        drop(receiver);
+    });
+}
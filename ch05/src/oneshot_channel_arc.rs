@@ -8,6 +8,8 @@ pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
     let a = Arc::new(Channel {
         message: UnsafeCell::new(MaybeUninit::uninit()),
         ready: AtomicBool::new(false),
+        disconnected: AtomicBool::new(false),
+        in_use: AtomicBool::new(false),
     });
     (
         Sender { channel: a.clone() },
@@ -23,11 +25,73 @@ pub struct Receiver<T> {
     channel: Arc<Channel<T>>,
 }
 
+// Senderがメッセージを送らずにドロップされた
+#[derive(Debug, PartialEq, Eq)]
+pub struct Disconnected;
+
 impl<T> Sender<T> {
     // 値渡しにより1度しか呼ばれないことが保証されているのでパニックしない
-    pub fn send(self, message: T) {
+    // Receiverがドロップされていれば、誰も読まないセルに書き込まずにメッセージを返す
+    pub fn send(self, message: T) -> Result<(), T> {
+        self.write(message)
+    }
+
+    // 複製して複数のスレッドから送れるようにする。最初にsend()したものだけが届く
+    pub fn shared(self) -> SharedSender<T> {
+        SharedSender {
+            sender: Arc::new(self),
+        }
+    }
+
+    // 1度しか呼ばれないことは呼び出し側が保証する
+    fn write(&self, message: T) -> Result<(), T> {
+        // チャネルを持っているのがこのSenderだけなら、Receiverはもういない
+        // 一度1になれば増えることはないので、確認した後で状態が変わることはない
+        if Arc::strong_count(&self.channel) == 1 {
+            return Err(message);
+        }
         unsafe { (*self.channel.message.get()).write(message) };
         self.channel.ready.store(true, Release);
+        Ok(())
+    }
+}
+
+// 送れなかったメッセージを返す
+#[derive(Debug, PartialEq, Eq)]
+pub enum SharedSendError<T> {
+    // ほかのSharedSenderが先に送った
+    AlreadySent(T),
+    // Receiverがドロップされていた
+    Disconnected(T),
+}
+
+// 複製できるSender。「最初の応答だけを使う」ように複数のスレッドに配る
+// 元のSenderを共有するので、すべての複製がドロップされたときにdisconnectedになる
+#[derive(Clone)]
+pub struct SharedSender<T> {
+    sender: Arc<Sender<T>>,
+}
+
+impl<T> SharedSender<T> {
+    // in_useをfalseからtrueに変えられた1つだけがセルに書き込める
+    pub fn send(&self, message: T) -> Result<(), SharedSendError<T>> {
+        let channel = &self.sender.channel;
+        if channel
+            .in_use
+            .compare_exchange(false, true, Relaxed, Relaxed)
+            .is_err()
+        {
+            return Err(SharedSendError::AlreadySent(message));
+        }
+        self.sender
+            .write(message)
+            .map_err(SharedSendError::Disconnected)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.disconnected.store(true, Release);
     }
 }
 
@@ -35,18 +99,44 @@ impl<T> Receiver<T> {
     pub fn is_ready(&self) -> bool {
         self.channel.ready.load(Relaxed)
     }
-    pub fn receive(self) -> T {
+}
+
+// &Receiverは別のスレッドに渡せるので、peek()で&Tを共有するにはTがSyncでなければならない
+impl<T: Sync> Receiver<T> {
+    // 受け取らずにメッセージを見る
+    // receive()はselfを消費するので、返した参照を使っている間に読み出されることはない
+    pub fn peek(&self) -> Option<&T> {
+        if !self.channel.ready.load(Acquire) {
+            return None;
+        }
+        Some(unsafe { (*self.channel.message.get()).assume_init_ref() })
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn receive(self) -> Result<T, Disconnected> {
         // falseに戻すことで値がないことをドロップに伝えられる
         if !self.channel.ready.swap(false, Acquire) {
-            panic!("")
+            // send()した後でドロップされた場合もあるので、readyをもう一度確認する
+            // Acquireで読むので、ドロップより前のsend()の書き込みも見える
+            if !self.channel.disconnected.load(Acquire) {
+                panic!("")
+            }
+            if !self.channel.ready.swap(false, Acquire) {
+                return Err(Disconnected);
+            }
         }
-        unsafe { (*self.channel.message.get()).assume_init_read() }
+        Ok(unsafe { (*self.channel.message.get()).assume_init_read() })
     }
 }
 
 struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
     ready: AtomicBool,
+    // Senderがドロップされた(send()した後も含む)
+    disconnected: AtomicBool,
+    // SharedSenderのどれかがsend()を始めた
+    in_use: AtomicBool,
 }
 
 // TがSendであればこのChannelはスレッド間で共有しても安全
@@ -59,3 +149,79 @@ impl<T> Drop for Channel<T> {
         }
     }
 }
+
+#[test]
+fn test_shared_sender() {
+    use std::thread;
+
+    // 最初に送ったSharedSenderだけが届き、ほかには送ったメッセージが返される
+    let (sender, receiver) = channel();
+    let sender = sender.shared();
+    let already_sent = thread::scope(|s| {
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let sender = sender.clone();
+                s.spawn(move || sender.send(i))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .filter_map(|h| match h.join().unwrap() {
+                Ok(()) => None,
+                Err(SharedSendError::AlreadySent(i)) => Some(i),
+                Err(SharedSendError::Disconnected(_)) => unreachable!(),
+            })
+            .count()
+    });
+    assert_eq!(already_sent, 3);
+    drop(sender);
+    assert!((0..4).contains(&receiver.receive().unwrap()));
+
+    // Receiverがドロップされていれば、最初のsend()にメッセージが返される
+    let (sender, receiver) = channel();
+    let sender = sender.shared();
+    drop(receiver);
+    assert_eq!(sender.send(1), Err(SharedSendError::Disconnected(1)));
+}
+
+#[test]
+fn test_disconnected() {
+    // 送らずにドロップされたらErrで戻る
+    let (sender, receiver) = channel::<i32>();
+    drop(sender);
+    assert_eq!(receiver.receive(), Err(Disconnected));
+
+    // 送った後でドロップされていても受け取れる
+    let (sender, receiver) = channel();
+    sender.send(1).unwrap();
+    assert_eq!(receiver.receive(), Ok(1));
+}
+
+#[test]
+fn test_send_without_receiver() {
+    // Receiverがいなければ書き込まずにメッセージを返す
+    let (sender, receiver) = channel();
+    drop(receiver);
+    assert_eq!(sender.send(String::from("hello")).unwrap_err(), "hello");
+}
+
+#[test]
+fn test_peek() {
+    let (sender, receiver) = channel();
+    assert_eq!(receiver.peek(), None);
+    sender.send(1).unwrap();
+    // 見ても受け取ったことにはならない
+    assert_eq!(receiver.peek(), Some(&1));
+    assert_eq!(receiver.receive(), Ok(1));
+
+    // Cellのように!Syncな型ではpeek()がなく、トレイトのメソッドが呼ばれる
+    trait NotSync {
+        fn peek(&self) -> &'static str {
+            "not Sync"
+        }
+    }
+    impl<T> NotSync for Receiver<T> {}
+    let (sender, receiver) = channel();
+    sender.send(std::cell::Cell::new(1)).unwrap();
+    assert_eq!(receiver.peek(), "not Sync");
+}
@@ -0,0 +1,156 @@
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+// ReceiverがFutureを実装するoneshotチャネル
+// 受け取る側のThreadの代わりにタスクのWakerを保存しておき、send()でwake()する
+// receive()はスレッドをunparkするWakerを作ってpollするので、同じ手順でスレッドからも待てる
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let a = Arc::new(Channel {
+        message: UnsafeCell::new(MaybeUninit::uninit()),
+        ready: AtomicBool::new(false),
+        disconnected: AtomicBool::new(false),
+        waker: Mutex::new(None),
+    });
+    (Sender { channel: a.clone() }, Receiver { channel: a })
+}
+
+struct Channel<T> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    ready: AtomicBool,
+    // Senderがメッセージを送らずにドロップされた
+    disconnected: AtomicBool,
+    // 最後にpollしたタスクのWaker
+    waker: Mutex<Option<Waker>>,
+}
+
+// TがSendであればこのChannelはスレッド間で共有しても安全
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+impl<T> Channel<T> {
+    fn wake(&self) {
+        if let Some(w) = self.waker.lock().unwrap().take() {
+            w.wake();
+        }
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        if *self.ready.get_mut() {
+            unsafe { self.message.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    // 値渡しにより1度しか呼ばれないことが保証されているのでパニックしない
+    // Receiverを起こすのはこの後のdrop()
+    pub fn send(self, message: T) {
+        unsafe { (*self.channel.message.get()).write(message) };
+        self.channel.ready.store(true, Release);
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // send()せずにドロップされたら、待っているReceiverにそれを伝える
+        if !self.channel.ready.load(Relaxed) {
+            self.channel.disconnected.store(true, Release);
+        }
+        self.channel.wake();
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    fn take(&self) -> Poll<T> {
+        // falseに戻すことで値がないことをドロップに伝えられる
+        if self.channel.ready.swap(false, Acquire) {
+            return Poll::Ready(unsafe { (*self.channel.message.get()).assume_init_read() });
+        }
+        if self.channel.disconnected.load(Acquire) {
+            panic!("sender dropped without sending");
+        }
+        Poll::Pending
+    }
+
+    // 非同期ランタイムを使わずにスレッドをparkして待つ
+    pub fn receive(mut self) -> T {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(message) = Pin::new(&mut self).poll(&mut cx) {
+                return message;
+            }
+            thread::park();
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = T;
+
+    // Senderがメッセージを送らずにドロップされていればパニックする
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        if let Poll::Ready(message) = self.take() {
+            return Poll::Ready(message);
+        }
+        // 別のタスクに移されていることもあるので、pollのたびに登録し直す
+        *self.channel.waker.lock().unwrap() = Some(cx.waker().clone());
+        // 登録する前に送られていたら起こされないので、確かめ直す
+        self.take()
+    }
+}
+
+// wake()でスレッドをunparkする
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+#[test]
+fn test_async_oneshot() {
+    use std::sync::atomic::AtomicUsize;
+
+    // 起こされた回数を数える
+    struct CountWaker(AtomicUsize);
+    impl Wake for CountWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Relaxed);
+        }
+    }
+
+    let count = Arc::new(CountWaker(AtomicUsize::new(0)));
+    let waker = Waker::from(count.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let (sender, mut receiver) = channel();
+    assert_eq!(Pin::new(&mut receiver).poll(&mut cx), Poll::Pending);
+    sender.send("hello");
+    assert_eq!(count.0.load(Relaxed), 1);
+    assert_eq!(Pin::new(&mut receiver).poll(&mut cx), Poll::Ready("hello"));
+}
+
+#[test]
+fn test_async_oneshot_blocking() {
+    let (sender, receiver) = channel();
+    thread::spawn(move || sender.send(String::from("hello")));
+    assert_eq!(receiver.receive(), "hello");
+}
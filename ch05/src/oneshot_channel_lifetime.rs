@@ -6,6 +6,8 @@ use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 pub struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
     ready: AtomicBool,
+    // Senderがドロップされた(send()した後も含む)
+    disconnected: AtomicBool,
 }
 
 unsafe impl<T> Sync for Channel<T> where T: Send {}
@@ -15,6 +17,7 @@ impl<T> Channel<T> {
         Self {
             message: UnsafeCell::new(MaybeUninit::uninit()),
             ready: AtomicBool::new(false),
+            disconnected: AtomicBool::new(false),
         }
     }
 
@@ -23,7 +26,7 @@ impl<T> Channel<T> {
     // SenderとReceiverがドロップされた後はもう一度split()を呼び出せる
     // ライフタイムを省略しない場合はこうなる
     // pub fn split<'a>(&'a mut self) -> (Sender<'a, T>, Receiver<'a, T>) {
-    pub fn split(&mut self) -> (Sender<T>, Receiver<T>) {
+    pub fn split(&mut self) -> (Sender<'_, T>, Receiver<'_, T>) {
         // 上書きすることで古い*selfのDropが実行される
         *self = Self::new();
         (Sender { channel: self }, Receiver { channel: self })
@@ -50,6 +53,16 @@ impl<T> Sender<'_, T> {
     }
 }
 
+impl<T> Drop for Sender<'_, T> {
+    fn drop(&mut self) {
+        self.channel.disconnected.store(true, Release);
+    }
+}
+
+// Senderがメッセージを送らずにドロップされた
+#[derive(Debug, PartialEq, Eq)]
+pub struct Disconnected;
+
 pub struct Receiver<'a, T> {
     channel: &'a Channel<T>,
 }
@@ -58,12 +71,71 @@ impl<T> Receiver<'_, T> {
     pub fn is_ready(&self) -> bool {
         self.channel.ready.load(Relaxed)
     }
+}
+
+// &Receiverは別のスレッドに渡せるので、peek()で&Tを共有するにはTがSyncでなければならない
+impl<T: Sync> Receiver<'_, T> {
+    // 受け取らずにメッセージを見る
+    // receive()はselfを消費するので、返した参照を使っている間に読み出されることはない
+    pub fn peek(&self) -> Option<&T> {
+        if !self.channel.ready.load(Acquire) {
+            return None;
+        }
+        Some(unsafe { (*self.channel.message.get()).assume_init_ref() })
+    }
+}
 
-    pub fn receive(self) -> T {
+impl<T> Receiver<'_, T> {
+    pub fn receive(self) -> Result<T, Disconnected> {
         // falseに戻すことで値がないことをドロップに伝えられる
         if !self.channel.ready.swap(false, Acquire) {
-            panic!("")
+            // send()した後でドロップされた場合もあるので、readyをもう一度確認する
+            // Acquireで読むので、ドロップより前のsend()の書き込みも見える
+            if !self.channel.disconnected.load(Acquire) {
+                panic!("")
+            }
+            if !self.channel.ready.swap(false, Acquire) {
+                return Err(Disconnected);
+            }
+        }
+        Ok(unsafe { (*self.channel.message.get()).assume_init_read() })
+    }
+}
+
+#[test]
+fn test_disconnected() {
+    let mut channel = Channel::new();
+
+    // 送らずにドロップされたらErrで戻る
+    let (sender, receiver) = channel.split();
+    drop(sender);
+    assert_eq!(receiver.receive(), Err(Disconnected));
+
+    // 送った後でドロップされていても受け取れる
+    let (sender, receiver) = channel.split();
+    sender.send("hello");
+    assert_eq!(receiver.receive(), Ok("hello"));
+}
+
+#[test]
+fn test_peek() {
+    let mut channel = Channel::new();
+    let (sender, receiver) = channel.split();
+    assert_eq!(receiver.peek(), None);
+    sender.send(1);
+    // 見ても受け取ったことにはならない
+    assert_eq!(receiver.peek(), Some(&1));
+    assert_eq!(receiver.receive(), Ok(1));
+
+    // Cellのように!Syncな型ではpeek()がなく、トレイトのメソッドが呼ばれる
+    trait NotSync {
+        fn peek(&self) -> &'static str {
+            "not Sync"
         }
-        unsafe { (*self.channel.message.get()).assume_init_read() }
     }
+    impl<T> NotSync for Receiver<'_, T> {}
+    let mut channel = Channel::new();
+    let (sender, receiver) = channel.split();
+    sender.send(std::cell::Cell::new(1));
+    assert_eq!(receiver.peek(), "not Sync");
 }
@@ -0,0 +1,171 @@
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicU8, AtomicUsize};
+use std::sync::Arc;
+use std::thread;
+use std::thread::Thread;
+
+// Senderを複製できるoneshotチャネル。最初にsend()したSenderのメッセージだけが届く
+// 複数のsend()が同時にセルに書き込まないように、readyのboolの代わりに
+// EMPTY -> WRITING -> READY -> TAKENと進む状態を使う
+// EMPTYからWRITINGに変えられたSenderだけがセルに書き込める
+//
+// 受け取る側はchannel()を呼んだスレッドでparkするので、Receiverはほかのスレッドに渡せない
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let a = Arc::new(Channel {
+        message: UnsafeCell::new(MaybeUninit::uninit()),
+        state: AtomicU8::new(EMPTY),
+        senders: AtomicUsize::new(1),
+        receiving_thread: thread::current(),
+    });
+    (
+        Sender { channel: a.clone() },
+        Receiver {
+            channel: a,
+            _no_send: PhantomData,
+        },
+    )
+}
+
+const EMPTY: u8 = 0;
+// あるSenderがセルに書き込んでいる
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+// Receiverが取り出した。その後のsend()も失敗させるためにEMPTYには戻さない
+const TAKEN: u8 = 3;
+
+struct Channel<T> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    state: AtomicU8,
+    // 生きているSenderの数
+    senders: AtomicUsize,
+    receiving_thread: Thread,
+}
+
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { self.message.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+// ほかのSenderが先に送っていたので、送れなかったメッセージを返す
+#[derive(Debug, PartialEq, Eq)]
+pub struct AlreadySent<T>(pub T);
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    // 複製したどのSenderからでも呼べるが、成功するのは全体で1回だけ
+    pub fn send(&self, message: T) -> Result<(), AlreadySent<T>> {
+        // セルに書き込む権利を取る。書き込み終わるまではReceiverもほかのSenderも触れない
+        if self
+            .channel
+            .state
+            .compare_exchange(EMPTY, WRITING, Relaxed, Relaxed)
+            .is_err()
+        {
+            return Err(AlreadySent(message));
+        }
+        unsafe { (*self.channel.message.get()).write(message) };
+        self.channel.state.store(READY, Release);
+        self.channel.receiving_thread.unpark();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Relaxed);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // 最後のSenderなら、送られていなくても待っているReceiverを起こす
+        if self.channel.senders.fetch_sub(1, Release) == 1 {
+            self.channel.receiving_thread.unpark();
+        }
+    }
+}
+
+// どのSenderもメッセージを送らずにドロップされた
+#[derive(Debug, PartialEq, Eq)]
+pub struct Disconnected;
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+    _no_send: PhantomData<*const ()>,
+}
+
+impl<T> Receiver<T> {
+    pub fn receive(self) -> Result<T, Disconnected> {
+        loop {
+            if let Some(message) = self.try_take() {
+                return Ok(message);
+            }
+            // Senderは送り終えてからドロップされるので、Acquireで0を見た後なら書き込みも見える
+            if self.channel.senders.load(Acquire) == 0 {
+                return self.try_take().ok_or(Disconnected);
+            }
+            thread::park();
+        }
+    }
+
+    fn try_take(&self) -> Option<T> {
+        // WRITINGの間はまだ読めないので、READYのときだけ取り出す
+        self.channel
+            .state
+            .compare_exchange(READY, TAKEN, Acquire, Relaxed)
+            .ok()?;
+        Some(unsafe { (*self.channel.message.get()).assume_init_read() })
+    }
+}
+
+#[test]
+fn test_first_send_wins() {
+    let (sender, receiver) = channel();
+    let results: Vec<_> = thread::scope(|s| {
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let sender = sender.clone();
+                s.spawn(move || sender.send(i).map(|()| i))
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    let winners: Vec<_> = results.iter().filter_map(|r| r.as_ref().ok()).collect();
+    assert_eq!(winners.len(), 1);
+    // 負けたSenderにはメッセージが返される
+    for (i, r) in results.iter().enumerate() {
+        if let Err(AlreadySent(m)) = r {
+            assert_eq!(*m, i);
+        }
+    }
+    assert_eq!(receiver.receive(), Ok(*winners[0]));
+    // 受け取られた後も送れない
+    assert_eq!(sender.send(100), Err(AlreadySent(100)));
+}
+
+#[test]
+fn test_all_senders_dropped() {
+    let (sender, receiver) = channel::<i32>();
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let sender = sender.clone();
+            s.spawn(move || drop(sender));
+        }
+        drop(sender);
+        assert_eq!(receiver.receive(), Err(Disconnected));
+    });
+}
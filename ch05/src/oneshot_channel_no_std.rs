@@ -0,0 +1,268 @@
+use core::cell::UnsafeCell;
+use core::hint;
+use core::mem::MaybeUninit;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+// ring_channelと同じくcoreの機能だけで書いたoneshotチャネル
+// スレッドをparkできないので、受け取る側はHookのwait()を呼びながら待つ
+// 既定のSpinはただスピンするだけだが、組み込み環境ならWFE/SEVのような命令を渡せる
+//
+// Channelをstaticやスタックに置いてsplit()するのが基本で、
+// allocフィーチャーを有効にすればArcで共有するarc::channel()も使える
+pub trait Hook {
+    // 相手が何かするまで待つ。何もせずに戻ってもよく、呼び出し側が確かめ直す
+    fn wait(&self);
+    // readyやdisconnectedを書き込んだ後で呼ばれる
+    fn wake(&self);
+}
+
+pub struct Spin;
+
+impl Hook for Spin {
+    fn wait(&self) {
+        hint::spin_loop();
+    }
+
+    fn wake(&self) {}
+}
+
+// 関数の組をHookとして使う
+pub struct FnHook<W, K> {
+    pub wait: W,
+    pub wake: K,
+}
+
+impl<W: Fn(), K: Fn()> Hook for FnHook<W, K> {
+    fn wait(&self) {
+        (self.wait)()
+    }
+
+    fn wake(&self) {
+        (self.wake)()
+    }
+}
+
+const EMPTY: u8 = 0;
+const READY: u8 = 1;
+// メッセージを送らずにSenderがドロップされた
+const DISCONNECTED: u8 = 2;
+// Receiverが取り出した
+const TAKEN: u8 = 3;
+
+pub struct Channel<T, H = Spin> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    state: AtomicU8,
+    hook: H,
+}
+
+unsafe impl<T, H> Sync for Channel<T, H>
+where
+    T: Send,
+    H: Sync,
+{
+}
+
+// Senderがメッセージを送らずにドロップされた
+#[derive(Debug, PartialEq, Eq)]
+pub struct Disconnected;
+
+impl<T> Channel<T> {
+    pub const fn new() -> Self {
+        Self::with_hook(Spin)
+    }
+}
+
+impl<T> Default for Channel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, H: Hook> Channel<T, H> {
+    pub const fn with_hook(hook: H) -> Self {
+        Self {
+            message: UnsafeCell::new(MaybeUninit::uninit()),
+            state: AtomicU8::new(EMPTY),
+            hook,
+        }
+    }
+
+    // 排他的借用にすることで1組しか作れないことを保証する
+    // 上書きするとhookも作り直すことになるので、状態だけを戻す
+    pub fn split(&mut self) -> (Sender<'_, T, H>, Receiver<'_, T, H>) {
+        if *self.state.get_mut() == READY {
+            unsafe { self.message.get_mut().assume_init_drop() }
+        }
+        *self.state.get_mut() = EMPTY;
+        (Sender { channel: self }, Receiver { channel: self })
+    }
+
+    // Senderから1度だけ呼ぶ
+    unsafe fn send(&self, message: T) {
+        (*self.message.get()).write(message);
+        self.state.store(READY, Release);
+        self.hook.wake();
+    }
+
+    // Senderのドロップで呼ぶ。send()した後なら何もしない
+    fn disconnect(&self) {
+        if self
+            .state
+            .compare_exchange(EMPTY, DISCONNECTED, Relaxed, Relaxed)
+            .is_ok()
+        {
+            self.hook.wake();
+        }
+    }
+
+    // Receiverだけが呼ぶ
+    fn try_take(&self) -> Option<Result<T, Disconnected>> {
+        match self.state.load(Acquire) {
+            READY => {
+                // TAKENにすることで値がないことをドロップに伝えられる
+                self.state.store(TAKEN, Relaxed);
+                Some(Ok(unsafe { (*self.message.get()).assume_init_read() }))
+            }
+            DISCONNECTED => Some(Err(Disconnected)),
+            _ => None,
+        }
+    }
+
+    fn wait_take(&self) -> Result<T, Disconnected> {
+        loop {
+            if let Some(result) = self.try_take() {
+                return result;
+            }
+            self.hook.wait();
+        }
+    }
+}
+
+impl<T, H> Drop for Channel<T, H> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { self.message.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+pub struct Sender<'a, T, H: Hook = Spin> {
+    channel: &'a Channel<T, H>,
+}
+
+impl<T, H: Hook> Sender<'_, T, H> {
+    // 値渡しにより1度しか呼ばれないことが保証されているのでパニックしない
+    pub fn send(self, message: T) {
+        unsafe { self.channel.send(message) };
+    }
+}
+
+impl<T, H: Hook> Drop for Sender<'_, T, H> {
+    fn drop(&mut self) {
+        self.channel.disconnect();
+    }
+}
+
+pub struct Receiver<'a, T, H: Hook = Spin> {
+    channel: &'a Channel<T, H>,
+}
+
+impl<T, H: Hook> Receiver<'_, T, H> {
+    // まだ届いていなければNoneを返す。Someを返した後はもう受け取れない
+    pub fn try_receive(&mut self) -> Option<Result<T, Disconnected>> {
+        self.channel.try_take()
+    }
+
+    // 届くか、Senderが送らずにドロップされるまでhookで待つ
+    pub fn receive(self) -> Result<T, Disconnected> {
+        self.channel.wait_take()
+    }
+}
+
+// allocがあれば、Channelを借用せずにArcで共有できる
+#[cfg(feature = "alloc")]
+pub mod arc {
+    extern crate alloc;
+
+    use super::{Channel, Disconnected, Hook, Spin};
+    use alloc::sync::Arc;
+
+    pub fn channel<T>() -> (ArcSender<T>, ArcReceiver<T>) {
+        channel_with_hook(Spin)
+    }
+
+    pub fn channel_with_hook<T, H: Hook>(hook: H) -> (ArcSender<T, H>, ArcReceiver<T, H>) {
+        let a = Arc::new(Channel::with_hook(hook));
+        (ArcSender { channel: a.clone() }, ArcReceiver { channel: a })
+    }
+
+    pub struct ArcSender<T, H: Hook = Spin> {
+        channel: Arc<Channel<T, H>>,
+    }
+
+    impl<T, H: Hook> ArcSender<T, H> {
+        pub fn send(self, message: T) {
+            unsafe { self.channel.send(message) };
+        }
+    }
+
+    impl<T, H: Hook> Drop for ArcSender<T, H> {
+        fn drop(&mut self) {
+            self.channel.disconnect();
+        }
+    }
+
+    pub struct ArcReceiver<T, H: Hook = Spin> {
+        channel: Arc<Channel<T, H>>,
+    }
+
+    impl<T, H: Hook> ArcReceiver<T, H> {
+        pub fn try_receive(&mut self) -> Option<Result<T, Disconnected>> {
+            self.channel.try_take()
+        }
+
+        pub fn receive(self) -> Result<T, Disconnected> {
+            self.channel.wait_take()
+        }
+    }
+}
+
+#[test]
+fn test_no_std_oneshot() {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    // 待つ間はほかのスレッドに譲り、起こした回数を数えるhook
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+    let hook = FnHook {
+        wait: thread::yield_now,
+        wake: || {
+            WAKES.fetch_add(1, Relaxed);
+        },
+    };
+
+    let mut channel = Channel::with_hook(hook);
+    thread::scope(|s| {
+        let (sender, mut receiver) = channel.split();
+        assert_eq!(receiver.try_receive(), None);
+        s.spawn(move || sender.send("hello"));
+        assert_eq!(receiver.receive(), Ok("hello"));
+    });
+    assert_eq!(WAKES.load(Relaxed), 1);
+
+    // 送らずにドロップされたらErrで戻る
+    let (sender, receiver) = channel.split();
+    drop(sender);
+    assert_eq!(receiver.receive(), Err(Disconnected));
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn test_no_std_oneshot_arc() {
+    use std::thread;
+
+    let (sender, receiver) = arc::channel();
+    thread::spawn(move || sender.send(String::from("hello")));
+    assert_eq!(receiver.receive().as_deref(), Ok("hello"));
+}
@@ -1,23 +1,53 @@
+use crate::cancellation_token::CancellationToken;
+use crate::waiter::{BlockingWaiter, Park, Waiter};
 use std::cell::UnsafeCell;
-use std::marker::PhantomData;
 use std::mem::MaybeUninit;
 use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 use std::thread;
-use std::thread::Thread;
+use std::time::{Duration, Instant};
 
-pub struct Channel<T> {
+// Receiverの待ち方はWで選ぶ(waiter.rs)。Channel::new()はスレッドをparkし、
+// Channel::with_waiter(Spin)ならスピンして待つ
+pub struct Channel<T, W = Park> {
     message: UnsafeCell<MaybeUninit<T>>,
     ready: AtomicBool,
+    // Senderがドロップされた(send()した後も含む)
+    disconnected: AtomicBool,
+    // Senderがパニックの巻き戻しでドロップされた。disconnectedより先に書き込む
+    sender_panicked: AtomicBool,
+    // 待っているReceiverを起こす
+    // Receiverはほかのスレッドに渡せるので、Parkはsplit()した時点ではなく待つときに登録する
+    waiter: W,
 }
 
-unsafe impl<T> Sync for Channel<T> where T: Send {}
+unsafe impl<T, W> Sync for Channel<T, W>
+where
+    T: Send,
+    W: Sync,
+{
+}
 
 impl<T> Channel<T> {
     pub const fn new() -> Self {
+        Self::with_waiter(Park::new())
+    }
+}
+
+impl<T> Default for Channel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, W: Waiter> Channel<T, W> {
+    pub const fn with_waiter(waiter: W) -> Self {
         Self {
             message: UnsafeCell::new(MaybeUninit::uninit()),
             ready: AtomicBool::new(false),
+            disconnected: AtomicBool::new(false),
+            sender_panicked: AtomicBool::new(false),
+            waiter,
         }
     }
 
@@ -25,24 +55,20 @@ impl<T> Channel<T> {
     // 排他的借用（&mut Channel)にすることで同じチャネルに対して複数のSenderとReceiverが作れないことを保証
     // SenderとReceiverがドロップされた後はもう一度split()を呼び出せる
     // ライフタイムを省略しない場合はこうなる
-    // pub fn split<'a>(&'a mut self) -> (Sender<'a, T>, Receiver<'a, T>) {
-    pub fn split(&mut self) -> (Sender<T>, Receiver<T>) {
-        // 上書きすることで古い*selfのDropが実行される
-        *self = Self::new();
-        (
-            Sender {
-                channel: self,
-                receiving_thread: thread::current(),
-            },
-            Receiver {
-                channel: self,
-                _no_send: PhantomData,
-            },
-        )
-    }
-}
-
-impl<T> Drop for Channel<T> {
+    // pub fn split<'a>(&'a mut self) -> (Sender<'a, T, W>, Receiver<'a, T, W>) {
+    pub fn split(&mut self) -> (Sender<'_, T, W>, Receiver<'_, T, W>) {
+        // 上書きするとwaiterも作り直すことになるので、状態だけを戻す
+        if *self.ready.get_mut() {
+            unsafe { self.message.get_mut().assume_init_drop() }
+        }
+        *self.ready.get_mut() = false;
+        *self.disconnected.get_mut() = false;
+        *self.sender_panicked.get_mut() = false;
+        (Sender { channel: self }, Receiver { channel: self })
+    }
+}
+
+impl<T, W> Drop for Channel<T, W> {
     fn drop(&mut self) {
         if *self.ready.get_mut() {
             unsafe { self.message.get_mut().assume_init_drop() }
@@ -50,31 +76,333 @@ impl<T> Drop for Channel<T> {
     }
 }
 
-pub struct Sender<'a, T> {
-    channel: &'a Channel<T>,
-    receiving_thread: Thread,
+pub struct Sender<'a, T, W: Waiter = Park> {
+    channel: &'a Channel<T, W>,
 }
 
-impl<T> Sender<'_, T> {
+impl<T, W: Waiter> Sender<'_, T, W> {
     // 値渡しにより1度しか呼ばれないことが保証されているのでパニックしない
     pub fn send(self, message: T) {
         unsafe { (*self.channel.message.get()).write(message) };
         self.channel.ready.store(true, Release);
-        self.receiving_thread.unpark();
+        self.channel.waiter.wake();
+    }
+}
+
+impl<T, W: Waiter> Drop for Sender<'_, T, W> {
+    fn drop(&mut self) {
+        // send()せずにドロップされても、待っているReceiverが起きて諦められるようにする
+        // 送る前にスレッドがパニックしたのなら、それもReceiverに伝える
+        if thread::panicking() {
+            self.channel.sender_panicked.store(true, Relaxed);
+        }
+        self.channel.disconnected.store(true, Release);
+        self.channel.waiter.wake();
+    }
+}
+
+// Senderがメッセージを送らずにドロップされた
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReceiveError {
+    Disconnected,
+    // send()する前にSenderを持っていたスレッドがパニックした
+    SenderPanicked,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReceiveTimeoutError {
+    // 時間内にメッセージが届かなかった
+    Timeout,
+    Disconnected,
+    SenderPanicked,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReceiveCancelError {
+    // メッセージが届く前にトークンがキャンセルされた
+    Cancelled,
+    Disconnected,
+    SenderPanicked,
+}
+
+impl From<ReceiveError> for ReceiveTimeoutError {
+    fn from(e: ReceiveError) -> Self {
+        match e {
+            ReceiveError::Disconnected => Self::Disconnected,
+            ReceiveError::SenderPanicked => Self::SenderPanicked,
+        }
+    }
+}
+
+impl From<ReceiveError> for ReceiveCancelError {
+    fn from(e: ReceiveError) -> Self {
+        match e {
+            ReceiveError::Disconnected => Self::Disconnected,
+            ReceiveError::SenderPanicked => Self::SenderPanicked,
+        }
     }
 }
 
-pub struct Receiver<'a, T> {
-    channel: &'a Channel<T>,
-    _no_send: PhantomData<*const ()>,
+// 待つスレッドは待つときに登録するので、split()したスレッドとは別のスレッドで受け取れる
+pub struct Receiver<'a, T, W: Waiter = Park> {
+    channel: &'a Channel<T, W>,
 }
 
-impl<T> Receiver<'_, T> {
-    pub fn receive(self) -> T {
+impl<T, W: BlockingWaiter> Receiver<'_, T, W> {
+    pub fn receive(mut self) -> Result<T, ReceiveError> {
+        self.channel
+            .waiter
+            .wait_until(|| self.is_ready_or_disconnected());
+        self.take()
+    }
+
+    // timeoutの間だけ待つ
+    // タイムアウトした後もReceiverは使えるので、もう一度待つこともできる
+    pub fn receive_timeout(&mut self, timeout: Duration) -> Result<T, ReceiveTimeoutError> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.receive_deadline(deadline),
+            // 表せないほど長ければ期限なしで待つ
+            None => {
+                self.channel
+                    .waiter
+                    .wait_until(|| self.is_ready_or_disconnected());
+                self.take().map_err(Into::into)
+            }
+        }
+    }
+
+    // deadlineまで待つ
+    pub fn receive_deadline(&mut self, deadline: Instant) -> Result<T, ReceiveTimeoutError> {
+        let waiter = &self.channel.waiter;
+        if !waiter.wait_until_deadline(|| self.is_ready_or_disconnected(), deadline) {
+            return Err(ReceiveTimeoutError::Timeout);
+        }
+        self.take().map_err(Into::into)
+    }
+}
+
+impl<T> Receiver<'_, T, Park> {
+    // メッセージが届くか、tokenがキャンセルされるまで待つ
+    // tokenに自分を登録しておき、cancel()からもunparkしてもらう
+    // cancel()はスレッドをunparkするので、parkして待つときだけ使える
+    // キャンセルされた後もReceiverは使える
+    pub fn receive_or_cancel(
+        &mut self,
+        token: &CancellationToken,
+    ) -> Result<T, ReceiveCancelError> {
+        let _registration = token.register();
+        self.channel
+            .waiter
+            .wait_until(|| self.is_ready_or_disconnected() || token.is_cancelled());
+        if !self.is_ready_or_disconnected() {
+            return Err(ReceiveCancelError::Cancelled);
+        }
+        self.take().map_err(Into::into)
+    }
+}
+
+impl<T, W: Waiter> Receiver<'_, T, W> {
+    // 待たずに受け取る。まだ届いていなければNoneを返す
+    // 参照で受け取るので、Noneなら後でもう一度試すかreceive()で待てる
+    // Someを返した後はメッセージが残っていないので、receive()はErr(ReceiveError::Disconnected)を返す
+    pub fn try_receive(&mut self) -> Option<T> {
         // falseに戻すことで値がないことをドロップに伝えられる
-        while !self.channel.ready.swap(false, Acquire) {
-            thread::park();
+        if !self.channel.ready.swap(false, Acquire) {
+            return None;
+        }
+        Some(unsafe { (*self.channel.message.get()).assume_init_read() })
+    }
+}
+
+// 複数のスレッドが&Receiverからpeek()すると同じ&Tを共有するので、TがSyncのときだけにする
+impl<T: Sync, W: Waiter> Receiver<'_, T, W> {
+    // 受け取らずにメッセージを見る。まだ届いていなければNoneを返す
+    // メッセージを取り出すのは&mut selfを取るこのReceiverだけなので、
+    // readyがtrueであれば、借用している間に読み出されることはない
+    pub fn peek(&self) -> Option<&T> {
+        if !self.channel.ready.load(Acquire) {
+            return None;
+        }
+        Some(unsafe { (*self.channel.message.get()).assume_init_ref() })
+    }
+}
+
+impl<T, W: Waiter> Receiver<'_, T, W> {
+    // これ以上待っても変わらなければtrue
+    // disconnectedはsend()してからドロップされた場合もtrueになるので、その後でもう一度readyを確認する
+    // Acquireで読むので、ドロップより前のsend()の書き込みも見える
+    fn is_ready_or_disconnected(&self) -> bool {
+        self.channel.ready.load(Acquire) || self.channel.disconnected.load(Acquire)
+    }
+
+    // is_ready_or_disconnected()がtrueになった後で呼ぶ
+    // 送られていなければ、Senderがドロップされた理由を返す
+    fn take(&mut self) -> Result<T, ReceiveError> {
+        if let Some(message) = self.try_receive() {
+            return Ok(message);
+        }
+        if self.channel.sender_panicked.load(Relaxed) {
+            Err(ReceiveError::SenderPanicked)
+        } else {
+            Err(ReceiveError::Disconnected)
         }
-        unsafe { (*self.channel.message.get()).assume_init_read() }
     }
 }
+
+#[test]
+fn test_try_receive() {
+    let mut channel = Channel::new();
+    thread::scope(|s| {
+        let (sender, mut receiver) = channel.split();
+        assert_eq!(receiver.try_receive(), None);
+        assert_eq!(receiver.peek(), None);
+        s.spawn(move || sender.send(String::from("hello")));
+        while receiver.peek().is_none() {
+            thread::yield_now();
+        }
+        // peek()では取り出さない
+        assert_eq!(receiver.peek().unwrap(), "hello");
+        loop {
+            if let Some(message) = receiver.try_receive() {
+                assert_eq!(message, "hello");
+                break;
+            }
+            thread::yield_now();
+        }
+    });
+
+    // TがSyncでなければpeek()は呼べないので、同じ名前のトレイトのメソッドになる
+    trait NotSync {
+        fn peek(&self) -> &'static str {
+            "not Sync"
+        }
+    }
+    impl<T, W: Waiter> NotSync for Receiver<'_, T, W> {}
+    let mut channel = Channel::new();
+    let (sender, receiver) = channel.split();
+    sender.send(std::cell::Cell::new(1));
+    assert_eq!(receiver.peek(), "not Sync");
+}
+
+#[test]
+fn test_receive_timeout() {
+    let mut channel = Channel::new();
+    thread::scope(|s| {
+        let (sender, mut receiver) = channel.split();
+        // 送られていなければ期限で諦める
+        let start = Instant::now();
+        assert_eq!(
+            receiver.receive_timeout(Duration::from_millis(50)),
+            Err(ReceiveTimeoutError::Timeout)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // タイムアウトした後も同じReceiverで受け取れる
+        s.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            sender.send(42);
+        });
+        assert_eq!(
+            receiver.receive_deadline(Instant::now() + Duration::from_secs(10)),
+            Ok(42)
+        );
+    });
+}
+
+#[test]
+fn test_disconnected() {
+    let mut channel = Channel::<i32>::new();
+    thread::scope(|s| {
+        let (sender, receiver) = channel.split();
+        // 送らずにドロップされたら、parkしているReceiverが起きてErrを返す
+        s.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            drop(sender);
+        });
+        assert_eq!(receiver.receive(), Err(ReceiveError::Disconnected));
+    });
+
+    // ドロップされる前に送ったメッセージは受け取れる
+    let (sender, mut receiver) = channel.split();
+    sender.send(1);
+    assert_eq!(receiver.receive_timeout(Duration::from_secs(10)), Ok(1));
+    assert_eq!(
+        receiver.receive_timeout(Duration::from_secs(10)),
+        Err(ReceiveTimeoutError::Disconnected)
+    );
+}
+
+#[test]
+fn test_receive_or_cancel() {
+    let mut channel = Channel::new();
+    let token = CancellationToken::new();
+    thread::scope(|s| {
+        let (sender, mut receiver) = channel.split();
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(10));
+            token.cancel();
+        });
+        // 何も送られなくてもキャンセルで戻る
+        assert_eq!(
+            receiver.receive_or_cancel(&token),
+            Err(ReceiveCancelError::Cancelled)
+        );
+        sender.send(1);
+        // キャンセル済みでも、届いているメッセージは受け取れる
+        assert_eq!(receiver.receive_or_cancel(&token), Ok(1));
+    });
+}
+
+#[test]
+fn test_receive_on_another_thread() {
+    let mut channel = Channel::new();
+    thread::scope(|s| {
+        let (sender, receiver) = channel.split();
+        // split()したスレッドとは別のスレッドで待つ
+        let t = s.spawn(move || receiver.receive());
+        thread::sleep(Duration::from_millis(10));
+        sender.send("hello");
+        assert_eq!(t.join().unwrap(), Ok("hello"));
+    });
+}
+
+#[test]
+fn test_sender_panicked() {
+    let mut channel = Channel::<i32>::new();
+    thread::scope(|s| {
+        let (sender, receiver) = channel.split();
+        let t = s.spawn(move || {
+            let _sender = sender;
+            panic!("sender failed");
+        });
+        // 待ち続けずに、パニックしたことがわかる
+        assert_eq!(receiver.receive(), Err(ReceiveError::SenderPanicked));
+        assert!(t.join().is_err());
+    });
+}
+
+#[test]
+fn test_waiters() {
+    use crate::waiter::Spin;
+
+    // スピンして待っても、同じ手順で受け取れる
+    let mut channel = Channel::with_waiter(Spin);
+    thread::scope(|s| {
+        let (sender, mut receiver) = channel.split();
+        assert_eq!(
+            receiver.receive_timeout(Duration::from_millis(10)),
+            Err(ReceiveTimeoutError::Timeout)
+        );
+        s.spawn(move || sender.send(1));
+        assert_eq!(receiver.receive(), Ok(1));
+    });
+
+    // split()し直すと、前に受け取られなかったメッセージはドロップされる
+    let mut channel = Channel::new();
+    let message = std::sync::Arc::new(());
+    let (sender, _) = channel.split();
+    sender.send(message.clone());
+    let (_, receiver) = channel.split();
+    assert_eq!(std::sync::Arc::strong_count(&message), 1);
+    assert_eq!(receiver.peek(), None);
+}
@@ -0,0 +1,121 @@
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::thread;
+use std::thread::Thread;
+
+// staticに置くためのoneshot_channel_nonblocking
+// split()が&mut selfを取ると、static CHANNELからSenderとReceiverを作れない
+// 代わりに&'static selfで受け取り、split_claimedフラグで1組しか作れないようにする
+// 一度しか使えないので、split()し直してチャネルを再利用することはできない
+pub struct Channel<T> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    ready: AtomicBool,
+    // Senderがドロップされた(send()した後も含む)
+    disconnected: AtomicBool,
+    // split()が呼ばれた
+    split_claimed: AtomicBool,
+}
+
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+impl<T> Channel<T> {
+    pub const fn new() -> Self {
+        Self {
+            message: UnsafeCell::new(MaybeUninit::uninit()),
+            ready: AtomicBool::new(false),
+            disconnected: AtomicBool::new(false),
+            split_claimed: AtomicBool::new(false),
+        }
+    }
+
+    // 2回目以降はパニック
+    // 複数のスレッドが同時に呼び出しても、フラグをswapで立てたスレッドだけが成功する
+    pub fn split(&'static self) -> (Sender<T>, Receiver<T>) {
+        if self.split_claimed.swap(true, Relaxed) {
+            panic!("channel already split!");
+        }
+        (
+            Sender {
+                channel: self,
+                receiving_thread: thread::current(),
+            },
+            Receiver {
+                channel: self,
+                _no_send: PhantomData,
+            },
+        )
+    }
+}
+
+pub struct Sender<T: 'static> {
+    channel: &'static Channel<T>,
+    receiving_thread: Thread,
+}
+
+impl<T> Sender<T> {
+    // 値渡しにより1度しか呼ばれないことが保証されているのでパニックしない
+    pub fn send(self, message: T) {
+        unsafe { (*self.channel.message.get()).write(message) };
+        self.channel.ready.store(true, Release);
+        self.receiving_thread.unpark();
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.disconnected.store(true, Release);
+        self.receiving_thread.unpark();
+    }
+}
+
+// Senderがメッセージを送らずにドロップされた
+#[derive(Debug, PartialEq, Eq)]
+pub struct Disconnected;
+
+// split()を呼んだスレッドがSenderにunparkしてもらうので、ほかのスレッドに渡せない
+pub struct Receiver<T: 'static> {
+    channel: &'static Channel<T>,
+    _no_send: PhantomData<*const ()>,
+}
+
+impl<T> Receiver<T> {
+    pub fn receive(self) -> Result<T, Disconnected> {
+        loop {
+            // falseに戻しておけば、チャネルが再びreadyに見えることはない
+            if self.channel.ready.swap(false, Acquire) {
+                return Ok(unsafe { (*self.channel.message.get()).assume_init_read() });
+            }
+            if self.channel.disconnected.load(Acquire) {
+                // ドロップする前にsend()していれば受け取れる
+                if self.channel.ready.swap(false, Acquire) {
+                    return Ok(unsafe { (*self.channel.message.get()).assume_init_read() });
+                }
+                return Err(Disconnected);
+            }
+            thread::park();
+        }
+    }
+}
+
+#[test]
+fn test_static_channel() {
+    static CHANNEL: Channel<String> = Channel::new();
+    thread::scope(|s| {
+        let (sender, receiver) = CHANNEL.split();
+        s.spawn(move || {
+            sender.send(String::from("hello"));
+        });
+        assert_eq!(receiver.receive().as_deref(), Ok("hello"));
+    });
+}
+
+#[test]
+#[should_panic(expected = "channel already split!")]
+fn test_split_twice() {
+    static CHANNEL: Channel<i32> = Channel::new();
+    let _pair = CHANNEL.split();
+    let _ = CHANNEL.split();
+}
@@ -0,0 +1,220 @@
+use crate::mpsc;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::Arc;
+
+// mpscチャネルに、epollやpollで待てるファイルディスクリプタを付けたもの
+// send()するたびにfdを読み込み可能にするので、イベントループはほかのソケットと一緒にReceiverを待てる
+// Linuxではeventfd、それ以外のUnixではパイプ(self-pipe)を使う
+//
+// fdが読み込み可能になるのは「メッセージがあるかもしれない」という合図でしかない
+// 起こされたらtry_receive()をErr(Empty)になるまで呼ぶ。Emptyを返す前にfdを読み込み不可に戻す
+pub fn channel<T>() -> io::Result<(Sender<T>, Receiver<T>)> {
+    let (sender, receiver) = mpsc::channel();
+    let notifier = Arc::new(Notifier::new()?);
+    Ok((
+        Sender {
+            inner: sender,
+            notifier: notifier.clone(),
+        },
+        Receiver {
+            inner: receiver,
+            notifier,
+        },
+    ))
+}
+
+struct Notifier {
+    read: OwnedFd,
+    write: OwnedFd,
+    // 生きているSenderの数
+    // mpsc::Senderのドロップより先に数え終わるように、mpscとは別に数える
+    senders: AtomicUsize,
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+impl Notifier {
+    // eventfdは1つのfdで読み書きできるので、同じfdを複製して両方に使う
+    #[cfg(target_os = "linux")]
+    fn new() -> io::Result<Self> {
+        let fd = check(unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) })?;
+        let read = unsafe { OwnedFd::from_raw_fd(fd) };
+        let write = read.try_clone()?;
+        Ok(Self {
+            read,
+            write,
+            senders: AtomicUsize::new(1),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        check(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        for fd in [&read, &write] {
+            let fd = fd.as_raw_fd();
+            let flags = check(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+            check(unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) })?;
+            check(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+        }
+        Ok(Self {
+            read,
+            write,
+            senders: AtomicUsize::new(1),
+        })
+    }
+
+    // fdを読み込み可能にする
+    // すでに読み込み可能でバッファやカウンタが埋まっていればEAGAINになるが、合図としては十分なので無視する
+    fn notify(&self) {
+        let one = 1u64.to_ne_bytes();
+        // eventfdは8バイトの書き込みでカウンタを増やす。パイプには1バイトだけ書く
+        let len = if cfg!(target_os = "linux") { 8 } else { 1 };
+        unsafe { libc::write(self.write.as_raw_fd(), one.as_ptr().cast(), len) };
+    }
+
+    // fdを読み込み不可に戻す
+    // eventfdは1回の読み込みでカウンタが0になる。パイプは空になるまで読む
+    fn clear(&self) {
+        let mut buf = [0u8; 64];
+        loop {
+            let n =
+                unsafe { libc::read(self.read.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if n <= 0 || cfg!(target_os = "linux") {
+                break;
+            }
+        }
+    }
+}
+
+pub struct Sender<T> {
+    inner: mpsc::Sender<T>,
+    notifier: Arc<Notifier>,
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, message: T) {
+        // 内側のmpscはclose()しないので、送れないことはない
+        if self.inner.send(message).is_err() {
+            unreachable!("pollable channel is never closed");
+        }
+        self.notifier.notify();
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.notifier.senders.fetch_add(1, Relaxed);
+        Self {
+            inner: self.inner.clone(),
+            notifier: self.notifier.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // 最後のSenderなら、イベントループを起こしてErr(Disconnected)を見せる
+        if self.notifier.senders.fetch_sub(1, Release) == 1 {
+            self.notifier.notify();
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryReceiveError {
+    Empty,
+    // すべてのSenderがドロップされ、残りのメッセージもすべて受け取った
+    Disconnected,
+}
+
+pub struct Receiver<T> {
+    inner: mpsc::Receiver<T>,
+    notifier: Arc<Notifier>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_receive(&mut self) -> Result<T, TryReceiveError> {
+        if let Some(message) = self.inner.try_receive() {
+            return Ok(message);
+        }
+        // 先にSenderの数を読んでおく。0なら、それまでに送られたメッセージはすべてつながっている
+        let disconnected = self.notifier.senders.load(Acquire) == 0;
+        // fdを戻してから確認し直すので、その間に送られたメッセージはここで受け取るか、
+        // notify()でfdがもう一度読み込み可能になる
+        self.notifier.clear();
+        match self.inner.try_receive() {
+            Some(message) => Ok(message),
+            None if disconnected => Err(TryReceiveError::Disconnected),
+            None => Err(TryReceiveError::Empty),
+        }
+    }
+
+    // fdを使わずにスレッドをparkして待つ
+    pub fn receive(&mut self) -> Option<T> {
+        self.inner.receive()
+    }
+}
+
+// epollやpollに登録するfd。読み込み可能になったらtry_receive()を呼ぶ
+impl<T> AsRawFd for Receiver<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.notifier.read.as_raw_fd()
+    }
+}
+
+#[test]
+fn test_pollable() {
+    use std::thread;
+
+    // fdが読み込み可能になるまでtimeoutミリ秒待つ
+    fn poll(fd: RawFd, timeout: i32) -> bool {
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        check(unsafe { libc::poll(&mut pfd, 1, timeout) }).unwrap() == 1
+    }
+
+    let (sender, mut receiver) = channel().unwrap();
+    let fd = receiver.as_raw_fd();
+    assert!(!poll(fd, 0));
+    assert_eq!(receiver.try_receive(), Err(TryReceiveError::Empty));
+
+    thread::scope(|s| {
+        s.spawn(move || {
+            for i in 0..100 {
+                sender.send(i);
+            }
+        });
+        // イベントループのように、fdが読み込み可能になるたびにEmptyまで受け取る
+        let mut next = 0;
+        loop {
+            assert!(poll(fd, 10_000));
+            loop {
+                match receiver.try_receive() {
+                    Ok(i) => {
+                        assert_eq!(i, next);
+                        next += 1;
+                    }
+                    Err(TryReceiveError::Empty) => break,
+                    Err(TryReceiveError::Disconnected) => {
+                        assert_eq!(next, 100);
+                        return;
+                    }
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,148 @@
+use crate::{mpsc, oneshot_channel_multi_sender as oneshot};
+use std::sync::{Arc, RwLock};
+
+// 要求を送ると、その応答を受け取るReceiverが返ってくるチャネル
+// 要求はmpscでServerに送り、応答は要求ごとに作るoneshotで返す
+// Serverは(要求, ResponseSender)の組を受け取り、ResponseSenderで応答を返す
+//
+// 応答のoneshotはsend()したスレッドでparkして待つので、ResponseReceiverはほかのスレッドに渡せない
+pub fn channel<Req, Resp>() -> (Client<Req, Resp>, Server<Req, Resp>) {
+    let (sender, receiver) = mpsc::channel();
+    let closed = Arc::new(RwLock::new(false));
+    (
+        Client {
+            sender,
+            closed: closed.clone(),
+        },
+        Server { receiver, closed },
+    )
+}
+
+// Serverがドロップされると、送られたまま受け取られない要求の応答を誰も返さない
+// Serverは閉じるときに書き込みロックを取り、残っている要求をドロップして待っているClientにNoResponseを返す
+// Clientは読み込みロックを取ったまま送るので、閉じた後に要求が積まれることはない
+type Closed = Arc<RwLock<bool>>;
+
+pub struct Client<Req, Resp> {
+    sender: mpsc::Sender<(Req, ResponseSender<Resp>)>,
+    closed: Closed,
+}
+
+impl<Req, Resp> Client<Req, Resp> {
+    // 要求を送る。Serverがドロップされていれば送らずに要求を返す
+    pub fn send(&self, request: Req) -> Result<ResponseReceiver<Resp>, Req> {
+        let closed = self.closed.read().unwrap();
+        if *closed {
+            return Err(request);
+        }
+        let (sender, receiver) = oneshot::channel();
+        // mpscはclose()しないので失敗しないが、失敗しても要求は返す
+        if let Err((request, _)) = self.sender.send((request, ResponseSender { sender })) {
+            return Err(request);
+        }
+        Ok(ResponseReceiver { receiver })
+    }
+}
+
+impl<Req, Resp> Clone for Client<Req, Resp> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            closed: self.closed.clone(),
+        }
+    }
+}
+
+pub struct Server<Req, Resp> {
+    receiver: mpsc::Receiver<(Req, ResponseSender<Resp>)>,
+    closed: Closed,
+}
+
+impl<Req, Resp> Server<Req, Resp> {
+    // 要求が届くまで待つ。すべてのClientがドロップされ、要求も残っていなければNoneを返す
+    pub fn receive(&mut self) -> Option<(Req, ResponseSender<Resp>)> {
+        self.receiver.receive()
+    }
+
+    pub fn try_receive(&mut self) -> Option<(Req, ResponseSender<Resp>)> {
+        self.receiver.try_receive()
+    }
+}
+
+impl<Req, Resp> Drop for Server<Req, Resp> {
+    fn drop(&mut self) {
+        *self.closed.write().unwrap() = true;
+        // ResponseSenderをドロップして、待っているClientを起こす
+        while self.receiver.try_receive().is_some() {}
+    }
+}
+
+// 1つの要求に1度だけ応答する
+// 応答せずにドロップすると、ResponseReceiverはNoResponseを返す
+pub struct ResponseSender<Resp> {
+    sender: oneshot::Sender<Resp>,
+}
+
+impl<Resp> ResponseSender<Resp> {
+    pub fn respond(self, response: Resp) {
+        // このSenderは複製していないので、先に送られていることはない
+        let _ = self.sender.send(response);
+    }
+}
+
+// Serverが応答せずにResponseSenderをドロップした
+#[derive(Debug, PartialEq, Eq)]
+pub struct NoResponse;
+
+pub struct ResponseReceiver<Resp> {
+    receiver: oneshot::Receiver<Resp>,
+}
+
+impl<Resp> ResponseReceiver<Resp> {
+    // 応答が届くまで待つ
+    pub fn receive(self) -> Result<Resp, NoResponse> {
+        self.receiver.receive().map_err(|_| NoResponse)
+    }
+}
+
+#[test]
+fn test_request_response() {
+    use std::thread;
+
+    let (client, mut server) = channel::<String, String>();
+    thread::scope(|s| {
+        s.spawn(move || {
+            while let Some((request, response)) = server.receive() {
+                // 空の要求には応答しない
+                if !request.is_empty() {
+                    response.respond(request.to_uppercase());
+                }
+            }
+        });
+        for i in 0..4 {
+            let client = client.clone();
+            s.spawn(move || {
+                let request = format!("hello {i}");
+                let response = client.send(request).unwrap();
+                assert_eq!(response.receive(), Ok(format!("HELLO {i}")));
+                assert_eq!(
+                    client.send(String::new()).unwrap().receive(),
+                    Err(NoResponse)
+                );
+            });
+        }
+        // 最後のClientがドロップされるとServerのスレッドが終わる
+        drop(client);
+    });
+}
+
+#[test]
+fn test_server_dropped() {
+    let (client, server) = channel::<i32, i32>();
+    // 受け取られる前にServerがドロップされたら、応答を待っていても戻る
+    let response = client.send(1).unwrap();
+    drop(server);
+    assert_eq!(response.receive(), Err(NoResponse));
+    // ドロップされた後は送れない
+    assert_eq!(client.send(2).err(), Some(2));
+}
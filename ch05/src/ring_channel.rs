@@ -0,0 +1,393 @@
+use core::cell::UnsafeCell;
+use core::hint;
+use core::mem::MaybeUninit;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use core::sync::atomic::{AtomicBool, AtomicUsize};
+
+// 最大N個のメッセージを持てる1対1のチャネル
+// バッファをChannelの中に直接持つのでヒープを確保せず、staticやスタックに置ける
+// coreの機能しか使わないので、no_stdの環境でもそのまま使える
+// そのためスレッドをparkできず、send()とreceive()はスピンして待つ
+pub struct Channel<T, const N: usize> {
+    buffer: [UnsafeCell<MaybeUninit<T>>; N],
+    // 次に受け取る位置。Receiverだけが書き込む
+    head: AtomicUsize,
+    // 次に書き込む位置。Senderだけが書き込む
+    // どちらも増え続けてwrapするので、tail - headがバッファにあるメッセージの数になる
+    tail: AtomicUsize,
+    // SenderとReceiverを作った
+    split: AtomicBool,
+}
+
+// TがSendであればこのChannelはスレッド間で共有しても安全
+unsafe impl<T, const N: usize> Sync for Channel<T, N> where T: Send {}
+
+impl<T, const N: usize> Channel<T, N> {
+    pub const fn new() -> Self {
+        const { assert!(N > 0, "capacity must be at least 1") };
+        Self {
+            buffer: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            split: AtomicBool::new(false),
+        }
+    }
+
+    // oneshot_channel_lifetimeと同じく、排他的借用にすることで1組しか作れないことを保証する
+    pub fn split(&mut self) -> (Sender<'_, T, N>, Receiver<'_, T, N>) {
+        // 上書きすることで古い*selfのDropが実行される
+        *self = Self::new();
+        *self.split.get_mut() = true;
+        (Sender { channel: self }, Receiver { channel: self })
+    }
+
+    // staticに置いたチャネルは&mutで借用できないので、最初の1回だけSenderとReceiverを返す
+    pub fn try_split(&self) -> Option<(Sender<'_, T, N>, Receiver<'_, T, N>)> {
+        if self.split.swap(true, Relaxed) {
+            return None;
+        }
+        Some((Sender { channel: self }, Receiver { channel: self }))
+    }
+
+    fn slot(&self, pos: usize) -> *mut MaybeUninit<T> {
+        self.buffer[pos % N].get()
+    }
+
+    // バッファにあるメッセージの数。読んでいる間にも相手が進めるので目安にしかならない
+    // Receiverはtailを読んでからheadをReleaseで進めるので、headをAcquireで読めば
+    // 後で読んだtailはReceiverが見たtail以上になり、headより小さくなることはない
+    // その間に両方が進むとNを超えることがあるので、Nで抑える
+    fn len(&self) -> usize {
+        let head = self.head.load(Acquire);
+        let tail = self.tail.load(Relaxed);
+        tail.wrapping_sub(head).min(N)
+    }
+}
+
+impl<T, const N: usize> Default for Channel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for Channel<T, N> {
+    fn drop(&mut self) {
+        // 受け取られなかったメッセージをドロップする
+        let head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+        let mut pos = head;
+        while pos != tail {
+            unsafe { self.buffer[pos % N].get_mut().assume_init_drop() };
+            pos = pos.wrapping_add(1);
+        }
+    }
+}
+
+pub struct Sender<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+}
+
+impl<T, const N: usize> Sender<'_, T, N> {
+    // 受け取られていないメッセージのおおよその数
+    // いっぱいに近ければ送るのをやめる、といった負荷制御やメトリクスに使う
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    // バッファがいっぱいならメッセージを返す
+    pub fn try_send(&mut self, message: T) -> Result<(), T> {
+        let tail = self.channel.tail.load(Relaxed);
+        // Receiverが読み終わった位置をAcquireで読んでから、そのスロットを上書きする
+        if tail.wrapping_sub(self.channel.head.load(Acquire)) == N {
+            return Err(message);
+        }
+        unsafe { (*self.channel.slot(tail)).write(message) };
+        self.channel.tail.store(tail.wrapping_add(1), Release);
+        Ok(())
+    }
+
+    // 空きができるまでスピンして待つ
+    // Receiverがドロップされていると戻らない
+    pub fn send(&mut self, mut message: T) {
+        while let Err(m) = self.try_send(message) {
+            message = m;
+            hint::spin_loop();
+        }
+    }
+
+    // まとめて送る。空いているスロットに書けるだけ書いてから、tailを1回だけ進める
+    // 入りきらなければ、空きができるまでスピンして続きを書く
+    pub fn send_all(&mut self, messages: impl IntoIterator<Item = T>) {
+        let mut messages = messages.into_iter().peekable();
+        while messages.peek().is_some() {
+            let tail = self.channel.tail.load(Relaxed);
+            let free = N - tail.wrapping_sub(self.channel.head.load(Acquire));
+            if free == 0 {
+                hint::spin_loop();
+                continue;
+            }
+            let mut n = 0;
+            for message in messages.by_ref().take(free) {
+                unsafe { (*self.channel.slot(tail.wrapping_add(n))).write(message) };
+                n += 1;
+            }
+            self.channel.tail.store(tail.wrapping_add(n), Release);
+        }
+    }
+}
+
+impl<'a, T, const N: usize> Sender<'a, T, N> {
+    // 空いているスロットを1つ予約する。バッファがいっぱいならNone
+    // メッセージをスロットの中で直接組み立てられるので、大きなTをコピーせずに済む
+    pub fn try_reserve(&mut self) -> Option<SendSlot<'_, 'a, T, N>> {
+        let tail = self.channel.tail.load(Relaxed);
+        if tail.wrapping_sub(self.channel.head.load(Acquire)) == N {
+            return None;
+        }
+        Some(SendSlot {
+            sender: self,
+            pos: tail,
+            initialized: false,
+        })
+    }
+
+    // 空きができるまでスピンして待ってから予約する
+    pub fn reserve(&mut self) -> SendSlot<'_, 'a, T, N> {
+        let tail = self.channel.tail.load(Relaxed);
+        while tail.wrapping_sub(self.channel.head.load(Acquire)) == N {
+            hint::spin_loop();
+        }
+        SendSlot {
+            sender: self,
+            pos: tail,
+            initialized: false,
+        }
+    }
+}
+
+// 予約したスロット。書き込んでからドロップすると送られる
+// 書き込まずにドロップすれば何も送られず、予約は取り消される
+// Senderを借用しているので、予約している間はほかに送れない
+pub struct SendSlot<'s, 'a, T, const N: usize> {
+    sender: &'s mut Sender<'a, T, N>,
+    pos: usize,
+    initialized: bool,
+}
+
+impl<T, const N: usize> SendSlot<'_, '_, T, N> {
+    // スロットに直接書き込む。前に書き込んだ値があればドロップする
+    pub fn write(&mut self, message: T) -> &mut T {
+        let slot = unsafe { &mut *self.sender.channel.slot(self.pos) };
+        if self.initialized {
+            unsafe { slot.assume_init_drop() };
+        }
+        self.initialized = true;
+        slot.write(message)
+    }
+
+    // 初期化されていないスロットをそのまま渡す
+    // 少しずつ書き込んで組み立てた後、assume_init()を呼ぶと送られるようになる
+    // 書き込まれた値があれば先にドロップするので、スロットは常に初期化されていない状態で渡す
+    pub fn as_uninit(&mut self) -> &mut MaybeUninit<T> {
+        let slot = unsafe { &mut *self.sender.channel.slot(self.pos) };
+        if self.initialized {
+            unsafe { slot.assume_init_drop() };
+            self.initialized = false;
+        }
+        slot
+    }
+
+    /// # Safety
+    /// as_uninit()で渡したスロットを、すべて初期化し終えていること
+    pub unsafe fn assume_init(&mut self) {
+        self.initialized = true;
+    }
+}
+
+impl<T, const N: usize> Drop for SendSlot<'_, '_, T, N> {
+    fn drop(&mut self) {
+        // 書き込んだ後でだけtailを進める。Releaseなので書き込みもReceiverに見える
+        if self.initialized {
+            self.sender
+                .channel
+                .tail
+                .store(self.pos.wrapping_add(1), Release);
+        }
+    }
+}
+
+pub struct Receiver<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+}
+
+impl<T, const N: usize> Receiver<'_, T, N> {
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+// 複数のスレッドが&Receiverからpeek()すると同じ&Tを共有するので、TがSyncのときだけにする
+impl<T: Sync, const N: usize> Receiver<'_, T, N> {
+    // 次に受け取るメッセージを取り出さずに見る
+    // headを進めるまでSenderはそのスロットを上書きしない
+    pub fn peek(&self) -> Option<&T> {
+        let head = self.channel.head.load(Relaxed);
+        if head == self.channel.tail.load(Acquire) {
+            return None;
+        }
+        Some(unsafe { (*self.channel.slot(head)).assume_init_ref() })
+    }
+}
+
+impl<T, const N: usize> Receiver<'_, T, N> {
+    pub fn try_receive(&mut self) -> Option<T> {
+        let head = self.channel.head.load(Relaxed);
+        if head == self.channel.tail.load(Acquire) {
+            return None;
+        }
+        let message = unsafe { (*self.channel.slot(head)).assume_init_read() };
+        // 読み出してから進めるので、Senderはこの後でしかスロットを上書きしない
+        self.channel.head.store(head.wrapping_add(1), Release);
+        Some(message)
+    }
+
+    // 1つ以上届くまでスピンして待ち、届いているメッセージをlimit個までbufに追加する
+    // headは最後に1回だけ進めるので、Senderのキャッシュラインに書き込む回数が減る
+    pub fn receive_many(&mut self, buf: &mut Vec<T>, limit: usize) -> usize {
+        if limit == 0 {
+            return 0;
+        }
+        let head = self.channel.head.load(Relaxed);
+        let mut available = 0;
+        while available == 0 {
+            available = self.channel.tail.load(Acquire).wrapping_sub(head);
+            hint::spin_loop();
+        }
+        let n = available.min(limit);
+        for i in 0..n {
+            buf.push(unsafe { (*self.channel.slot(head.wrapping_add(i))).assume_init_read() });
+        }
+        self.channel.head.store(head.wrapping_add(n), Release);
+        n
+    }
+
+    // メッセージが届くまでスピンして待つ
+    // Senderがドロップされていると戻らない
+    pub fn receive(&mut self) -> T {
+        loop {
+            if let Some(message) = self.try_receive() {
+                return message;
+            }
+            hint::spin_loop();
+        }
+    }
+}
+
+#[test]
+fn test_ring_channel() {
+    use std::thread;
+
+    let mut channel = Channel::<String, 4>::new();
+    thread::scope(|s| {
+        let (mut sender, mut receiver) = channel.split();
+        // スピンで待つので、CPUが少ないと相手に切り替わるまで進まない。数を抑えておく
+        s.spawn(move || {
+            for i in 0..100 {
+                sender.send(i.to_string());
+            }
+        });
+        for i in 0..100 {
+            assert_eq!(receiver.receive(), i.to_string());
+        }
+    });
+
+    // staticに置いてヒープを使わずに共有できる
+    static CHANNEL: Channel<u32, 2> = Channel::new();
+    let (mut sender, mut receiver) = CHANNEL.try_split().unwrap();
+    assert!(CHANNEL.try_split().is_none());
+    assert!(receiver.is_empty());
+    assert_eq!(sender.try_send(1), Ok(()));
+    assert_eq!(sender.try_send(2), Ok(()));
+    assert_eq!(sender.try_send(3), Err(3));
+    assert_eq!((sender.len(), sender.capacity()), (2, 2));
+    assert_eq!(receiver.peek(), Some(&1));
+    assert_eq!(receiver.try_receive(), Some(1));
+    assert_eq!(sender.try_send(3), Ok(()));
+    assert_eq!(receiver.try_receive(), Some(2));
+    assert_eq!(receiver.try_receive(), Some(3));
+    assert_eq!(receiver.try_receive(), None);
+    assert_eq!(sender.len(), 0);
+
+    // TがSyncでなければpeek()は呼べないので、同じ名前のトレイトのメソッドになる
+    trait NotSync {
+        fn peek(&self) -> &'static str {
+            "not Sync"
+        }
+    }
+    impl<T, const N: usize> NotSync for Receiver<'_, T, N> {}
+    let mut channel = Channel::<_, 1>::new();
+    let (mut sender, receiver) = channel.split();
+    assert_eq!(sender.try_send(std::cell::Cell::new(1)), Ok(()));
+    assert_eq!(receiver.peek(), "not Sync");
+}
+
+#[test]
+fn test_ring_channel_batch() {
+    use std::thread;
+
+    let mut channel = Channel::<u32, 8>::new();
+    thread::scope(|s| {
+        let (mut sender, mut receiver) = channel.split();
+        // バッファより多くまとめて送っても、空いた分ずつ書き込む
+        s.spawn(move || sender.send_all(0..100));
+        let mut buf = Vec::new();
+        while buf.len() < 100 {
+            let n = receiver.receive_many(&mut buf, 16);
+            assert!((1..=8).contains(&n));
+        }
+        assert_eq!(buf, (0..100).collect::<Vec<_>>());
+    });
+}
+
+#[test]
+fn test_reserve() {
+    let mut channel = Channel::<[u64; 1024], 2>::new();
+    let (mut sender, mut receiver) = channel.split();
+
+    // 書き込まずにドロップした予約は送られない
+    drop(sender.reserve());
+    assert!(receiver.try_receive().is_none());
+
+    // スロットの中で組み立てる
+    {
+        let mut slot = sender.reserve();
+        let p = slot.as_uninit().as_mut_ptr() as *mut u64;
+        for i in 0..1024 {
+            unsafe { p.add(i).write(i as u64) };
+        }
+        unsafe { slot.assume_init() };
+    }
+    sender.try_reserve().unwrap().write([7; 1024]);
+    // バッファがいっぱいなら予約できない
+    assert!(sender.try_reserve().is_none());
+
+    let first = receiver.try_receive().unwrap();
+    assert!(first.iter().enumerate().all(|(i, &x)| x == i as u64));
+    assert_eq!(receiver.try_receive(), Some([7; 1024]));
+}
@@ -0,0 +1,75 @@
+use crate::oneshot_channel_nonblocking::{Channel, Receiver, Sender};
+use std::thread::{Scope, ScopedJoinHandle};
+
+// main.rsのように、スタックに置いたChannelをsplit()して片方をスコープ付きスレッドに渡す手順をまとめたもの
+//
+//     let mut channel = Channel::new();
+//     thread::scope(|s| {
+//         let (_, receiver) = scoped_channel(s, &mut channel).spawn_sender(|sender| sender.send(1));
+//         assert_eq!(receiver.receive(), Ok(1));
+//     });
+//
+// Channelはスコープより長く生きるので、スレッドに渡したSenderやReceiverがスコープの中で使われても安全
+pub fn scoped_channel<'scope, 'env, 'a, T>(
+    scope: &'scope Scope<'scope, 'env>,
+    channel: &'a mut Channel<T>,
+) -> ScopedChannel<'scope, 'env, 'a, T> {
+    let (sender, receiver) = channel.split();
+    ScopedChannel {
+        scope,
+        sender,
+        receiver,
+    }
+}
+
+pub struct ScopedChannel<'scope, 'env, 'a, T> {
+    scope: &'scope Scope<'scope, 'env>,
+    sender: Sender<'a, T>,
+    receiver: Receiver<'a, T>,
+}
+
+impl<'scope, 'a, T> ScopedChannel<'scope, '_, 'a, T>
+where
+    T: Send,
+    'a: 'scope,
+{
+    // Senderをfに渡して新しいスレッドで実行し、Receiverはこのスレッドで使う
+    pub fn spawn_sender<F, R>(self, f: F) -> (ScopedJoinHandle<'scope, R>, Receiver<'a, T>)
+    where
+        F: FnOnce(Sender<'a, T>) -> R + Send + 'scope,
+        R: Send + 'scope,
+    {
+        let sender = self.sender;
+        (self.scope.spawn(move || f(sender)), self.receiver)
+    }
+
+    // Receiverをfに渡して新しいスレッドで実行し、Senderはこのスレッドで使う
+    pub fn spawn_receiver<F, R>(self, f: F) -> (Sender<'a, T>, ScopedJoinHandle<'scope, R>)
+    where
+        F: FnOnce(Receiver<'a, T>) -> R + Send + 'scope,
+        R: Send + 'scope,
+    {
+        let receiver = self.receiver;
+        (self.sender, self.scope.spawn(move || f(receiver)))
+    }
+}
+
+#[test]
+fn test_scoped_channel() {
+    use std::thread;
+
+    let mut channel = Channel::new();
+    thread::scope(|s| {
+        let (_, receiver) =
+            scoped_channel(s, &mut channel).spawn_sender(|sender| sender.send("hello"));
+        assert_eq!(receiver.receive(), Ok("hello"));
+    });
+
+    // 同じChannelをもう一度使える
+    thread::scope(|s| {
+        let (sender, handle) =
+            scoped_channel(s, &mut channel).spawn_receiver(|receiver| receiver.receive());
+        sender.send("world");
+        assert_eq!(handle.join().unwrap(), Ok("world"));
+    });
+}
@@ -0,0 +1,147 @@
+use crate::mpsc;
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+// 複数のReceiverのうち、どれかが受け取れるようになるまで待つ
+// 受け取れるReceiverの番号(追加した順)を返すだけなので、受け取るのはSelectを捨てた後に行う
+//
+//     let i = {
+//         let mut sel = Select::new();
+//         sel.receive(&a);
+//         sel.receive(&b);
+//         sel.ready()
+//     };
+//     match i { 0 => a.try_receive(), _ => b.try_receive() }
+//
+// 「受け取れる」にはすべてのSenderがドロップされた場合も含む(receive()がNoneで返る)
+// 複数が受け取れるときは先に追加した方を返す
+pub struct Select<'a> {
+    handles: Vec<&'a dyn Selectable>,
+}
+
+// Selectで待てるもの
+pub trait Selectable {
+    // すぐに受け取れるならtrue。そうでなければ届いたときにthreadをunparkするように登録する
+    fn poll_ready(&self, thread: &Thread) -> bool;
+}
+
+impl<T> Selectable for mpsc::Receiver<T> {
+    fn poll_ready(&self, thread: &Thread) -> bool {
+        mpsc::Receiver::poll_ready(self, thread)
+    }
+}
+
+// ready_timeout()の時間内にどれも受け取れるようにならなかった
+#[derive(Debug, PartialEq, Eq)]
+pub struct Timeout;
+
+impl<'a> Select<'a> {
+    pub fn new() -> Self {
+        Self {
+            handles: Vec::new(),
+        }
+    }
+
+    // 待つReceiverを追加して、その番号を返す
+    pub fn receive<T>(&mut self, receiver: &'a mpsc::Receiver<T>) -> usize {
+        self.handles.push(receiver);
+        self.handles.len() - 1
+    }
+
+    // どれかが受け取れるようになるまで待つ
+    pub fn ready(&self) -> usize {
+        match self.wait(None) {
+            Ok(i) => i,
+            Err(Timeout) => unreachable!(),
+        }
+    }
+
+    // ready()と同じだが、timeoutを過ぎたらErr(Timeout)を返す
+    // イベントループで、メッセージがなくても定期的に後片付けをしたいときに使う
+    // Instantで表せないほど長いtimeoutは、期限なしで待つ
+    pub fn ready_timeout(&self, timeout: Duration) -> Result<usize, Timeout> {
+        self.wait(Instant::now().checked_add(timeout))
+    }
+
+    // 待たずに、今受け取れるものがあればその番号を返す(selectのdefault節)
+    pub fn try_select(&self) -> Option<usize> {
+        self.wait(Some(Instant::now())).ok()
+    }
+
+    fn wait(&self, deadline: Option<Instant>) -> Result<usize, Timeout> {
+        // 何も追加されていなければ、いつまでも起こされない
+        assert!(!self.handles.is_empty(), "nothing to select!");
+        let current = thread::current();
+        loop {
+            // 前のループで登録したものも含めてすべて登録し直してから、parkする
+            if let Some(i) = self.handles.iter().position(|h| h.poll_ready(&current)) {
+                return Ok(i);
+            }
+            match deadline {
+                None => thread::park(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Timeout);
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+            }
+        }
+    }
+}
+
+impl Default for Select<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_select() {
+    let (a_sender, mut a) = mpsc::channel::<i32>();
+    let (b_sender, mut b) = mpsc::channel::<&str>();
+    thread::scope(|s| {
+        s.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            b_sender.send("hello").unwrap();
+        });
+        let i = {
+            let mut sel = Select::new();
+            sel.receive(&a);
+            sel.receive(&b);
+            sel.ready()
+        };
+        assert_eq!(i, 1);
+        assert_eq!(b.try_receive(), Some("hello"));
+    });
+    // Senderがすべてドロップされても受け取れる状態になる(receive()はNoneで返る)
+    drop(a_sender);
+    let mut sel = Select::new();
+    sel.receive(&a);
+    assert_eq!(sel.ready(), 0);
+    assert_eq!(a.receive(), None);
+}
+
+#[test]
+fn test_ready_timeout() {
+    let (sender, mut receiver) = mpsc::channel();
+    {
+        let mut sel = Select::new();
+        sel.receive(&receiver);
+        assert_eq!(sel.try_select(), None);
+        let start = Instant::now();
+        assert_eq!(sel.ready_timeout(Duration::from_millis(10)), Err(Timeout));
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+    sender.send(1).unwrap();
+    {
+        let mut sel = Select::new();
+        sel.receive(&receiver);
+        assert_eq!(sel.try_select(), Some(0));
+        assert_eq!(sel.ready_timeout(Duration::from_secs(10)), Ok(0));
+        // 期限が表せなくてもパニックせずに待つ
+        assert_eq!(sel.ready_timeout(Duration::MAX), Ok(0));
+    }
+    assert_eq!(receiver.try_receive(), Some(1));
+}
@@ -0,0 +1,359 @@
+use crate::waiter::{BlockingWaiter, Park, Waiter};
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::Ordering::{Acquire, Release, SeqCst};
+use std::sync::atomic::{AtomicBool, AtomicU32};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// 何度でもsend()とreceive()ができる1対1のチャネル
+// oneshotと違い、1つのセルを使い回すのでメッセージごとにメモリを確保しない
+// セルが空ならsend()、埋まっていればreceive()だけが進めるように、seqの偶奇で状態を表す
+//
+// 相手の待ち方はWで選ぶ(waiter.rs)。channel()はスレッドをparkし、channel_with::<T, Spin>()ならスピンする
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    channel_with()
+}
+
+pub fn channel_with<T, W: BlockingWaiter>() -> (Sender<T, W>, Receiver<T, W>) {
+    let a = Arc::new(Channel {
+        message: UnsafeCell::new(MaybeUninit::uninit()),
+        seq: AtomicU32::new(0),
+        disconnected: AtomicBool::new(false),
+        sender: W::default(),
+        receiver: W::default(),
+    });
+    (Sender { channel: a.clone() }, Receiver { channel: a })
+}
+
+// SenderとReceiverで待つものが違うので、Waiterはそれぞれに持つ
+// 1つを共有すると、片方が待っている間にもう片方が待ち始めたときに起こし分けられない
+struct Channel<T, W> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    // 偶数: 空。send()が書き込んでから奇数にする
+    // 奇数: メッセージがある。receive()が読み出してから偶数にする
+    seq: AtomicU32,
+    // SenderかReceiverのどちらかがドロップされた
+    disconnected: AtomicBool,
+    // セルが空くのを待つSender
+    sender: W,
+    // セルが埋まるのを待つReceiver
+    receiver: W,
+}
+
+// TがSendであればこのChannelはスレッド間で共有しても安全
+unsafe impl<T, W> Sync for Channel<T, W>
+where
+    T: Send,
+    W: Sync,
+{
+}
+
+enum Wait {
+    Ready,
+    Disconnected,
+    TimedOut,
+}
+
+impl<T, W: Waiter> Channel<T, W> {
+    // seqの偶奇がfullになるか、相手がドロップされるか、deadlineを過ぎるまで待つ
+    fn wait_until(&self, full: bool, waiter: &W, deadline: Option<Instant>) -> Wait
+    where
+        W: BlockingWaiter,
+    {
+        let ready = || self.seq.load(Acquire) % 2 == full as u32;
+        // Senderは、close()した後やReceiverがいなくなった後はセルが空いていても書き込まない
+        if !full && self.disconnected.load(Acquire) {
+            return Wait::Disconnected;
+        }
+        let done = || ready() || self.disconnected.load(Acquire);
+        let in_time = match deadline {
+            None => {
+                waiter.wait_until(done);
+                true
+            }
+            Some(deadline) => waiter.wait_until_deadline(done, deadline),
+        };
+        // ドロップする前に送られたメッセージは受け取れるので、disconnectedより先にreadyを見る
+        if ready() {
+            Wait::Ready
+        } else if !in_time {
+            Wait::TimedOut
+        } else {
+            Wait::Disconnected
+        }
+    }
+
+    // 相手が待っていれば起こす
+    fn disconnect(&self, other: &W) {
+        self.disconnected.store(true, Release);
+        other.wake();
+    }
+}
+
+impl<T, W> Drop for Channel<T, W> {
+    fn drop(&mut self) {
+        if *self.seq.get_mut() % 2 == 1 {
+            unsafe { self.message.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+pub struct Sender<T, W: Waiter = Park> {
+    channel: Arc<Channel<T, W>>,
+}
+
+// どちらの場合も送れなかったメッセージを返す
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendTimeoutError<T> {
+    // 時間内にセルが空かなかった
+    Timeout(T),
+    // Receiverがドロップされた
+    Disconnected(T),
+}
+
+impl<T, W: BlockingWaiter> Sender<T, W> {
+    // 前のメッセージが受け取られるまで待つ
+    // &mut selfにすることで、同時にsend()できるのは1スレッドだけになる
+    // Receiverがドロップされていれば送らずに返す
+    pub fn send(&mut self, message: T) -> Result<(), T> {
+        let channel = &*self.channel;
+        match channel.wait_until(false, &channel.sender, None) {
+            Wait::Ready => {
+                self.write(message);
+                Ok(())
+            }
+            _ => Err(message),
+        }
+    }
+
+    // 前のメッセージが受け取られるのをtimeoutの間だけ待つ
+    // Receiverが遅れていると送る側も止まるので、待ち続けずに諦めたいときに使う
+    pub fn send_timeout(
+        &mut self,
+        message: T,
+        timeout: Duration,
+    ) -> Result<(), SendTimeoutError<T>> {
+        let channel = &*self.channel;
+        match channel.wait_until(false, &channel.sender, Instant::now().checked_add(timeout)) {
+            Wait::Ready => {
+                self.write(message);
+                Ok(())
+            }
+            Wait::Disconnected => Err(SendTimeoutError::Disconnected(message)),
+            Wait::TimedOut => Err(SendTimeoutError::Timeout(message)),
+        }
+    }
+
+    // セルが空いていることを確認してから呼ぶ
+    fn write(&mut self, message: T) {
+        let channel = &*self.channel;
+        unsafe { (*channel.message.get()).write(message) };
+        channel.seq.fetch_add(1, SeqCst);
+        channel.receiver.wake();
+    }
+}
+
+impl<T, W: Waiter> Sender<T, W> {
+    // チャネルを閉じる。Senderをドロップしたときと同じく、Receiverは残りを受け取ってからNoneを返す
+    // Senderを別の場所でドロップしなくても終わりを伝えられ、閉じた後のsend()はErrを返す
+    pub fn close(&mut self) {
+        self.channel.disconnect(&self.channel.receiver);
+    }
+}
+
+impl<T, W: Waiter> Drop for Sender<T, W> {
+    fn drop(&mut self) {
+        self.channel.disconnect(&self.channel.receiver);
+    }
+}
+
+pub struct Receiver<T, W: Waiter = Park> {
+    channel: Arc<Channel<T, W>>,
+}
+
+impl<T, W: BlockingWaiter> Receiver<T, W> {
+    // メッセージが届くまで待つ
+    // Senderがドロップされ、受け取っていないメッセージもなければNoneを返す
+    pub fn receive(&mut self) -> Option<T> {
+        let channel = &*self.channel;
+        if !matches!(
+            channel.wait_until(true, &channel.receiver, None),
+            Wait::Ready
+        ) {
+            return None;
+        }
+        let message = unsafe { (*channel.message.get()).assume_init_read() };
+        channel.seq.fetch_add(1, SeqCst);
+        channel.sender.wake();
+        Some(message)
+    }
+}
+
+// &Receiverを共有したスレッドが同時にpeek()できるので、TがSyncでなければならない
+impl<T: Sync, W: Waiter> Receiver<T, W> {
+    // 受け取らずにメッセージを見る
+    // seqが奇数の間はreceive()が偶数に戻すまでSenderはセルに触れないので、
+    // 借用している間にメッセージが書き換えられることはない
+    pub fn peek(&self) -> Option<&T> {
+        if self.channel.seq.load(Acquire).is_multiple_of(2) {
+            return None;
+        }
+        Some(unsafe { (*self.channel.message.get()).assume_init_ref() })
+    }
+}
+
+impl<T, W: Waiter> Receiver<T, W> {
+    // 待たずに受け取る
+    pub fn try_receive(&mut self) -> Option<T> {
+        if self.channel.seq.load(Acquire).is_multiple_of(2) {
+            return None;
+        }
+        let message = unsafe { (*self.channel.message.get()).assume_init_read() };
+        self.channel.seq.fetch_add(1, SeqCst);
+        self.channel.sender.wake();
+        Some(message)
+    }
+}
+
+impl<T, W: Waiter> Drop for Receiver<T, W> {
+    fn drop(&mut self) {
+        self.channel.disconnect(&self.channel.sender);
+    }
+}
+
+// for message in receiverのように受け取る
+// 1つずつreceive()で待ち、Senderがドロップされてセルも空なら終わる
+pub struct Iter<'a, T, W: Waiter = Park> {
+    receiver: &'a mut Receiver<T, W>,
+}
+
+pub struct IntoIter<T, W: Waiter = Park> {
+    receiver: Receiver<T, W>,
+}
+
+impl<T, W: BlockingWaiter> Receiver<T, W> {
+    pub fn iter(&mut self) -> Iter<'_, T, W> {
+        Iter { receiver: self }
+    }
+}
+
+impl<T, W: BlockingWaiter> Iterator for Iter<'_, T, W> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.receive()
+    }
+}
+
+impl<T, W: BlockingWaiter> Iterator for IntoIter<T, W> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.receive()
+    }
+}
+
+impl<T, W: BlockingWaiter> IntoIterator for Receiver<T, W> {
+    type Item = T;
+    type IntoIter = IntoIter<T, W>;
+
+    fn into_iter(self) -> IntoIter<T, W> {
+        IntoIter { receiver: self }
+    }
+}
+
+impl<'a, T, W: BlockingWaiter> IntoIterator for &'a mut Receiver<T, W> {
+    type Item = T;
+    type IntoIter = Iter<'a, T, W>;
+
+    fn into_iter(self) -> Iter<'a, T, W> {
+        self.iter()
+    }
+}
+
+#[test]
+fn test_spsc_channel() {
+    use std::thread;
+
+    let (mut sender, mut receiver) = channel();
+    thread::scope(|s| {
+        s.spawn(move || {
+            for i in 0..10000 {
+                sender.send(i.to_string()).unwrap();
+            }
+        });
+        // Senderがドロップされたら終わる
+        assert!(receiver.iter().eq((0..10000).map(|i| i.to_string())));
+    });
+
+    let (mut sender, receiver) = channel();
+    sender.send(1).unwrap();
+    assert_eq!(receiver.peek(), Some(&1));
+    // 受け取られるまで次は送れない
+    let timeout = Duration::from_millis(20);
+    let start = Instant::now();
+    assert_eq!(
+        sender.send_timeout(2, timeout),
+        Err(SendTimeoutError::Timeout(2))
+    );
+    assert!(start.elapsed() >= timeout);
+    drop(receiver);
+    assert_eq!(sender.send(2), Err(2));
+    assert_eq!(
+        sender.send_timeout(3, timeout),
+        Err(SendTimeoutError::Disconnected(3))
+    );
+
+    // TがSyncでなければpeek()は呼べないので、同じ名前のトレイトのメソッドになる
+    trait NotSync {
+        fn peek(&self) -> &'static str {
+            "not Sync"
+        }
+    }
+    impl<T, W: Waiter> NotSync for Receiver<T, W> {}
+    let (mut sender, receiver) = channel();
+    sender.send(std::cell::Cell::new(1)).unwrap();
+    assert_eq!(receiver.peek(), "not Sync");
+}
+
+#[test]
+fn test_close() {
+    let (mut sender, mut receiver) = channel();
+    assert_eq!(sender.send(1), Ok(()));
+    sender.close();
+    // 閉じる前に送ったメッセージは受け取れる
+    assert_eq!(receiver.receive(), Some(1));
+    assert_eq!(receiver.receive(), None);
+    assert_eq!(sender.send(2), Err(2));
+}
+
+#[test]
+fn test_waiters() {
+    use std::thread;
+
+    // 同じ手順のまま、待ち方だけを変える
+    // CPUが1つだとSpinは相手に切り替わるまで回り続けるので、数は少なめにする
+    fn check<W: BlockingWaiter>() {
+        let (mut sender, mut receiver) = channel_with::<i32, W>();
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..100 {
+                    sender.send(i).unwrap();
+                }
+            });
+            assert!(receiver.iter().eq(0..100));
+        });
+
+        let (mut sender, _receiver) = channel_with::<i32, W>();
+        sender.send(1).unwrap();
+        assert_eq!(
+            sender.send_timeout(2, Duration::from_millis(10)),
+            Err(SendTimeoutError::Timeout(2))
+        );
+    }
+
+    check::<crate::waiter::Spin>();
+    #[cfg(target_os = "linux")]
+    check::<crate::waiter::Futex>();
+}
@@ -0,0 +1,755 @@
+// 各チャネルに、送受信やドロップの順番をランダムに変えたやり取りを何度も繰り返させる
+// メッセージにはTrackedを使い、すべてがちょうど1回ずつドロップされることを確かめる
+// 2回ドロップされれば同じセルを2回読み出しており、ドロップされなければリークしている
+//
+// CPUが少ないと同時に動くスレッドが限られるので、繰り返しの回数で組み合わせを稼ぐ
+// どのテストもwatchdog()の中で動かすので、デッドロックすればテストが失敗する
+use crate::{
+    broadcast, mailbox, mpmc, mpsc, oneshot_channel_ack, oneshot_channel_arc,
+    oneshot_channel_async, oneshot_channel_lifetime, oneshot_channel_multi_sender,
+    oneshot_channel_no_std, oneshot_channel_nonblocking, oneshot_channel_static, request_response,
+    ring_channel, spsc_channel, watch,
+};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
+use std::time::Duration;
+
+const ITERATIONS: u64 = 200;
+
+// これより長くかかったらデッドロックしたとみなす
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// fを別のスレッドで実行し、TIMEOUTまでに終わらなければパニックする
+// 止まったスレッドは残るが、テスト全体が止まったままになるよりよい
+fn watchdog(f: impl FnOnce() + Send + 'static) {
+    let (done, finished) = std::sync::mpsc::channel();
+    let handle = thread::spawn(move || {
+        f();
+        let _ = done.send(());
+    });
+    match finished.recv_timeout(TIMEOUT) {
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+            panic!("did not finish within {TIMEOUT:?}; deadlocked?")
+        }
+        // fがパニックしていればここで伝える
+        _ => {
+            if let Err(e) = handle.join() {
+                std::panic::resume_unwind(e);
+            }
+        }
+    }
+}
+
+// メッセージごとに、作られたかとドロップされたかを記録する
+struct Tracker {
+    created: Vec<AtomicBool>,
+    dropped: Vec<AtomicBool>,
+}
+
+impl Tracker {
+    fn new(n: usize) -> Self {
+        let flags = || (0..n).map(|_| AtomicBool::new(false)).collect();
+        Self {
+            created: flags(),
+            dropped: flags(),
+        }
+    }
+
+    fn message(&self, id: usize) -> Tracked<'_> {
+        self.created[id].store(true, Relaxed);
+        Tracked { id, tracker: self }
+    }
+
+    // すべてのスレッドが終わり、チャネルもドロップされた後で呼ぶ
+    fn assert_all_dropped(&self) {
+        for (id, (created, dropped)) in self.created.iter().zip(&self.dropped).enumerate() {
+            assert_eq!(
+                created.load(Relaxed),
+                dropped.load(Relaxed),
+                "message {id} leaked"
+            );
+        }
+    }
+}
+
+struct Tracked<'a> {
+    id: usize,
+    tracker: &'a Tracker,
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        let twice = self.tracker.dropped[self.id].swap(true, Relaxed);
+        assert!(!twice, "message {} dropped twice", self.id);
+    }
+}
+
+// 繰り返しごとにシードを変える、再現できる乱数(xorshift)
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+
+    fn chance(&mut self, percent: u64) -> bool {
+        self.below(100) < percent
+    }
+}
+
+#[test]
+fn stress_mpsc() {
+    watchdog(|| {
+        for seed in 0..ITERATIONS {
+            let mut rng = Rng::new(seed);
+            let senders = 1 + rng.below(4) as usize;
+            let per_sender = rng.below(50) as usize;
+            let tracker = Tracker::new(senders * per_sender);
+            {
+                let (sender, mut receiver) = mpsc::channel();
+                thread::scope(|s| {
+                    for t in 0..senders {
+                        let sender = sender.clone();
+                        let mut rng = Rng::new(seed * 31 + t as u64);
+                        let tracker = &tracker;
+                        s.spawn(move || {
+                            let mut i = 0;
+                            while i < per_sender {
+                                let id = t * per_sender + i;
+                                // close()された後は送れずに返され、ここでドロップされる
+                                if rng.chance(30) {
+                                    let n = (1 + rng.below(8) as usize).min(per_sender - i);
+                                    let messages = (id..id + n).map(|id| tracker.message(id));
+                                    if let Err(messages) = sender.send_all(messages) {
+                                        assert!(sender.is_closed());
+                                        drop(messages);
+                                    }
+                                    i += n;
+                                } else {
+                                    if let Err(message) = sender.send(tracker.message(id)) {
+                                        assert!(sender.is_closed());
+                                        drop(message);
+                                    }
+                                    i += 1;
+                                }
+                                if rng.chance(1) {
+                                    sender.close();
+                                }
+                                if rng.chance(5) {
+                                    thread::yield_now();
+                                }
+                            }
+                        });
+                    }
+                    drop(sender);
+                    // 途中でReceiverを捨てることもある。残りはチャネルと一緒にドロップされる
+                    let mut buf = Vec::new();
+                    while !rng.chance(2) {
+                        let disconnected = match rng.below(3) {
+                            0 => receiver.receive().is_none(),
+                            1 => {
+                                drop(receiver.try_receive());
+                                false
+                            }
+                            _ => receiver.receive_many(&mut buf, 1 + rng.below(8) as usize) == 0,
+                        };
+                        buf.clear();
+                        if disconnected {
+                            break;
+                        }
+                    }
+                    drop(receiver);
+                });
+            }
+            tracker.assert_all_dropped();
+        }
+    });
+}
+
+#[test]
+fn stress_spsc() {
+    watchdog(|| {
+        for seed in 0..ITERATIONS {
+            let mut rng = Rng::new(seed);
+            let n = rng.below(50) as usize;
+            let tracker = Tracker::new(n);
+            {
+                let (mut sender, mut receiver) = spsc_channel::channel();
+                thread::scope(|s| {
+                    let mut sender_rng = Rng::new(seed * 31);
+                    let tracker = &tracker;
+                    s.spawn(move || {
+                        for id in 0..n {
+                            // Receiverがドロップされていれば、返されたメッセージはここでドロップされる
+                            if sender.send(tracker.message(id)).is_err() || sender_rng.chance(3) {
+                                return;
+                            }
+                        }
+                    });
+                    while !rng.chance(5) {
+                        if rng.chance(50) {
+                            if receiver.receive().is_none() {
+                                break;
+                            }
+                        } else if receiver.try_receive().is_none() {
+                            thread::yield_now();
+                        }
+                    }
+                    drop(receiver);
+                });
+            }
+            tracker.assert_all_dropped();
+        }
+    });
+}
+
+#[test]
+fn stress_ring() {
+    watchdog(|| {
+        for seed in 0..ITERATIONS {
+            let mut rng = Rng::new(seed);
+            let n = rng.below(50) as usize;
+            let tracker = Tracker::new(n);
+            {
+                let mut channel = ring_channel::Channel::<_, 4>::new();
+                thread::scope(|s| {
+                    let (mut sender, mut receiver) = channel.split();
+                    let tracker = &tracker;
+                    s.spawn(move || {
+                        // スピンして待つと1つのCPUでは進まないので、空きがなければ譲る
+                        for id in 0..n {
+                            let mut message = tracker.message(id);
+                            while let Err(m) = sender.try_send(message) {
+                                message = m;
+                                thread::yield_now();
+                            }
+                        }
+                    });
+                    // 受け取りきらずに終わることもある。残りはChannelのドロップで解放される
+                    // 切断を知る方法がないので、Senderが送り終えられるようにバッファに収まる数だけ残す
+                    let limit = n - rng.below(n.min(4) as u64 + 1) as usize;
+                    let mut received = 0;
+                    let mut buf = Vec::new();
+                    while received < limit {
+                        received += if rng.chance(50) {
+                            receiver.try_receive().is_some() as usize
+                        } else {
+                            let n = receiver.receive_many(&mut buf, limit - received);
+                            buf.clear();
+                            n
+                        };
+                        thread::yield_now();
+                    }
+                });
+            }
+            tracker.assert_all_dropped();
+        }
+    });
+}
+
+#[test]
+fn stress_oneshot() {
+    watchdog(|| {
+        for seed in 0..ITERATIONS {
+            let mut rng = Rng::new(seed);
+            let tracker = Tracker::new(1);
+            {
+                // 受け取られなかったメッセージは、ブロックの終わりにチャネルと一緒にドロップされる
+                let mut channel = oneshot_channel_nonblocking::Channel::new();
+                thread::scope(|s| {
+                    let (sender, mut receiver) = channel.split();
+                    let send = rng.chance(70);
+                    let tracker = &tracker;
+                    s.spawn(move || {
+                        if send {
+                            sender.send(tracker.message(0));
+                        }
+                    });
+                    match rng.below(3) {
+                        0 => drop(receiver.receive()),
+                        1 => drop(receiver.try_receive()),
+                        // 受け取らずに捨てる
+                        _ => {}
+                    }
+                });
+            }
+            tracker.assert_all_dropped();
+        }
+
+        for seed in 0..ITERATIONS {
+            let mut rng = Rng::new(seed);
+            let senders = 1 + rng.below(4) as usize;
+            let tracker = Tracker::new(senders);
+            {
+                let (sender, receiver) = oneshot_channel_multi_sender::channel();
+                thread::scope(|s| {
+                    for id in 0..senders {
+                        let sender = sender.clone();
+                        let tracker = &tracker;
+                        let send = rng.chance(70);
+                        s.spawn(move || {
+                            // 負けたSenderには返されるので、ここでドロップされる
+                            if send {
+                                let _ = sender.send(tracker.message(id));
+                            }
+                        });
+                    }
+                    drop(sender);
+                    if rng.chance(80) {
+                        drop(receiver.receive());
+                    } else {
+                        drop(receiver);
+                    }
+                });
+            }
+            tracker.assert_all_dropped();
+        }
+    });
+}
+
+#[test]
+fn stress_broadcast() {
+    watchdog(|| {
+        for seed in 0..ITERATIONS {
+            let mut rng = Rng::new(seed);
+            // 容量が小さいほど、送っている間に同じスロットを読みに来やすい
+            let capacity = 1 + rng.below(3) as usize;
+            let n = rng.below(1000);
+            let receivers = 1 + rng.below(3) as usize;
+            let (sender, receiver) = broadcast::channel(capacity);
+            thread::scope(|s| {
+                for r in 0..receivers {
+                    let mut receiver = receiver.clone();
+                    let mut rng = Rng::new(seed * 31 + r as u64);
+                    s.spawn(move || {
+                        // 追い越されても、受け取るメッセージの番号は増えていく
+                        let mut next = 0;
+                        loop {
+                            // try_receive()は送っている最中のスロットを読みに来るので、多めに呼ぶ
+                            let result = if rng.chance(90) {
+                                match receiver.try_receive() {
+                                    Ok(i) => Ok(i),
+                                    Err(broadcast::TryReceiveError::Empty) => continue,
+                                    Err(broadcast::TryReceiveError::Lagged(n)) => {
+                                        Err(broadcast::ReceiveError::Lagged(n))
+                                    }
+                                    Err(broadcast::TryReceiveError::Disconnected) => {
+                                        Err(broadcast::ReceiveError::Disconnected)
+                                    }
+                                }
+                            } else {
+                                receiver.receive()
+                            };
+                            match result {
+                                Ok(i) => {
+                                    assert_eq!(i, next);
+                                    next += 1;
+                                }
+                                Err(broadcast::ReceiveError::Lagged(n)) => next += n,
+                                Err(broadcast::ReceiveError::Disconnected) => break,
+                            }
+                        }
+                        assert_eq!(next, n);
+                    });
+                }
+                drop(receiver);
+                for i in 0..n {
+                    sender.send(i).unwrap();
+                    if rng.chance(10) {
+                        thread::yield_now();
+                    }
+                }
+                drop(sender);
+            });
+        }
+    });
+}
+
+#[test]
+fn stress_mpmc() {
+    watchdog(|| {
+        for seed in 0..ITERATIONS {
+            let mut rng = Rng::new(seed);
+            let capacity = 1 + rng.below(4) as usize;
+            let senders = 1 + rng.below(3) as usize;
+            let receivers = 1 + rng.below(3) as usize;
+            let per_sender = rng.below(50) as usize;
+            let tracker = Tracker::new(senders * per_sender);
+            {
+                let (sender, receiver) = mpmc::channel(capacity);
+                thread::scope(|s| {
+                    for t in 0..senders {
+                        let sender = sender.clone();
+                        let mut rng = Rng::new(seed * 31 + t as u64);
+                        let tracker = &tracker;
+                        s.spawn(move || {
+                            for i in 0..per_sender {
+                                let message = tracker.message(t * per_sender + i);
+                                // すべてのReceiverがドロップされていれば返される
+                                let sent = if rng.chance(50) {
+                                    sender.send(message).is_ok()
+                                } else {
+                                    let mut message = message;
+                                    loop {
+                                        match sender.try_send(message) {
+                                            Ok(()) => break true,
+                                            Err(mpmc::TrySendError::Full(m)) => {
+                                                message = m;
+                                                thread::yield_now();
+                                            }
+                                            Err(mpmc::TrySendError::Disconnected(_)) => {
+                                                break false
+                                            }
+                                        }
+                                    }
+                                };
+                                if !sent {
+                                    return;
+                                }
+                            }
+                        });
+                    }
+                    drop(sender);
+                    for r in 0..receivers {
+                        let receiver = receiver.clone();
+                        let mut rng = Rng::new(seed * 37 + r as u64);
+                        s.spawn(move || {
+                            // 途中でReceiverを捨てることもある
+                            while !rng.chance(3) {
+                                if rng.chance(50) {
+                                    if receiver.receive().is_none() {
+                                        break;
+                                    }
+                                } else if receiver.try_receive().is_none() {
+                                    thread::yield_now();
+                                }
+                            }
+                        });
+                    }
+                    drop(receiver);
+                });
+            }
+            tracker.assert_all_dropped();
+        }
+    });
+}
+
+#[test]
+fn stress_watch() {
+    watchdog(|| {
+        for seed in 0..ITERATIONS {
+            let mut rng = Rng::new(seed);
+            let n = rng.below(100);
+            let receivers = 1 + rng.below(3) as usize;
+            let (sender, receiver) = watch::channel(0);
+            thread::scope(|s| {
+                for r in 0..receivers {
+                    let mut receiver = receiver.clone();
+                    let mut rng = Rng::new(seed * 31 + r as u64);
+                    s.spawn(move || {
+                        // 途中の値は飛ばされることがあるが、読む値は減らない
+                        let mut last = 0;
+                        while receiver.changed().is_ok() {
+                            let v = *receiver.borrow_and_update();
+                            assert!(v >= last);
+                            last = v;
+                            if rng.chance(10) {
+                                thread::yield_now();
+                            }
+                        }
+                        // Senderがドロップされる前に送った最後の値は必ず読める
+                        assert_eq!(*receiver.borrow(), n);
+                    });
+                }
+                drop(receiver);
+                for i in 1..=n {
+                    sender.send(i).unwrap();
+                    if rng.chance(10) {
+                        thread::yield_now();
+                    }
+                }
+                drop(sender);
+            });
+        }
+    });
+}
+
+#[test]
+fn stress_mailbox() {
+    watchdog(|| {
+        for seed in 0..ITERATIONS {
+            let mut rng = Rng::new(seed);
+            let senders = 1 + rng.below(3) as usize;
+            let per_sender = rng.below(50) as usize;
+            let tracker = Tracker::new(senders * per_sender);
+            {
+                // 上書きされたメッセージはsend()の中でドロップされる
+                let (sender, mut receiver) = mailbox::channel();
+                thread::scope(|s| {
+                    for t in 0..senders {
+                        let sender = sender.clone();
+                        let mut rng = Rng::new(seed * 31 + t as u64);
+                        let tracker = &tracker;
+                        s.spawn(move || {
+                            for i in 0..per_sender {
+                                sender.send(tracker.message(t * per_sender + i));
+                                if rng.chance(10) {
+                                    thread::yield_now();
+                                }
+                            }
+                        });
+                    }
+                    drop(sender);
+                    while !rng.chance(5) {
+                        if rng.chance(50) {
+                            if receiver.receive().is_none() {
+                                break;
+                            }
+                        } else if receiver.try_receive().is_none() {
+                            thread::yield_now();
+                        }
+                    }
+                    drop(receiver);
+                });
+            }
+            tracker.assert_all_dropped();
+        }
+    });
+}
+
+#[test]
+fn stress_request_response() {
+    watchdog(|| {
+        for seed in 0..ITERATIONS {
+            let mut rng = Rng::new(seed);
+            let clients = 1 + rng.below(3) as usize;
+            let per_client = rng.below(20) as usize;
+            let tracker = Tracker::new(clients * per_client);
+            {
+                let (client, mut server) = request_response::channel::<Tracked, usize>();
+                thread::scope(|s| {
+                    for c in 0..clients {
+                        let client = client.clone();
+                        let tracker = &tracker;
+                        s.spawn(move || {
+                            for i in 0..per_client {
+                                let id = c * per_client + i;
+                                // Serverがドロップされていれば要求が返される
+                                let Ok(response) = client.send(tracker.message(id)) else {
+                                    return;
+                                };
+                                // 応答されずにドロップされればErr(NoResponse)になる
+                                if let Ok(r) = response.receive() {
+                                    assert_eq!(r, id);
+                                }
+                            }
+                        });
+                    }
+                    drop(client);
+                    // 応答しなかったり、途中でServerを捨てたりする
+                    while !rng.chance(3) {
+                        let Some((request, response)) = server.receive() else {
+                            break;
+                        };
+                        if rng.chance(80) {
+                            response.respond(request.id);
+                        }
+                    }
+                    drop(server);
+                });
+            }
+            tracker.assert_all_dropped();
+        }
+    });
+}
+
+#[test]
+fn stress_oneshot_variants() {
+    watchdog(|| {
+        for seed in 0..ITERATIONS {
+            let mut rng = Rng::new(seed);
+            let tracker = Tracker::new(4);
+            {
+                // Arcで共有するoneshot。Receiverが先にドロップされればメッセージは返される
+                let (sender, receiver) = oneshot_channel_arc::channel();
+                let send = rng.chance(70);
+                thread::scope(|s| {
+                    let tracker = &tracker;
+                    s.spawn(move || {
+                        if send {
+                            let _ = sender.send(tracker.message(0));
+                        }
+                    });
+                });
+                // receive()は送られていなければパニックするので、Senderが終わってから受け取る
+                if rng.chance(70) {
+                    drop(receiver.receive());
+                }
+
+                // 受け取ったことをSenderに知らせるoneshot
+                let mut channel = oneshot_channel_ack::Channel::new();
+                thread::scope(|s| {
+                    let (sender, receiver) = channel.split();
+                    let send = rng.chance(70);
+                    let tracker = &tracker;
+                    s.spawn(move || {
+                        if send {
+                            let _ = sender.send(tracker.message(1)).wait();
+                        }
+                    });
+                    if rng.chance(70) {
+                        drop(receiver.receive());
+                    }
+                });
+
+                // coreだけで書いたoneshot。1つのCPUではスピンしても進まないので譲りながら待つ
+                let mut channel =
+                    oneshot_channel_no_std::Channel::with_hook(oneshot_channel_no_std::FnHook {
+                        wait: thread::yield_now,
+                        wake: || {},
+                    });
+                thread::scope(|s| {
+                    let (sender, receiver) = channel.split();
+                    let send = rng.chance(70);
+                    let tracker = &tracker;
+                    s.spawn(move || {
+                        if send {
+                            sender.send(tracker.message(2));
+                        }
+                    });
+                    if rng.chance(70) {
+                        drop(receiver.receive());
+                    }
+                });
+
+                // ライフタイムで借用するoneshot。待てないので、Senderが終わってから受け取る
+                let mut channel = oneshot_channel_lifetime::Channel::new();
+                let (sender, receiver) = channel.split();
+                let send = rng.chance(70);
+                thread::scope(|s| {
+                    let tracker = &tracker;
+                    s.spawn(move || {
+                        if send {
+                            sender.send(tracker.message(3));
+                        }
+                    });
+                });
+                if rng.chance(70) {
+                    drop(receiver.receive());
+                }
+            }
+            tracker.assert_all_dropped();
+        }
+    });
+}
+
+#[test]
+fn stress_oneshot_async() {
+    watchdog(|| {
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::task::{Context, Waker};
+
+        for seed in 0..ITERATIONS {
+            let mut rng = Rng::new(seed);
+            let tracker = Tracker::new(1);
+            {
+                // Senderが送らずにドロップされるとパニックするので、必ず送る
+                let (sender, mut receiver) = oneshot_channel_async::channel();
+                thread::scope(|s| {
+                    let tracker = &tracker;
+                    s.spawn(move || sender.send(tracker.message(0)));
+                    match rng.below(3) {
+                        0 => drop(receiver.receive()),
+                        // 起こされなくてもよいように、pollし直しながら待つ
+                        1 => {
+                            let mut cx = Context::from_waker(Waker::noop());
+                            while Pin::new(&mut receiver).poll(&mut cx).is_pending() {
+                                thread::yield_now();
+                            }
+                        }
+                        // 受け取らずに捨てる
+                        _ => drop(receiver),
+                    }
+                });
+            }
+            tracker.assert_all_dropped();
+        }
+    });
+}
+
+#[test]
+fn stress_oneshot_static() {
+    watchdog(|| {
+        for seed in 0..ITERATIONS {
+            let mut rng = Rng::new(seed);
+            // staticなチャネルはドロップされないので、Trackedは使わずに値だけを確かめる
+            let channel = Box::leak(Box::new(oneshot_channel_static::Channel::new()));
+            let (sender, receiver) = channel.split();
+            let send = rng.chance(70);
+            let handle = thread::spawn(move || {
+                if send {
+                    sender.send(seed);
+                }
+            });
+            match receiver.receive() {
+                Ok(v) => assert!(send && v == seed),
+                Err(oneshot_channel_static::Disconnected) => assert!(!send),
+            }
+            handle.join().unwrap();
+        }
+    });
+}
+
+#[cfg(unix)]
+#[test]
+fn stress_pollable() {
+    watchdog(|| {
+        use crate::pollable;
+
+        for seed in 0..ITERATIONS {
+            let mut rng = Rng::new(seed);
+            let senders = 1 + rng.below(3) as usize;
+            let per_sender = rng.below(50) as usize;
+            let tracker = Tracker::new(senders * per_sender);
+            {
+                let (sender, mut receiver) = pollable::channel().unwrap();
+                thread::scope(|s| {
+                    for t in 0..senders {
+                        let sender = sender.clone();
+                        let mut rng = Rng::new(seed * 31 + t as u64);
+                        let tracker = &tracker;
+                        s.spawn(move || {
+                            for i in 0..per_sender {
+                                sender.send(tracker.message(t * per_sender + i));
+                                if rng.chance(10) {
+                                    thread::yield_now();
+                                }
+                            }
+                        });
+                    }
+                    drop(sender);
+                    // Emptyが返っても、残りのSenderが送り終えればDisconnectedになる
+                    let mut received = 0;
+                    loop {
+                        match receiver.try_receive() {
+                            Ok(_) => received += 1,
+                            Err(pollable::TryReceiveError::Empty) => thread::yield_now(),
+                            Err(pollable::TryReceiveError::Disconnected) => break,
+                        }
+                    }
+                    assert_eq!(received, senders * per_sender);
+                });
+            }
+            tracker.assert_all_dropped();
+        }
+    });
+}
@@ -0,0 +1,287 @@
+use std::hint;
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+use std::sync::atomic::{fence, AtomicBool};
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+// チャネルが相手を待つ方法
+// 送る側は状態を書き換えてからwake()を呼び、受け取る側は状態を確かめる関数を渡して待つ
+// チャネルはこのトレイトに対してジェネリックに書いておき、待ち方は使う側が選ぶ
+pub trait Waiter: Default + Send + Sync {
+    // 状態を書き換えた後で呼ぶ。待っている側がいなければ何もしない
+    fn wake(&self);
+}
+
+// スレッドを止めて待てるWaiter
+pub trait BlockingWaiter: Waiter {
+    // readyがtrueを返すまで待つ
+    // readyはAcquireで状態を読み、wake()の前に書き込まれたものが見えるようにする
+    fn wait_until(&self, ready: impl FnMut() -> bool);
+
+    // wait_until()と同じだが、deadlineを過ぎてもreadyがfalseならfalseを返す
+    fn wait_until_deadline(&self, ready: impl FnMut() -> bool, deadline: Instant) -> bool;
+}
+
+// thread::park()で待つ
+// 待つスレッドは待つときに登録するので、受け取る側をほかのスレッドに渡してもよい
+#[derive(Default)]
+pub struct Park {
+    // falseなら、wake()はロックせずにunparkを省略する
+    parked: AtomicBool,
+    thread: Mutex<Option<Thread>>,
+}
+
+impl Park {
+    pub const fn new() -> Self {
+        Self {
+            parked: AtomicBool::new(false),
+            thread: Mutex::new(None),
+        }
+    }
+
+    // 登録してからreadyを確かめ直し、まだならtimeoutの間だけparkする
+    // 戻った後はreadyを確かめ直す(起こされずに戻ることもある)
+    fn park(&self, ready: &mut impl FnMut() -> bool, timeout: Option<Duration>) {
+        *self.thread.lock().unwrap() = Some(thread::current());
+        self.parked.store(true, Relaxed);
+        // wake()のfenceと対になる
+        // どちらかが必ず相手の書き込みを見るので、起こされずに眠り続けることはない
+        fence(SeqCst);
+        if ready() {
+            return;
+        }
+        match timeout {
+            None => thread::park(),
+            Some(timeout) => thread::park_timeout(timeout),
+        }
+    }
+}
+
+impl Waiter for Park {
+    fn wake(&self) {
+        // 状態の書き込みを、待つ側が登録した後の確認より前に置く
+        fence(SeqCst);
+        if self.parked.swap(false, Relaxed) {
+            if let Some(t) = &*self.thread.lock().unwrap() {
+                t.unpark();
+            }
+        }
+    }
+}
+
+impl BlockingWaiter for Park {
+    fn wait_until(&self, mut ready: impl FnMut() -> bool) {
+        while !ready() {
+            self.park(&mut ready, None);
+        }
+    }
+
+    fn wait_until_deadline(&self, mut ready: impl FnMut() -> bool, deadline: Instant) -> bool {
+        loop {
+            if ready() {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            self.park(&mut ready, Some(deadline - now));
+        }
+    }
+}
+
+// スピンして待つ。wake()は何もしないので送る側が最も軽い
+// 相手がすぐに応えるとわかっている場合や、スレッドを止められない環境で使う
+#[derive(Default)]
+pub struct Spin;
+
+impl Waiter for Spin {
+    fn wake(&self) {}
+}
+
+impl BlockingWaiter for Spin {
+    fn wait_until(&self, mut ready: impl FnMut() -> bool) {
+        while !ready() {
+            hint::spin_loop();
+        }
+    }
+
+    fn wait_until_deadline(&self, mut ready: impl FnMut() -> bool, deadline: Instant) -> bool {
+        loop {
+            if ready() {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            hint::spin_loop();
+        }
+    }
+}
+
+// futexで待つ(Linuxのみ)
+// wake()のたびにseqを進めるので、確かめた後で進んでいればFUTEX_WAITはすぐに戻る
+#[cfg(target_os = "linux")]
+#[derive(Default)]
+pub struct Futex {
+    seq: std::sync::atomic::AtomicU32,
+}
+
+#[cfg(target_os = "linux")]
+impl Waiter for Futex {
+    fn wake(&self) {
+        use std::sync::atomic::Ordering::Release;
+        self.seq.fetch_add(1, Release);
+        unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                self.seq.as_ptr(),
+                libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+                i32::MAX,
+            );
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Futex {
+    // seqがまだexpectedなら、wake()されるかtimeoutを過ぎるまで待つ
+    fn futex_wait(&self, expected: u32, timeout: Option<Duration>) {
+        let timespec = timeout.map(|d| libc::timespec {
+            tv_sec: d.as_secs() as libc::time_t,
+            tv_nsec: d.subsec_nanos() as libc::c_long,
+        });
+        let timespec_ptr = match &timespec {
+            Some(t) => t as *const libc::timespec,
+            None => std::ptr::null(),
+        };
+        unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                self.seq.as_ptr(),
+                libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+                expected,
+                timespec_ptr,
+            );
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl BlockingWaiter for Futex {
+    fn wait_until(&self, mut ready: impl FnMut() -> bool) {
+        use std::sync::atomic::Ordering::Acquire;
+        loop {
+            // 先にseqを読んでおく。readyがfalseを返した後でwake()されていれば値が変わっている
+            let seq = self.seq.load(Acquire);
+            if ready() {
+                return;
+            }
+            self.futex_wait(seq, None);
+        }
+    }
+
+    fn wait_until_deadline(&self, mut ready: impl FnMut() -> bool, deadline: Instant) -> bool {
+        use std::sync::atomic::Ordering::Acquire;
+        loop {
+            let seq = self.seq.load(Acquire);
+            if ready() {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            // time_tに収まらないほど長ければ期限なしで待つ。起こされたら確かめ直す
+            let timeout = deadline - now;
+            let timeout = (timeout.as_secs() <= libc::time_t::MAX as u64).then_some(timeout);
+            self.futex_wait(seq, timeout);
+        }
+    }
+}
+
+// 非同期タスクから待つ。スレッドは止めずに、タスクのWakerを登録してPendingを返す
+#[derive(Default)]
+pub struct AsyncWaker {
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Waiter for AsyncWaker {
+    fn wake(&self) {
+        if let Some(w) = self.waker.lock().unwrap().take() {
+            w.wake();
+        }
+    }
+}
+
+impl AsyncWaker {
+    // readyがtrueを返せばReady。そうでなければcxのWakerを登録してPendingを返す
+    pub fn poll_until(&self, cx: &mut Context<'_>, mut ready: impl FnMut() -> bool) -> Poll<()> {
+        if ready() {
+            return Poll::Ready(());
+        }
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        // 登録する前にwake()されていたら起こされないので、確かめ直す
+        if ready() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[test]
+fn test_blocking_waiters() {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering::{Acquire, Release};
+
+    fn check<W: BlockingWaiter>() {
+        let waiter = W::default();
+        let flag = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(std::time::Duration::from_millis(10));
+                flag.store(true, Release);
+                waiter.wake();
+            });
+            waiter.wait_until(|| flag.load(Acquire));
+        });
+    }
+
+    check::<Park>();
+    check::<Spin>();
+    #[cfg(target_os = "linux")]
+    check::<Futex>();
+}
+
+#[test]
+fn test_wait_until_deadline() {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering::{Acquire, Release};
+
+    fn check<W: BlockingWaiter>() {
+        let waiter = W::default();
+        let flag = AtomicBool::new(false);
+        // 誰も起こさなければ期限で諦める
+        let timeout = Duration::from_millis(10);
+        let start = Instant::now();
+        assert!(!waiter.wait_until_deadline(|| flag.load(Acquire), start + timeout));
+        assert!(start.elapsed() >= timeout);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(timeout);
+                flag.store(true, Release);
+                waiter.wake();
+            });
+            let deadline = Instant::now() + Duration::from_secs(10);
+            assert!(waiter.wait_until_deadline(|| flag.load(Acquire), deadline));
+        });
+    }
+
+    check::<Park>();
+    check::<Spin>();
+    #[cfg(target_os = "linux")]
+    check::<Futex>();
+}
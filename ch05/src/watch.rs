@@ -0,0 +1,200 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockReadGuard};
+
+// 最新の値だけを共有するチャネル
+// send()は1つの値を上書きしてversionを進める。古い値は受け取られなくても捨てられる
+// Receiverは最後に読んだversionを覚えていて、それより新しい値が送られるまで待てる
+// 設定や状態のように、途中の値よりも今の値が必要なものを配るのに使う
+pub fn channel<T>(initial: T) -> (Sender<T>, Receiver<T>) {
+    let a = Arc::new(Channel {
+        value: RwLock::new(initial),
+        version: AtomicU64::new(0),
+        state: Mutex::new(State {
+            closed: false,
+            receivers: 1,
+        }),
+        changed: Condvar::new(),
+    });
+    (
+        Sender { channel: a.clone() },
+        Receiver {
+            channel: a,
+            seen: 0,
+        },
+    )
+}
+
+struct Channel<T> {
+    value: RwLock<T>,
+    // send()するたびにインクリメントする
+    // valueの書き込みロックを持ったまま進めるので、読み込みロックの間は値とversionが一致する
+    version: AtomicU64,
+    state: Mutex<State>,
+    // versionが進んだか、Senderがドロップされた
+    changed: Condvar,
+}
+
+struct State {
+    // Senderがドロップされた
+    closed: bool,
+    receivers: usize,
+}
+
+// Senderがドロップされ、最後に読んだ値より新しい値はもう送られない
+#[derive(Debug, PartialEq, Eq)]
+pub struct Disconnected;
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    // Receiverが1つもなければ、誰も読まないので値を返す
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let mut v = self.channel.value.write().unwrap();
+        let state = self.channel.state.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(value);
+        }
+        *v = value;
+        // stateをロックしたまま進めるので、changed()が確認してから待つまでの間に取りこぼさない
+        self.channel.version.fetch_add(1, Release);
+        drop(state);
+        drop(v);
+        self.channel.changed.notify_all();
+        Ok(())
+    }
+
+    pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+        self.channel.value.read().unwrap()
+    }
+
+    // 最新の値を読むReceiverを作る。今の値は読んだものとして扱う
+    pub fn subscribe(&self) -> Receiver<T> {
+        // 読み込みロックを持っている間はversionが進まない
+        let _v = self.channel.value.read().unwrap();
+        self.channel.state.lock().unwrap().receivers += 1;
+        Receiver {
+            channel: self.channel.clone(),
+            seen: self.channel.version.load(Acquire),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.state.lock().unwrap().closed = true;
+        self.channel.changed.notify_all();
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+    // 最後に読んだ値のversion
+    seen: u64,
+}
+
+impl<T> Receiver<T> {
+    // 最新の値を読む。読んだことは記録しないので、has_changed()の結果は変わらない
+    pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+        self.channel.value.read().unwrap()
+    }
+
+    // 最新の値を読み、読んだこととして記録する
+    pub fn borrow_and_update(&mut self) -> RwLockReadGuard<'_, T> {
+        let v = self.channel.value.read().unwrap();
+        self.seen = self.channel.version.load(Acquire);
+        v
+    }
+
+    // 最後に読んだ後で新しい値が送られたか
+    pub fn has_changed(&self) -> bool {
+        self.channel.version.load(Acquire) != self.seen
+    }
+
+    // 最後に読んだ後で新しい値が送られるまで待ち、読んだこととして記録する
+    // 待っている間に複数回送られても1回しか戻らない
+    // Senderがドロップされ、新しい値もなければErrを返す
+    pub fn changed(&mut self) -> Result<(), Disconnected> {
+        let state = self.channel.state.lock().unwrap();
+        let state = self
+            .channel
+            .changed
+            .wait_while(state, |s| !s.closed && !self.has_changed())
+            .unwrap();
+        if !self.has_changed() {
+            debug_assert!(state.closed);
+            return Err(Disconnected);
+        }
+        self.seen = self.channel.version.load(Acquire);
+        Ok(())
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    // 複製したReceiverは、元のReceiverが読んだところまで読んだものとして扱う
+    fn clone(&self) -> Self {
+        self.channel.state.lock().unwrap().receivers += 1;
+        Self {
+            channel: self.channel.clone(),
+            seen: self.seen,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.state.lock().unwrap().receivers -= 1;
+    }
+}
+
+#[test]
+fn test_watch() {
+    use std::thread;
+
+    let (sender, receiver) = channel(0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let mut receiver = receiver.clone();
+            s.spawn(move || {
+                // 途中の値は飛ばされることがあるが、読める値は増え続け、最後の値は必ず読める
+                let mut last = 0;
+                while receiver.changed().is_ok() {
+                    let v = *receiver.borrow();
+                    assert!(v >= last);
+                    last = v;
+                }
+                assert_eq!(last, 1000);
+            });
+        }
+        s.spawn(move || {
+            for i in 1..=1000 {
+                sender.send(i).unwrap();
+            }
+        });
+    });
+}
+
+#[test]
+fn test_has_changed() {
+    let (sender, mut receiver) = channel("a");
+    assert!(!receiver.has_changed());
+    sender.send("b").unwrap();
+    sender.send("c").unwrap();
+    assert!(receiver.has_changed());
+    // 古い値は上書きされている
+    assert_eq!(*receiver.borrow_and_update(), "c");
+    assert!(!receiver.has_changed());
+
+    let mut late = sender.subscribe();
+    assert!(!late.has_changed());
+    drop(sender);
+    assert_eq!(late.changed(), Err(Disconnected));
+    // ドロップされても最後の値は読める
+    assert_eq!(*late.borrow(), "c");
+
+    let (sender, receiver) = channel(1);
+    drop(receiver);
+    assert_eq!(sender.send(2), Err(2));
+}
@@ -1,12 +1,14 @@
-use std::borrow::Cow::Borrowed;
+use std::alloc::{alloc, handle_alloc_error, Layout};
 use std::cell::UnsafeCell;
 use std::mem::ManuallyDrop;
 use std::ops::Deref;
-use std::ptr::NonNull;
+use std::ptr::{addr_of_mut, NonNull};
 use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 use std::sync::atomic::{fence, AtomicUsize};
 
-struct ArcData<T> {
+// [T]やstrのような動的サイズ型も置けるように、dataを最後に置いてレイアウトを固定する
+#[repr(C)]
+struct ArcData<T: ?Sized> {
     // Arcの参照カウント
     data_ref_count: AtomicUsize,
     // Weakの参照カウント
@@ -15,7 +17,7 @@ struct ArcData<T> {
     data: UnsafeCell<ManuallyDrop<T>>,
 }
 
-pub struct Arc<T> {
+pub struct Arc<T: ?Sized> {
     ptr: NonNull<ArcData<T>>,
 }
 
@@ -30,6 +32,31 @@ impl<T> Arc<T> {
         }
     }
 
+    // 自分自身へのWeakを持つデータを作る
+    // fの実行中はdata_ref_countが0なので、渡されたWeakはアップグレードできない
+    pub fn new_cyclic(f: impl FnOnce(&Weak<T>) -> T) -> Arc<T> {
+        let mut uninit = Box::<ArcData<T>>::new_uninit();
+        let p = uninit.as_mut_ptr();
+        unsafe {
+            addr_of_mut!((*p).data_ref_count).write(AtomicUsize::new(0));
+            // このWeakの分。fが終わったらArc全体で持つ1として引き継ぐ
+            addr_of_mut!((*p).alloc_ref_count).write(AtomicUsize::new(1));
+        }
+        // dataは未初期化だがManuallyDropなので、fがパニックしてWeakがArcDataを解放してもドロップされない
+        let ptr = NonNull::from(Box::leak(uninit)).cast::<ArcData<T>>();
+        let weak = Weak { ptr };
+        let data = f(&weak);
+        unsafe {
+            (*ptr.as_ref().data.get()) = ManuallyDrop::new(data);
+        }
+        // ReleaseはWeak::upgradeのAcquireと対応し、書き込んだデータを見えるようにする
+        unsafe { ptr.as_ref() }.data_ref_count.store(1, Release);
+        std::mem::forget(weak);
+        Arc { ptr }
+    }
+}
+
+impl<T: ?Sized> Arc<T> {
     fn data(&self) -> &ArcData<T> {
         unsafe { self.ptr.as_ref() }
     }
@@ -60,6 +87,45 @@ impl<T> Arc<T> {
         unsafe { Some(&mut *arc.data().data.get()) }
     }
 
+    // 同じArcDataを指していればtrue。中身の値は比べない
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        std::ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
+    }
+
+    // 参照カウントをそのままにしてデータへのポインタを返す
+    // AtomicPtrやFFIに渡したポインタは、from_raw()でArcに戻さないとリークする
+    pub fn into_raw(arc: Self) -> *const T {
+        let arc = ManuallyDrop::new(arc);
+        // ManuallyDrop<T>はTと同じレイアウト
+        arc.data().data.get() as *const T
+    }
+
+    // into_raw()が返したポインタからArcを戻す
+    // 安全性: ptrはinto_raw()で得たもので、そのArcの分の参照カウントを引き継ぐこと
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        // dataフィールドの位置からArcDataの先頭を求める
+        // repr(C)なので、dataは参照カウントの後ろにTのアラインメントに合わせて置かれている
+        let (_, offset) = Layout::new::<ArcData<()>>()
+            .extend(Layout::for_value(unsafe { &*ptr }))
+            .unwrap();
+        let ptr = unsafe { ptr.byte_sub(offset) } as *mut ArcData<T>;
+        Arc {
+            ptr: unsafe { NonNull::new_unchecked(ptr) },
+        }
+    }
+
+    // 安全性: ptrはinto_raw()で得たもので、そのArcの分の参照カウントがまだ残っていること
+    pub unsafe fn increment_strong_count(ptr: *const T) {
+        // 借りたArcをドロップせずに複製し、複製の方も手放す
+        let arc = ManuallyDrop::new(unsafe { Arc::from_raw(ptr) });
+        std::mem::forget(Arc::clone(&arc));
+    }
+
+    // 安全性: ptrはinto_raw()で得たもので、デクリメントする分の参照カウントを持っていること
+    pub unsafe fn decrement_strong_count(ptr: *const T) {
+        drop(unsafe { Arc::from_raw(ptr) });
+    }
+
     pub fn downgrade(arc: &Self) -> Weak<T> {
         let mut n = arc.data().alloc_ref_count.load(Relaxed);
         loop {
@@ -83,11 +149,113 @@ impl<T> Arc<T> {
     }
 }
 
-unsafe impl<T: Sync + Send> Send for Arc<T> {}
+impl<T: Clone> Arc<T> {
+    // 他にArcがあれば複製し、Weakしかなければデータを新しいArcDataに移してから&mut Tを返す
+    // どちらの場合も残ったWeakは元のArcDataを指したままなので、もうアップグレードできない
+    pub fn make_mut(arc: &mut Self) -> &mut T {
+        // data_ref_countを1から0にしてWeak::upgradeを失敗させる
+        // AcquireはArc::dropのReleaseデクリメントと対応する
+        if arc
+            .data()
+            .data_ref_count
+            .compare_exchange(1, 0, Acquire, Relaxed)
+            .is_err()
+        {
+            // 他のArcが存在するので、複製を持った新しいArcに置き換える
+            *arc = Arc::new((**arc).clone());
+        } else if arc.data().alloc_ref_count.load(Relaxed) != 1 {
+            // Arcはこれだけだが、Weakが残っている
+            // data_ref_countは0なのでWeakからはもう触られない。データだけを取り出して新しいArcDataに移す
+            let weak = Weak { ptr: arc.ptr };
+            unsafe {
+                let data = ManuallyDrop::take(&mut *arc.data().data.get());
+                // 元のArcはdata_ref_countをデクリメント済みなのでドロップせずに上書きする
+                std::ptr::write(arc, Arc::new(data));
+            }
+            // Arc全体で持っていたalloc_ref_countの1を返す
+            drop(weak);
+        } else {
+            // ArcもWeakもほかにないので、data_ref_countを戻すだけでよい
+            arc.data().data_ref_count.store(1, Release);
+        }
+        // ここではarcが唯一の参照になっている
+        unsafe { &mut *arc.data().data.get() }
+    }
+}
 
-unsafe impl<T: Sync + Send> Sync for Arc<T> {}
+// 要素数が実行時に決まるので、ArcDataの大きさを計算して割り当てる
+impl<T> Arc<[T]> {
+    pub fn from_slice(slice: &[T]) -> Self
+    where
+        T: Clone,
+    {
+        Self::from_vec(slice.to_vec())
+    }
 
-impl<T> Deref for Arc<T> {
+    // 要素をVecから割り当てた領域に移す
+    fn from_vec(mut v: Vec<T>) -> Self {
+        let len = v.len();
+        let ptr = Self::allocate(len);
+        unsafe {
+            std::ptr::copy_nonoverlapping(v.as_ptr(), addr_of_mut!((*ptr).data) as *mut T, len);
+            // 要素はもうArcDataのものなので、Vecには領域の解放だけをさせる
+            v.set_len(0);
+            Arc {
+                ptr: NonNull::new_unchecked(ptr),
+            }
+        }
+    }
+
+    // 参照カウントだけを初期化したArcData<[T]>を割り当てる
+    // 最後にArcDataを解放するWeakはBox::from_rawを使うので、Boxと同じレイアウトにする
+    fn allocate(len: usize) -> *mut ArcData<[T]> {
+        let (layout, _) = Layout::new::<ArcData<()>>()
+            .extend(Layout::array::<T>(len).unwrap())
+            .unwrap();
+        let layout = layout.pad_to_align();
+        let mem = unsafe { alloc(layout) };
+        if mem.is_null() {
+            handle_alloc_error(layout);
+        }
+        // 要素数をメタデータに持つポインタにする
+        let ptr = std::ptr::slice_from_raw_parts_mut(mem as *mut T, len) as *mut ArcData<[T]>;
+        unsafe {
+            addr_of_mut!((*ptr).data_ref_count).write(AtomicUsize::new(1));
+            addr_of_mut!((*ptr).alloc_ref_count).write(AtomicUsize::new(1));
+        }
+        ptr
+    }
+}
+
+impl<T> FromIterator<T> for Arc<[T]> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self::from_vec(iter.into_iter().collect())
+    }
+}
+
+impl Arc<str> {
+    pub fn from_str(s: &str) -> Self {
+        // strと[u8]はレイアウトもメタデータも同じ
+        let bytes = ManuallyDrop::new(Arc::<[u8]>::from_slice(s.as_bytes()));
+        Arc {
+            ptr: unsafe { NonNull::new_unchecked(bytes.ptr.as_ptr() as *mut ArcData<str>) },
+        }
+    }
+}
+
+// Arc<[T; 3]>からArc<[T]>へ、Arc<F>からArc<dyn Fn()>へのような変換を許す
+// 安定版のRustにはないので、--cfg nightlyを渡してnightlyでビルドしたときだけ有効にする
+#[cfg(nightly)]
+impl<T: ?Sized + std::marker::Unsize<U>, U: ?Sized> std::ops::CoerceUnsized<Arc<U>> for Arc<T> {}
+
+#[cfg(nightly)]
+impl<T: ?Sized + std::marker::Unsize<U>, U: ?Sized> std::ops::CoerceUnsized<Weak<U>> for Weak<T> {}
+
+unsafe impl<T: ?Sized + Sync + Send> Send for Arc<T> {}
+
+unsafe impl<T: ?Sized + Sync + Send> Sync for Arc<T> {}
+
+impl<T: ?Sized> Deref for Arc<T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -96,7 +264,7 @@ impl<T> Deref for Arc<T> {
     }
 }
 
-impl<T> Clone for Arc<T> {
+impl<T: ?Sized> Clone for Arc<T> {
     fn clone(&self) -> Self {
         // インクリメントは data_ref_count だけでよい
         if self.data().data_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
@@ -106,7 +274,7 @@ impl<T> Clone for Arc<T> {
     }
 }
 
-impl<T> Drop for Arc<T> {
+impl<T: ?Sized> Drop for Arc<T> {
     fn drop(&mut self) {
         if self.data().data_ref_count.fetch_sub(1, Release) == 1 {
             fence(Acquire);
@@ -122,47 +290,83 @@ impl<T> Drop for Arc<T> {
     }
 }
 
-pub struct Weak<T> {
+pub struct Weak<T: ?Sized> {
     ptr: NonNull<ArcData<T>>,
 }
 
+// Weak::new()が使うアドレス。ArcDataのアラインメントは1より大きいので、実際の割り当てと重ならない
+const DANGLING: usize = usize::MAX;
+
 impl<T> Weak<T> {
-    fn data(&self) -> &ArcData<T> {
-        unsafe { self.ptr.as_ref() }
+    // 何も割り当てずに作る。アップグレードは常に失敗する
+    // 構造体のWeakフィールドの初期値として使う
+    pub const fn new() -> Weak<T> {
+        Weak {
+            ptr: unsafe { NonNull::new_unchecked(std::ptr::without_provenance_mut(DANGLING)) },
+        }
+    }
+}
+
+impl<T: ?Sized> Weak<T> {
+    // Weak::new()で作ったものならNoneを返す
+    fn data(&self) -> Option<&ArcData<T>> {
+        if self.ptr.as_ptr().addr() == DANGLING {
+            None
+        } else {
+            unsafe { Some(self.ptr.as_ref()) }
+        }
     }
 
     pub fn upgrade(&self) -> Option<Arc<T>> {
-        let mut n = self.data().data_ref_count.load(Relaxed);
+        let data = self.data()?;
+        let mut n = data.data_ref_count.load(Relaxed);
         loop {
             if n == 0 {
                 return None;
             }
             assert!(n < usize::MAX);
-            if let Err(e) =
-                self.data()
-                    .data_ref_count
-                    .compare_exchange_weak(n, n + 1, Relaxed, Relaxed)
+            if let Err(e) = data
+                .data_ref_count
+                .compare_exchange_weak(n, n + 1, Acquire, Relaxed)
             {
                 n = e;
                 continue;
             }
+            // Acquireはnew_cyclicのReleaseと対応する
             return Some(Arc { ptr: self.ptr });
         }
     }
+
+    // データがドロップされた後でも、同じArcDataを指しているかどうかで比べる
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        std::ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
+    }
+}
+
+impl<T> Default for Weak<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl<T> Clone for Weak<T> {
+impl<T: ?Sized> Clone for Weak<T> {
     fn clone(&self) -> Self {
-        if self.data().alloc_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
-            std::process::abort();
+        if let Some(data) = self.data() {
+            if data.alloc_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
+                std::process::abort();
+            }
         }
         Weak { ptr: self.ptr }
     }
 }
 
-impl<T> Drop for Weak<T> {
+impl<T: ?Sized> Drop for Weak<T> {
     fn drop(&mut self) {
-        if self.data().alloc_ref_count.fetch_sub(1, Release) == 1 {
+        // 割り当てがなければ何もしない
+        let Some(data) = self.data() else {
+            return;
+        };
+        if data.alloc_ref_count.fetch_sub(1, Release) == 1 {
             fence(Acquire);
             unsafe {
                 drop(Box::from_raw(self.ptr.as_ptr()));
@@ -171,9 +375,9 @@ impl<T> Drop for Weak<T> {
     }
 }
 
-unsafe impl<T: Sync + Send> Send for Weak<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Send for Weak<T> {}
 
-unsafe impl<T: Sync + Send> Sync for Weak<T> {}
+unsafe impl<T: ?Sized + Sync + Send> Sync for Weak<T> {}
 
 #[test]
 fn test() {
@@ -206,3 +410,138 @@ fn test() {
     assert_eq!(NUM_DROPS.load(Relaxed), 1);
     assert!(z.upgrade().is_none());
 }
+
+#[test]
+fn test_make_mut() {
+    // 他のArcがあれば複製される
+    let mut x = Arc::new(1);
+    let y = x.clone();
+    *Arc::make_mut(&mut x) += 1;
+    assert_eq!((*x, *y), (2, 1));
+
+    // Weakしか残っていなければ複製せずに移し、Weakは切り離される
+    let w = Arc::downgrade(&y);
+    let mut y = y;
+    *Arc::make_mut(&mut y) += 10;
+    assert_eq!(*y, 11);
+    assert!(w.upgrade().is_none());
+
+    // 唯一のArcならそのまま書き換える
+    let z = Arc::downgrade(&y);
+    drop(z);
+    *Arc::make_mut(&mut y) += 100;
+    assert_eq!(*y, 111);
+    assert!(Arc::get_mut(&mut y).is_some());
+}
+
+#[test]
+fn test_ptr_eq() {
+    let x = Arc::new(1);
+    let y = x.clone();
+    let z = Arc::new(1);
+    assert!(Arc::ptr_eq(&x, &y));
+    // 値が等しくても別のArcDataなら異なる
+    assert!(!Arc::ptr_eq(&x, &z));
+
+    let wx = Arc::downgrade(&x);
+    let wz = Arc::downgrade(&z);
+    drop((x, y, z));
+    assert!(Weak::ptr_eq(&wx, &wx.clone()));
+    assert!(!Weak::ptr_eq(&wx, &wz));
+}
+
+#[test]
+fn test_new_cyclic() {
+    struct Node {
+        me: Weak<Node>,
+        value: i32,
+    }
+
+    let node = Arc::new_cyclic(|me| {
+        // まだ作っている途中なのでアップグレードできない
+        assert!(me.upgrade().is_none());
+        Node {
+            me: me.clone(),
+            value: 1,
+        }
+    });
+    let me = node.me.upgrade().unwrap();
+    assert!(Arc::ptr_eq(&node, &me));
+    assert_eq!(me.value, 1);
+
+    let weak = Arc::downgrade(&node);
+    drop((node, me));
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn test_weak_new() {
+    struct Node {
+        parent: Weak<Node>,
+    }
+
+    let root = Arc::new(Node {
+        parent: Weak::default(),
+    });
+    assert!(root.parent.upgrade().is_none());
+    let w = Weak::<Node>::new();
+    assert!(w.clone().upgrade().is_none());
+    assert!(Weak::ptr_eq(&w, &root.parent));
+    assert!(!Weak::ptr_eq(&w, &Arc::downgrade(&root)));
+}
+
+#[test]
+fn test_into_raw() {
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+    struct DetectDrop(i32);
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    // 生ポインタとしてAtomicPtrに置いておける
+    use std::sync::atomic::AtomicPtr;
+    use std::sync::atomic::Ordering::AcqRel;
+
+    let slot = AtomicPtr::new(Arc::into_raw(Arc::new(DetectDrop(1))).cast_mut());
+    let ptr = slot.load(Acquire).cast_const();
+    assert_eq!(unsafe { (*ptr).0 }, 1);
+
+    unsafe { Arc::increment_strong_count(ptr) };
+    let x = unsafe { Arc::from_raw(ptr) };
+    assert_eq!(x.0, 1);
+    drop(x);
+    assert_eq!(NUM_DROPS.load(Relaxed), 0);
+
+    unsafe { Arc::decrement_strong_count(slot.swap(std::ptr::null_mut(), AcqRel)) };
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+}
+
+#[test]
+fn test_unsized() {
+    let x = Arc::from_slice(&[1, 2, 3]);
+    let y = x.clone();
+    assert_eq!(&*y, [1, 2, 3]);
+    let w = Arc::downgrade(&x);
+    drop((x, y));
+    assert!(w.upgrade().is_none());
+
+    let s = Arc::from_str("hello");
+    assert_eq!(&*s, "hello");
+    let s = unsafe { Arc::from_raw(Arc::into_raw(s)) };
+    assert_eq!(s.len(), 5);
+
+    // 要素はArcDataに移されてから一度だけドロップされる
+    let v: Arc<[String]> = (0..3).map(|i| i.to_string()).collect();
+    assert_eq!(v[2], "2");
+}
+
+#[cfg(nightly)]
+#[test]
+fn test_coerce_unsized() {
+    let x: Arc<[i32]> = Arc::new([1, 2, 3]);
+    assert_eq!(&*x, [1, 2, 3]);
+    let f: Arc<dyn Fn() -> i32> = Arc::new(|| 1);
+    assert_eq!(f(), 1);
+}
@@ -1,3 +1,6 @@
+#![allow(dead_code)]
+#![cfg_attr(nightly, feature(coerce_unsized, unsize))]
+
 mod arc;
 mod arc_optimization;
 mod arc_weak;
@@ -0,0 +1,285 @@
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::marker::PhantomPinned;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::ptr;
+use std::task::{Context, Poll, Waker};
+
+// lock()がFutureを返すMutex
+// 待機中はスレッドをブロックせずにWakerを登録してPendingを返すので、
+// 非同期ランタイムのワーカースレッドを止めずに済む
+// 待機キューそのものはcrate::mutex::Mutexで保護する。保持するのはキューの操作の間だけなので短い
+pub struct Mutex<T> {
+    state: crate::mutex::Mutex<State>,
+    value: UnsafeCell<T>,
+}
+
+struct State {
+    locked: bool,
+    // 待ち始めた順に並べた双方向リスト
+    // ノードは各LockFutureの中に置くので、待機するたびにメモリを確保しない
+    head: *mut Node,
+    tail: *mut Node,
+}
+
+// ノードを指すポインタはstateをロックしている間しか使わない
+unsafe impl Send for State {}
+
+struct Node {
+    // キューにつながっている間だけSome
+    waker: Option<Waker>,
+    prev: *mut Node,
+    next: *mut Node,
+}
+
+impl State {
+    // nodeはキューにつながっていないこと
+    unsafe fn push_back(&mut self, node: *mut Node, waker: Waker) {
+        unsafe {
+            (*node).waker = Some(waker);
+            (*node).prev = self.tail;
+            (*node).next = ptr::null_mut();
+            if self.tail.is_null() {
+                self.head = node;
+            } else {
+                (*self.tail).next = node;
+            }
+        }
+        self.tail = node;
+    }
+
+    // nodeはキューにつながっていること
+    unsafe fn remove(&mut self, node: *mut Node) -> Waker {
+        unsafe {
+            let Node { waker, prev, next } = &mut *node;
+            if prev.is_null() {
+                self.head = *next;
+            } else {
+                (**prev).next = *next;
+            }
+            if next.is_null() {
+                self.tail = *prev;
+            } else {
+                (**next).prev = *prev;
+            }
+            waker.take().unwrap()
+        }
+    }
+}
+
+unsafe impl<T> Sync for Mutex<T> where T: Send {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: crate::mutex::Mutex::new(State {
+                locked: false,
+                head: ptr::null_mut(),
+                tail: ptr::null_mut(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> LockFuture<'_, T> {
+        LockFuture {
+            mutex: self,
+            node: UnsafeCell::new(Node {
+                waker: None,
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+            }),
+            queued: false,
+            _pinned: PhantomPinned,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        // 待機中のタスクがいる場合もlocked == trueになっているので追い越すことはない
+        if state.locked {
+            None
+        } else {
+            state.locked = true;
+            Some(MutexGuard { mutex: self })
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    fn unlock(&self) {
+        let mut state = self.state.lock();
+        let head = state.head;
+        if head.is_null() {
+            state.locked = false;
+            return;
+        }
+        // lockedをtrueのままにして、先頭で待っているタスクにロックを直接渡す
+        // 起こされたタスクはWakerが取り除かれていることでロックを渡されたことを知る
+        // stateを手放すとノードは破棄されうるので、Wakerはその前に取り出しておく
+        let waker = unsafe { state.remove(head) };
+        drop(state);
+        waker.wake();
+    }
+}
+
+pub struct LockFuture<'a, T> {
+    mutex: &'a Mutex<T>,
+    // キューに登録している間は他のスレッドからも触るので、stateをロックしている間だけ読み書きする
+    node: UnsafeCell<Node>,
+    // キューに登録したことがあるか。このLockFutureからしか触らない
+    queued: bool,
+    // nodeのアドレスをキューに登録するので、ピン留めされた後は動かせないようにする
+    _pinned: PhantomPinned,
+}
+
+// nodeのポインタはstateをロックしている間しか使わないので、別のスレッドでpollしてもよい
+unsafe impl<T> Send for LockFuture<'_, T> where T: Send {}
+
+impl<'a, T> Future for LockFuture<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // nodeを動かさないので、ピン留めを外しても問題ない
+        let this = unsafe { self.get_unchecked_mut() };
+        let mutex = this.mutex;
+        let node = this.node.get();
+        let mut state = mutex.state.lock();
+        if this.queued {
+            // まだ順番が来ていない。別のタスクから呼ばれた場合に備えてWakerを更新する
+            // Wakerが取り除かれていればロックを渡されている
+            if let Some(waker) = unsafe { &mut (*node).waker } {
+                waker.clone_from(cx.waker());
+                return Poll::Pending;
+            }
+        } else if state.locked {
+            unsafe { state.push_back(node, cx.waker().clone()) };
+            this.queued = true;
+            return Poll::Pending;
+        } else {
+            state.locked = true;
+        }
+        this.queued = false;
+        Poll::Ready(MutexGuard { mutex })
+    }
+}
+
+impl<T> Drop for LockFuture<'_, T> {
+    fn drop(&mut self) {
+        // 完了する前に破棄された場合(タイムアウトやselectなど)
+        // ピン留めされていれば、nodeのメモリはこのdropが終わるまで再利用されない
+        if !self.queued {
+            return;
+        }
+        let node = self.node.get();
+        let mut state = self.mutex.state.lock();
+        if unsafe { (*node).waker.is_some() } {
+            unsafe { state.remove(node) };
+        } else {
+            // ロックを渡された後なので、受け取らずに次のタスクに渡す
+            drop(state);
+            self.mutex.unlock();
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T> Sync for MutexGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+// テスト用の最小限のエグゼキュータ。wakeされるまでスレッドをparkする
+#[cfg(test)]
+pub(crate) fn block_on<F: Future>(f: F) -> F::Output {
+    use std::sync::Arc;
+    use std::task::Wake;
+    use std::thread::{self, Thread};
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut f = std::pin::pin!(f);
+    loop {
+        match f.as_mut().poll(&mut cx) {
+            Poll::Ready(v) => return v,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[test]
+fn test_async_mutex() {
+    use std::thread;
+
+    let mutex = Mutex::new(0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                block_on(async {
+                    for _ in 0..1000 {
+                        *mutex.lock().await += 1;
+                    }
+                })
+            });
+        }
+    });
+    assert_eq!(mutex.into_inner(), 4000);
+}
+
+#[test]
+fn test_drop_lock_future() {
+    let mutex = Mutex::new(0);
+    let mut cx = Context::from_waker(Waker::noop());
+
+    let guard = mutex.try_lock().unwrap();
+    let mut f1 = Box::pin(mutex.lock());
+    let mut f2 = Box::pin(mutex.lock());
+    let mut f3 = Box::pin(mutex.lock());
+    assert!(f1.as_mut().poll(&mut cx).is_pending());
+    assert!(f2.as_mut().poll(&mut cx).is_pending());
+    assert!(f3.as_mut().poll(&mut cx).is_pending());
+
+    // 順番を待っている間に破棄されたf3はキューから外れる
+    drop(f3);
+    // f1にロックが渡されるが、f1はロックを受け取らずに破棄されるのでf2に渡る
+    drop(guard);
+    drop(f1);
+    assert!(mutex.try_lock().is_none());
+    let Poll::Ready(mut g) = f2.as_mut().poll(&mut cx) else {
+        panic!("f2 should own the lock");
+    };
+    *g += 1;
+    drop(g);
+    assert_eq!(*mutex.try_lock().unwrap(), 1);
+}
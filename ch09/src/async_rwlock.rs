@@ -0,0 +1,323 @@
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+// read()/write()がFutureを返すRwLock
+// async_mutex.rsと同じく、待機キューはcrate::mutex::Mutexで保護し、ロックは待っているタスクに直接渡す
+// ライタが待っていれば新しいリーダも後ろに並ぶので、ライタは飢餓状態にならない
+pub struct RwLock<T> {
+    state: crate::mutex::Mutex<State>,
+    value: UnsafeCell<T>,
+}
+
+struct State {
+    readers: usize,
+    writer: bool,
+    // 待ち始めた順に並べる
+    // 空でなければ、ライトロック中か先頭でライタが待っている
+    waiters: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+struct Waiter {
+    id: u64,
+    write: bool,
+    waker: Waker,
+}
+
+impl State {
+    fn try_read(&mut self) -> bool {
+        // 待っているライタがいれば追い越さない
+        if self.writer || !self.waiters.is_empty() {
+            return false;
+        }
+        self.readers += 1;
+        true
+    }
+
+    fn try_write(&mut self) -> bool {
+        if self.writer || self.readers > 0 {
+            return false;
+        }
+        self.writer = true;
+        true
+    }
+
+    // ロックを取得できるようになった先頭の待機タスクにロックを渡し、起こすWakerを返す
+    // 先頭がライタならそのライタだけ、リーダなら次のライタまでのリーダをすべて入れる
+    fn grant(&mut self) -> Vec<Waker> {
+        let mut wakers = Vec::new();
+        if self.writer {
+            return wakers;
+        }
+        while let Some(w) = self.waiters.front() {
+            if w.write {
+                if self.readers == 0 {
+                    self.writer = true;
+                    wakers.push(self.waiters.pop_front().unwrap().waker);
+                }
+                break;
+            }
+            self.readers += 1;
+            wakers.push(self.waiters.pop_front().unwrap().waker);
+        }
+        wakers
+    }
+}
+
+unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: crate::mutex::Mutex::new(State {
+                readers: 0,
+                writer: false,
+                waiters: VecDeque::new(),
+                next_id: 0,
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> ReadFuture<'_, T> {
+        ReadFuture(Acquire {
+            rwlock: self,
+            write: false,
+            id: None,
+        })
+    }
+
+    pub fn write(&self) -> WriteFuture<'_, T> {
+        WriteFuture(Acquire {
+            rwlock: self,
+            write: true,
+            id: None,
+        })
+    }
+
+    pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+        if self.state.lock().try_read() {
+            Some(ReadGuard { rwlock: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+        if self.state.lock().try_write() {
+            Some(WriteGuard { rwlock: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    fn unlock(&self, write: bool) {
+        let mut state = self.state.lock();
+        if write {
+            state.writer = false;
+        } else {
+            state.readers -= 1;
+        }
+        let wakers = state.grant();
+        drop(state);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+// ReadFutureとWriteFutureで共通の処理
+struct Acquire<'a, T> {
+    rwlock: &'a RwLock<T>,
+    write: bool,
+    // キューに登録済みであればそのid
+    id: Option<u64>,
+}
+
+impl<T> Acquire<'_, T> {
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.rwlock.state.lock();
+        match self.id {
+            Some(id) => {
+                // キューから取り除かれていればロックを渡されている
+                if let Some(w) = state.waiters.iter_mut().find(|w| w.id == id) {
+                    w.waker.clone_from(cx.waker());
+                    return Poll::Pending;
+                }
+            }
+            None => {
+                let acquired = if self.write {
+                    state.try_write()
+                } else {
+                    state.try_read()
+                };
+                if !acquired {
+                    let id = state.next_id;
+                    state.next_id += 1;
+                    state.waiters.push_back(Waiter {
+                        id,
+                        write: self.write,
+                        waker: cx.waker().clone(),
+                    });
+                    self.id = Some(id);
+                    return Poll::Pending;
+                }
+            }
+        }
+        self.id = None;
+        Poll::Ready(())
+    }
+}
+
+impl<T> Drop for Acquire<'_, T> {
+    fn drop(&mut self) {
+        // 完了する前に破棄された場合(タイムアウトやselectなど)
+        let Some(id) = self.id else {
+            return;
+        };
+        let mut state = self.rwlock.state.lock();
+        if let Some(i) = state.waiters.iter().position(|w| w.id == id) {
+            state.waiters.remove(i);
+            // 先頭のライタがいなくなれば、後ろのリーダが入れるかもしれない
+            let wakers = state.grant();
+            drop(state);
+            for waker in wakers {
+                waker.wake();
+            }
+        } else {
+            // ロックを渡された後なので、受け取らずに次のタスクに渡す
+            drop(state);
+            self.rwlock.unlock(self.write);
+        }
+    }
+}
+
+pub struct ReadFuture<'a, T>(Acquire<'a, T>);
+
+impl<'a, T> Future for ReadFuture<'a, T> {
+    type Output = ReadGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let rwlock = self.0.rwlock;
+        self.0.poll(cx).map(|()| ReadGuard { rwlock })
+    }
+}
+
+pub struct WriteFuture<'a, T>(Acquire<'a, T>);
+
+impl<'a, T> Future for WriteFuture<'a, T> {
+    type Output = WriteGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let rwlock = self.0.rwlock;
+        self.0.poll(cx).map(|()| WriteGuard { rwlock })
+    }
+}
+
+pub struct ReadGuard<'a, T> {
+    rwlock: &'a RwLock<T>,
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.rwlock.unlock(false);
+    }
+}
+
+pub struct WriteGuard<'a, T> {
+    rwlock: &'a RwLock<T>,
+}
+
+unsafe impl<T> Sync for WriteGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.rwlock.unlock(true);
+    }
+}
+
+#[test]
+fn test_async_rwlock() {
+    use crate::async_mutex::block_on;
+    use std::thread;
+
+    let rwlock = RwLock::new((0, 0));
+    thread::scope(|s| {
+        for _ in 0..2 {
+            s.spawn(|| {
+                block_on(async {
+                    for _ in 0..1000 {
+                        let mut w = rwlock.write().await;
+                        w.0 += 1;
+                        w.1 += 1;
+                    }
+                })
+            });
+            s.spawn(|| {
+                block_on(async {
+                    for _ in 0..1000 {
+                        let r = rwlock.read().await;
+                        assert_eq!(r.0, r.1);
+                    }
+                })
+            });
+        }
+    });
+    assert_eq!(rwlock.into_inner(), (2000, 2000));
+}
+
+#[test]
+fn test_writer_preference() {
+    let rwlock = RwLock::new(0);
+    let mut cx = Context::from_waker(Waker::noop());
+
+    let r1 = rwlock.try_read().unwrap();
+    let mut w = Box::pin(rwlock.write());
+    assert!(w.as_mut().poll(&mut cx).is_pending());
+    // ライタが待っているので、新しいリーダは後ろに並ぶ
+    assert!(rwlock.try_read().is_none());
+    let mut r2 = Box::pin(rwlock.read());
+    assert!(r2.as_mut().poll(&mut cx).is_pending());
+
+    drop(r1);
+    let Poll::Ready(mut g) = w.as_mut().poll(&mut cx) else {
+        panic!("writer should own the lock");
+    };
+    *g += 1;
+    assert!(r2.as_mut().poll(&mut cx).is_pending());
+    drop(g);
+    let Poll::Ready(g) = r2.as_mut().poll(&mut cx) else {
+        panic!("reader should own the lock");
+    };
+    assert_eq!(*g, 1);
+}
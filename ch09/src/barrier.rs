@@ -0,0 +1,117 @@
+use atomic_wait::{wait, wake_all};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed};
+
+// 下位16ビット: 現在の世代に到着したスレッドの数
+// 上位16ビット: 世代
+const COUNT_MASK: u32 = 0xffff;
+const GENERATION_ONE: u32 = COUNT_MASK + 1;
+
+pub struct Barrier {
+    // 到着数と世代を1つのアトミック変数にまとめる
+    // 別々に持つと、前の世代の遅れたスレッドが次の世代の到着数と古い世代を組み合わせて観測してしまう
+    state: AtomicU32,
+    n: u32,
+}
+
+pub struct BarrierWaitResult {
+    is_leader: bool,
+}
+
+impl BarrierWaitResult {
+    // 各世代で最後に到着した1スレッドだけがtrueになる
+    pub fn is_leader(&self) -> bool {
+        self.is_leader
+    }
+}
+
+impl Barrier {
+    pub const fn new(n: usize) -> Self {
+        assert!(n <= COUNT_MASK as usize, "too many threads");
+        Self {
+            state: AtomicU32::new(0),
+            n: n as u32,
+        }
+    }
+
+    pub fn wait(&self) -> BarrierWaitResult {
+        let mut s = self.state.load(Relaxed);
+        let generation = loop {
+            let count = (s & COUNT_MASK) + 1;
+            let generation = s & !COUNT_MASK;
+            // 最後のスレッドは世代を進めて到着数を0に戻す
+            let new = if count >= self.n {
+                generation.wrapping_add(GENERATION_ONE)
+            } else {
+                s + 1
+            };
+            // Releaseでこれまでの書き込みを次の世代に公開し、
+            // リーダはAcquireで他のスレッドの書き込みを観測する
+            match self.state.compare_exchange_weak(s, new, AcqRel, Relaxed) {
+                Ok(_) if count >= self.n => {
+                    wake_all(&self.state);
+                    return BarrierWaitResult { is_leader: true };
+                }
+                Ok(_) => break generation,
+                Err(e) => s = e,
+            }
+        };
+        // 自分が到着した世代が終わるまで待機する
+        // 次の世代のスレッドが到着数を変えても世代が同じ間は待ち続ける
+        loop {
+            let s = self.state.load(Acquire);
+            if s & !COUNT_MASK != generation {
+                return BarrierWaitResult { is_leader: false };
+            }
+            wait(&self.state, s);
+        }
+    }
+}
+
+#[test]
+fn test_barrier() {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    const N: usize = 4;
+    const ROUNDS: usize = 2000;
+
+    let barrier = Barrier::new(N);
+    let arrived = AtomicUsize::new(0);
+    let leaders = AtomicUsize::new(0);
+
+    // 同じスレッドの組でラウンドを繰り返す
+    thread::scope(|s| {
+        for _ in 0..N {
+            s.spawn(|| {
+                for round in 0..ROUNDS {
+                    arrived.fetch_add(1, Relaxed);
+                    if barrier.wait().is_leader() {
+                        leaders.fetch_add(1, Relaxed);
+                    }
+                    // 全スレッドが到着するまで次に進んでいない
+                    assert_eq!(arrived.load(Relaxed), (round + 1) * N);
+                    barrier.wait();
+                }
+            });
+        }
+    });
+    assert_eq!(leaders.load(Relaxed), ROUNDS);
+
+    // スレッドの組を入れ替えながら同じバリアを使い続ける
+    leaders.store(0, Relaxed);
+    for _ in 0..ROUNDS / 10 {
+        thread::scope(|s| {
+            for _ in 0..N {
+                s.spawn(|| {
+                    for _ in 0..10 {
+                        if barrier.wait().is_leader() {
+                            leaders.fetch_add(1, Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+    }
+    assert_eq!(leaders.load(Relaxed), ROUNDS);
+}
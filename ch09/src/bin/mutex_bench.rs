@@ -0,0 +1,184 @@
+// mutex.rs, mutex_opt.rs, mutex_spin.rsの性能を比べる
+// cargo run --release --bin mutex_bench -- --threads 1,2,4,8 --cs 100 --idle 1000
+// オプションはUSAGEを参照
+
+use ch09::{mutex, mutex_opt, mutex_spin};
+use std::hint::black_box;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 各Mutexのロックとアンロックだけをそろえて同じ計測コードで扱う
+trait BenchMutex: Sync {
+    const NAME: &'static str;
+    fn new() -> Self;
+    fn with_lock(&self, f: impl FnOnce(&mut u64));
+}
+
+macro_rules! bench_mutex {
+    ($name:literal, $ty:ty) => {
+        impl BenchMutex for $ty {
+            const NAME: &'static str = $name;
+
+            fn new() -> Self {
+                <$ty>::new(0)
+            }
+
+            fn with_lock(&self, f: impl FnOnce(&mut u64)) {
+                f(&mut self.lock())
+            }
+        }
+    };
+}
+
+bench_mutex!("mutex", mutex::Mutex<u64>);
+bench_mutex!("mutex_opt", mutex_opt::Mutex<u64>);
+bench_mutex!("mutex_spin", mutex_spin::Mutex<u64>);
+
+const USAGE: &str = "\
+usage: mutex_bench [options]
+
+  --threads  スレッド数(カンマ区切りで複数指定できる)
+  --cs       ロックを保持している間に回すループの回数
+  --idle     アンロックしてから次にロックするまでに回すループの回数
+  --millis   1回の計測時間(ミリ秒)
+  --help     このメッセージを表示する";
+
+// 引数の誤りはpanicではなく使い方を表示して終了する
+fn usage_error(message: &str) -> ! {
+    eprintln!("{message}\n\n{USAGE}");
+    std::process::exit(2);
+}
+
+fn parse<T: std::str::FromStr>(arg: &str, value: &str) -> T {
+    value
+        .parse()
+        .unwrap_or_else(|_| usage_error(&format!("invalid value for {arg}: {value}")))
+}
+
+struct Config {
+    threads: Vec<usize>,
+    cs: u32,
+    idle: u32,
+    duration: Duration,
+}
+
+fn parse_args() -> Config {
+    let mut config = Config {
+        threads: vec![1, 2, 4, 8],
+        cs: 100,
+        idle: 1000,
+        duration: Duration::from_millis(500),
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            println!("{USAGE}");
+            std::process::exit(0);
+        }
+        let Some(value) = args.next() else {
+            usage_error(&format!("missing value for {arg}"));
+        };
+        let value = value.as_str();
+        match arg.as_str() {
+            "--threads" => {
+                config.threads = value.split(',').map(|n| parse("--threads", n)).collect()
+            }
+            "--cs" => config.cs = parse("--cs", value),
+            "--idle" => config.idle = parse("--idle", value),
+            "--millis" => config.duration = Duration::from_millis(parse("--millis", value)),
+            _ => usage_error(&format!("unknown option: {arg}")),
+        }
+    }
+    config
+}
+
+// 最適化で消されないようにblack_boxを通してループする
+fn work(n: u32) {
+    for i in 0..n {
+        black_box(i);
+    }
+}
+
+// ロックの取得にかかった時間の分布は、一度もロックを取得できなかった場合(--threads 0など)はNone
+struct Measurement {
+    ops_per_sec: f64,
+    p50: Option<Duration>,
+    p99: Option<Duration>,
+}
+
+fn run<M: BenchMutex>(threads: usize, config: &Config) -> Measurement {
+    let mutex = M::new();
+    let start = Instant::now();
+    let deadline = start + config.duration;
+    // 各スレッドで計測したロックの取得にかかった時間
+    let mut latencies: Vec<Duration> = thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut latencies = Vec::new();
+                    while Instant::now() < deadline {
+                        let t = Instant::now();
+                        mutex.with_lock(|v| {
+                            latencies.push(t.elapsed());
+                            work(config.cs);
+                            *v += 1;
+                        });
+                        work(config.idle);
+                    }
+                    latencies
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    });
+    let elapsed = start.elapsed();
+
+    let mut total = 0;
+    mutex.with_lock(|v| total = *v);
+    assert_eq!(total, latencies.len() as u64);
+
+    latencies.sort_unstable();
+    let percentile =
+        |p: usize| (!latencies.is_empty()).then(|| latencies[(latencies.len() - 1) * p / 100]);
+    Measurement {
+        ops_per_sec: total as f64 / elapsed.as_secs_f64(),
+        p50: percentile(50),
+        p99: percentile(99),
+    }
+}
+
+fn bench<M: BenchMutex>(config: &Config) {
+    for &threads in &config.threads {
+        let r = run::<M>(threads, config);
+        println!(
+            "{:<12}{:>8}{:>16.0}{:>12}{:>12}",
+            M::NAME,
+            threads,
+            r.ops_per_sec,
+            nanos(r.p50),
+            nanos(r.p99)
+        );
+    }
+}
+
+fn nanos(d: Option<Duration>) -> String {
+    d.map_or("-".to_string(), |d| d.as_nanos().to_string())
+}
+
+fn main() {
+    let config = parse_args();
+    println!(
+        "cs: {}, idle: {}, duration: {:?}",
+        config.cs, config.idle, config.duration
+    );
+    println!(
+        "{:<12}{:>8}{:>16}{:>12}{:>12}",
+        "variant", "threads", "ops/s", "p50(ns)", "p99(ns)"
+    );
+    bench::<mutex::Mutex<u64>>(&config);
+    bench::<mutex_opt::Mutex<u64>>(&config);
+    bench::<mutex_spin::Mutex<u64>>(&config);
+}
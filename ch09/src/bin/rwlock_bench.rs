@@ -0,0 +1,253 @@
+// rwlock_no_busyloop.rs、rwlock_avoid_writer_starvation.rs、rwlock_phase_fair.rsで、
+// リーダとライタがロックを待つ時間を比べる
+// cargo run --release --bin rwlock_bench -- --readers 1,4,8 --writers 1 --read-cs 1000
+// オプションはUSAGEを参照
+//
+// リーダを優先すると、リーダが途切れない間はライタがロックを取得できないので、
+// ライタの待ち時間のp99やmaxが計測時間近くまで伸び、書き込み回数も減る
+// ライタを優先すると、ライタを増やしたときに今度はリーダの待ち時間が伸びる(--writers 4など)
+// フェーズフェアにすると、どちらも相手のフェーズを1回待つだけなので、両方の待ち時間が抑えられる
+
+use ch09::raw_rwlock::{ReaderPreferred, WriterPreferred};
+use ch09::{rwlock_fairness, rwlock_phase_fair};
+use std::hint::black_box;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 各RwLockのロックとアンロックだけをそろえて同じ計測コードで扱う
+trait BenchRwLock: Sync {
+    const NAME: &'static str;
+    fn new() -> Self;
+    fn with_read(&self, f: impl FnOnce(&u64));
+    fn with_write(&self, f: impl FnOnce(&mut u64));
+    fn into_inner(self) -> u64;
+}
+
+macro_rules! bench_rwlock {
+    ($name:literal, $ty:ty) => {
+        impl BenchRwLock for $ty {
+            const NAME: &'static str = $name;
+
+            fn new() -> Self {
+                <$ty>::new(0)
+            }
+
+            fn with_read(&self, f: impl FnOnce(&u64)) {
+                f(&self.read())
+            }
+
+            fn with_write(&self, f: impl FnOnce(&mut u64)) {
+                f(&mut self.write())
+            }
+
+            fn into_inner(self) -> u64 {
+                <$ty>::into_inner(self)
+            }
+        }
+    };
+}
+
+bench_rwlock!("no_busyloop", rwlock_fairness::RwLock<u64, ReaderPreferred>);
+bench_rwlock!("avoid_starv", rwlock_fairness::RwLock<u64, WriterPreferred>);
+bench_rwlock!("phase_fair", rwlock_phase_fair::RwLock<u64>);
+
+const USAGE: &str = "\
+usage: rwlock_bench [options]
+
+  --readers   リーダのスレッド数(カンマ区切りで複数指定できる)
+  --writers   ライタのスレッド数
+  --read-cs   リードロックを保持している間に回すループの回数
+  --write-cs  ライトロックを保持している間に回すループの回数
+  --idle      アンロックしてから次にロックするまでに回すループの回数
+  --millis    1回の計測時間(ミリ秒)
+  --help      このメッセージを表示する";
+
+// 引数の誤りはpanicではなく使い方を表示して終了する
+fn usage_error(message: &str) -> ! {
+    eprintln!("{message}\n\n{USAGE}");
+    std::process::exit(2);
+}
+
+fn parse<T: std::str::FromStr>(arg: &str, value: &str) -> T {
+    value
+        .parse()
+        .unwrap_or_else(|_| usage_error(&format!("invalid value for {arg}: {value}")))
+}
+
+struct Config {
+    readers: Vec<usize>,
+    writers: usize,
+    read_cs: u32,
+    write_cs: u32,
+    idle: u32,
+    duration: Duration,
+}
+
+fn parse_args() -> Config {
+    let mut config = Config {
+        readers: vec![1, 4, 8],
+        writers: 1,
+        read_cs: 1000,
+        write_cs: 100,
+        idle: 100,
+        duration: Duration::from_millis(500),
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            println!("{USAGE}");
+            std::process::exit(0);
+        }
+        let Some(value) = args.next() else {
+            usage_error(&format!("missing value for {arg}"));
+        };
+        let value = value.as_str();
+        match arg.as_str() {
+            "--readers" => {
+                config.readers = value.split(',').map(|n| parse("--readers", n)).collect()
+            }
+            "--writers" => config.writers = parse("--writers", value),
+            "--read-cs" => config.read_cs = parse("--read-cs", value),
+            "--write-cs" => config.write_cs = parse("--write-cs", value),
+            "--idle" => config.idle = parse("--idle", value),
+            "--millis" => config.duration = Duration::from_millis(parse("--millis", value)),
+            _ => usage_error(&format!("unknown option: {arg}")),
+        }
+    }
+    if config.writers == 0 {
+        usage_error("at least one writer is required");
+    }
+    config
+}
+
+// 最適化で消されないようにblack_boxを通してループする
+fn work(n: u32) {
+    for i in 0..n {
+        black_box(i);
+    }
+}
+
+// ロックの取得にかかった時間の分布
+// 一度もロックを取得できなかった場合はNone
+struct Waits {
+    p50: Option<Duration>,
+    p99: Option<Duration>,
+    max: Option<Duration>,
+}
+
+impl Waits {
+    fn new(mut waits: Vec<Duration>) -> Self {
+        waits.sort_unstable();
+        let percentile = |p: usize| (!waits.is_empty()).then(|| waits[(waits.len() - 1) * p / 100]);
+        Self {
+            p50: percentile(50),
+            p99: percentile(99),
+            max: percentile(100),
+        }
+    }
+}
+
+struct Measurement {
+    reads: u64,
+    writes: u64,
+    read_waits: Waits,
+    write_waits: Waits,
+}
+
+// 各スレッドで計測したロックの取得にかかった時間をまとめる
+fn join_all(handles: Vec<thread::ScopedJoinHandle<'_, Vec<Duration>>>) -> Vec<Duration> {
+    handles
+        .into_iter()
+        .flat_map(|h| h.join().unwrap())
+        .collect()
+}
+
+fn run<L: BenchRwLock>(readers: usize, config: &Config) -> Measurement {
+    let rwlock = L::new();
+    let deadline = Instant::now() + config.duration;
+    let (read_waits, write_waits) = thread::scope(|s| {
+        let readers: Vec<_> = (0..readers)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut waits = Vec::new();
+                    while Instant::now() < deadline {
+                        let t = Instant::now();
+                        rwlock.with_read(|v| {
+                            waits.push(t.elapsed());
+                            black_box(*v);
+                            work(config.read_cs);
+                        });
+                        work(config.idle);
+                    }
+                    waits
+                })
+            })
+            .collect();
+        let writers: Vec<_> = (0..config.writers)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut waits = Vec::new();
+                    while Instant::now() < deadline {
+                        let t = Instant::now();
+                        rwlock.with_write(|v| {
+                            waits.push(t.elapsed());
+                            work(config.write_cs);
+                            *v += 1;
+                        });
+                        work(config.idle);
+                    }
+                    waits
+                })
+            })
+            .collect();
+        (join_all(readers), join_all(writers))
+    });
+
+    let writes = rwlock.into_inner();
+    assert_eq!(writes, write_waits.len() as u64);
+
+    Measurement {
+        reads: read_waits.len() as u64,
+        writes,
+        read_waits: Waits::new(read_waits),
+        write_waits: Waits::new(write_waits),
+    }
+}
+
+fn bench<L: BenchRwLock>(config: &Config) {
+    for &readers in &config.readers {
+        let r = run::<L>(readers, config);
+        println!(
+            "{:<12}{:>8}{:>12}{:>12}{:>12}{:>12}{:>12}{:>12}{:>12}",
+            L::NAME,
+            readers,
+            r.reads,
+            r.writes,
+            micros(r.read_waits.p99),
+            micros(r.read_waits.max),
+            micros(r.write_waits.p50),
+            micros(r.write_waits.p99),
+            micros(r.write_waits.max)
+        );
+    }
+}
+
+fn micros(d: Option<Duration>) -> String {
+    d.map_or("-".to_string(), |d| d.as_micros().to_string())
+}
+
+fn main() {
+    let config = parse_args();
+    println!(
+        "writers: {}, read-cs: {}, write-cs: {}, idle: {}, duration: {:?}",
+        config.writers, config.read_cs, config.write_cs, config.idle, config.duration
+    );
+    // r-はリーダ、w-はライタがロックを待った時間(マイクロ秒)
+    println!(
+        "{:<12}{:>8}{:>12}{:>12}{:>12}{:>12}{:>12}{:>12}{:>12}",
+        "variant", "readers", "reads", "writes", "r-p99", "r-max", "w-p50", "w-p99", "w-max"
+    );
+    bench::<rwlock_fairness::RwLock<u64, ReaderPreferred>>(&config);
+    bench::<rwlock_fairness::RwLock<u64, WriterPreferred>>(&config);
+    bench::<rwlock_phase_fair::RwLock<u64>>(&config);
+}
@@ -0,0 +1,152 @@
+use crate::mutex_spin::{DefaultSpin, SpinPolicy};
+use atomic_wait::{wait, wake_one};
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicPtr, AtomicU32};
+
+// CLHキューロック
+// ロックを取得しようとするスレッドはノードを末尾に追加し、直前のノードが解放されるのを待つ
+// 各スレッドが見るのは直前のノードだけなので、1つの値を全スレッドで取り合うことがなく、
+// 待ち始めた順にロックを取得できる
+// 一定回数スピンしても解放されなければ直前のノードのfutexで待機する
+pub struct ClhLock<T, P = DefaultSpin> {
+    // 最後に追加されたノード。最初は解放済みのダミーノードを指す
+    tail: AtomicPtr<Node>,
+    _policy: PhantomData<fn() -> P>,
+    value: UnsafeCell<T>,
+}
+
+struct Node {
+    /// 0: released
+    /// 1: locked: 次のスレッドはスピン中
+    /// 2: locked: 次のスレッドはfutexで待機中
+    state: AtomicU32,
+}
+
+unsafe impl<T, P> Sync for ClhLock<T, P> where T: Send {}
+
+// スリープせずにスピンし続ける。本来のCLHロックと同じ動作になる
+pub struct SpinForever;
+
+impl SpinPolicy for SpinForever {
+    // u32::MAXの場合は回数の上限なしとして扱う
+    const SPIN_LIMIT: u32 = u32::MAX;
+
+    fn relax(_: u32) {
+        std::hint::spin_loop();
+    }
+}
+
+impl<T> ClhLock<T> {
+    pub fn new(value: T) -> Self {
+        Self::with_policy(value)
+    }
+}
+
+impl<T, P> ClhLock<T, P> {
+    pub fn with_policy(value: T) -> Self {
+        let dummy = Box::into_raw(Box::new(Node {
+            state: AtomicU32::new(0),
+        }));
+        Self {
+            tail: AtomicPtr::new(dummy),
+            _policy: PhantomData,
+            value: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T, P: SpinPolicy> ClhLock<T, P> {
+    pub fn lock(&self) -> ClhLockGuard<'_, T, P> {
+        let node = Box::into_raw(Box::new(Node {
+            state: AtomicU32::new(1),
+        }));
+        // AcqRel: 自分のノードの初期化を次のスレッドに見せ、直前のノードの初期化を見る
+        let prev = self.tail.swap(node, AcqRel);
+        unsafe { wait_released::<P>(&(*prev).state) };
+        // 直前のノードはもう誰も参照しないので、ロックを取得したスレッドが解放する
+        drop(unsafe { Box::from_raw(prev) });
+        ClhLockGuard { lock: self, node }
+    }
+}
+
+fn wait_released<P: SpinPolicy>(state: &AtomicU32) {
+    let mut spin_count = 0;
+    while state.load(Acquire) != 0 {
+        if P::SPIN_LIMIT == u32::MAX || spin_count < P::SPIN_LIMIT {
+            P::relax(spin_count);
+            spin_count = spin_count.saturating_add(1);
+            continue;
+        }
+        // futexで待機することを直前のスレッドに知らせる
+        // 失敗した場合は解放されているか、すでに2になっている
+        let _ = state.compare_exchange(1, 2, Relaxed, Relaxed);
+        wait(state, 2);
+    }
+}
+
+impl<T, P> Drop for ClhLock<T, P> {
+    fn drop(&mut self) {
+        // ガードが残っていないので、末尾のノードは解放済みで誰も待っていない
+        drop(unsafe { Box::from_raw(*self.tail.get_mut()) });
+    }
+}
+
+pub struct ClhLockGuard<'a, T, P = DefaultSpin> {
+    lock: &'a ClhLock<T, P>,
+    node: *mut Node,
+}
+
+unsafe impl<T, P> Sync for ClhLockGuard<'_, T, P> where T: Sync {}
+
+impl<T, P> Deref for ClhLockGuard<'_, T, P> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T, P> DerefMut for ClhLockGuard<'_, T, P> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T, P> Drop for ClhLockGuard<'_, T, P> {
+    fn drop(&mut self) {
+        // 0にした直後に次のスレッドがノードを解放するかもしれないので、
+        // これ以降はノードを参照せずにアドレスだけを使う
+        let state = unsafe { &(*self.node).state } as *const AtomicU32;
+        if unsafe { (*state).swap(0, Release) } == 2 {
+            // wake_oneは解放済みのアドレスを渡しても問題ない
+            wake_one(state);
+        }
+    }
+}
+
+#[test]
+fn test_clh_lock() {
+    use crate::mutex_spin::NoSpin;
+    use std::thread;
+
+    fn count<P: SpinPolicy>(lock: ClhLock<u32, P>) -> u32 {
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..2000 {
+                        *lock.lock() += 1;
+                    }
+                });
+            }
+        });
+        let n = *lock.lock();
+        n
+    }
+
+    assert_eq!(count(ClhLock::new(0)), 8000);
+    assert_eq!(count(ClhLock::<_, NoSpin>::with_policy(0)), 8000);
+    assert_eq!(count(ClhLock::<_, SpinForever>::with_policy(0)), 8000);
+}
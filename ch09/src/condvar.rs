@@ -1,18 +1,118 @@
-use crate::mutex::{Mutex, MutexGuard};
-use atomic_wait::{wait, wake_all, wake_one};
-use std::sync::atomic::AtomicU32;
+use crate::futex::{requeue, wait_timeout, wake_all, wake_one};
+use crate::mutex_spin::SpinPolicy;
+use crate::{mutex, mutex_opt, mutex_spin};
+use atomic_wait::wait;
+use std::ops::DerefMut;
+use std::ptr;
 use std::sync::atomic::Ordering::Relaxed;
-use std::thread;
-use std::time::Duration;
+use std::sync::atomic::{AtomicPtr, AtomicU32};
+use std::time::{Duration, Instant};
+
+// Condvarで待機する間、ガードを手放してアンロックし、後でロックし直せるMutexGuard
+// mutex.rs, mutex_opt.rs, mutex_spin.rsのどのMutexでも同じCondvarで待機できる
+pub trait CondvarGuard<'a>: DerefMut + Sized {
+    type Mutex: ?Sized + 'a;
+
+    // ガードをドロップした後にロックし直すためのMutex
+    fn mutex(&self) -> &'a Self::Mutex;
+
+    fn lock(mutex: &'a Self::Mutex) -> Self;
+
+    // notify_all()で起こす代わりに待機スレッドを移すfutex
+    // アンロックするたびにそのfutexで待機しているスレッドを1つ起こすMutexだけが返せる
+    // nullの場合はすべて起こす
+    fn requeue_futex(_mutex: &Self::Mutex) -> *const AtomicU32 {
+        ptr::null()
+    }
+}
+
+impl<'a, T: ?Sized> CondvarGuard<'a> for mutex::MutexGuard<'a, T> {
+    type Mutex = mutex::Mutex<T>;
+
+    fn mutex(&self) -> &'a Self::Mutex {
+        // private だったものを pub(crate) mutex: &'a Mutex<T> に変更
+        self.mutex
+    }
+
+    fn lock(mutex: &'a Self::Mutex) -> Self {
+        mutex.lock()
+    }
+
+    // ロックを省略した場合はアンロックで起こさないので、移したスレッドが取り残される
+    #[cfg(not(feature = "elision"))]
+    fn requeue_futex(mutex: &Self::Mutex) -> *const AtomicU32 {
+        &mutex.state
+    }
+}
+
+// mutex_opt.rsとmutex_spin.rsは待機スレッドがいなければ(stateが1なら)アンロックしても起こさない
+// 移したスレッドは自分でstateを2にしていないので、起こされたスレッドはtry_lock()で1にせず、
+// RawMutex::lock_contended()で2にしてからロックする
+// こうすると、移したスレッドが残っている間はアンロックのたびにどれか1つが起こされる
+impl<'a, T> CondvarGuard<'a> for mutex_opt::MutexGuard<'a, T> {
+    type Mutex = mutex_opt::Mutex<T>;
+
+    fn mutex(&self) -> &'a Self::Mutex {
+        self.mutex
+    }
+
+    fn lock(mutex: &'a Self::Mutex) -> Self {
+        mutex.raw.lock_contended();
+        mutex_opt::MutexGuard { mutex }
+    }
+
+    fn requeue_futex(mutex: &Self::Mutex) -> *const AtomicU32 {
+        mutex.raw.futex()
+    }
+}
+
+impl<'a, T, P: SpinPolicy> CondvarGuard<'a> for mutex_spin::MutexGuard<'a, T, P> {
+    type Mutex = mutex_spin::Mutex<T, P>;
+
+    fn mutex(&self) -> &'a Self::Mutex {
+        self.mutex
+    }
+
+    // 起こされた時点でほかのスレッドもロックを待っているので、スピンせずに待機する
+    fn lock(mutex: &'a Self::Mutex) -> Self {
+        mutex.raw.lock_contended();
+        mutex_spin::MutexGuard { mutex }
+    }
+
+    fn requeue_futex(mutex: &Self::Mutex) -> *const AtomicU32 {
+        mutex.raw.futex()
+    }
+}
 
 pub struct Condvar {
     counter: AtomicU32,
+    // 最後に待機したスレッドのMutexのfutex
+    // notify_all()で起こされたスレッドはすぐにそのMutexを取り合うので、1つだけ起こして残りはMutexで待たせる
+    requeue_to: AtomicPtr<AtomicU32>,
+    // 最初に待機したときのMutexのアドレス
+    // 別のMutexで待機するスレッドがいると、notifyが別のMutexの条件を待つスレッドに届いて通知を取りこぼす
+    #[cfg(debug_assertions)]
+    mutex: AtomicPtr<()>,
 }
 
 impl Condvar {
     pub const fn new() -> Self {
         Self {
             counter: AtomicU32::new(0),
+            requeue_to: AtomicPtr::new(ptr::null_mut()),
+            #[cfg(debug_assertions)]
+            mutex: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_mutex<M: ?Sized>(&self, mutex: &M) {
+        let addr = (mutex as *const M).cast::<()>().cast_mut();
+        if let Err(first) = self
+            .mutex
+            .compare_exchange(ptr::null_mut(), addr, Relaxed, Relaxed)
+        {
+            assert!(first == addr, "Condvar is used with more than one Mutex");
         }
     }
 
@@ -21,36 +121,134 @@ impl Condvar {
         wake_one(&self.counter);
     }
     pub fn notify_all(&self) {
-        self.counter.fetch_add(1, Relaxed);
-        wake_all(&self.counter);
+        let counter_value = self.counter.fetch_add(1, Relaxed).wrapping_add(1);
+        let to = self.requeue_to.load(Relaxed);
+        if to.is_null() {
+            wake_all(&self.counter);
+        } else {
+            // 移したスレッドはMutexがアンロックされるたびに1つずつ起こされる
+            requeue(&self.counter, counter_value, to);
+        }
     }
 
-    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    pub fn wait<'a, G: CondvarGuard<'a>>(&self, guard: G) -> G {
         let counter_value = self.counter.load(Relaxed);
-        // private だったものを pub(crate) mutex: &'a Mutex<T> に変更
-        let mutex = guard.mutex;
+        let mutex = guard.mutex();
+        #[cfg(debug_assertions)]
+        self.check_mutex(mutex);
+        self.requeue_to
+            .store(G::requeue_futex(mutex).cast_mut(), Relaxed);
         drop(guard);
 
         wait(&self.counter, counter_value);
-        mutex.lock()
+        G::lock(mutex)
+    }
+
+    // conditionがtrueを返す間は待機し続ける
+    // 誤って起こされた場合もここでループするので、呼び出し側でwhileを書かなくてよい
+    pub fn wait_while<'a, G: CondvarGuard<'a>>(
+        &self,
+        mut guard: G,
+        mut condition: impl FnMut(&mut G::Target) -> bool,
+    ) -> G {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    // wait()と同じく誤って起こされることがあるので、呼び出し側は条件を確認してループする
+    pub fn wait_timeout<'a, G: CondvarGuard<'a>>(
+        &self,
+        guard: G,
+        timeout: Duration,
+    ) -> (G, WaitTimeoutResult) {
+        let counter_value = self.counter.load(Relaxed);
+        let mutex = guard.mutex();
+        #[cfg(debug_assertions)]
+        self.check_mutex(mutex);
+        self.requeue_to
+            .store(G::requeue_futex(mutex).cast_mut(), Relaxed);
+        drop(guard);
+
+        let start = Instant::now();
+        wait_timeout(&self.counter, counter_value, timeout);
+        // タイムアウトと同時にnotifyされた場合、このスレッドがwakeを受け取っているかもしれない
+        // counterが変わっていればタイムアウトではなく通知されたものとして返し、通知を取りこぼさないようにする
+        let timed_out = self.counter.load(Relaxed) == counter_value && start.elapsed() >= timeout;
+        (G::lock(mutex), WaitTimeoutResult(timed_out))
+    }
+
+    // deadlineを過ぎていれば待機せずにタイムアウトとして返す
+    pub fn wait_until<'a, G: CondvarGuard<'a>>(
+        &self,
+        guard: G,
+        deadline: Instant,
+    ) -> (G, WaitTimeoutResult) {
+        match deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if !remaining.is_zero() => self.wait_timeout(guard, remaining),
+            _ => (guard, WaitTimeoutResult(true)),
+        }
+    }
+
+    // wait_while()のタイムアウト付き版
+    // timeoutまでにconditionがfalseにならなければ、timed_out()がtrueを返す
+    // 誤って起こされても待つ時間が延びないように、最初に期限を決めてwait_until()で待つ
+    pub fn wait_timeout_while<'a, G: CondvarGuard<'a>>(
+        &self,
+        mut guard: G,
+        timeout: Duration,
+        mut condition: impl FnMut(&mut G::Target) -> bool,
+    ) -> (G, WaitTimeoutResult) {
+        let Some(deadline) = Instant::now().checked_add(timeout) else {
+            return (self.wait_while(guard, condition), WaitTimeoutResult(false));
+        };
+        while condition(&mut *guard) {
+            let (g, result) = self.wait_until(guard, deadline);
+            guard = g;
+            // タイムアウトと同時に条件が満たされていれば、タイムアウトとはしない
+            if result.timed_out() {
+                let timed_out = condition(&mut *guard);
+                return (guard, WaitTimeoutResult(timed_out));
+            }
+        }
+        (guard, WaitTimeoutResult(false))
+    }
+}
+
+// std::sync::WaitTimeoutResultは外から作れないので同じものを用意する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
     }
 }
 
 #[test]
 fn test_condvar() {
+    use crate::mutex::Mutex;
+    use crate::parker::parker;
+    use std::thread;
+
     let mutex = Mutex::new(0);
     let condvar = Condvar::new();
+    let (parker, unparker) = parker();
 
     let mut wakeups = 0;
 
     thread::scope(|s| {
         s.spawn(|| {
-            thread::sleep(Duration::from_secs(1));
+            // ロックを取得できるのはメインスレッドがwait()でアンロックした後
+            let parker = parker;
+            parker.park();
             *mutex.lock() = 123;
             condvar.notify_one();
         });
 
         let mut m = mutex.lock();
+        unparker.unpark();
         while *m < 100 {
             m = condvar.wait(m);
             wakeups += 1;
@@ -60,3 +258,172 @@ fn test_condvar() {
 
     assert!(wakeups < 10);
 }
+
+#[test]
+fn test_wait_while() {
+    use crate::mutex::Mutex;
+    use std::thread;
+
+    let mutex = Mutex::new(0);
+    let condvar = Condvar::new();
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            for _ in 0..10 {
+                *mutex.lock() += 1;
+                condvar.notify_one();
+            }
+        });
+
+        // 途中の値で起こされても、条件を満たすまで戻らない
+        let m = condvar.wait_while(mutex.lock(), |v| *v < 10);
+        assert_eq!(*m, 10);
+    });
+}
+
+#[test]
+fn test_wait_timeout() {
+    use crate::mutex::Mutex;
+    use std::thread;
+
+    let mutex = Mutex::new(0);
+    let condvar = Condvar::new();
+
+    // 誰も通知しなければタイムアウトする
+    let start = Instant::now();
+    let (m, result) = condvar.wait_timeout(mutex.lock(), Duration::from_millis(20));
+    assert!(result.timed_out());
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    drop(m);
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(10));
+            *mutex.lock() = 123;
+            condvar.notify_one();
+        });
+
+        let mut m = mutex.lock();
+        while *m < 100 {
+            let (guard, result) = condvar.wait_timeout(m, Duration::from_secs(10));
+            assert!(!result.timed_out());
+            m = guard;
+        }
+        assert_eq!(*m, 123);
+    });
+}
+
+#[test]
+fn test_wait_timeout_while() {
+    use crate::mutex::Mutex;
+    use std::thread;
+
+    let mutex = Mutex::new(0);
+    let condvar = Condvar::new();
+
+    // 通知されても条件を満たさなければ、期限まで待ってタイムアウトする
+    thread::scope(|s| {
+        s.spawn(|| {
+            for _ in 0..5 {
+                thread::sleep(Duration::from_millis(5));
+                *mutex.lock() += 1;
+                condvar.notify_one();
+            }
+        });
+        let start = Instant::now();
+        let (m, result) =
+            condvar.wait_timeout_while(mutex.lock(), Duration::from_millis(100), |v| *v < 100);
+        assert!(result.timed_out());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(*m < 100);
+    });
+
+    *mutex.lock() = 0;
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(10));
+            *mutex.lock() = 123;
+            condvar.notify_one();
+        });
+        let (m, result) =
+            condvar.wait_timeout_while(mutex.lock(), Duration::from_secs(10), |v| *v < 100);
+        assert!(!result.timed_out());
+        assert_eq!(*m, 123);
+    });
+
+    // 期限を過ぎていれば待たずに戻る
+    let (_m, result) = condvar.wait_until(mutex.lock(), Instant::now());
+    assert!(result.timed_out());
+}
+
+#[test]
+fn test_mutex_variants() {
+    use crate::mutex_spin::NoSpin;
+    use std::thread;
+
+    // どのMutexのガードでも同じように待機できる
+    fn check<'a, G: CondvarGuard<'a, Target = u32>>(mutex: &'a G::Mutex)
+    where
+        G::Mutex: Sync,
+    {
+        let condvar = Condvar::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..10 {
+                    *G::lock(mutex) += 1;
+                    condvar.notify_one();
+                }
+            });
+            let g = condvar.wait_while(G::lock(mutex), |v| *v < 10);
+            assert_eq!(*g, 10);
+        });
+    }
+
+    check::<mutex::MutexGuard<u32>>(&mutex::Mutex::new(0));
+    check::<mutex_opt::MutexGuard<u32>>(&mutex_opt::Mutex::new(0));
+    check::<mutex_spin::MutexGuard<u32>>(&mutex_spin::Mutex::new(0));
+    check::<mutex_spin::MutexGuard<u32, NoSpin>>(&mutex_spin::Mutex::with_policy(0));
+}
+
+#[test]
+fn test_notify_all_requeue() {
+    use std::thread;
+
+    fn check<'a, G: CondvarGuard<'a, Target = (bool, u32)>>(mutex: &'a G::Mutex)
+    where
+        G::Mutex: Sync,
+    {
+        let condvar = Condvar::new();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut m = condvar.wait_while(G::lock(mutex), |(ready, _)| !*ready);
+                    m.1 += 1;
+                });
+            }
+            // すべてのスレッドがwait()するのを待つ
+            thread::sleep(Duration::from_millis(50));
+            G::lock(mutex).0 = true;
+            // 1つだけ起こして残りはMutexに移すが、アンロックで順番に起こされるのですべて終わる
+            condvar.notify_all();
+        });
+        assert_eq!(*G::lock(mutex), (true, 4));
+    }
+
+    check::<mutex::MutexGuard<_>>(&mutex::Mutex::new((false, 0)));
+    check::<mutex_opt::MutexGuard<_>>(&mutex_opt::Mutex::new((false, 0)));
+    check::<mutex_spin::MutexGuard<_>>(&mutex_spin::Mutex::new((false, 0)));
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "Condvar is used with more than one Mutex")]
+fn test_multiple_mutexes() {
+    use crate::mutex::Mutex;
+
+    let condvar = Condvar::new();
+    let a = Mutex::new(());
+    let b = Mutex::new(());
+    let _ = condvar.wait_timeout(a.lock(), Duration::ZERO);
+    let _ = condvar.wait_timeout(b.lock(), Duration::ZERO);
+}
@@ -0,0 +1,139 @@
+use crate::condvar::CondvarGuard;
+use atomic_wait::{wait, wake_all};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+// 待機し始めた順に起こすCondvar
+// condvar.rsはfutexに起こすスレッドを任せるので、notify_one()で起こされる順番は決まっていない
+// ここではwait()で番号札を配り、notify_one()は一番小さい番号のスレッドだけを通す
+// futexでは特定のスレッドだけを起こせないので、notify_one()でもすべて起こして番号を確認させる
+pub struct Condvar {
+    // 次にwait()したスレッドに渡す番号
+    // Mutexをロックしたまま取るので、番号の順番はMutexを手放した順番になる
+    next_ticket: AtomicU32,
+    // この値より小さい番号のスレッドは起きてよい
+    served: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            next_ticket: AtomicU32::new(0),
+            served: AtomicU32::new(0),
+        }
+    }
+
+    // 待っているスレッドのうち、一番長く待っているスレッドを起こす
+    pub fn notify_one(&self) {
+        let mut s = self.served.load(Relaxed);
+        // 待っているスレッドがいなければ何もしない
+        while s != self.next_ticket.load(Relaxed) {
+            match self
+                .served
+                .compare_exchange_weak(s, s.wrapping_add(1), Release, Relaxed)
+            {
+                Ok(_) => {
+                    wake_all(&self.served);
+                    return;
+                }
+                Err(e) => s = e,
+            }
+        }
+    }
+
+    pub fn notify_all(&self) {
+        let t = self.next_ticket.load(Relaxed);
+        if self.served.swap(t, Release) != t {
+            wake_all(&self.served);
+        }
+    }
+
+    // condvar.rsと違い、notifyされるまで戻らない
+    pub fn wait<'a, G: CondvarGuard<'a>>(&self, guard: G) -> G {
+        let ticket = self.next_ticket.fetch_add(1, Relaxed);
+        let mutex = guard.mutex();
+        drop(guard);
+
+        loop {
+            let s = self.served.load(Acquire);
+            // 一周しても正しく比べられるように差の符号で判定する
+            if s.wrapping_sub(ticket) as i32 > 0 {
+                break;
+            }
+            wait(&self.served, s);
+        }
+        G::lock(mutex)
+    }
+
+    pub fn wait_while<'a, G: CondvarGuard<'a>>(
+        &self,
+        mut guard: G,
+        mut condition: impl FnMut(&mut G::Target) -> bool,
+    ) -> G {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+}
+
+#[test]
+fn test_fifo_order() {
+    use crate::mutex::Mutex;
+    use std::thread;
+
+    let mutex = Mutex::new(Vec::new());
+    let condvar = Condvar::new();
+
+    thread::scope(|s| {
+        for i in 0..8 {
+            let (mutex, condvar) = (&mutex, &condvar);
+            s.spawn(move || {
+                let mut woken = condvar.wait(mutex.lock());
+                woken.push(i);
+            });
+            // 前のスレッドが番号札を取ってから次のスレッドを起動する
+            while condvar.next_ticket.load(Relaxed) != i + 1 {
+                thread::yield_now();
+            }
+        }
+        for n in 1..=8 {
+            condvar.notify_one();
+            // 起こしたスレッドが記録するまで次を起こさない
+            while mutex.lock().len() != n {
+                thread::yield_now();
+            }
+        }
+    });
+
+    // 後から待ったスレッドに追い越されない
+    assert_eq!(mutex.into_inner(), (0..8).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_condvar_fair() {
+    use crate::mutex::Mutex;
+    use std::thread;
+
+    // notify_one()で1つずつしか起こさなくても、すべての待機スレッドが取りこぼされずに進む
+    let mutex = Mutex::new(0);
+    let condvar = Condvar::new();
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..500 {
+                    let mut m = condvar.wait_while(mutex.lock(), |n| *n == 0);
+                    *m -= 1;
+                }
+            });
+        }
+        s.spawn(|| {
+            for _ in 0..2000 {
+                *mutex.lock() += 1;
+                condvar.notify_one();
+            }
+        });
+    });
+    assert_eq!(mutex.into_inner(), 0);
+}
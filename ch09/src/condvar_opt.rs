@@ -1,13 +1,13 @@
-use crate::mutex::{Mutex, MutexGuard};
+use crate::mutex::MutexGuard;
 use atomic_wait::{wait, wake_all, wake_one};
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::atomic::{AtomicU32, AtomicUsize};
-use std::thread;
-use std::time::Duration;
 
 pub struct Condvar {
     counter: AtomicU32,
     num_waiters: AtomicUsize,
+    #[cfg(feature = "stats")]
+    stats: crate::stats::CondvarWaitStats,
 }
 
 impl Condvar {
@@ -15,24 +15,42 @@ impl Condvar {
         Self {
             counter: AtomicU32::new(0),
             num_waiters: AtomicUsize::new(0),
+            #[cfg(feature = "stats")]
+            stats: crate::stats::CondvarWaitStats::new(),
         }
     }
 
     // 待機スレッドがいなければwakeは不要
     pub fn notify_one(&self) {
+        #[cfg(feature = "stats")]
+        self.stats.record_notify();
         if self.num_waiters.load(Relaxed) > 0 {
             self.counter.fetch_add(1, Relaxed);
             wake_one(&self.counter);
         }
     }
     pub fn notify_all(&self) {
+        #[cfg(feature = "stats")]
+        self.stats.record_notify();
         if self.num_waiters.load(Relaxed) > 0 {
             self.counter.fetch_add(1, Relaxed);
             wake_all(&self.counter);
         }
     }
 
-    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    // 通知されずに戻った場合は、statsでspurious_wakeupsとして数える
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let (guard, _notified) = self.wait_notified(guard);
+        #[cfg(feature = "stats")]
+        if !_notified {
+            self.stats.record_spurious_wakeup();
+        }
+        guard
+    }
+
+    // wait()と同じだが、戻るまでの間に通知されたかも返す
+    // counterが変わっていなければ、futexが通知なしに戻った
+    fn wait_notified<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> (MutexGuard<'a, T>, bool) {
         // waiterのインクリメント
         self.num_waiters.fetch_add(1, Relaxed);
 
@@ -41,30 +59,69 @@ impl Condvar {
         let mutex = guard.mutex;
         drop(guard);
 
+        #[cfg(feature = "stats")]
+        let start = std::time::Instant::now();
         wait(&self.counter, counter_value);
+        #[cfg(feature = "stats")]
+        self.stats.record_wait(start);
+        let notified = self.counter.load(Relaxed) != counter_value;
 
         // waiterのデクリメント
         self.num_waiters.fetch_sub(1, Relaxed);
 
-        mutex.lock()
+        (mutex.lock(), notified)
+    }
+
+    // conditionがtrueを返す間は待機し続ける
+    // 起こされた後にもう一度待機した回数は、通知されたかどうかにかかわらず
+    // statsでspurious_wakeupsとして数える(1回の起床を2回数えないようにwait()は通さない)
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        if !condition(&mut *guard) {
+            return guard;
+        }
+        guard = self.wait_notified(guard).0;
+        while condition(&mut *guard) {
+            #[cfg(feature = "stats")]
+            self.stats.record_spurious_wakeup();
+            guard = self.wait_notified(guard).0;
+        }
+        guard
+    }
+
+    // このCondvarを作ってからの待機と通知の回数
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::CondvarStats {
+        self.stats.get()
     }
 }
 
 #[test]
 fn test_condvar() {
+    use crate::mutex::Mutex;
+    use crate::parker::parker;
+    use std::thread;
+
     let mutex = Mutex::new(0);
     let condvar = Condvar::new();
+    let (parker, unparker) = parker();
 
     let mut wakeups = 0;
 
     thread::scope(|s| {
         s.spawn(|| {
-            thread::sleep(Duration::from_secs(1));
+            // ロックを取得できるのはメインスレッドがwait()でアンロックした後
+            let parker = parker;
+            parker.park();
             *mutex.lock() = 123;
             condvar.notify_one();
         });
 
         let mut m = mutex.lock();
+        unparker.unpark();
         while *m < 100 {
             m = condvar.wait(m);
             wakeups += 1;
@@ -74,3 +131,49 @@ fn test_condvar() {
 
     assert!(wakeups < 10);
 }
+
+#[cfg(feature = "stats")]
+#[test]
+fn test_condvar_stats() {
+    use crate::mutex::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    let mutex = Mutex::new(0);
+    let condvar = Condvar::new();
+
+    // 待機スレッドがいなくても通知の回数は数える
+    condvar.notify_one();
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            for _ in 0..3 {
+                thread::sleep(Duration::from_millis(20));
+                *mutex.lock() += 1;
+                condvar.notify_all();
+            }
+        });
+        let m = condvar.wait_while(mutex.lock(), |v| *v < 3);
+        assert_eq!(*m, 3);
+    });
+
+    let stats = condvar.stats();
+    assert_eq!(stats.notifications, 4);
+    // 3回目の通知まで条件を満たさないので、最初の待機以外はすべてもう一度待機したもの
+    assert!(stats.waits >= 2);
+    assert_eq!(stats.spurious_wakeups, stats.waits - 1);
+    assert!(stats.wait_time >= Duration::from_millis(40));
+
+    // 通知されて戻ったwait()は数えない
+    let condvar = Condvar::new();
+    thread::scope(|s| {
+        let m = mutex.lock();
+        s.spawn(|| {
+            drop(mutex.lock());
+            condvar.notify_one();
+        });
+        drop(condvar.wait(m));
+    });
+    assert_eq!(condvar.stats().waits, 1);
+    assert_eq!(condvar.stats().spurious_wakeups, 0);
+}
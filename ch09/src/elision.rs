@@ -0,0 +1,165 @@
+// Intel TSX(RTM)によるロックの省略(lock elision)
+// ロックを取得せずにクリティカルセクションをトランザクションとして投機的に実行し、
+// 他のスレッドと競合しなければそのままコミットする
+// stateを読んでおけば、他のスレッドが実際にロックを取得したときにトランザクションがアボートする
+// アボートした場合は最初からやり直して通常のfutexによるロックを使う
+//
+// x86以外やRTMが使えないCPUでは常に通常のロックを使う
+use std::cell::Cell;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicU32, AtomicU64};
+
+static COMMITS: AtomicU64 = AtomicU64::new(0);
+static ABORTS: AtomicU64 = AtomicU64::new(0);
+
+// アボートした場合に再試行する回数
+const RETRIES: u32 = 3;
+
+thread_local! {
+    // ロックを省略しているMutexのstateのアドレス。省略していなければ0
+    // トランザクション中に書き込むので、アボートすると0に戻る
+    static ELIDED: Cell<usize> = const { Cell::new(0) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElisionStats {
+    // ロックを取得せずにコミットできた回数
+    pub commits: u64,
+    // アボートして通常のロックにフォールバックした回数(再試行も含む)
+    pub aborts: u64,
+}
+
+pub fn stats() -> ElisionStats {
+    ElisionStats {
+        commits: COMMITS.load(Relaxed),
+        aborts: ABORTS.load(Relaxed),
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod rtm {
+    use std::arch::asm;
+
+    pub const XBEGIN_STARTED: u32 = !0;
+    // 再試行すれば成功するかもしれないアボート
+    pub const XABORT_RETRY: u32 = 1 << 1;
+
+    pub fn supported() -> bool {
+        std::is_x86_feature_detected!("rtm")
+    }
+
+    // トランザクションを開始するとXBEGIN_STARTEDを返す
+    // アボートするとxbeginの直後に戻ってきて、eaxにアボートの理由が入る
+    #[inline(always)]
+    pub unsafe fn xbegin() -> u32 {
+        let mut status = XBEGIN_STARTED;
+        asm!("xbegin 2f", "2:", inout("eax") status, options(nostack));
+        status
+    }
+
+    #[inline(always)]
+    pub unsafe fn xend() {
+        asm!("xend", options(nostack));
+    }
+
+    #[inline(always)]
+    pub unsafe fn xabort() {
+        asm!("xabort 0xff", options(nostack));
+    }
+}
+
+// ロックを省略できた場合はtrueを返す。このときトランザクションを実行中になっている
+// falseの場合は呼び出し側で通常通りロックする
+// 省略できるのはスレッドごとに1つだけで、省略中にロックした他のMutexは通常通りロックする
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[inline(always)]
+pub fn try_elide(state: &AtomicU32) -> bool {
+    if !rtm::supported() || ELIDED.get() != 0 {
+        return false;
+    }
+    for _ in 0..RETRIES {
+        let status = unsafe { rtm::xbegin() };
+        if status == rtm::XBEGIN_STARTED {
+            // ロックされていなければそのままクリティカルセクションを実行する
+            if state.load(Relaxed) == 0 {
+                ELIDED.set(state as *const AtomicU32 as usize);
+                return true;
+            }
+            // すでにロックされているので省略できない。xbeginの直後に戻る
+            unsafe { rtm::xabort() };
+        }
+        // トランザクション中のカウンタの更新は他のスレッドとの競合になるので、アボート後に数える
+        ABORTS.fetch_add(1, Relaxed);
+        if status & rtm::XABORT_RETRY == 0 {
+            break;
+        }
+    }
+    false
+}
+
+// stateのロックを省略していればトランザクションをコミットしてtrueを返す
+// 省略していなければfalseを返すので、呼び出し側で通常通りアンロックする
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[inline(always)]
+pub fn end(state: &AtomicU32) -> bool {
+    if ELIDED.get() != state as *const AtomicU32 as usize {
+        return false;
+    }
+    ELIDED.set(0);
+    unsafe { rtm::xend() };
+    COMMITS.fetch_add(1, Relaxed);
+    true
+}
+
+// stateのロックをこのスレッドが省略していればトランザクションをアボートする
+// 省略している間stateは0のままなので、同じスレッドがstateを読み書きするとロックされていないように見えてしまう
+// アボートするとtry_elide()のxbeginに戻って通常のロックでやり直すので、その後はstateが1になっている
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[inline(always)]
+pub fn abort_if_elided(state: &AtomicU32) {
+    if ELIDED.get() == state as *const AtomicU32 as usize {
+        unsafe { rtm::xabort() };
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+pub fn try_elide(_: &AtomicU32) -> bool {
+    false
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+pub fn end(_: &AtomicU32) -> bool {
+    false
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+pub fn abort_if_elided(_: &AtomicU32) {}
+
+#[test]
+fn test_elision() {
+    use crate::mutex::Mutex;
+    use std::thread;
+
+    let before = stats();
+    let mutex = Mutex::new(0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    *mutex.lock() += 1;
+                }
+            });
+        }
+    });
+    // 省略できたかどうかにかかわらず結果は同じ
+    assert_eq!(mutex.into_inner(), 4000);
+
+    let after = stats();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let supported = rtm::supported();
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let supported = false;
+    if !supported {
+        assert_eq!(before, after);
+    }
+}
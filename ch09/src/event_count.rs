@@ -0,0 +1,125 @@
+use atomic_wait::{wait, wake_all, wake_one};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{fence, AtomicU32};
+
+// ロックフリーなデータ構造に、空なら待機する機能を後から付けるためのもの
+// Mutexを持たないので、データ構造の操作はロックフリーのまま待機できる
+//
+// 待機する側:
+//   1. 取り出しを試す
+//   2. 失敗したらprepare_wait()
+//   3. もう一度取り出しを試し、成功したらcancel_wait()
+//   4. 失敗したらcommit_wait()で待機して1に戻る
+// 通知する側:
+//   データを追加してからnotify_one()またはnotify_all()
+pub struct EventCount {
+    // 通知するたびにインクリメントする
+    // prepare_wait()の後で変わっていれば、待機せずに戻る
+    epoch: AtomicU32,
+    // prepare_wait()してからcommit_wait()またはcancel_wait()するまでのスレッドの数
+    // 0なら通知する側はepochを書き換えずにwakeも呼ばない
+    waiters: AtomicU32,
+}
+
+// prepare_wait()した時点のepoch
+#[must_use = "call commit_wait() or cancel_wait() with this key"]
+pub struct Key(u32);
+
+impl EventCount {
+    pub const fn new() -> Self {
+        Self {
+            epoch: AtomicU32::new(0),
+            waiters: AtomicU32::new(0),
+        }
+    }
+
+    pub fn prepare_wait(&self) -> Key {
+        self.waiters.fetch_add(1, Relaxed);
+        // この後で呼び出し側がデータ構造を読む前に、waitersのインクリメントを通知する側に見せる
+        // 通知する側のfenceと合わせて、どちらかが必ず相手の書き込みを見る
+        fence(SeqCst);
+        Key(self.epoch.load(Acquire))
+    }
+
+    // prepare_wait()の後でデータを取り出せた場合に呼ぶ
+    pub fn cancel_wait(&self, key: Key) {
+        let _ = key;
+        self.waiters.fetch_sub(1, Relaxed);
+    }
+
+    // prepare_wait()の後に通知されていなければ、通知されるまで待機する
+    pub fn commit_wait(&self, key: Key) {
+        while self.epoch.load(Acquire) == key.0 {
+            wait(&self.epoch, key.0);
+        }
+        self.waiters.fetch_sub(1, Relaxed);
+    }
+
+    pub fn notify_one(&self) {
+        if self.has_waiters() {
+            self.epoch.fetch_add(1, Release);
+            wake_one(&self.epoch);
+        }
+    }
+
+    pub fn notify_all(&self) {
+        if self.has_waiters() {
+            self.epoch.fetch_add(1, Release);
+            wake_all(&self.epoch);
+        }
+    }
+
+    fn has_waiters(&self) -> bool {
+        // 呼び出し側がデータ構造に書き込んだ後でwaitersを読む
+        // 0に見えれば、prepare_wait()したスレッドはその書き込みを見てから待機するかを決める
+        fence(SeqCst);
+        self.waiters.load(Relaxed) > 0
+    }
+}
+
+#[test]
+fn test_event_count() {
+    use std::sync::atomic::AtomicU64;
+    use std::thread;
+
+    // ロックフリーなカウンタから1つずつ取り出す。空なら待機する
+    let items = AtomicU64::new(0);
+    let ec = EventCount::new();
+    let try_take = || {
+        items
+            .fetch_update(Acquire, Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+    };
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    loop {
+                        if try_take() {
+                            break;
+                        }
+                        let key = ec.prepare_wait();
+                        if try_take() {
+                            ec.cancel_wait(key);
+                            break;
+                        }
+                        ec.commit_wait(key);
+                    }
+                }
+            });
+        }
+        s.spawn(|| {
+            for i in 0..4000 {
+                items.fetch_add(1, Release);
+                if i % 2 == 0 {
+                    ec.notify_one();
+                } else {
+                    ec.notify_all();
+                }
+            }
+        });
+    });
+    assert_eq!(items.into_inner(), 0);
+    assert_eq!(ec.waiters.into_inner(), 0);
+}
@@ -0,0 +1,314 @@
+use std::sync::atomic::{AtomicU32, AtomicU64};
+use std::time::Duration;
+
+// atomic_waitにはタイムアウト付きのwaitがないのでLinuxではfutexシステムコールを直接使う
+// wait()と同様に誤って起こされる場合があるので、呼び出し側は状態と経過時間を確認してループする
+#[cfg(target_os = "linux")]
+pub fn wait_timeout(a: &AtomicU32, expected: u32, timeout: Duration) {
+    // 相対時間で指定する。tv_secに収まらないほど長い場合は無期限に待機する
+    let timespec = libc::timespec {
+        tv_sec: timeout.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+        tv_nsec: timeout.subsec_nanos() as _,
+    };
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            &timespec as *const libc::timespec,
+        );
+    }
+}
+
+// WindowsではWaitOnAddressにタイムアウトを指定できる
+// atomic_waitもWaitOnAddressを使うので、atomic_wait::wake_*で起こされる
+#[cfg(windows)]
+pub fn wait_timeout(a: &AtomicU32, expected: u32, timeout: Duration) {
+    // INFINITE(u32::MAX)を超えないようにする
+    let ms = timeout.as_millis().try_into().unwrap_or(u32::MAX - 1);
+    unsafe {
+        wait_on_address::WaitOnAddress(
+            a as *const AtomicU32 as *const _,
+            &expected as *const u32 as *const _,
+            4,
+            ms,
+        );
+    }
+}
+
+// それ以外のプラットフォームではatomic_waitにタイムアウトがないので、parking_lotの待ち行列で待機する
+// atomic_wait::wake_*では起こされないので、wait_timeoutと組み合わせる場合は下のwake_one/wake_allで起こす
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn wait_timeout(a: &AtomicU32, expected: u32, timeout: Duration) {
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::time::Instant;
+
+    crate::parking_lot::park(
+        a as *const AtomicU32 as usize,
+        || a.load(Relaxed) == expected,
+        Instant::now().checked_add(timeout),
+    );
+}
+
+// wait_timeoutで待機しているスレッドも起こす
+// atomic_wait::waitで待機しているスレッドとあわせて2つ起こす場合があるが、誤って起こされる場合と同じく扱える
+pub fn wake_one(a: *const AtomicU32) {
+    atomic_wait::wake_one(a);
+    #[cfg(not(any(target_os = "linux", windows)))]
+    crate::parking_lot::unpark_one(a as usize);
+}
+
+pub fn wake_all(a: *const AtomicU32) {
+    atomic_wait::wake_all(a);
+    #[cfg(not(any(target_os = "linux", windows)))]
+    crate::parking_lot::unpark_all(a as usize);
+}
+
+// fromで待機しているスレッドを1つだけ起こし、残りをtoで待機させる
+// fromの値がexpectedでなければ移さずにすべて起こす
+// toは移したスレッドがいる場合だけ使うので、すでに解放されたアドレスでもよい
+#[cfg(target_os = "linux")]
+pub fn requeue(from: &AtomicU32, expected: u32, to: *const AtomicU32) {
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            from as *const AtomicU32,
+            libc::FUTEX_CMP_REQUEUE | libc::FUTEX_PRIVATE_FLAG,
+            1,
+            // 4番目の引数はタイムアウトの代わりに移すスレッドの数として使われる
+            i32::MAX as usize,
+            to,
+            expected,
+        )
+    };
+    if r == -1 {
+        atomic_wait::wake_all(from);
+    }
+}
+
+// それ以外のプラットフォームでは移せないので、すべて起こして代わりにする
+#[cfg(not(target_os = "linux"))]
+pub fn requeue(from: &AtomicU32, _expected: u32, _to: *const AtomicU32) {
+    wake_all(from);
+}
+
+// AtomicU64に対するwait/wake
+// u32と同様に誤って起こされる場合があるので呼び出し側でループする
+pub fn wait64(a: &AtomicU64, expected: u64) {
+    wait64_impl(a, expected, None);
+}
+
+pub fn wait64_timeout(a: &AtomicU64, expected: u64, timeout: Duration) {
+    wait64_impl(a, expected, Some(timeout));
+}
+
+pub fn wake_one64(a: *const AtomicU64) {
+    wake64_impl(a, false);
+}
+
+pub fn wake_all64(a: *const AtomicU64) {
+    wake64_impl(a, true);
+}
+
+// Linuxではfutex2(futex_wait/futex_wake)の64ビットサイズを使う
+// カーネルが対応していなければparking_lotにフォールバックする
+#[cfg(target_os = "linux")]
+mod futex2 {
+    use std::sync::atomic::AtomicU64;
+    use std::sync::atomic::AtomicU8;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::time::Duration;
+
+    const SYS_FUTEX_WAKE: libc::c_long = 454;
+    const SYS_FUTEX_WAIT: libc::c_long = 455;
+    const FUTEX2_SIZE_U64: libc::c_uint = 0x03;
+    const FUTEX2_PRIVATE: libc::c_uint = libc::FUTEX_PRIVATE_FLAG as libc::c_uint;
+    const FUTEX_BITSET_MATCH_ANY: libc::c_ulong = !0;
+
+    // 0: 未確認, 1: 対応, 2: 非対応
+    static SUPPORTED: AtomicU8 = AtomicU8::new(0);
+
+    // waitとwakeで別の仕組みを使うとwakeが失われるので、
+    // 最初に一度だけ確認して以降は同じ結果を使う
+    pub fn supported() -> bool {
+        match SUPPORTED.load(Relaxed) {
+            1 => true,
+            2 => false,
+            _ => {
+                // 値が一致しないので対応していればEAGAINですぐに戻る
+                let probe = AtomicU64::new(0);
+                let r = unsafe { wait(&probe, 1, None) };
+                let ok =
+                    r == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EAGAIN);
+                SUPPORTED.store(if ok { 1 } else { 2 }, Relaxed);
+                ok
+            }
+        }
+    }
+
+    pub unsafe fn wait(a: &AtomicU64, expected: u64, timeout: Option<Duration>) -> libc::c_long {
+        // futex2のタイムアウトはCLOCK_MONOTONICでの絶対時刻
+        let deadline = timeout.map(|timeout| {
+            let mut now = libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            };
+            libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now);
+            let nanos = now.tv_nsec as u64 + timeout.subsec_nanos() as u64;
+            libc::timespec {
+                tv_sec: now
+                    .tv_sec
+                    .saturating_add(timeout.as_secs().try_into().unwrap_or(libc::time_t::MAX))
+                    .saturating_add((nanos / 1_000_000_000) as libc::time_t),
+                tv_nsec: (nanos % 1_000_000_000) as _,
+            }
+        });
+        libc::syscall(
+            SYS_FUTEX_WAIT,
+            a as *const AtomicU64,
+            expected as libc::c_ulong,
+            FUTEX_BITSET_MATCH_ANY,
+            FUTEX2_SIZE_U64 | FUTEX2_PRIVATE,
+            deadline
+                .as_ref()
+                .map_or(std::ptr::null(), |t| t as *const libc::timespec),
+            libc::CLOCK_MONOTONIC,
+        )
+    }
+
+    pub unsafe fn wake(a: *const AtomicU64, all: bool) {
+        libc::syscall(
+            SYS_FUTEX_WAKE,
+            a,
+            FUTEX_BITSET_MATCH_ANY,
+            if all { i32::MAX } else { 1 },
+            FUTEX2_SIZE_U64 | FUTEX2_PRIVATE,
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn wait64_impl(a: &AtomicU64, expected: u64, timeout: Option<Duration>) {
+    if futex2::supported() {
+        unsafe { futex2::wait(a, expected, timeout) };
+    } else {
+        fallback::wait(a, expected, timeout);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn wake64_impl(a: *const AtomicU64, all: bool) {
+    if futex2::supported() {
+        unsafe { futex2::wake(a, all) };
+    } else {
+        fallback::wake(a, all);
+    }
+}
+
+// WindowsのWaitOnAddressは8バイトの値をそのまま待機できる
+#[cfg(windows)]
+mod wait_on_address {
+    use std::ffi::c_void;
+
+    #[link(name = "synchronization")]
+    extern "system" {
+        pub fn WaitOnAddress(
+            address: *const c_void,
+            compare_address: *const c_void,
+            address_size: usize,
+            milliseconds: u32,
+        ) -> i32;
+        pub fn WakeByAddressSingle(address: *const c_void);
+        pub fn WakeByAddressAll(address: *const c_void);
+    }
+}
+
+#[cfg(windows)]
+fn wait64_impl(a: &AtomicU64, expected: u64, timeout: Option<Duration>) {
+    // INFINITE(u32::MAX)を超えないようにする
+    let ms = timeout.map_or(u32::MAX, |t| {
+        t.as_millis().try_into().unwrap_or(u32::MAX - 1)
+    });
+    unsafe {
+        wait_on_address::WaitOnAddress(
+            a as *const AtomicU64 as *const _,
+            &expected as *const u64 as *const _,
+            8,
+            ms,
+        );
+    }
+}
+
+#[cfg(windows)]
+fn wake64_impl(a: *const AtomicU64, all: bool) {
+    unsafe {
+        if all {
+            wait_on_address::WakeByAddressAll(a as *const _);
+        } else {
+            wait_on_address::WakeByAddressSingle(a as *const _);
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn wait64_impl(a: &AtomicU64, expected: u64, timeout: Option<Duration>) {
+    fallback::wait(a, expected, timeout);
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn wake64_impl(a: *const AtomicU64, all: bool) {
+    fallback::wake(a, all);
+}
+
+// 64ビットを直接待機できない場合はparking_lotの待ち行列で待機する
+#[cfg(not(windows))]
+mod fallback {
+    use crate::parking_lot::{park, unpark_all, unpark_one};
+    use std::sync::atomic::AtomicU64;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::time::{Duration, Instant};
+
+    pub fn wait(a: &AtomicU64, expected: u64, timeout: Option<Duration>) {
+        // 値の確認は待ち行列のロックを保持したまま行われる
+        park(
+            a as *const AtomicU64 as usize,
+            || a.load(Relaxed) == expected,
+            timeout.and_then(|t| Instant::now().checked_add(t)),
+        );
+    }
+
+    pub fn wake(a: *const AtomicU64, all: bool) {
+        if all {
+            unpark_all(a as usize);
+        } else {
+            unpark_one(a as usize);
+        }
+    }
+}
+
+#[test]
+fn test_wait64() {
+    use std::sync::atomic::Ordering::{Acquire, Release};
+    use std::thread;
+    use std::time::Instant;
+
+    // 上位32ビットだけが変わっても起こされる
+    let a = AtomicU64::new(1 << 40);
+
+    let start = Instant::now();
+    wait64_timeout(&a, 1 << 40, Duration::from_millis(50));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(100));
+            a.store(2 << 40, Release);
+            wake_all64(&a);
+        });
+        while a.load(Acquire) == 1 << 40 {
+            wait64(&a, 1 << 40);
+        }
+    });
+    assert_eq!(a.load(Acquire), 2 << 40);
+}
@@ -0,0 +1,114 @@
+use crate::futex::{wait_timeout, wake_all};
+use atomic_wait::wait;
+use std::fmt;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Release};
+use std::time::Instant;
+
+pub struct CountDownLatch {
+    // 0になるまでの残りカウント
+    count: AtomicU32,
+}
+
+// タイムアウトした時点で残っていたカウントを持つ
+#[derive(Debug, PartialEq, Eq)]
+pub struct TimedOut {
+    pub remaining: u32,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "timed out waiting for latch ({} count(s) remaining)",
+            self.remaining
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+impl CountDownLatch {
+    pub const fn new(count: u32) -> Self {
+        Self {
+            count: AtomicU32::new(count),
+        }
+    }
+
+    pub fn count_down(&self) {
+        let mut c = self.count.load(Acquire);
+        // すでに0の場合は何もしない
+        while c != 0 {
+            match self.count.compare_exchange_weak(c, c - 1, Release, Acquire) {
+                Ok(_) => {
+                    if c == 1 {
+                        wake_all(&self.count);
+                    }
+                    return;
+                }
+                Err(e) => c = e,
+            }
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count.load(Acquire)
+    }
+
+    // ブロックせずに0になっているかを確認する
+    pub fn try_wait(&self) -> bool {
+        self.count.load(Acquire) == 0
+    }
+
+    pub fn wait(&self) {
+        loop {
+            let c = self.count.load(Acquire);
+            if c == 0 {
+                return;
+            }
+            wait(&self.count, c);
+        }
+    }
+
+    pub fn wait_deadline(&self, deadline: Instant) -> Result<(), TimedOut> {
+        loop {
+            let c = self.count.load(Acquire);
+            if c == 0 {
+                return Ok(());
+            }
+            match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => wait_timeout(&self.count, c, remaining),
+                _ => return Err(TimedOut { remaining: c }),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_latch() {
+    use std::thread;
+    use std::time::Duration;
+
+    let latch = CountDownLatch::new(2);
+
+    thread::scope(|s| {
+        s.spawn(|| latch.count_down());
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(100));
+            latch.count_down();
+        });
+        latch.wait();
+    });
+    assert!(latch.try_wait());
+
+    // 報告しないコンポーネントがあればタイムアウトする
+    let latch = CountDownLatch::new(2);
+    latch.count_down();
+    assert!(!latch.try_wait());
+    let e = latch
+        .wait_deadline(Instant::now() + Duration::from_millis(50))
+        .unwrap_err();
+    assert_eq!(e, TimedOut { remaining: 1 });
+    latch.count_down();
+    assert_eq!(latch.wait_deadline(Instant::now()), Ok(()));
+}
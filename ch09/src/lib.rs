@@ -0,0 +1,47 @@
+#![allow(dead_code)]
+
+// ベンチマーク(src/bin)から使うモジュールだけをpubにする
+
+mod async_mutex;
+mod async_rwlock;
+mod barrier;
+mod clh_lock;
+mod condvar;
+mod condvar_fair;
+mod condvar_opt;
+#[cfg(feature = "elision")]
+mod elision;
+mod event_count;
+mod futex;
+mod latch;
+#[cfg(feature = "lockdep")]
+mod lockdep;
+mod monitor;
+pub mod mutex;
+mod mutex_byte;
+pub mod mutex_opt;
+mod mutex_pi;
+#[cfg(feature = "poison")]
+mod mutex_poison;
+pub mod mutex_spin;
+mod once;
+mod parker;
+mod parking_lot;
+mod raw_mutex;
+pub mod raw_rwlock;
+mod reentrant_mutex;
+mod rwlock;
+mod rwlock_avoid_writer_starvation;
+mod rwlock_big_reader;
+pub mod rwlock_fairness;
+mod rwlock_no_busyloop;
+pub mod rwlock_phase_fair;
+mod rwlock_priority;
+mod semaphore;
+mod seqlock;
+mod shard;
+mod sharded_lock;
+#[cfg(feature = "stats")]
+mod stats;
+mod thread_id;
+mod waitgroup;
@@ -0,0 +1,166 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::panic::Location;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+// ロックの取得順序を記録して、順序が循環したらデッドロックの可能性として報告する
+// 実際にデッドロックしなくても、テストの中で一度でも逆の順序で取得すれば検出できる
+
+// ロックごとの識別子。アドレスは解放後に再利用されるので使わない
+// constで作れるように最初に使われたときに割り当てる
+pub struct LockClass {
+    id: AtomicUsize,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+impl LockClass {
+    pub const fn new() -> Self {
+        Self {
+            id: AtomicUsize::new(0),
+        }
+    }
+
+    fn id(&self) -> usize {
+        let id = self.id.load(Relaxed);
+        if id != 0 {
+            return id;
+        }
+        let new_id = NEXT_ID.fetch_add(1, Relaxed);
+        // 同時に割り当てられた場合は先に書き込まれた方を使う
+        match self.id.compare_exchange(0, new_id, Relaxed, Relaxed) {
+            Ok(_) => new_id,
+            Err(id) => id,
+        }
+    }
+}
+
+// a -> b: aを保持したままbを取得したことがある。値は最初にその順序で取得した場所
+// 記録するのはデバッグ用なので標準ライブラリのMutexで保護する
+type Graph = BTreeMap<usize, BTreeMap<usize, &'static Location<'static>>>;
+
+static GRAPH: std::sync::Mutex<Graph> = std::sync::Mutex::new(BTreeMap::new());
+
+thread_local! {
+    // このスレッドが保持しているロックを取得した順に並べたもの
+    static HELD: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+// ブロックする前に呼ぶ
+// 実際にデッドロックしてからでは報告できないので、待機を始める前に確認する
+#[track_caller]
+pub fn check(class: &LockClass) {
+    let id = class.id();
+    let location = Location::caller();
+    let held = HELD.with(|held| held.borrow().clone());
+    let mut graph = GRAPH.lock().unwrap();
+    for &h in &held {
+        // 同じロックの再取得はここでは扱わない
+        if h == id || graph.get(&h).is_some_and(|e| e.contains_key(&id)) {
+            continue;
+        }
+        // id -> ... -> h の経路があれば h -> id を追加すると循環する
+        if let Some(path) = find_path(&graph, id, h) {
+            let report = report(&graph, &path, h, id, location);
+            // パニックでGRAPHがポイズンしないように先に解放する
+            drop(graph);
+            panic!("{report}");
+        }
+        graph.entry(h).or_default().insert(id, location);
+    }
+}
+
+// ロックを取得した後に呼ぶ
+// try_lockはブロックしないのでcheckを呼ばずにこれだけ呼ぶ
+pub fn acquired(class: &LockClass) {
+    let id = class.id();
+    HELD.with(|held| held.borrow_mut().push(id));
+}
+
+// アンロックした後に呼ぶ
+// ガードは別のスレッドに渡されることもあるので、保持していなければ何もしない
+pub fn released(class: &LockClass) {
+    let id = class.id();
+    // スレッドの終了処理中はスレッドローカル変数にアクセスできない場合がある
+    let _ = HELD.try_with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(i) = held.iter().rposition(|&h| h == id) {
+            held.remove(i);
+        }
+    });
+}
+
+// このスレッドが保持していると記録されているロックの数
+#[cfg(test)]
+pub fn held_count() -> usize {
+    HELD.with(|held| held.borrow().len())
+}
+
+// fromからtoへの経路を深さ優先で探す
+fn find_path(graph: &Graph, from: usize, to: usize) -> Option<Vec<usize>> {
+    let mut visited = vec![from];
+    let mut stack = vec![vec![from]];
+    while let Some(path) = stack.pop() {
+        let last = *path.last().unwrap();
+        if last == to {
+            return Some(path);
+        }
+        for &next in graph.get(&last).into_iter().flat_map(|e| e.keys()) {
+            if !visited.contains(&next) {
+                visited.push(next);
+                let mut path = path.clone();
+                path.push(next);
+                stack.push(path);
+            }
+        }
+    }
+    None
+}
+
+fn report(
+    graph: &Graph,
+    path: &[usize],
+    held: usize,
+    id: usize,
+    location: &Location<'_>,
+) -> String {
+    let mut s = format!(
+        "possible deadlock: acquiring lock #{id} while holding lock #{held} at {location}\n\
+         lock order cycle:\n  lock #{held} -> lock #{id} at {location}\n"
+    );
+    for w in path.windows(2) {
+        let _ = writeln!(
+            s,
+            "  lock #{} -> lock #{} at {}",
+            w[0], w[1], graph[&w[0]][&w[1]]
+        );
+    }
+    s
+}
+
+#[test]
+#[should_panic(expected = "possible deadlock")]
+fn test_lockdep() {
+    use crate::mutex::Mutex;
+    use crate::rwlock::RwLock;
+
+    let a = Mutex::new(0);
+    let b = RwLock::new(0);
+
+    // 同じ順序で取得する分には問題ない
+    for _ in 0..2 {
+        let _a = a.lock();
+        let _b = b.write();
+    }
+    {
+        let _b = b.read();
+        // try_lockはブロックしないので順序の記録には使わない
+        assert!(a.try_lock().is_some());
+    }
+
+    // 逆の順序で取得するとデッドロックしなくても検出される
+    let _b = b.read();
+    let _a = a.lock();
+}
@@ -1,11 +1,3 @@
-mod condvar_opt;
-mod mutex;
-mod mutex_opt;
-mod mutex_spin;
-mod rwlock;
-mod rwlock_avoid_writer_starvation;
-mod rwlock_no_busyloop;
-
 fn main() {
     println!("Hello, world!");
 }
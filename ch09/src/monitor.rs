@@ -0,0 +1,114 @@
+use crate::condvar::Condvar;
+use crate::mutex::{Mutex, MutexGuard};
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+
+// MutexとCondvarを1つにまとめたもの
+// 別々に持つと、別のMutexのガードでCondvarを待ってしまい通知を取りこぼすことがある
+// Monitorのガードでしか待機できないので、組み合わせを間違えることがない
+pub struct Monitor<T: ?Sized> {
+    changed: Condvar,
+    mutex: Mutex<T>,
+}
+
+impl<T> Monitor<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            changed: Condvar::new(),
+            mutex: Mutex::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.mutex.into_inner()
+    }
+}
+
+impl<T: ?Sized> Monitor<T> {
+    pub fn lock(&self) -> MonitorGuard<'_, T> {
+        MonitorGuard {
+            monitor: self,
+            guard: self.mutex.lock(),
+        }
+    }
+
+    // 誤って起こされることがあるので、呼び出し側は条件を確認してループする
+    pub fn wait<'a>(&self, guard: MonitorGuard<'a, T>) -> MonitorGuard<'a, T> {
+        // 別のMonitorのガードでは待機できない
+        assert!(
+            ptr::eq(self, guard.monitor),
+            "guard belongs to a different Monitor"
+        );
+        MonitorGuard {
+            monitor: guard.monitor,
+            guard: self.changed.wait(guard.guard),
+        }
+    }
+
+    pub fn wait_while<'a>(
+        &self,
+        mut guard: MonitorGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MonitorGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.changed.notify_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.changed.notify_all();
+    }
+}
+
+pub struct MonitorGuard<'a, T: ?Sized> {
+    monitor: &'a Monitor<T>,
+    guard: MutexGuard<'a, T>,
+}
+
+impl<T: ?Sized> Deref for MonitorGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for MonitorGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[test]
+fn test_monitor() {
+    use std::collections::VecDeque;
+    use std::thread;
+
+    let monitor = Monitor::new(VecDeque::new());
+    thread::scope(|s| {
+        s.spawn(|| {
+            for i in 0..100 {
+                monitor.lock().push_back(i);
+                monitor.notify_one();
+            }
+        });
+        for i in 0..100 {
+            let mut q = monitor.wait_while(monitor.lock(), |q| q.is_empty());
+            assert_eq!(q.pop_front(), Some(i));
+        }
+    });
+    assert!(monitor.into_inner().is_empty());
+}
+
+#[test]
+#[should_panic(expected = "guard belongs to a different Monitor")]
+fn test_wrong_monitor() {
+    let a = Monitor::new(0);
+    let b = Monitor::new(0);
+    let _ = b.wait(a.lock());
+}
@@ -1,44 +1,282 @@
-use atomic_wait::{wait, wake_one};
+use crate::futex::{wait_timeout, wake_one};
+#[cfg(feature = "owner_check")]
+use crate::thread_id::current_thread_id;
+use atomic_wait::wait;
 use std::cell::UnsafeCell;
+use std::fmt;
+use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
 use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub struct Mutex<T> {
+// Mutex<[u8]>やMutex<dyn Trait>にできるようにvalueは最後のフィールドにする
+pub struct Mutex<T: ?Sized> {
     /// 0: unlocked
     /// 1: locked
-    state: AtomicU32,
+    // condvar.rsでnotify_all()したスレッドをここに移すためにpub(crate)にする
+    pub(crate) state: AtomicU32,
+    #[cfg(feature = "lockdep")]
+    class: crate::lockdep::LockClass,
+    #[cfg(feature = "stats")]
+    stats: crate::stats::LockStats,
+    // ロックを保持しているスレッド。保持していなければ0
+    #[cfg(feature = "owner_check")]
+    owner: std::sync::atomic::AtomicUsize,
     value: UnsafeCell<T>,
 }
 
-unsafe impl<T> Sync for Mutex<T> where T: Send {}
+unsafe impl<T: ?Sized> Sync for Mutex<T> where T: Send {}
 
 impl<T> Mutex<T> {
     pub const fn new(value: T) -> Self {
         Self {
             state: AtomicU32::new(0),
+            #[cfg(feature = "lockdep")]
+            class: crate::lockdep::LockClass::new(),
+            #[cfg(feature = "stats")]
+            stats: crate::stats::LockStats::new(),
+            #[cfg(feature = "owner_check")]
+            owner: std::sync::atomic::AtomicUsize::new(0),
             value: UnsafeCell::new(value),
         }
     }
 
-    pub fn lock(&self) -> MutexGuard<T> {
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        // ロックを省略できればstateを書き換えずにクリティカルセクションを実行する
+        // 統計はトランザクションの競合になるので数えない
+        #[cfg(feature = "elision")]
+        if crate::elision::try_elide(&self.state) {
+            #[cfg(feature = "lockdep")]
+            crate::lockdep::acquired(&self.class);
+            #[cfg(feature = "owner_check")]
+            self.owner.store(current_thread_id(), Relaxed);
+            return MutexGuard::new(self);
+        }
+        self.lock_no_elision()
+    }
+
+    // stateを読み書きする前に呼ぶ
+    // このスレッドがロックを省略していると、stateは0のままなのでロックできてしまい&mut Tが2つできる
+    #[inline(always)]
+    fn abort_elision(&self) {
+        #[cfg(feature = "elision")]
+        crate::elision::abort_if_elided(&self.state);
+    }
+
+    // ガードが別のスレッドに渡される場合はロックを省略できないので、必ずstateを書き換える
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    fn lock_no_elision(&self) -> MutexGuard<'_, T> {
+        self.abort_elision();
+        // 自分が保持しているロックを待つと永久に起こされない
+        // ownerが自分のIDになっているのは自分が書き込んだ場合だけなのでRelaxedでよい
+        #[cfg(feature = "owner_check")]
+        if self.owner.load(Relaxed) == current_thread_id() {
+            panic!("Mutex relocked by owner thread: this would deadlock");
+        }
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::check(&self.class);
+        #[cfg(feature = "stats")]
+        let mut wait_start = None;
         // wait()は誤って起こされる場合があるのでループと一緒に使う
         // stateをlockedに
         while self.state.swap(1, Acquire) == 1 {
+            #[cfg(feature = "stats")]
+            self.stats.record_wait(&mut wait_start);
             // lockedである限りブロック
             wait(&self.state, 1);
         }
-        MutexGuard { mutex: self }
+        #[cfg(feature = "stats")]
+        self.stats.record_acquire(wait_start);
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::acquired(&self.class);
+        #[cfg(feature = "owner_check")]
+        self.owner.store(current_thread_id(), Relaxed);
+        MutexGuard::new(self)
+    }
+
+    // Arcを保持する'staticなガードを返す。別のスレッドにそのまま渡せる
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn lock_arc(self: &Arc<Self>) -> ArcMutexGuard<T> {
+        // アンロックはArcMutexGuardのドロップで行う
+        self.raw_lock();
+        // ガードはどのスレッドでドロップされるかわからないので、このスレッドの記録からは外す
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::released(&self.class);
+        ArcMutexGuard {
+            mutex: self.clone(),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.abort_elision();
+        if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_ok() {
+            #[cfg(feature = "stats")]
+            self.stats.record_acquire(None);
+            #[cfg(feature = "lockdep")]
+            crate::lockdep::acquired(&self.class);
+            #[cfg(feature = "owner_check")]
+            self.owner.store(current_thread_id(), Relaxed);
+            Some(MutexGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<MutexGuard<'_, T>> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_until(deadline),
+            None => Some(self.lock()),
+        }
+    }
+
+    pub fn try_lock_until(&self, deadline: Instant) -> Option<MutexGuard<'_, T>> {
+        self.abort_elision();
+        #[cfg(feature = "stats")]
+        let mut wait_start = None;
+        while self.state.swap(1, Acquire) == 1 {
+            match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => {
+                    #[cfg(feature = "stats")]
+                    self.stats.record_wait(&mut wait_start);
+                    wait_timeout(&self.state, 1, remaining);
+                }
+                _ => {
+                    // タイムアウトと同時にアンロックされた場合、
+                    // 他のスレッドを起こすはずだったwakeをこのスレッドが受け取っているかもしれない
+                    // 待機中のスレッドが取り残されないように代わりに起こしておく
+                    wake_one(&self.state);
+                    return None;
+                }
+            }
+        }
+        #[cfg(feature = "stats")]
+        self.stats.record_acquire(wait_start);
+        // タイムアウトするのでデッドロックはしない。try_lockと同じく取得だけ記録する
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::acquired(&self.class);
+        #[cfg(feature = "owner_check")]
+        self.owner.store(current_thread_id(), Relaxed);
+        Some(MutexGuard::new(self))
+    }
+
+    // このMutexを作ってからの取得回数と待機時間
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::Stats {
+        self.stats.get()
+    }
+
+    // ガードを使わずにロックする。FFIや独自のガード型から使う
+    // アンロックはraw_unlockで行う
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn raw_lock(&self) {
+        std::mem::forget(self.lock_no_elision());
+        // 別のスレッドでアンロックされることもあるので所有スレッドは記録しない
+        #[cfg(feature = "owner_check")]
+        self.owner.store(0, Relaxed);
+    }
+
+    pub fn raw_try_lock(&self) -> bool {
+        let locked = self.try_lock().map(std::mem::forget).is_some();
+        // 失敗したときのownerはロックしている他のスレッドのものなので消さない
+        #[cfg(feature = "owner_check")]
+        if locked {
+            self.owner.store(0, Relaxed);
+        }
+        locked
+    }
+
+    /// # Safety
+    /// raw_lockやraw_try_lockでロックしているか、
+    /// std::mem::forgetしたガードの代わりにアンロックする場合のみ呼び出せる
+    pub unsafe fn raw_unlock(&self) {
+        // MutexGuardを作って即座にドロップすることでアンロックする
+        drop(MutexGuard::new(self))
+    }
+
+    // ロックしている間だけ読み書きできる
+    pub fn data_ptr(&self) -> *mut T {
+        self.value.get()
+    }
+
+    // ロックしている間だけfを実行する。ガードを長く持ち続けてしまうことがない
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    // 呼び出した時点の状態。戻った後も同じ状態とは限らないのでテストやログで使う
+    // 他のスレッドがロックを省略している間はロックされていないように見える
+    pub fn is_locked(&self) -> bool {
+        self.abort_elision();
+        self.state.load(Relaxed) == 1
     }
 }
 
-pub struct MutexGuard<'a, T> {
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+        // ロックできた場合だけ値を表示する。統計に数えないようにtry_lockは使わない
+        self.abort_elision();
+        if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_ok() {
+            let guard = MutexGuard::new(self);
+            d.field("data", &&*guard);
+        } else {
+            d.field("data", &format_args!("<locked>"));
+        }
+        d.finish_non_exhaustive()
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
     pub(crate) mutex: &'a Mutex<T>,
+    // 別のスレッドでドロップされると、ownerやロックの省略がロックしたスレッドの記録と食い違う
+    // 別のスレッドに渡すガードはlock_arc()で作る
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    fn new(mutex: &'a Mutex<T>) -> Self {
+        Self {
+            mutex,
+            _not_send: PhantomData,
+        }
+    }
 }
 
-unsafe impl<T> Sync for MutexGuard<'_, T> where T: Sync {}
+unsafe impl<T: ?Sized> Sync for MutexGuard<'_, T> where T: Sync {}
+
+impl<T: ?Sized> MutexGuard<'_, T> {
+    // 一時的にアンロックしてfを実行し、ロックし直す
+    // ガードを&mutで借りるので、アンロックしている間に値にアクセスすることはできない
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn unlocked<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        // fがパニックしてもロックし直す
+        // そうしないとガードのドロップで他のスレッドのロックをアンロックしてしまう
+        struct Relock<'a, T: ?Sized>(&'a Mutex<T>);
+
+        // raw_lock()と違い、ownerにこのスレッドを記録し直す
+        impl<T: ?Sized> Drop for Relock<'_, T> {
+            fn drop(&mut self) {
+                std::mem::forget(self.0.lock_no_elision());
+            }
+        }
+
+        unsafe { self.mutex.raw_unlock() };
+        let _relock = Relock(self.mutex);
+        f()
+    }
+}
 
-impl<T> Deref for MutexGuard<'_, T> {
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -46,14 +284,23 @@ impl<T> Deref for MutexGuard<'_, T> {
     }
 }
 
-impl<T> DerefMut for MutexGuard<'_, T> {
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.mutex.value.get() }
     }
 }
 
-impl<T> Drop for MutexGuard<'_, T> {
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
     fn drop(&mut self) {
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::released(&self.mutex.class);
+        #[cfg(feature = "owner_check")]
+        self.mutex.owner.store(0, Relaxed);
+        // ロックを省略していた場合はコミットするだけでよい
+        #[cfg(feature = "elision")]
+        if crate::elision::end(&self.mutex.state) {
+            return;
+        }
         // stateをunlockedに
         self.mutex.state.store(0, Release);
         // Mutexでlockを取得できるのは1スレッドだけなので、起こすのは1スレッドだけで良い
@@ -61,3 +308,167 @@ impl<T> Drop for MutexGuard<'_, T> {
         wake_one(&self.mutex.state);
     }
 }
+
+pub struct ArcMutexGuard<T: ?Sized> {
+    mutex: Arc<Mutex<T>>,
+}
+
+// 自動実装ではT: Sendだけで&Tを共有できてしまう
+unsafe impl<T: ?Sized> Sync for ArcMutexGuard<T> where T: Sync {}
+
+impl<T: ?Sized> Deref for ArcMutexGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for ArcMutexGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for ArcMutexGuard<T> {
+    fn drop(&mut self) {
+        unsafe { self.mutex.raw_unlock() }
+    }
+}
+
+#[test]
+fn test_unsized() {
+    let mutex: Arc<Mutex<[u8]>> = Arc::new(Mutex::new([1, 2, 3]));
+    mutex.lock()[0] = 10;
+    assert_eq!(*mutex.lock(), [10, 2, 3]);
+
+    let mutex: Box<Mutex<dyn std::fmt::Display>> = Box::new(Mutex::new(123));
+    assert_eq!(mutex.lock().to_string(), "123");
+}
+
+#[test]
+fn test_try_lock_for() {
+    use std::thread;
+
+    let mutex = Mutex::new(0);
+
+    thread::scope(|s| {
+        let mut g = mutex.lock();
+        s.spawn(|| {
+            assert!(mutex.try_lock().is_none());
+            assert!(mutex.try_lock_for(Duration::from_millis(10)).is_none());
+            // アンロックされるまで待機できる
+            let g = mutex.try_lock_for(Duration::from_secs(10)).unwrap();
+            assert_eq!(*g, 123);
+        });
+        thread::sleep(Duration::from_millis(100));
+        *g = 123;
+    });
+}
+
+#[test]
+fn test_lock_arc() {
+    use std::thread;
+
+    let mutex = Arc::new(Mutex::new(0));
+    let mut guard = mutex.lock_arc();
+
+    // ガードをそのまま別のスレッドに渡してそこでアンロックする
+    let t = thread::spawn(move || {
+        *guard += 1;
+    });
+    t.join().unwrap();
+
+    assert_eq!(*mutex.lock(), 1);
+}
+
+#[test]
+fn test_raw_lock() {
+    use std::thread;
+
+    let mutex = Mutex::new(0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..5000 {
+                    mutex.raw_lock();
+                    unsafe {
+                        *mutex.data_ptr() += 1;
+                        mutex.raw_unlock();
+                    }
+                }
+            });
+        }
+    });
+
+    // forgetしたガードの代わりにアンロックできる
+    std::mem::forget(mutex.lock());
+    assert!(!mutex.raw_try_lock());
+    unsafe { mutex.raw_unlock() };
+    assert!(mutex.raw_try_lock());
+    unsafe { mutex.raw_unlock() };
+    assert_eq!(mutex.into_inner(), 20000);
+}
+
+#[test]
+fn test_with_and_unlocked() {
+    use std::thread;
+
+    let mutex = Mutex::new(Vec::new());
+    mutex.with(|v| v.push(1));
+
+    thread::scope(|s| {
+        let mut g = mutex.lock();
+        g.push(2);
+        // アンロックしている間に他のスレッドがロックを取得できる
+        g.unlocked(|| {
+            s.spawn(|| mutex.with(|v| v.push(3))).join().unwrap();
+        });
+        g.push(4);
+    });
+
+    assert_eq!(mutex.into_inner(), [1, 2, 3, 4]);
+}
+
+#[test]
+fn test_debug() {
+    let mutex = Mutex::new(vec![1, 2]);
+    assert!(!mutex.is_locked());
+    assert_eq!(format!("{mutex:?}"), "Mutex { data: [1, 2], .. }");
+
+    let g = mutex.lock();
+    assert!(mutex.is_locked());
+    // ロックを省略していても、同じスレッドからは2つ目のガードを作れない
+    assert!(mutex.try_lock().is_none());
+    assert_eq!(format!("{mutex:?}"), "Mutex { data: <locked>, .. }");
+    drop(g);
+    assert!(!mutex.is_locked());
+}
+
+#[cfg(feature = "owner_check")]
+#[test]
+#[should_panic(expected = "relocked by owner")]
+fn test_relock_by_owner() {
+    let mutex = Mutex::new(0);
+    let _g = mutex.lock();
+    // デッドロックせずにパニックする
+    let _g2 = mutex.lock();
+}
+
+#[cfg(feature = "owner_check")]
+#[test]
+fn test_owner_tracking() {
+    use std::thread;
+
+    let mutex = Mutex::new(0);
+    thread::scope(|s| {
+        let mut g = mutex.lock();
+        // 失敗したraw_try_lock()で他のスレッドのownerが消えない
+        s.spawn(|| assert!(!mutex.raw_try_lock())).join().unwrap();
+        // unlocked()でロックし直した後もこのスレッドがownerになっている
+        g.unlocked(|| {});
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mutex.lock())).is_err());
+        *g += 1;
+    });
+    assert_eq!(mutex.into_inner(), 1);
+}
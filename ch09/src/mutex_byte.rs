@@ -0,0 +1,194 @@
+use crate::parking_lot::{park, unpark_one, unpark_one_with, ParkResult};
+use std::cell::UnsafeCell;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+// 状態遷移はmutex_opt.rsと同じだが、futexの代わりにparking_lotの待ち行列で待機する
+// futexは4バイトのワードが必要だが、待ち行列はアドレスだけで引けるので状態は1バイトで済む
+pub struct Mutex<T> {
+    /// 0: unlocked
+    /// 1: locked: 他の待機スレッドなし
+    /// 2: locked: 他の待機スレッドあり
+    state: AtomicU8,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for Mutex<T> where T: Send {}
+
+// unlock_fairでロックを解放せずに直接渡されたことを表すparkの戻り値
+const TOKEN_HANDOFF: usize = 1;
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU8::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
+            while self.state.swap(2, Acquire) != 0 {
+                // 状態の確認は待ち行列のロックを保持したまま行われるので、
+                // 確認後のアンロックによるunpark_oneを取りこぼすことはない
+                let r = park(self.addr(), || self.state.load(Relaxed) == 2, None);
+                if r == ParkResult::Unparked(TOKEN_HANDOFF) {
+                    // ロックは解放されずにこのスレッドに渡されている
+                    break;
+                }
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+
+    fn addr(&self) -> usize {
+        &self.state as *const AtomicU8 as usize
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T> Sync for MutexGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    // 最も長く待機しているスレッドにロックを直接渡してアンロックする
+    // 通常のアンロックでは起こされたスレッドより先に別のスレッドがロックを取得できるが、
+    // ロックを解放しないまま渡すので割り込まれることはない
+    pub fn unlock_fair(self) {
+        let mutex = self.mutex;
+        std::mem::forget(self);
+        // 待機スレッドがいなければ通常のアンロックと同じ
+        if mutex.state.compare_exchange(1, 0, Release, Relaxed).is_ok() {
+            return;
+        }
+        unpark_one_with(mutex.addr(), |r| {
+            if r.unparked {
+                // 他に待機スレッドが残っていなければ「待機スレッドなし」にしておく
+                if !r.have_more {
+                    mutex.state.store(1, Relaxed);
+                }
+                TOKEN_HANDOFF
+            } else {
+                // stateを2にしたスレッドがまだ待ち行列に入っていない
+                // 待ち行列に入る前の状態の確認で0が見えるので、そのスレッドはロックを取得し直す
+                mutex.state.store(0, Release);
+                0
+            }
+        });
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.mutex.state.swap(0, Release) == 2 {
+            // 2の場合のみ待ち行列の先頭のスレッドを起こす
+            unpark_one(self.mutex.addr());
+        }
+    }
+}
+
+// すべてのアンロックをunlock_fairで行うMutex
+// 待機スレッドの多いロックでも待ち始めた順に取得できる
+pub struct FairMutex<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> FairMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> FairMutexGuard<'_, T> {
+        FairMutexGuard {
+            inner: ManuallyDrop::new(self.inner.lock()),
+        }
+    }
+}
+
+pub struct FairMutexGuard<'a, T> {
+    inner: ManuallyDrop<MutexGuard<'a, T>>,
+}
+
+impl<T> Deref for FairMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for FairMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T> Drop for FairMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // innerはこの後使わないので取り出してよい
+        unsafe { ManuallyDrop::take(&mut self.inner) }.unlock_fair();
+    }
+}
+
+#[test]
+fn test_mutex_byte() {
+    use std::thread;
+
+    // 状態は1バイトだけ
+    assert_eq!(std::mem::size_of::<Mutex<()>>(), 1);
+    assert_eq!(std::mem::size_of::<Mutex<u8>>(), 2);
+
+    let mutex = Mutex::new(0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..5000 {
+                    *mutex.lock() += 1;
+                }
+            });
+        }
+    });
+    assert_eq!(*mutex.lock(), 20000);
+}
+
+#[test]
+fn test_unlock_fair() {
+    use std::thread;
+    use std::time::Duration;
+
+    let mutex = FairMutex::new(Vec::new());
+    thread::scope(|s| {
+        let g = mutex.lock();
+        for i in 0..3 {
+            let mutex = &mutex;
+            s.spawn(move || mutex.lock().push(i));
+            // 待ち行列に並ぶ順番を固定する
+            thread::sleep(Duration::from_millis(50));
+        }
+        drop(g);
+        // 通常のアンロックであれば起こされたスレッドより先に取得できてしまうが、
+        // ロックは待機していたスレッドに渡されているので最後尾に並ぶことになる
+        mutex.lock().push(100);
+    });
+    assert_eq!(*mutex.lock(), [0, 1, 2, 100]);
+}
@@ -1,14 +1,12 @@
-use atomic_wait::{wait, wake_one};
+use crate::raw_mutex::RawMutex;
 use std::cell::UnsafeCell;
+use std::fmt;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 
+// 3状態の遷移はRawMutexにまとめてある
 pub struct Mutex<T> {
-    /// 0: unlocked
-    /// 1: locked: 他の待機スレッドなし
-    /// 2: locked: 他の待機スレッドあり
-    state: AtomicU32,
+    // condvar.rsから使うためにpub(crate)にする
+    pub(crate) raw: RawMutex,
     value: UnsafeCell<T>,
 }
 
@@ -17,26 +15,43 @@ unsafe impl<T> Sync for Mutex<T> where T: Send {}
 impl<T> Mutex<T> {
     pub const fn new(value: T) -> Self {
         Self {
-            state: AtomicU32::new(0),
+            raw: RawMutex::new(),
             value: UnsafeCell::new(value),
         }
     }
 
-    pub fn lock(&self) -> MutexGuard<T> {
-        // ロックされていなかったら1にする
-        if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
-            // すでにロックされていた場合はスリープする前に2にする
-            // wakeされた場合は0になっているので2に戻す
-            while self.state.swap(2, Acquire) != 0 {
-                wait(&self.state, 2);
-            }
-        }
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.raw.lock();
         MutexGuard { mutex: self }
     }
+
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    pub fn has_waiters(&self) -> bool {
+        self.raw.has_waiters()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+        // ロックできた場合だけ値を表示する
+        if self.raw.try_lock() {
+            let guard = MutexGuard { mutex: self };
+            d.field("data", &&*guard);
+        } else {
+            d.field("data", &format_args!("<locked>"));
+            d.field("has_waiters", &self.has_waiters());
+        }
+        d.finish_non_exhaustive()
+    }
 }
 
 pub struct MutexGuard<'a, T> {
-    mutex: &'a Mutex<T>,
+    // condvar.rsから使うためにpub(crate)にする
+    pub(crate) mutex: &'a Mutex<T>,
 }
 
 unsafe impl<T> Sync for MutexGuard<'_, T> where T: Sync {}
@@ -57,10 +72,31 @@ impl<T> DerefMut for MutexGuard<'_, T> {
 
 impl<T> Drop for MutexGuard<'_, T> {
     fn drop(&mut self) {
-        if self.mutex.state.swap(0, Release) == 2 {
-            // 2の場合のみwakeする
-            // 起こされた時には 0 になっている
-            wake_one(&self.mutex.state);
-        }
+        // ガードがあるのはロックを取得しているときだけ
+        unsafe { self.mutex.raw.unlock() };
     }
 }
+
+#[test]
+fn test_has_waiters() {
+    use std::thread;
+    use std::time::Duration;
+
+    let mutex = Mutex::new(0);
+    thread::scope(|s| {
+        let g = mutex.lock();
+        assert!(mutex.is_locked());
+        assert!(!mutex.has_waiters());
+        s.spawn(|| *mutex.lock() += 1);
+        while !mutex.has_waiters() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(
+            format!("{mutex:?}"),
+            "Mutex { data: <locked>, has_waiters: true, .. }"
+        );
+        drop(g);
+    });
+    assert!(!mutex.is_locked());
+    assert_eq!(format!("{mutex:?}"), "Mutex { data: 1, .. }");
+}
@@ -0,0 +1,157 @@
+// 優先度継承(priority inheritance)付きのMutex
+// futexの値に所有スレッドのTIDを入れておくと、FUTEX_LOCK_PIで待機したときに
+// カーネルが所有スレッドの優先度を待機スレッドの優先度まで一時的に引き上げる
+// 低優先度のスレッドがロックを保持したまま中優先度のスレッドに割り込まれ続ける優先度逆転を防げる
+
+// Linux以外では優先度継承なしの通常のMutexを使う
+// main.rsのdead_codeと同様に、このクレートの中で使われていなくても警告しない
+#[cfg(not(target_os = "linux"))]
+#[allow(unused_imports)]
+pub use crate::mutex::{Mutex as PiMutex, MutexGuard as PiMutexGuard};
+#[cfg(target_os = "linux")]
+#[allow(unused_imports)]
+pub use linux::{PiMutex, PiMutexGuard};
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::cell::UnsafeCell;
+    use std::marker::PhantomData;
+    use std::ops::{Deref, DerefMut};
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+    pub struct PiMutex<T> {
+        /// 0: unlocked
+        /// それ以外: 所有スレッドのTID
+        /// 待機スレッドがいるとカーネルがFUTEX_WAITERSビットを立てる
+        state: AtomicU32,
+        value: UnsafeCell<T>,
+    }
+
+    unsafe impl<T> Sync for PiMutex<T> where T: Send {}
+
+    fn current_tid() -> u32 {
+        thread_local! {
+            static TID: u32 = unsafe { libc::syscall(libc::SYS_gettid) as u32 };
+        }
+        TID.with(|tid| *tid)
+    }
+
+    fn futex(state: &AtomicU32, op: libc::c_int) -> Result<(), i32> {
+        let r = unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                state as *const AtomicU32,
+                op | libc::FUTEX_PRIVATE_FLAG,
+                0,
+                std::ptr::null::<libc::timespec>(),
+            )
+        };
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error().raw_os_error().unwrap_or(0))
+        }
+    }
+
+    impl<T> PiMutex<T> {
+        pub const fn new(value: T) -> Self {
+            Self {
+                state: AtomicU32::new(0),
+                value: UnsafeCell::new(value),
+            }
+        }
+
+        pub fn lock(&self) -> PiMutexGuard<'_, T> {
+            // 競合していなければカーネルを呼ばずにTIDを書き込むだけでよい
+            if self
+                .state
+                .compare_exchange(0, current_tid(), Acquire, Relaxed)
+                .is_err()
+            {
+                // カーネルが待機スレッドの登録と所有権の受け渡しを行う
+                // 戻ったときにはstateにこのスレッドのTIDが書き込まれている
+                loop {
+                    match futex(&self.state, libc::FUTEX_LOCK_PI) {
+                        Ok(()) => break,
+                        Err(libc::EINTR | libc::EAGAIN) => continue,
+                        Err(e) => panic!("FUTEX_LOCK_PI failed: errno {e}"),
+                    }
+                }
+            }
+            PiMutexGuard {
+                mutex: self,
+                _no_send: PhantomData,
+            }
+        }
+
+        pub fn try_lock(&self) -> Option<PiMutexGuard<'_, T>> {
+            self.state
+                .compare_exchange(0, current_tid(), Acquire, Relaxed)
+                .ok()
+                .map(|_| PiMutexGuard {
+                    mutex: self,
+                    _no_send: PhantomData,
+                })
+        }
+
+        pub fn into_inner(self) -> T {
+            self.value.into_inner()
+        }
+    }
+
+    // stateのTIDとアンロックするスレッドが一致している必要があるのでSendにしない
+    pub struct PiMutexGuard<'a, T> {
+        mutex: &'a PiMutex<T>,
+        _no_send: PhantomData<*const ()>,
+    }
+
+    unsafe impl<T> Sync for PiMutexGuard<'_, T> where T: Sync {}
+
+    impl<T> Deref for PiMutexGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { &*self.mutex.value.get() }
+        }
+    }
+
+    impl<T> DerefMut for PiMutexGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { &mut *self.mutex.value.get() }
+        }
+    }
+
+    impl<T> Drop for PiMutexGuard<'_, T> {
+        fn drop(&mut self) {
+            // 待機スレッドがいなければTIDを0に戻すだけでよい
+            // FUTEX_WAITERSビットが立っている場合はカーネルに次の所有スレッドを選ばせる
+            if self
+                .mutex
+                .state
+                .compare_exchange(current_tid(), 0, Release, Relaxed)
+                .is_err()
+            {
+                futex(&self.mutex.state, libc::FUTEX_UNLOCK_PI).expect("FUTEX_UNLOCK_PI failed");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_pi_mutex() {
+    use std::thread;
+
+    let mutex = PiMutex::new(0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..5000 {
+                    *mutex.lock() += 1;
+                }
+            });
+        }
+    });
+    assert!(mutex.try_lock().is_some());
+    assert_eq!(mutex.into_inner(), 20000);
+}
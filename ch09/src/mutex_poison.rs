@@ -0,0 +1,148 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
+
+// ロックを保持したままパニックしたことを記録するMutex
+// ロックの仕組みはcrate::mutex::Mutexをそのまま使う
+pub struct Mutex<T> {
+    poisoned: AtomicBool,
+    inner: crate::mutex::Mutex<T>,
+}
+
+pub struct PoisonError<G> {
+    guard: G,
+}
+
+impl<G> PoisonError<G> {
+    // パニックしたスレッドが残した値をそのまま使う
+    pub fn into_inner(self) -> G {
+        self.guard
+    }
+
+    pub fn get_ref(&self) -> &G {
+        &self.guard
+    }
+
+    pub fn get_mut(&mut self) -> &mut G {
+        &mut self.guard
+    }
+}
+
+impl<G> fmt::Debug for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoisonError").finish_non_exhaustive()
+    }
+}
+
+impl<G> fmt::Display for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "poisoned lock: another task failed inside".fmt(f)
+    }
+}
+
+impl<G> std::error::Error for PoisonError<G> {}
+
+pub type LockResult<G> = Result<G, PoisonError<G>>;
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            poisoned: AtomicBool::new(false),
+            inner: crate::mutex::Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        let guard = MutexGuard {
+            mutex: self,
+            // すでにパニック中のスレッドがロックした場合はポイズンしない
+            panicking: thread::panicking(),
+            inner: self.inner.lock(),
+        };
+        // ロックの取得でAcquireされているのでRelaxedでよい
+        if self.poisoned.load(Relaxed) {
+            Err(PoisonError { guard })
+        } else {
+            Ok(guard)
+        }
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Relaxed)
+    }
+
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Relaxed);
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poisoned.into_inner();
+        let value = self.inner.into_inner();
+        if poisoned {
+            Err(PoisonError { guard: value })
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    panicking: bool,
+    inner: crate::mutex::MutexGuard<'a, T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // ロックを保持している間にパニックが始まった場合はポイズンする
+        // アンロックはこの後innerのドロップで行われるのでRelaxedでよい
+        if !self.panicking && thread::panicking() {
+            self.mutex.poisoned.store(true, Relaxed);
+        }
+    }
+}
+
+#[test]
+fn test_poison() {
+    let mutex = Mutex::new(0);
+
+    thread::scope(|s| {
+        let r = s
+            .spawn(|| {
+                let mut g = mutex.lock().unwrap();
+                *g = 123;
+                panic!("panic while holding the lock");
+            })
+            .join();
+        assert!(r.is_err());
+    });
+
+    assert!(mutex.is_poisoned());
+    // パニックしたスレッドが書き込んだ値を取り出せる
+    let g = match mutex.lock() {
+        Ok(_) => panic!("should be poisoned"),
+        Err(e) => e.into_inner(),
+    };
+    assert_eq!(*g, 123);
+    drop(g);
+
+    mutex.clear_poison();
+    assert_eq!(*mutex.lock().unwrap(), 123);
+    assert_eq!(mutex.into_inner().unwrap(), 123);
+}
@@ -1,58 +1,219 @@
-use atomic_wait::{wait, wake_one};
+use crate::raw_mutex::RawMutex;
 use std::cell::UnsafeCell;
+use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
 use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::{Duration, Instant};
 
-pub struct Mutex<T> {
-    /// 0: unlocked
-    /// 1: locked: 他の待機スレッドなし
-    /// 2: locked: 他の待機スレッドあり
-    state: AtomicU32,
+// futexで待機する前のスピンの仕方を決める
+pub trait SpinPolicy {
+    // スピンする回数の上限
+    const SPIN_LIMIT: u32;
+
+    // i回目のスピンで行う処理
+    fn relax(i: u32);
+}
+
+// 書籍と同じく100回を上限にspin_loop()でスピンする
+pub struct DefaultSpin;
+
+impl SpinPolicy for DefaultSpin {
+    const SPIN_LIMIT: u32 = 100;
+
+    fn relax(_: u32) {
+        std::hint::spin_loop();
+    }
+}
+
+// スピンせずにすぐにfutexで待機する
+pub struct NoSpin;
+
+impl SpinPolicy for NoSpin {
+    const SPIN_LIMIT: u32 = 0;
+
+    fn relax(_: u32) {}
+}
+
+// スピンの代わりにOSに他のスレッドへ実行を譲る
+// コア数よりスレッド数が多い場合に向いている
+pub struct Yield<const N: u32>;
+
+impl<const N: u32> SpinPolicy for Yield<N> {
+    const SPIN_LIMIT: u32 = N;
+
+    fn relax(_: u32) {
+        std::thread::yield_now();
+    }
+}
+
+// スピンするたびにspin_loop()の回数を倍にする(上限は2^MAX_SHIFT回)
+// 1u32をシフトするので、MAX_SHIFTは32未満でなければならない
+pub struct Backoff<const N: u32, const MAX_SHIFT: u32 = 6>;
+
+impl<const N: u32, const MAX_SHIFT: u32> Backoff<N, MAX_SHIFT> {
+    // relax()から参照して、32以上ならコンパイル時にエラーにする
+    const VALID_SHIFT: () = assert!(MAX_SHIFT < 32, "MAX_SHIFT must be less than 32");
+}
+
+impl<const N: u32, const MAX_SHIFT: u32> SpinPolicy for Backoff<N, MAX_SHIFT> {
+    const SPIN_LIMIT: u32 = N;
+
+    fn relax(i: u32) {
+        let () = Self::VALID_SHIFT;
+        for _ in 0..1u32 << i.min(MAX_SHIFT) {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+pub struct Mutex<T, P = DefaultSpin> {
+    // condvar.rsから使うためにpub(crate)にする
+    pub(crate) raw: RawMutex,
+    // PはスピンのしかたにしかかかわらないのでSend/Syncに影響させない
+    _policy: PhantomData<fn() -> P>,
     value: UnsafeCell<T>,
 }
 
-unsafe impl<T> Sync for Mutex<T> where T: Send {}
+unsafe impl<T, P> Sync for Mutex<T, P> where T: Send {}
 
 impl<T> Mutex<T> {
     pub const fn new(value: T) -> Self {
+        Self::with_policy(value)
+    }
+}
+
+impl<T, P> Mutex<T, P> {
+    // Mutex::<_, Yield<10>>::with_policy(value) のように型でスピンの仕方を指定する
+    pub const fn with_policy(value: T) -> Self {
         Self {
-            state: AtomicU32::new(0),
+            raw: RawMutex::new(),
+            _policy: PhantomData,
             value: UnsafeCell::new(value),
         }
     }
+}
 
-    pub fn lock(&self) -> MutexGuard<T> {
-        if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
+impl<T, P: SpinPolicy> Mutex<T, P> {
+    pub fn lock(&self) -> MutexGuard<'_, T, P> {
+        if !self.raw.try_lock() {
             // すでにロックされている
-            lock_contended(&self.state)
+            lock_contended::<P>(&self.raw)
         }
         MutexGuard { mutex: self }
     }
 }
 
-fn lock_contended(state: &AtomicU32) {
-    // スピンロックで100回を上限にスピンする
+fn lock_contended<P: SpinPolicy>(raw: &RawMutex) {
+    // P::SPIN_LIMIT回を上限にスピンする
+    // 他に待機スレッドがいる場合はスピンしても順番は回ってこないのでやめる
     let mut spin_count = 0;
-    while state.load(Relaxed) == 1 && spin_count < 100 {
+    while raw.is_locked() && !raw.has_waiters() && spin_count < P::SPIN_LIMIT {
+        P::relax(spin_count);
         spin_count += 1;
-        std::hint::spin_loop();
     }
-    if state.compare_exchange(0, 1, Acquire, Relaxed).is_ok() {
-        return;
+    if !raw.try_lock() {
+        raw.lock_contended();
+    }
+}
+
+pub struct MutexGuard<'a, T, P = DefaultSpin> {
+    // condvar.rsから使うためにpub(crate)にする
+    pub(crate) mutex: &'a Mutex<T, P>,
+}
+
+unsafe impl<T, P> Sync for MutexGuard<'_, T, P> where T: Sync {}
+
+impl<T, P> Deref for MutexGuard<'_, T, P> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T, P> DerefMut for MutexGuard<'_, T, P> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
     }
-    while state.swap(2, Acquire) != 0 {
-        wait(state, 2);
+}
+
+impl<T, P> Drop for MutexGuard<'_, T, P> {
+    fn drop(&mut self) {
+        unsafe { self.mutex.raw.unlock() };
     }
 }
 
-pub struct MutexGuard<'a, T> {
-    mutex: &'a Mutex<T>,
+// 最近ロックが保持されていた時間からスピンするかどうかを決めるMutex
+// 固定の回数スピンする代わりに、保持時間が短ければその程度の時間だけスピンし、
+// 長ければfutexで待機するまでの時間が無駄になるのですぐに待機する
+pub struct AdaptiveMutex<T> {
+    raw: RawMutex,
+    // ロックを保持していた時間(ナノ秒)の指数移動平均
+    // 更新するのはロックを保持しているスレッドだけなのでRelaxedでよい
+    hold_nanos: AtomicU32,
+    value: UnsafeCell<T>,
 }
 
-unsafe impl<T> Sync for MutexGuard<'_, T> where T: Sync {}
+unsafe impl<T> Sync for AdaptiveMutex<T> where T: Send {}
 
-impl<T> Deref for MutexGuard<'_, T> {
+// 平均の保持時間がこれより長い場合はスピンしない
+// futexで待機して起こされるまでにかかる時間と同じくらいにしておく
+const ADAPTIVE_SPIN_MAX: Duration = Duration::from_micros(10);
+
+impl<T> AdaptiveMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            raw: RawMutex::new(),
+            hold_nanos: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    // 保持時間を測るのは待ってから取得したときだけにして、競合していなければ時刻を取得しない
+    // スピンするかを決めるのは競合したときなので、そのときの保持時間がわかれば足りる
+    pub fn lock(&self) -> AdaptiveMutexGuard<'_, T> {
+        let locked_at = if self.raw.try_lock() {
+            None
+        } else {
+            self.lock_contended();
+            Some(Instant::now())
+        };
+        AdaptiveMutexGuard {
+            mutex: self,
+            locked_at,
+        }
+    }
+
+    fn lock_contended(&self) {
+        let hold = self.average_hold_time();
+        if hold < ADAPTIVE_SPIN_MAX {
+            // 平均の2倍までは解放されるのを待つ
+            let deadline = Instant::now() + hold * 2;
+            while self.raw.is_locked() && !self.raw.has_waiters() && Instant::now() < deadline {
+                std::hint::spin_loop();
+            }
+            if self.raw.try_lock() {
+                return;
+            }
+        }
+        self.raw.lock_contended();
+    }
+
+    pub fn average_hold_time(&self) -> Duration {
+        Duration::from_nanos(self.hold_nanos.load(Relaxed).into())
+    }
+}
+
+pub struct AdaptiveMutexGuard<'a, T> {
+    mutex: &'a AdaptiveMutex<T>,
+    // 待ってから取得したときだけSome
+    locked_at: Option<Instant>,
+}
+
+unsafe impl<T> Sync for AdaptiveMutexGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for AdaptiveMutexGuard<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -60,18 +221,83 @@ impl<T> Deref for MutexGuard<'_, T> {
     }
 }
 
-impl<T> DerefMut for MutexGuard<'_, T> {
+impl<T> DerefMut for AdaptiveMutexGuard<'_, T> {
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.mutex.value.get() }
     }
 }
 
-impl<T> Drop for MutexGuard<'_, T> {
+impl<T> Drop for AdaptiveMutexGuard<'_, T> {
     fn drop(&mut self) {
-        if self.mutex.state.swap(0, Release) == 2 {
-            // 2の場合のみwakeする
-            // 起こされた時には 0 になっている
-            wake_one(&self.mutex.state);
+        // 直近の値の重みを1/8にして平均を更新する
+        if let Some(locked_at) = self.locked_at {
+            let sample = locked_at.elapsed().as_nanos().min(u32::MAX.into()) as i64;
+            let avg = self.mutex.hold_nanos.load(Relaxed) as i64;
+            let avg = avg + (sample - avg) / 8;
+            self.mutex.hold_nanos.store(avg as u32, Relaxed);
+        }
+        unsafe { self.mutex.raw.unlock() };
+    }
+}
+
+#[test]
+fn test_spin_policy() {
+    use std::thread;
+
+    fn run<P: SpinPolicy>(mutex: Mutex<u32, P>) {
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..5000 {
+                        *mutex.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*mutex.lock(), 20000);
+    }
+
+    run(Mutex::new(0));
+    run(Mutex::<_, NoSpin>::with_policy(0));
+    run(Mutex::<_, Yield<10>>::with_policy(0));
+    run(Mutex::<_, Backoff<10>>::with_policy(0));
+}
+
+#[test]
+fn test_adaptive_mutex() {
+    use std::thread;
+
+    let mutex = AdaptiveMutex::new(0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..5000 {
+                    *mutex.lock() += 1;
+                }
+            });
         }
+    });
+    assert_eq!(*mutex.lock(), 20000);
+
+    // 競合せずに取得したときは保持時間を測らない
+    let hold = mutex.average_hold_time();
+    for _ in 0..20 {
+        let _g = mutex.lock();
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(mutex.average_hold_time(), hold);
+
+    // 待ってから取得したスレッドが長く保持するとスピンしなくなる
+    for _ in 0..20 {
+        let g = mutex.lock();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _g = mutex.lock();
+                thread::sleep(Duration::from_millis(1));
+            });
+            thread::sleep(Duration::from_millis(1));
+            drop(g);
+        });
     }
+    assert!(mutex.average_hold_time() > ADAPTIVE_SPIN_MAX);
 }
@@ -0,0 +1,166 @@
+use atomic_wait::{wait, wake_all};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+// 初期化がまだ行われていない
+const INCOMPLETE: u32 = 0;
+// 初期化中にパニックした
+const POISONED: u32 = 1;
+// 初期化中: 待機スレッドなし
+const RUNNING: u32 = 2;
+// 初期化中: 待機スレッドあり
+const QUEUED: u32 = 3;
+// 初期化済み
+const COMPLETE: u32 = 4;
+
+pub struct Once {
+    state: AtomicU32,
+}
+
+pub struct OnceState {
+    poisoned: bool,
+}
+
+impl OnceState {
+    // 以前の初期化がパニックしていればtrue
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+}
+
+impl Once {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(INCOMPLETE),
+        }
+    }
+
+    // 初期化済みかどうかはAcquireロード1回で確認できる
+    // trueなら初期化処理で書き込まれた値はすべて観測できる
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Acquire) == COMPLETE
+    }
+
+    // 前回の初期化がパニックしていた場合はパニックする
+    pub fn call_once<F: FnOnce()>(&self, f: F) {
+        if self.is_completed() {
+            return;
+        }
+        let mut f = Some(f);
+        self.call(false, &mut |_| f.take().unwrap()());
+    }
+
+    // 前回の初期化がパニックしていても再度初期化を試みる
+    pub fn call_once_force<F: FnOnce(&OnceState)>(&self, f: F) {
+        if self.is_completed() {
+            return;
+        }
+        let mut f = Some(f);
+        self.call(true, &mut |state| f.take().unwrap()(state));
+    }
+
+    #[cold]
+    fn call(&self, ignore_poisoning: bool, f: &mut dyn FnMut(&OnceState)) {
+        let mut s = self.state.load(Acquire);
+        loop {
+            match s {
+                POISONED if !ignore_poisoning => {
+                    panic!("Once instance has previously been poisoned");
+                }
+                INCOMPLETE | POISONED => {
+                    if let Err(e) = self.state.compare_exchange(s, RUNNING, Acquire, Acquire) {
+                        s = e;
+                        continue;
+                    }
+                    // fがパニックした場合はガードのドロップでPOISONEDに戻す
+                    let mut guard = CompletionGuard {
+                        state: &self.state,
+                        set_state_on_drop_to: POISONED,
+                    };
+                    f(&OnceState {
+                        poisoned: s == POISONED,
+                    });
+                    guard.set_state_on_drop_to = COMPLETE;
+                    return;
+                }
+                RUNNING | QUEUED => {
+                    // 待機スレッドがいることを初期化中のスレッドに伝えてから待機する
+                    if s == RUNNING {
+                        if let Err(e) = self
+                            .state
+                            .compare_exchange(RUNNING, QUEUED, Relaxed, Acquire)
+                        {
+                            s = e;
+                            continue;
+                        }
+                    }
+                    wait(&self.state, QUEUED);
+                    s = self.state.load(Acquire);
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+struct CompletionGuard<'a> {
+    state: &'a AtomicU32,
+    set_state_on_drop_to: u32,
+}
+
+impl Drop for CompletionGuard<'_> {
+    fn drop(&mut self) {
+        // QUEUEDの場合のみwakeする
+        if self.state.swap(self.set_state_on_drop_to, Release) == QUEUED {
+            wake_all(self.state);
+        }
+    }
+}
+
+#[test]
+fn test_once() {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    let once = Once::new();
+    let calls = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        for _ in 0..10 {
+            s.spawn(|| {
+                once.call_once(|| {
+                    thread::sleep(std::time::Duration::from_millis(100));
+                    calls.fetch_add(1, Relaxed);
+                });
+                // call_onceから戻った時点で初期化は完了している
+                assert!(once.is_completed());
+                assert_eq!(calls.load(Relaxed), 1);
+            });
+        }
+    });
+}
+
+#[test]
+fn test_once_poison() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let once = Once::new();
+
+    let r = catch_unwind(AssertUnwindSafe(|| {
+        once.call_once(|| panic!("init failed"))
+    }));
+    assert!(r.is_err());
+    assert!(!once.is_completed());
+
+    // パニックした後のcall_onceはパニックする
+    let r = catch_unwind(AssertUnwindSafe(|| once.call_once(|| {})));
+    assert!(r.is_err());
+
+    // call_once_forceはパニックしたことを確認して初期化をやり直せる
+    let mut poisoned = false;
+    once.call_once_force(|state| poisoned = state.is_poisoned());
+    assert!(poisoned);
+    assert!(once.is_completed());
+
+    once.call_once(|| unreachable!());
+}
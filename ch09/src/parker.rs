@@ -0,0 +1,130 @@
+use crate::futex::{wait_timeout, wake_one};
+use atomic_wait::wait;
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::Arc;
+use std::time::Instant;
+
+// トークンなし
+const EMPTY: u32 = 0;
+// トークンあり
+const NOTIFIED: u32 = 1;
+// park中
+const PARKED: u32 = u32::MAX;
+
+pub fn parker() -> (Parker, Unparker) {
+    let state = Arc::new(AtomicU32::new(EMPTY));
+    (
+        Parker {
+            state: state.clone(),
+            _no_sync: PhantomData,
+        },
+        Unparker { state },
+    )
+}
+
+// parkできるのは1スレッドだけなのでSyncにしない
+pub struct Parker {
+    state: Arc<AtomicU32>,
+    _no_sync: PhantomData<Cell<()>>,
+}
+
+#[derive(Clone)]
+pub struct Unparker {
+    state: Arc<AtomicU32>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParkResult {
+    // unpark()で起こされた、またはトークンがすでにあった
+    Unparked,
+    TimedOut,
+}
+
+impl Parker {
+    pub fn park(&self) {
+        // EMPTYならPARKEDに、NOTIFIEDならトークンを消費してEMPTYにする
+        if self.state.fetch_sub(1, Acquire) == NOTIFIED {
+            return;
+        }
+        loop {
+            wait(&self.state, PARKED);
+            // 誤って起こされた場合はトークンがないのでもう一度待機する
+            if self
+                .state
+                .compare_exchange(NOTIFIED, EMPTY, Acquire, Acquire)
+                .is_ok()
+            {
+                return;
+            }
+        }
+    }
+
+    pub fn park_deadline(&self, deadline: Instant) -> ParkResult {
+        if self.state.fetch_sub(1, Acquire) == NOTIFIED {
+            return ParkResult::Unparked;
+        }
+        loop {
+            match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => {
+                    wait_timeout(&self.state, PARKED, remaining)
+                }
+                _ => break,
+            }
+            if self
+                .state
+                .compare_exchange(NOTIFIED, EMPTY, Acquire, Acquire)
+                .is_ok()
+            {
+                return ParkResult::Unparked;
+            }
+        }
+        // タイムアウトと同時にunpark()された場合はトークンを受け取ったことにする
+        if self.state.swap(EMPTY, Acquire) == NOTIFIED {
+            ParkResult::Unparked
+        } else {
+            ParkResult::TimedOut
+        }
+    }
+}
+
+impl Unparker {
+    // park()より先に呼ばれた場合もトークンとして残るので失われない
+    // トークンは1つだけで、複数回呼んでも蓄積されない
+    pub fn unpark(&self) {
+        if self.state.swap(NOTIFIED, Release) == PARKED {
+            wake_one(&*self.state);
+        }
+    }
+}
+
+#[test]
+fn test_parker() {
+    use std::thread;
+    use std::time::Duration;
+
+    let (parker, unparker) = parker();
+
+    // 先にunparkしたトークンは失われない
+    unparker.unpark();
+    unparker.unpark();
+    parker.park();
+    // トークンは1つしか蓄積されない
+    assert_eq!(
+        parker.park_deadline(Instant::now() + Duration::from_millis(10)),
+        ParkResult::TimedOut
+    );
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(100));
+            unparker.unpark();
+        });
+        assert_eq!(
+            parker.park_deadline(Instant::now() + Duration::from_secs(10)),
+            ParkResult::Unparked
+        );
+    });
+}
@@ -0,0 +1,239 @@
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::Arc;
+use std::thread::{self, Thread};
+use std::time::Instant;
+
+// アドレスのハッシュで選ぶ待ち行列の数
+const NUM_BUCKETS: usize = 64;
+
+static BUCKETS: [Bucket; NUM_BUCKETS] = [const { Bucket::new() }; NUM_BUCKETS];
+
+// 異なるアドレスの待機スレッドが同じバケットに入ることもあるのでアドレスも記録する
+struct Waiter {
+    addr: usize,
+    thread: Thread,
+    // バケットのロックを保持したまま待ち行列から取り除いたときにtrueにする
+    unparked: AtomicBool,
+    // 起こす側から渡される値。unparkedをtrueにする前に書き込む
+    token: AtomicUsize,
+}
+
+// 待ち行列はスピンロックで保護する
+// ロックを保持するのは待ち行列の操作の間だけなので短い
+struct Bucket {
+    locked: AtomicBool,
+    queue: UnsafeCell<VecDeque<Arc<Waiter>>>,
+}
+
+unsafe impl Sync for Bucket {}
+
+impl Bucket {
+    const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            queue: UnsafeCell::new(VecDeque::new()),
+        }
+    }
+
+    fn lock(&self) -> BucketGuard<'_> {
+        while self.locked.swap(true, Acquire) {
+            while self.locked.load(Relaxed) {
+                std::hint::spin_loop();
+            }
+        }
+        BucketGuard { bucket: self }
+    }
+}
+
+struct BucketGuard<'a> {
+    bucket: &'a Bucket,
+}
+
+impl Deref for BucketGuard<'_> {
+    type Target = VecDeque<Arc<Waiter>>;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.bucket.queue.get() }
+    }
+}
+
+impl DerefMut for BucketGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.bucket.queue.get() }
+    }
+}
+
+impl Drop for BucketGuard<'_> {
+    fn drop(&mut self) {
+        self.bucket.locked.store(false, Release);
+    }
+}
+
+fn bucket(addr: usize) -> &'static Bucket {
+    // 下位ビットはアラインメントでほぼ同じなので掛け算で散らす
+    &BUCKETS[addr.wrapping_mul(0x9E37_79B9_7F4A_7C15_u64 as usize)
+        >> (usize::BITS - NUM_BUCKETS.trailing_zeros())]
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParkResult {
+    // 起こした側がunpark_one_withで渡した値。それ以外で起こされた場合は0
+    Unparked(usize),
+    // validateがfalseを返したので待機しなかった
+    Invalid,
+    TimedOut,
+}
+
+// addrで待機する
+// validateはバケットのロックを保持したまま呼ばれるので、
+// 値の確認と待ち行列への追加の間にunparkが入り込むことはない
+pub fn park(addr: usize, validate: impl FnOnce() -> bool, deadline: Option<Instant>) -> ParkResult {
+    let waiter = Arc::new(Waiter {
+        addr,
+        thread: thread::current(),
+        unparked: AtomicBool::new(false),
+        token: AtomicUsize::new(0),
+    });
+    {
+        let mut queue = bucket(addr).lock();
+        if !validate() {
+            return ParkResult::Invalid;
+        }
+        // 後ろに追加して先頭から起こすので、待ち始めた順に起こされる
+        queue.push_back(waiter.clone());
+    }
+    loop {
+        // thread::park()は誤って戻る場合があるのでフラグを確認する
+        if waiter.unparked.load(Acquire) {
+            return ParkResult::Unparked(waiter.token.load(Relaxed));
+        }
+        match deadline {
+            None => thread::park(),
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => thread::park_timeout(remaining),
+                _ => break,
+            },
+        }
+    }
+    // タイムアウトした場合は自分で待ち行列から取り除く
+    // ロックを取得する前にunparkされていればそちらを優先する
+    let mut queue = bucket(addr).lock();
+    if waiter.unparked.load(Acquire) {
+        return ParkResult::Unparked(waiter.token.load(Relaxed));
+    }
+    queue.retain(|w| !Arc::ptr_eq(w, &waiter));
+    ParkResult::TimedOut
+}
+
+// addrで最も長く待機しているスレッドを1つ起こす
+pub fn unpark_one(addr: usize) -> bool {
+    unpark_one_with(addr, |_| 0).unparked
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnparkResult {
+    // スレッドを起こしたか
+    pub unparked: bool,
+    // 起こしたスレッドの他にもaddrで待機しているスレッドが残っているか
+    pub have_more: bool,
+}
+
+// unpark_oneと同じだが、バケットのロックを保持したままcallbackを呼ぶ
+// callbackの中でロックの状態を更新すれば、待機スレッドの有無と矛盾しないように更新できる
+// callbackの戻り値は起こされたスレッドのparkの戻り値として渡される
+pub fn unpark_one_with(addr: usize, callback: impl FnOnce(UnparkResult) -> usize) -> UnparkResult {
+    let (waiter, result) = {
+        let mut queue = bucket(addr).lock();
+        let waiter = queue
+            .iter()
+            .position(|w| w.addr == addr)
+            .map(|i| queue.remove(i).unwrap());
+        let result = UnparkResult {
+            unparked: waiter.is_some(),
+            have_more: queue.iter().any(|w| w.addr == addr),
+        };
+        let token = callback(result);
+        if let Some(waiter) = &waiter {
+            waiter.token.store(token, Relaxed);
+            waiter.unparked.store(true, Release);
+        }
+        (waiter, result)
+    };
+    if let Some(waiter) = waiter {
+        waiter.thread.unpark();
+    }
+    result
+}
+
+// addrで待機しているすべてのスレッドを起こす
+pub fn unpark_all(addr: usize) -> usize {
+    let mut waiters = Vec::new();
+    {
+        let mut queue = bucket(addr).lock();
+        queue.retain(|w| {
+            if w.addr != addr {
+                return true;
+            }
+            w.unparked.store(true, Release);
+            waiters.push(w.clone());
+            false
+        });
+    }
+    // ロックを解放してから起こす
+    for w in &waiters {
+        w.thread.unpark();
+    }
+    waiters.len()
+}
+
+#[test]
+fn test_parking_lot() {
+    use std::sync::atomic::AtomicU8;
+    use std::time::Duration;
+
+    // 1バイトの状態でも待機できる
+    let state = AtomicU8::new(0);
+    let addr = &state as *const AtomicU8 as usize;
+    let order = crate::mutex::Mutex::new(Vec::new());
+
+    assert_eq!(
+        park(addr, || state.load(Relaxed) == 1, None),
+        ParkResult::Invalid
+    );
+    assert_eq!(
+        park(
+            addr,
+            || state.load(Relaxed) == 0,
+            Some(Instant::now() + Duration::from_millis(10))
+        ),
+        ParkResult::TimedOut
+    );
+    assert!(!unpark_one(addr));
+
+    thread::scope(|s| {
+        for i in 0..3 {
+            let order = &order;
+            let state = &state;
+            s.spawn(move || {
+                while state.load(Acquire) == 0 {
+                    park(addr, || state.load(Relaxed) == 0, None);
+                }
+                order.lock().push(i);
+            });
+            // 待ち行列に並ぶ順番を固定する
+            thread::sleep(Duration::from_millis(50));
+        }
+        state.store(1, Release);
+        // 待ち始めた順に起こされる
+        for _ in 0..3 {
+            assert!(unpark_one(addr));
+            thread::sleep(Duration::from_millis(50));
+        }
+    });
+
+    assert_eq!(*order.lock(), [0, 1, 2]);
+}
@@ -0,0 +1,109 @@
+use atomic_wait::{wait, wake_one};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+// mutex_opt.rsの状態遷移だけを取り出したもの
+// データを持たないので、ガードやデータの持ち方が違うMutexでも共有できる
+// 3状態のfutexを使うmutex_opt.rs, mutex_spin.rs, reentrant_mutex.rsもこれを使う
+// rwlock.rsはライタ同士の順番待ちに、condvar.rsは起こされたスレッドがロックし直すのに使う
+pub struct RawMutex {
+    /// 0: unlocked
+    /// 1: locked: 他の待機スレッドなし
+    /// 2: locked: 他の待機スレッドあり
+    state: AtomicU32,
+}
+
+impl RawMutex {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(0),
+        }
+    }
+
+    pub fn lock(&self) {
+        // ロックされていなかったら1にする
+        if !self.try_lock() {
+            self.lock_contended();
+        }
+    }
+
+    // すでにロックされていた場合の処理
+    // スピンしてから待機する場合は、try_lockに失敗してスピンした後に呼ぶ
+    #[cold]
+    pub fn lock_contended(&self) {
+        // スリープする前に2にする
+        // wakeされた場合は0になっているので2に戻す
+        while self.state.swap(2, Acquire) != 0 {
+            wait(&self.state, 2);
+        }
+    }
+
+    pub fn try_lock(&self) -> bool {
+        self.state.compare_exchange(0, 1, Acquire, Relaxed).is_ok()
+    }
+
+    /// # Safety
+    /// 現在のスレッドがlock()またはtry_lock()でロックを取得している場合のみ呼び出せる
+    pub unsafe fn unlock(&self) {
+        // 2の場合のみwakeする
+        // 起こされた時には 0 になっている
+        if self.state.swap(0, Release) == 2 {
+            wake_one(&self.state);
+        }
+    }
+
+    // 呼び出した時点の状態。戻った後も同じ状態とは限らないのでテストやログで使う
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Relaxed) != 0
+    }
+
+    // condvar.rsのnotify_all()で待機スレッドを移す先のfutex
+    // 移したスレッドはlock_contended()でロックし直すので、stateを2にしてからロックを待つ
+    pub(crate) fn futex(&self) -> &AtomicU32 {
+        &self.state
+    }
+
+    // 待機スレッドがいなくても、起こされたスレッドがロックを取得した直後などは2のままなのでtrueになる
+    // falseの場合は待機スレッドがいないことが確定している
+    pub fn has_waiters(&self) -> bool {
+        self.state.load(Relaxed) == 2
+    }
+}
+
+#[test]
+fn test_raw_mutex() {
+    use std::cell::UnsafeCell;
+    use std::thread;
+
+    struct Counter {
+        raw: RawMutex,
+        value: UnsafeCell<u32>,
+    }
+    unsafe impl Sync for Counter {}
+
+    let counter = Counter {
+        raw: RawMutex::new(),
+        value: UnsafeCell::new(0),
+    };
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let counter = &counter;
+            s.spawn(move || {
+                for _ in 0..5000 {
+                    counter.raw.lock();
+                    unsafe {
+                        *counter.value.get() += 1;
+                        counter.raw.unlock();
+                    }
+                }
+            });
+        }
+    });
+
+    assert!(counter.raw.try_lock());
+    assert!(counter.raw.is_locked());
+    assert!(!counter.raw.try_lock());
+    unsafe { counter.raw.unlock() };
+    assert_eq!(counter.value.into_inner(), 20000);
+}
@@ -0,0 +1,532 @@
+// try_upgrade_until()はwait_timeoutで待つので、ほかのプラットフォームでも起こせるようにcrate::futexのwakeを使う
+use crate::futex::{wait_timeout, wake_all, wake_one};
+use atomic_wait::wait;
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::time::Instant;
+
+// rwlock_no_busyloop.rsとrwlock_avoid_writer_starvation.rsの状態遷移だけを取り出したもの
+// 2つの違いは、待機中のライタがstateのビットで新しいリーダを止めるかどうかだけなので、
+// その違いをFairnessPolicyで型として選ぶ
+// raw_mutex.rsと同じくデータを持たないので、ガードやデータの持ち方はrwlock_fairness.rsに任せる
+// アップグレード可能なリードロックはstateの最上位ビットで表す
+pub struct RawRwLock<P> {
+    // リードロックの数 * P::READER + ライタが待機していればP::WRITER_WAITING
+    // アップグレード可能なリードロックが取得されていればUPGRADABLEも立てる
+    // ライタロックされている場合はu32:MAX(UPGRADABLEも立っている)
+    state: AtomicU32,
+    // ライタを起こす際にインクリメントする
+    writer_wake_counter: AtomicU32,
+    _policy: PhantomData<fn() -> P>,
+}
+
+// アップグレード可能なリードロックを持つ1つのスレッドがいる
+// 通常のリーダの数には含めないので、lock_exclusive()はs < P::READERにならずに待つ
+const UPGRADABLE: u32 = 1 << 31;
+
+// READERがWRITER_WAITINGより上の桁になるのはWRITER_WAITINGが0か1の場合だけなので、
+// このモジュールの外では実装できないようにする
+mod sealed {
+    pub trait Sealed {}
+}
+
+pub trait FairnessPolicy: sealed::Sealed {
+    // 待機中のライタがstateに立てるビット
+    // 0の場合はライタが待機していても新しいリーダを止めない
+    const WRITER_WAITING: u32;
+    // 1つのリードロックでstateに足す値。WRITER_WAITINGより上の桁で数える
+    const READER: u32 = Self::WRITER_WAITING + 1;
+}
+
+// リーダを優先する(rwlock_no_busyloop.rs)
+// リーダが途切れなければ、ライタは待ち続ける
+pub struct ReaderPreferred;
+
+impl sealed::Sealed for ReaderPreferred {}
+
+impl FairnessPolicy for ReaderPreferred {
+    const WRITER_WAITING: u32 = 0;
+}
+
+// ライタを優先する(rwlock_avoid_writer_starvation.rs)
+// リーダはstateが偶数なら+2してロックを取得し、奇数なら待機する
+pub struct WriterPreferred;
+
+impl sealed::Sealed for WriterPreferred {}
+
+impl FairnessPolicy for WriterPreferred {
+    const WRITER_WAITING: u32 = 1;
+}
+
+impl<P: FairnessPolicy> Default for RawRwLock<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: FairnessPolicy> RawRwLock<P> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            writer_wake_counter: AtomicU32::new(0),
+            _policy: PhantomData,
+        }
+    }
+
+    // ライトロックされておらず、新しいリーダを止めるライタも待っていない
+    fn can_read(s: u32) -> bool {
+        s != u32::MAX && s & P::WRITER_WAITING == 0
+    }
+
+    // 通常のリーダの数が上限に達したときのstate(UPGRADABLEとWRITER_WAITINGを除く)
+    // UPGRADABLEのビットに繰り上がったり、u32::MAXになったりしないようにする
+    const READERS_FULL: u32 = UPGRADABLE - 2 * P::READER;
+
+    // 上限に達したら、パニックせずにリーダが減るまで待つ
+    fn has_reader_room(s: u32) -> bool {
+        s & !UPGRADABLE < Self::READERS_FULL
+    }
+
+    pub fn lock_shared(&self) {
+        let mut s = self.state.load(Relaxed);
+        loop {
+            if Self::can_read(s) && Self::has_reader_room(s) {
+                match self
+                    .state
+                    .compare_exchange_weak(s, s + P::READER, Acquire, Relaxed)
+                {
+                    Ok(_) => return,
+                    Err(e) => s = e,
+                }
+            }
+            // ライトロックされているか、新しいリーダを止めるライタが待機しているか、
+            // リーダの数が上限に達している場合はwait()して後で再度試みる
+            if !Self::can_read(s) || !Self::has_reader_room(s) {
+                wait(&self.state, s);
+                s = self.state.load(Relaxed);
+            }
+        }
+    }
+
+    // ライトロックされているか、新しいリーダを止めるライタが待機しているか、
+    // リーダの数が上限に達していればfalseを返す
+    pub fn try_lock_shared(&self) -> bool {
+        let mut s = self.state.load(Relaxed);
+        while Self::can_read(s) && Self::has_reader_room(s) {
+            match self
+                .state
+                .compare_exchange_weak(s, s + P::READER, Acquire, Relaxed)
+            {
+                Ok(_) => return true,
+                Err(e) => s = e,
+            }
+        }
+        false
+    }
+
+    /// # Safety
+    /// 現在のスレッドがリードロックを取得している場合のみ呼び出せる
+    pub unsafe fn unlock_shared(&self) {
+        // 最後のリーダで、待機中のライタがいるかもしれない場合はライタを起こす
+        // WriterPreferredの場合は3から1になったときで、待機中のライタがいることがわかっている
+        //
+        // writer_wake_counterに対するAcquireロード操作が
+        // stateをアンロックした直後で待機しているライタを起こす前に行われる
+        // Releaseインクリメント操作との間に先行発生関係を形成する
+        //
+        // これによりライタがwriter_wake_counterの値がインクリメントされた値を観測することなく、
+        // まだデクリメントされていないstateを観測することを保証する
+        //
+        // アップグレード可能なリーダがいれば、upgrade()で待っているかもしれない
+        // 通常のライタと同じアドレスで待っているので、どちらも起こす
+        let s = self.state.fetch_sub(P::READER, Release);
+        if s & !UPGRADABLE == P::READER + P::WRITER_WAITING {
+            if s & UPGRADABLE == 0 {
+                self.wake_writer();
+            } else {
+                self.wake_all_writers();
+            }
+        }
+        // リーダの数が上限に達していたので、待っているリーダがいるかもしれない
+        // アップグレード可能なリードロックを待つスレッドも同じアドレスで待っているので、すべて起こす
+        if s & !UPGRADABLE & !P::WRITER_WAITING == Self::READERS_FULL {
+            wake_all(&self.state);
+        }
+    }
+
+    pub fn lock_exclusive(&self) {
+        let mut s = self.state.load(Relaxed);
+        loop {
+            // アンロックされていたらロックを試みる
+            if s < P::READER {
+                match self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
+                    Ok(_) => return,
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            // WriterPreferredの場合はビットを立てて新しいリーダをブロックする
+            if P::WRITER_WAITING != 0 && s & P::WRITER_WAITING == 0 {
+                match self
+                    .state
+                    .compare_exchange(s, s | P::WRITER_WAITING, Relaxed, Relaxed)
+                {
+                    Ok(_) => {}
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            // まだロックされていたら待機する
+            // ただし、チェック後にwake通知が来ていない場合のみ
+            let w = self.writer_wake_counter.load(Acquire);
+            s = self.state.load(Relaxed);
+            if s >= P::READER {
+                wait(&self.writer_wake_counter, w);
+                s = self.state.load(Relaxed);
+            }
+        }
+    }
+
+    // リードロックかライトロックされていればfalseを返す
+    // 待機しているライタがいても、アンロックされていればlock_exclusive()と同じく取得する
+    pub fn try_lock_exclusive(&self) -> bool {
+        let mut s = self.state.load(Relaxed);
+        while s < P::READER {
+            match self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
+                Ok(_) => return true,
+                Err(e) => s = e,
+            }
+        }
+        false
+    }
+
+    /// # Safety
+    /// 現在のスレッドがライトロックを取得している場合のみ呼び出せる
+    pub unsafe fn unlock_exclusive(&self) {
+        self.state.store(0, Release);
+        self.wake_writer();
+        // 待機しているすべてのリーダまたは1つのライタをすべて起こす
+        wake_all(&self.state);
+    }
+
+    /// ライトロックをアンロックせずにリードロックに変える
+    ///
+    /// # Safety
+    /// 現在のスレッドがライトロックを取得している場合のみ呼び出せる
+    pub unsafe fn downgrade(&self) {
+        // WriterPreferredの場合、待機中のライタはライトロック中にビットを立てられていないので、
+        // このままだと最後のリーダが3から1にするのを待ち続けてしまう
+        // リーダと一緒に起こして、もう一度ビットを立てさせる
+        self.state.store(P::READER, Release);
+        self.wake_writer();
+        wake_all(&self.state);
+    }
+
+    // アップグレード可能なリードロックは同時に1つだけ取得できる
+    // 通常のリーダとは共存し、ライタとは共存しない
+    pub fn lock_upgradable(&self) {
+        let mut s = self.state.load(Relaxed);
+        loop {
+            if s & UPGRADABLE == 0 && Self::can_read(s) {
+                match self
+                    .state
+                    .compare_exchange_weak(s, s | UPGRADABLE, Acquire, Relaxed)
+                {
+                    Ok(_) => return,
+                    Err(e) => s = e,
+                }
+            } else {
+                wait(&self.state, s);
+                s = self.state.load(Relaxed);
+            }
+        }
+    }
+
+    // ほかのスレッドがアップグレード可能なリードロックかライトロックを持っているか、
+    // WriterPreferredでライタが待機していればfalseを返す
+    pub fn try_lock_upgradable(&self) -> bool {
+        let mut s = self.state.load(Relaxed);
+        while s & UPGRADABLE == 0 && Self::can_read(s) {
+            match self
+                .state
+                .compare_exchange_weak(s, s | UPGRADABLE, Acquire, Relaxed)
+            {
+                Ok(_) => return true,
+                Err(e) => s = e,
+            }
+        }
+        false
+    }
+
+    /// # Safety
+    /// 現在のスレッドがアップグレード可能なリードロックを取得している場合のみ呼び出せる
+    pub unsafe fn unlock_upgradable(&self) {
+        // 通常のリーダがいなければ、待機中のライタが入れる
+        if self.state.fetch_and(!UPGRADABLE, Release) & !UPGRADABLE < P::READER {
+            self.wake_writer();
+        }
+        // アップグレード可能なリードロックを待っているスレッドを起こす
+        wake_all(&self.state);
+    }
+
+    /// アップグレード可能なリードロックを、アンロックせずにライトロックに変える
+    /// 通常のリーダがすべて出ていくまで待つ。WriterPreferredの場合は待っている間、新しいリーダを止める
+    /// ReaderPreferredの場合は、リーダが途切れなければ待ち続ける
+    ///
+    /// # Safety
+    /// 現在のスレッドがアップグレード可能なリードロックを取得している場合のみ呼び出せる
+    pub unsafe fn upgrade(&self) {
+        self.upgrade_until(None);
+    }
+
+    /// deadlineまでに通常のリーダがいなくならなければfalseを返し、アップグレード可能なリードロックのままにする
+    ///
+    /// # Safety
+    /// 現在のスレッドがアップグレード可能なリードロックを取得している場合のみ呼び出せる
+    pub unsafe fn try_upgrade_until(&self, deadline: Instant) -> bool {
+        self.upgrade_until(Some(deadline))
+    }
+
+    // deadlineがNoneならアップグレードできるまで待つので、常にtrueを返す
+    fn upgrade_until(&self, deadline: Option<Instant>) -> bool {
+        let mut s = self.state.load(Relaxed);
+        loop {
+            // 通常のリーダがいなければライトロックにする
+            if s & !UPGRADABLE < P::READER {
+                match self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
+                    Ok(_) => return true,
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            // lock_exclusive()と同じく、WriterPreferredの場合はビットを立てて新しいリーダをブロックする
+            if P::WRITER_WAITING != 0 && s & P::WRITER_WAITING == 0 {
+                match self
+                    .state
+                    .compare_exchange(s, s | P::WRITER_WAITING, Relaxed, Relaxed)
+                {
+                    Ok(_) => {}
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            let w = self.writer_wake_counter.load(Acquire);
+            s = self.state.load(Relaxed);
+            if s & !UPGRADABLE >= P::READER {
+                match deadline {
+                    None => wait(&self.writer_wake_counter, w),
+                    Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                        Some(remaining) if !remaining.is_zero() => {
+                            wait_timeout(&self.writer_wake_counter, w, remaining)
+                        }
+                        _ => {
+                            self.give_up_upgrade();
+                            return false;
+                        }
+                    },
+                }
+                s = self.state.load(Relaxed);
+            }
+        }
+    }
+
+    // WriterPreferredの場合、立てたビットが残ると新しいリーダがいつまでも入れなくなる
+    // 待機中のライタが立てたビットかもしれないので、downgrade()と同じくライタも起こしてもう一度立てさせる
+    fn give_up_upgrade(&self) {
+        if P::WRITER_WAITING != 0 {
+            self.state.fetch_and(!P::WRITER_WAITING, Relaxed);
+            self.wake_all_writers();
+            wake_all(&self.state);
+        }
+    }
+
+    /// 通常のリーダがいればfalseを返し、アップグレード可能なリードロックのままにする
+    ///
+    /// # Safety
+    /// 現在のスレッドがアップグレード可能なリードロックを取得している場合のみ呼び出せる
+    pub unsafe fn try_upgrade(&self) -> bool {
+        let mut s = self.state.load(Relaxed);
+        while s & !UPGRADABLE < P::READER {
+            match self
+                .state
+                .compare_exchange_weak(s, u32::MAX, Acquire, Relaxed)
+            {
+                Ok(_) => return true,
+                Err(e) => s = e,
+            }
+        }
+        false
+    }
+
+    fn wake_writer(&self) {
+        self.writer_wake_counter.fetch_add(1, Release);
+        wake_one(&self.writer_wake_counter);
+    }
+
+    fn wake_all_writers(&self) {
+        self.writer_wake_counter.fetch_add(1, Release);
+        wake_all(&self.writer_wake_counter);
+    }
+
+    // 以下は呼び出した時点の状態。戻った後も同じ状態とは限らないのでテストやログで使う
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Relaxed) >= P::READER
+    }
+
+    pub fn is_locked_exclusive(&self) -> bool {
+        self.state.load(Relaxed) == u32::MAX
+    }
+
+    pub fn reader_count(&self) -> u32 {
+        match self.state.load(Relaxed) {
+            u32::MAX => 0,
+            s => (s & !UPGRADABLE) / P::READER,
+        }
+    }
+}
+
+impl RawRwLock<WriterPreferred> {
+    // 待機しているライタを無視してリードロックを取得する
+    // すでにリードロックを持っているスレッドがlock_shared()を呼ぶと、待機中のライタを待つが
+    // そのライタは自分のリードロックが外れるのを待っているのでデッドロックする
+    // ReaderPreferredはもともと待機中のライタを待たないので必要ない
+    pub fn lock_shared_recursive(&self) {
+        let mut s = self.state.load(Relaxed);
+        loop {
+            // 奇数のまま+2するので、リーダの数の上限でu32::MAXにならないようにする
+            if s != u32::MAX && Self::has_reader_room(s) {
+                match self.state.compare_exchange_weak(s, s + 2, Acquire, Relaxed) {
+                    Ok(_) => return,
+                    Err(e) => s = e,
+                }
+            } else {
+                wait(&self.state, s);
+                s = self.state.load(Relaxed);
+            }
+        }
+    }
+
+    // ライトロックされているか、リーダの数が上限に達していればfalseを返す
+    pub fn try_lock_shared_recursive(&self) -> bool {
+        let mut s = self.state.load(Relaxed);
+        while s != u32::MAX && Self::has_reader_room(s) {
+            match self.state.compare_exchange_weak(s, s + 2, Acquire, Relaxed) {
+                Ok(_) => return true,
+                Err(e) => s = e,
+            }
+        }
+        false
+    }
+
+    // u32::MAXも奇数なので除く
+    // ReaderPreferredは待機中のライタをstateに記録しないのでわからない
+    pub fn has_waiting_writer(&self) -> bool {
+        let s = self.state.load(Relaxed);
+        s != u32::MAX && s % 2 == 1
+    }
+}
+
+#[test]
+fn test_raw_rwlock() {
+    use std::cell::UnsafeCell;
+    use std::thread;
+
+    struct Counter<P> {
+        raw: RawRwLock<P>,
+        value: UnsafeCell<u32>,
+    }
+    unsafe impl<P> Sync for Counter<P> {}
+
+    fn count<P: FairnessPolicy>() {
+        let counter = Counter::<P> {
+            raw: RawRwLock::new(),
+            value: UnsafeCell::new(0),
+        };
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                let counter = &counter;
+                s.spawn(move || {
+                    for _ in 0..2000 {
+                        counter.raw.lock_exclusive();
+                        unsafe { *counter.value.get() += 1 };
+                        // ライトロックのままリーダを通し、自分も読み続ける
+                        unsafe { counter.raw.downgrade() };
+                        let v = unsafe { *counter.value.get() };
+                        assert!(v >= 1);
+                        unsafe { counter.raw.unlock_shared() };
+
+                        counter.raw.lock_shared();
+                        unsafe { counter.raw.unlock_shared() };
+                    }
+                });
+            }
+        });
+
+        assert!(counter.raw.try_lock_exclusive());
+        assert!(counter.raw.is_locked_exclusive());
+        assert!(!counter.raw.try_lock_shared());
+        unsafe { counter.raw.downgrade() };
+        assert!(!counter.raw.is_locked_exclusive());
+        assert_eq!(counter.raw.reader_count(), 1);
+        assert!(counter.raw.try_lock_shared());
+        assert!(!counter.raw.try_lock_exclusive());
+        unsafe {
+            counter.raw.unlock_shared();
+            counter.raw.unlock_shared();
+        }
+        assert!(!counter.raw.is_locked());
+        assert_eq!(counter.value.into_inner(), 8000);
+    }
+
+    count::<ReaderPreferred>();
+    count::<WriterPreferred>();
+}
+
+#[test]
+fn test_reader_limit() {
+    use std::thread;
+    use std::time::Duration;
+
+    fn check<P: FairnessPolicy>() {
+        let raw = RawRwLock::<P>::new();
+        // 上限の直前までリードロックされていることにする
+        raw.state
+            .store(RawRwLock::<P>::READERS_FULL - P::READER, Relaxed);
+        assert!(raw.try_lock_shared());
+        assert_eq!(raw.reader_count(), RawRwLock::<P>::READERS_FULL / P::READER);
+        // パニックせずに、減るまで待つ
+        assert!(!raw.try_lock_shared());
+        thread::scope(|s| {
+            let t = s.spawn(|| {
+                raw.lock_shared();
+                unsafe { raw.unlock_shared() };
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert!(!t.is_finished());
+            unsafe { raw.unlock_shared() };
+        });
+        assert_eq!(
+            raw.state.load(Relaxed),
+            RawRwLock::<P>::READERS_FULL - P::READER
+        );
+    }
+
+    check::<ReaderPreferred>();
+    check::<WriterPreferred>();
+
+    // ライタを追い越すリーダも上限を超えない
+    let raw = RawRwLock::<WriterPreferred>::new();
+    raw.state
+        .store(RawRwLock::<WriterPreferred>::READERS_FULL + 1, Relaxed);
+    assert!(!raw.try_lock_shared_recursive());
+}
@@ -0,0 +1,108 @@
+use crate::raw_mutex::RawMutex;
+use crate::thread_id::current_thread_id;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+pub struct ReentrantMutex<T> {
+    raw: RawMutex,
+    // ロックを保持しているスレッドのID。保持していなければ0
+    owner: AtomicUsize,
+    // 同じスレッドがロックした回数。ロックを保持しているスレッドだけがアクセスする
+    lock_count: UnsafeCell<u32>,
+    value: T,
+}
+
+// 複数のスレッドから&Tにアクセスされることはないので T: Send でよい
+unsafe impl<T> Sync for ReentrantMutex<T> where T: Send {}
+
+impl<T> ReentrantMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            raw: RawMutex::new(),
+            owner: AtomicUsize::new(0),
+            lock_count: UnsafeCell::new(0),
+            value,
+        }
+    }
+
+    pub fn lock(&self) -> ReentrantMutexGuard<'_, T> {
+        let this_thread = current_thread_id();
+        // ownerが自分のIDになっているのは自分がロックを保持している場合だけなのでRelaxedでよい
+        if self.owner.load(Relaxed) == this_thread {
+            let count = unsafe { &mut *self.lock_count.get() };
+            *count = count.checked_add(1).expect("lock count overflow");
+        } else {
+            self.raw.lock();
+            self.owner.store(this_thread, Relaxed);
+            unsafe { *self.lock_count.get() = 1 };
+        }
+        ReentrantMutexGuard {
+            mutex: self,
+            _no_send: PhantomData,
+        }
+    }
+}
+
+// 同じスレッドから複数のガードが作られるので&Tしか渡せない
+// 別のスレッドでドロップされるとownerがずれるのでSendにしない
+pub struct ReentrantMutexGuard<'a, T> {
+    mutex: &'a ReentrantMutex<T>,
+    _no_send: PhantomData<*const ()>,
+}
+
+unsafe impl<T> Sync for ReentrantMutexGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for ReentrantMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.mutex.value
+    }
+}
+
+impl<T> Drop for ReentrantMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let count = unsafe { &mut *self.mutex.lock_count.get() };
+        *count -= 1;
+        // 最後のガードがドロップされたときだけアンロックする
+        if *count == 0 {
+            self.mutex.owner.store(0, Relaxed);
+            unsafe { self.mutex.raw.unlock() };
+        }
+    }
+}
+
+#[test]
+fn test_reentrant_mutex() {
+    use std::cell::RefCell;
+    use std::thread;
+
+    let mutex = ReentrantMutex::new(RefCell::new(Vec::new()));
+
+    // コールバックの中から同じスレッドで再度ロックしてもデッドロックしない
+    fn push_twice(mutex: &ReentrantMutex<RefCell<Vec<i32>>>, v: i32) {
+        let g = mutex.lock();
+        g.borrow_mut().push(v);
+        mutex.lock().borrow_mut().push(v);
+    }
+
+    thread::scope(|s| {
+        for i in 0..4 {
+            let mutex = &mutex;
+            s.spawn(move || {
+                for _ in 0..100 {
+                    let g = mutex.lock();
+                    push_twice(mutex, i);
+                    let len = g.borrow().len();
+                    // ロックを保持している間は他のスレッドに割り込まれない
+                    assert_eq!(g.borrow()[len - 2..], [i, i]);
+                }
+            });
+        }
+    });
+
+    assert_eq!(mutex.lock().borrow().len(), 800);
+}
@@ -1,56 +1,259 @@
+use crate::raw_mutex::RawMutex;
 use atomic_wait::{wait, wake_all, wake_one};
 use std::cell::UnsafeCell;
+use std::fmt;
 use std::ops::{Deref, DerefMut};
 use std::sync::atomic::AtomicU32;
 use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub struct RwLock<T> {
+// RwLock<[u8]>やRwLock<dyn Trait>にできるようにvalueは最後のフィールドにする
+pub struct RwLock<T: ?Sized> {
     // リードロックの数。ライタロックの場合はu32:MAX
+    // リードロックの数がu32::MAX - 1に達したら、それ以上のリーダは減るまで待つ
     state: AtomicU32,
+    // ライタ同士の順番待ち。stateで待つのはこれを取得した先頭のライタだけになる
+    writer_lock: RawMutex,
+    // write()でwait()しているライタの数。状態を調べるためだけに使う
+    waiting_writers: AtomicU32,
+    // ライタがこの時間より長く待ったら、待った時間を渡して呼び出す
+    slow_writer: Option<(Duration, fn(Duration))>,
+    #[cfg(feature = "lockdep")]
+    class: crate::lockdep::LockClass,
+    #[cfg(feature = "stats")]
+    stats: crate::stats::LockStats,
     value: UnsafeCell<T>,
 }
 
 // 複数リーダが同時にデータにアクセスするため Sync が必要
-unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
+unsafe impl<T: ?Sized> Sync for RwLock<T> where T: Send + Sync {}
 
 impl<T> RwLock<T> {
     pub const fn new(value: T) -> Self {
         Self {
             state: AtomicU32::new(0),
+            writer_lock: RawMutex::new(),
+            waiting_writers: AtomicU32::new(0),
+            slow_writer: None,
+            #[cfg(feature = "lockdep")]
+            class: crate::lockdep::LockClass::new(),
+            #[cfg(feature = "stats")]
+            stats: crate::stats::LockStats::new(),
             value: UnsafeCell::new(value),
         }
     }
 
-    pub fn read(&self) -> ReadGuard<T> {
+    // ライタがthresholdより長く待ったときにcallbackを呼ぶ。ログやメトリクスに使う
+    // callbackはライトロックを取得した後に呼ぶので、このRwLockをロックしてはいけない
+    pub const fn with_slow_writer_callback(
+        mut self,
+        threshold: Duration,
+        callback: fn(Duration),
+    ) -> Self {
+        self.slow_writer = Some((threshold, callback));
+        self
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::check(&self.class);
+        #[cfg(feature = "stats")]
+        let mut wait_start = None;
         let mut s = self.state.load(Relaxed);
         loop {
-            if s < u32::MAX {
-                assert!(s != u32::MAX - 1, "too many readers");
+            if s < u32::MAX - 1 {
                 match self.state.compare_exchange_weak(s, s + 1, Acquire, Relaxed) {
-                    Ok(_) => return ReadGuard { rwlock: self },
+                    Ok(_) => {
+                        #[cfg(feature = "stats")]
+                        self.stats.record_acquire(wait_start);
+                        #[cfg(feature = "lockdep")]
+                        crate::lockdep::acquired(&self.class);
+                        return ReadGuard { rwlock: self };
+                    }
                     Err(e) => s = e,
                 }
             }
-            // RwLockがライトロックされている場合は wait() して後で再度試みる
-            if s == u32::MAX {
-                wait(&self.state, u32::MAX);
+            // RwLockがライトロックされているか、リーダの数が上限に達している場合は
+            // wait() して後で再度試みる
+            if s >= u32::MAX - 1 {
+                #[cfg(feature = "stats")]
+                self.stats.record_wait(&mut wait_start);
+                wait(&self.state, s);
                 s = self.state.load(Relaxed);
             }
         }
     }
-    pub fn write(&self) -> WriteGuard<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::check(&self.class);
+        #[cfg(feature = "stats")]
+        let mut wait_start = None;
+        // コールバックがなければ時刻を取得しない
+        let mut slow_start = None;
+        let mut before_wait = || {
+            #[cfg(feature = "stats")]
+            self.stats.record_wait(&mut wait_start);
+            if self.slow_writer.is_some() && slow_start.is_none() {
+                slow_start = Some(Instant::now());
+            }
+        };
+        // 先にライタ同士で順番を決めるので、WriteGuardのドロップでwake_allしても
+        // stateで待っていて起こされるライタは1つだけになる
+        if !self.writer_lock.try_lock() {
+            before_wait();
+            self.waiting_writers.fetch_add(1, Relaxed);
+            self.writer_lock.lock_contended();
+            self.waiting_writers.fetch_sub(1, Relaxed);
+        }
         while let Err(s) = self.state.compare_exchange(0, u32::MAX, Acquire, Relaxed) {
+            before_wait();
+            self.waiting_writers.fetch_add(1, Relaxed);
             wait(&self.state, s);
+            self.waiting_writers.fetch_sub(1, Relaxed);
+        }
+        #[cfg(feature = "stats")]
+        self.stats.record_acquire(wait_start);
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::acquired(&self.class);
+        if let (Some((threshold, callback)), Some(start)) = (self.slow_writer, slow_start) {
+            let waited = start.elapsed();
+            if waited > threshold {
+                callback(waited);
+            }
         }
         WriteGuard { rwlock: self }
     }
+
+    // ライトロックされているか、リーダの数が上限に達していればNoneを返す
+    pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+        let mut s = self.state.load(Relaxed);
+        while s < u32::MAX - 1 {
+            match self.state.compare_exchange_weak(s, s + 1, Acquire, Relaxed) {
+                Ok(_) => {
+                    #[cfg(feature = "stats")]
+                    self.stats.record_acquire(None);
+                    #[cfg(feature = "lockdep")]
+                    crate::lockdep::acquired(&self.class);
+                    return Some(ReadGuard { rwlock: self });
+                }
+                Err(e) => s = e,
+            }
+        }
+        None
+    }
+
+    // リードロックかライトロックされていればNoneを返す
+    pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+        if !self.writer_lock.try_lock() {
+            return None;
+        }
+        if self
+            .state
+            .compare_exchange(0, u32::MAX, Acquire, Relaxed)
+            .is_ok()
+        {
+            #[cfg(feature = "stats")]
+            self.stats.record_acquire(None);
+            #[cfg(feature = "lockdep")]
+            crate::lockdep::acquired(&self.class);
+            Some(WriteGuard { rwlock: self })
+        } else {
+            unsafe { self.writer_lock.unlock() };
+            None
+        }
+    }
+
+    // Arcを保持する'staticなガードを返す。アンロックはガードのドロップで行う
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn read_arc(self: &Arc<Self>) -> ArcReadGuard<T> {
+        std::mem::forget(self.read());
+        // ガードはどのスレッドでドロップされるかわからないので、このスレッドの記録からは外す
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::released(&self.class);
+        ArcReadGuard {
+            rwlock: self.clone(),
+        }
+    }
+
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn write_arc(self: &Arc<Self>) -> ArcWriteGuard<T> {
+        std::mem::forget(self.write());
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::released(&self.class);
+        ArcWriteGuard {
+            rwlock: self.clone(),
+        }
+    }
+
+    // &mut selfなので他に参照がなく、ロックせずにアクセスできる
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    // 以下は呼び出した時点の状態。戻った後も同じ状態とは限らないのでテストやログで使う
+    // ライトロックされている場合は0を返す
+    pub fn reader_count(&self) -> u32 {
+        match self.state.load(Relaxed) {
+            u32::MAX => 0,
+            s => s,
+        }
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.state.load(Relaxed) == u32::MAX
+    }
+
+    // stateには待機中のライタが含まれないので、wait()の前後で別に数えている
+    pub fn has_waiting_writer(&self) -> bool {
+        self.waiting_writers.load(Relaxed) > 0
+    }
+
+    // このRwLockを作ってからの取得回数と待機時間。リードとライトの合計
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::Stats {
+        self.stats.get()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("RwLock");
+        let s = self.state.load(Relaxed);
+        // ライトロックされていなければリードロックして値を表示する
+        if s < u32::MAX - 1
+            && self
+                .state
+                .compare_exchange(s, s + 1, Acquire, Relaxed)
+                .is_ok()
+        {
+            // ガードのドロップで記録を消すので、read()と同じく取得したことを記録する
+            // 記録しないと、このスレッドがすでに持っているリードロックの記録が消えてしまう
+            #[cfg(feature = "lockdep")]
+            crate::lockdep::acquired(&self.class);
+            let guard = ReadGuard { rwlock: self };
+            d.field("data", &&*guard);
+            d.field("readers", &s);
+        } else {
+            d.field("data", &format_args!("<locked>"));
+        }
+        d.finish_non_exhaustive()
+    }
 }
 
-pub struct ReadGuard<'a, T> {
+pub struct ReadGuard<'a, T: ?Sized> {
     rwlock: &'a RwLock<T>,
 }
 
-impl<T> Deref for ReadGuard<'_, T> {
+impl<T: ?Sized> Deref for ReadGuard<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -58,21 +261,29 @@ impl<T> Deref for ReadGuard<'_, T> {
     }
 }
 
-impl<T> Drop for ReadGuard<'_, T> {
+impl<T: ?Sized> Drop for ReadGuard<'_, T> {
     fn drop(&mut self) {
-        if self.rwlock.state.fetch_sub(1, Release) == 1 {
-            // 待機中ライタがいればそれを起こす
-            // 待機中リーダがいないことは確定済み
-            wake_one(&self.rwlock.state);
+        match self.rwlock.state.fetch_sub(1, Release) {
+            1 => {
+                // 待機中ライタがいればそれを起こす
+                // 待機中リーダがいないことは確定済み
+                wake_one(&self.rwlock.state);
+            }
+            // リーダの数が上限に達していたので、待っているリーダがいるかもしれない
+            // ライタも同じアドレスで待っているので、すべて起こしてリーダを確実に起こす
+            s if s == u32::MAX - 1 => wake_all(&self.rwlock.state),
+            _ => {}
         }
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::released(&self.rwlock.class);
     }
 }
 
-pub struct WriteGuard<'a, T> {
+pub struct WriteGuard<'a, T: ?Sized> {
     rwlock: &'a RwLock<T>,
 }
 
-impl<T> Deref for WriteGuard<'_, T> {
+impl<T: ?Sized> Deref for WriteGuard<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -80,16 +291,199 @@ impl<T> Deref for WriteGuard<'_, T> {
     }
 }
 
-impl<T> DerefMut for WriteGuard<'_, T> {
+impl<T: ?Sized> DerefMut for WriteGuard<'_, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         unsafe { &mut *self.rwlock.value.get() }
     }
 }
 
-impl<T> Drop for WriteGuard<'_, T> {
+impl<T: ?Sized> Drop for WriteGuard<'_, T> {
     fn drop(&mut self) {
         self.rwlock.state.store(0, Release);
-        // 待機しているすべてのリーダまたは1つのライタをすべて起こす
+        // 待機しているすべてのリーダと、stateで待っている先頭のライタを起こす
         wake_all(&self.rwlock.state);
+        // 次のライタを先頭にする
+        unsafe { self.rwlock.writer_lock.unlock() };
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::released(&self.rwlock.class);
+    }
+}
+
+pub struct ArcReadGuard<T: ?Sized> {
+    rwlock: Arc<RwLock<T>>,
+}
+
+impl<T: ?Sized> Deref for ArcReadGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
     }
 }
+
+impl<T: ?Sized> Drop for ArcReadGuard<T> {
+    fn drop(&mut self) {
+        // ReadGuardを作って即座にドロップすることでアンロックする
+        drop(ReadGuard {
+            rwlock: &self.rwlock,
+        })
+    }
+}
+
+pub struct ArcWriteGuard<T: ?Sized> {
+    rwlock: Arc<RwLock<T>>,
+}
+
+impl<T: ?Sized> Deref for ArcWriteGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for ArcWriteGuard<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for ArcWriteGuard<T> {
+    fn drop(&mut self) {
+        drop(WriteGuard {
+            rwlock: &self.rwlock,
+        })
+    }
+}
+
+#[test]
+fn test_unsized() {
+    let rwlock: Arc<RwLock<[u8]>> = Arc::new(RwLock::new([1, 2, 3]));
+    rwlock.write()[0] = 10;
+    assert_eq!(*rwlock.read(), [10, 2, 3]);
+}
+
+#[test]
+fn test_arc_guards() {
+    use std::thread;
+
+    let rwlock = Arc::new(RwLock::new(0));
+
+    let mut w = rwlock.write_arc();
+    thread::spawn(move || *w += 1).join().unwrap();
+
+    let r1 = rwlock.read_arc();
+    let r2 = rwlock.read_arc();
+    let t = thread::spawn(move || *r1 + *r2);
+    assert_eq!(t.join().unwrap(), 2);
+
+    assert_eq!(*rwlock.write(), 1);
+}
+
+#[test]
+fn test_try_read_write() {
+    let rwlock = RwLock::new(0);
+    let r = rwlock.try_read().unwrap();
+    assert!(rwlock.try_read().is_some());
+    assert!(rwlock.try_write().is_none());
+    drop(r);
+    let mut w = rwlock.try_write().unwrap();
+    *w += 1;
+    assert!(rwlock.try_read().is_none());
+    assert!(rwlock.try_write().is_none());
+    drop(w);
+    assert_eq!(*rwlock.try_read().unwrap(), 1);
+}
+
+#[test]
+fn test_introspection() {
+    use std::thread;
+    use std::time::Duration;
+
+    let mut rwlock = RwLock::new(0);
+    *rwlock.get_mut() += 1;
+    assert_eq!(rwlock.reader_count(), 0);
+    assert!(!rwlock.is_write_locked());
+
+    thread::scope(|s| {
+        let r1 = rwlock.read();
+        let r2 = rwlock.read();
+        assert_eq!(rwlock.reader_count(), 2);
+        assert!(!rwlock.has_waiting_writer());
+        s.spawn(|| *rwlock.write() += 1);
+        while !rwlock.has_waiting_writer() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(r1);
+        drop(r2);
+    });
+    assert!(!rwlock.has_waiting_writer());
+
+    let w = rwlock.write();
+    assert!(rwlock.is_write_locked());
+    assert_eq!(rwlock.reader_count(), 0);
+    drop(w);
+    assert_eq!(rwlock.into_inner(), 2);
+}
+
+#[test]
+fn test_slow_writer_callback() {
+    use std::sync::atomic::AtomicU64;
+    use std::thread;
+
+    static SLOW_WRITES: AtomicU64 = AtomicU64::new(0);
+
+    let rwlock = RwLock::new(0).with_slow_writer_callback(Duration::from_millis(20), |waited| {
+        assert!(waited > Duration::from_millis(20));
+        SLOW_WRITES.fetch_add(1, Relaxed);
+    });
+    // 待たなければ呼ばれない
+    *rwlock.write() += 1;
+    assert_eq!(SLOW_WRITES.load(Relaxed), 0);
+
+    thread::scope(|s| {
+        let r = rwlock.read();
+        s.spawn(|| *rwlock.write() += 1);
+        thread::sleep(Duration::from_millis(50));
+        drop(r);
+    });
+    assert_eq!(SLOW_WRITES.load(Relaxed), 1);
+}
+
+#[test]
+fn test_reader_limit() {
+    use std::thread;
+    use std::time::Duration;
+
+    let rwlock = RwLock::new(0);
+    // 上限の直前までリードロックされていることにする
+    rwlock.state.store(u32::MAX - 2, Relaxed);
+    let r = rwlock.try_read().unwrap();
+    assert_eq!(rwlock.reader_count(), u32::MAX - 1);
+    // パニックせずに、減るまで待つ
+    assert!(rwlock.try_read().is_none());
+    thread::scope(|s| {
+        let t = s.spawn(|| *rwlock.read());
+        thread::sleep(Duration::from_millis(50));
+        assert!(!t.is_finished());
+        drop(r);
+        assert_eq!(t.join().unwrap(), 0);
+    });
+    assert_eq!(rwlock.state.load(Relaxed), u32::MAX - 2);
+    rwlock.state.store(0, Relaxed);
+}
+
+#[test]
+fn test_debug() {
+    let rwlock = RwLock::new(1);
+    assert_eq!(format!("{rwlock:?}"), "RwLock { data: 1, readers: 0, .. }");
+    let r = rwlock.read();
+    // 自分のリードロックは数に含めない
+    assert_eq!(format!("{rwlock:?}"), "RwLock { data: 1, readers: 1, .. }");
+    // 表示してもrのリードロックの記録は残る
+    #[cfg(feature = "lockdep")]
+    assert_eq!(crate::lockdep::held_count(), 1);
+    drop(r);
+    let _w = rwlock.write();
+    assert_eq!(format!("{rwlock:?}"), "RwLock { data: <locked>, .. }");
+}
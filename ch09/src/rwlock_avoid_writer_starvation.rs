@@ -1,129 +1,6 @@
-use atomic_wait::{wait, wake_all, wake_one};
-use std::cell::UnsafeCell;
-use std::ops::{Deref, DerefMut};
-use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::raw_rwlock::WriterPreferred;
 
-pub struct RwLock<T> {
-    // リードロックの数の2倍とライタが待機していれば+1
-    // ライタロックされている場合はu32:MAX
-    //
-    // リーダはstateが偶数なら+2してロックを取得し、奇数なら待機する
-    state: AtomicU32,
-    // ライタを起こす際にインクリメントする
-    writer_wake_counter: AtomicU32,
-    value: UnsafeCell<T>,
-}
-
-// 複数リーダが同時にデータにアクセスするため Sync が必要
-unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
-
-impl<T> RwLock<T> {
-    pub const fn new(value: T) -> Self {
-        Self {
-            state: AtomicU32::new(0),
-            writer_wake_counter: AtomicU32::new(0),
-            value: UnsafeCell::new(value),
-        }
-    }
-
-    pub fn read(&self) -> ReadGuard<T> {
-        let mut s = self.state.load(Relaxed);
-        loop {
-            if s % 2 == 0 {
-                assert!(s != u32::MAX - 2, "too many readers");
-                match self.state.compare_exchange_weak(s, s + 2, Acquire, Relaxed) {
-                    Ok(_) => return ReadGuard { rwlock: self },
-                    Err(e) => s = e,
-                }
-            }
-            if s % 2 == 1 {
-                wait(&self.state, s);
-                s = self.state.load(Relaxed);
-            }
-        }
-    }
-    pub fn write(&self) -> WriteGuard<T> {
-        let mut s = self.state.load(Relaxed);
-        loop {
-            // アンロックされていたらロックを試みる
-            if s <= 1 {
-                match self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
-                    Ok(_) => return WriteGuard { rwlock: self },
-                    Err(e) => {
-                        s = e;
-                        continue;
-                    }
-                }
-            }
-            // stateを奇数にして新しいリーダをブロックする
-            if s % 2 == 0 {
-                match self.state.compare_exchange(s, s + 1, Relaxed, Relaxed) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        s = e;
-                        continue;
-                    }
-                }
-            }
-            // まだロックされていたら待機
-            let w = self.writer_wake_counter.load(Acquire);
-            s = self.state.load(Relaxed);
-            if s >= 2 {
-                wait(&self.writer_wake_counter, w);
-                s = self.state.load(Relaxed);
-            }
-        }
-    }
-}
-
-pub struct ReadGuard<'a, T> {
-    rwlock: &'a RwLock<T>,
-}
-
-impl<T> Deref for ReadGuard<'_, T> {
-    type Target = T;
-
-    fn deref(&self) -> &T {
-        unsafe { &*self.rwlock.value.get() }
-    }
-}
-
-impl<T> Drop for ReadGuard<'_, T> {
-    fn drop(&mut self) {
-        if self.rwlock.state.fetch_sub(2, Release) == 3 {
-            // 3から1になった場合はRwLockがアンロックされ、さらに待機中のライタがいる
-            // このライタを起こす
-            self.rwlock.writer_wake_counter.fetch_add(1, Release);
-            wake_one(&self.rwlock.writer_wake_counter);
-        }
-    }
-}
-
-pub struct WriteGuard<'a, T> {
-    rwlock: &'a RwLock<T>,
-}
-
-impl<T> Deref for WriteGuard<'_, T> {
-    type Target = T;
-
-    fn deref(&self) -> &T {
-        unsafe { &*self.rwlock.value.get() }
-    }
-}
-
-impl<T> DerefMut for WriteGuard<'_, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.rwlock.value.get() }
-    }
-}
-
-impl<T> Drop for WriteGuard<'_, T> {
-    fn drop(&mut self) {
-        self.rwlock.state.store(0, Release);
-        self.rwlock.writer_wake_counter.fetch_add(1, Release);
-        wake_one(&self.rwlock.writer_wake_counter);
-        // 待機しているすべてのリーダまたは1つのライタをすべて起こす
-        wake_all(&self.rwlock.state);
-    }
-}
+// ライタが飢餓状態にならないRwLock
+// リーダはライタが待機していれば新たにロックを取得しない
+// 実装はrwlock_fairness.rsとraw_rwlock.rsでrwlock_no_busyloop.rsと共有している
+pub type RwLock<T> = crate::rwlock_fairness::RwLock<T, WriterPreferred>;
@@ -0,0 +1,165 @@
+use crate::raw_mutex::RawMutex;
+use crate::shard::{self, Shard};
+use atomic_wait::{wait, wake_all, wake_one};
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Release, SeqCst};
+
+// リーダの数をシャードに分けて数えるRwLock(Linuxのbrlockと同じ考え方)
+// リーダは自分のシャードのカウンタだけを書き換えるので、別のシャードのリーダとキャッシュラインを取り合わない
+// 代わりにライタはすべてのシャードを見て回るので、ライトロックはシャードの数だけ遅くなる
+pub struct BigReaderRwLock<T> {
+    // 各シャードのリードロックの数
+    shards: Box<[Shard<AtomicU32>]>,
+    // 1: ライタがいる(ライトロック中か、リーダが出ていくのを待っている)
+    writer: AtomicU32,
+    // ライタ同士の排他制御
+    writer_lock: RawMutex,
+    value: UnsafeCell<T>,
+}
+
+// 複数リーダが同時にデータにアクセスするため Sync が必要
+unsafe impl<T> Sync for BigReaderRwLock<T> where T: Send + Sync {}
+
+impl<T> BigReaderRwLock<T> {
+    // CPUの数だけシャードを作る
+    pub fn new(value: T) -> Self {
+        Self::with_shards(value, shard::default_count())
+    }
+
+    pub fn with_shards(value: T, n: usize) -> Self {
+        assert!(n > 0, "at least one shard is required");
+        Self {
+            shards: (0..n).map(|_| Shard(AtomicU32::new(0))).collect(),
+            writer: AtomicU32::new(0),
+            writer_lock: RawMutex::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        let shard = shard::current(self.shards.len());
+        let count = &self.shards[shard].0;
+        loop {
+            // SeqCst: ライタはwriterを1にしてからシャードを見るので、
+            // ここでwriterが0に見えれば、ライタはこのインクリメントを見て待つ
+            count.fetch_add(1, SeqCst);
+            if self.writer.load(SeqCst) == 0 {
+                return ReadGuard {
+                    rwlock: self,
+                    shard,
+                };
+            }
+            // ライタがいるので、取り消してライタが終わるまで待つ
+            self.unlock_read(shard);
+            wait(&self.writer, 1);
+        }
+    }
+
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        self.writer_lock.lock();
+        // 新しいリーダを止める
+        self.writer.store(1, SeqCst);
+        // すでにいるリーダがすべて出ていくのを待つ
+        for shard in self.shards.iter() {
+            loop {
+                let s = shard.0.load(SeqCst);
+                if s == 0 {
+                    break;
+                }
+                wait(&shard.0, s);
+            }
+        }
+        WriteGuard { rwlock: self }
+    }
+
+    fn unlock_read(&self, shard: usize) {
+        let count = &self.shards[shard].0;
+        // 最後のリーダがいなくなったときに、ライタがいればこのシャードで待っているので起こす
+        if count.fetch_sub(1, SeqCst) == 1 && self.writer.load(SeqCst) == 1 {
+            wake_one(count);
+        }
+    }
+}
+
+pub struct ReadGuard<'a, T> {
+    rwlock: &'a BigReaderRwLock<T>,
+    shard: usize,
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // 別のスレッドでドロップされても、インクリメントしたシャードをデクリメントする
+        self.rwlock.unlock_read(self.shard);
+    }
+}
+
+pub struct WriteGuard<'a, T> {
+    rwlock: &'a BigReaderRwLock<T>,
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.rwlock.writer.store(0, Release);
+        // 待っているリーダをすべて起こす
+        wake_all(&self.rwlock.writer);
+        unsafe { self.rwlock.writer_lock.unlock() };
+    }
+}
+
+#[test]
+fn test_big_reader_rwlock() {
+    use std::thread;
+
+    // スレッドよりシャードが少なくても、同じシャードを共有するだけで動く
+    for shards in [1, 3, 8] {
+        let rwlock = BigReaderRwLock::with_shards((0u32, 0u32), shards);
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let mut w = rwlock.write();
+                        w.0 += 1;
+                        w.1 += 1;
+                    }
+                });
+            }
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..5000 {
+                        let r = rwlock.read();
+                        assert_eq!(r.0, r.1);
+                    }
+                });
+            }
+        });
+        assert_eq!(rwlock.into_inner(), (3000, 3000));
+    }
+}
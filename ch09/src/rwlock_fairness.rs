@@ -0,0 +1,483 @@
+use crate::raw_rwlock::{FairnessPolicy, RawRwLock, WriterPreferred};
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// rwlock_no_busyloop.rsとrwlock_avoid_writer_starvation.rsのRwLock
+// 状態遷移はraw_rwlock.rsにあり、リーダとライタのどちらを優先するかをPで選ぶ
+// RwLock<[u8]>やRwLock<dyn Trait>にできるようにvalueは最後のフィールドにする
+pub struct RwLock<T: ?Sized, P> {
+    raw: RawRwLock<P>,
+    value: UnsafeCell<T>,
+}
+
+// 複数リーダが同時にデータにアクセスするため Sync が必要
+unsafe impl<T: ?Sized, P> Sync for RwLock<T, P> where T: Send + Sync {}
+
+impl<T, P: FairnessPolicy> RwLock<T, P> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            raw: RawRwLock::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized, P: FairnessPolicy> RwLock<T, P> {
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    // 以下は呼び出した時点の状態。戻った後も同じ状態とは限らないのでテストやログで使う
+    pub fn reader_count(&self) -> u32 {
+        self.raw.reader_count()
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.raw.is_locked_exclusive()
+    }
+
+    pub fn read(&self) -> ReadGuard<'_, T, P> {
+        self.raw.lock_shared();
+        ReadGuard { rwlock: self }
+    }
+
+    pub fn write(&self) -> WriteGuard<'_, T, P> {
+        self.raw.lock_exclusive();
+        WriteGuard { rwlock: self }
+    }
+
+    // ライトロックされているか、WriterPreferredでライタが待機していればNoneを返す
+    pub fn try_read(&self) -> Option<ReadGuard<'_, T, P>> {
+        if self.raw.try_lock_shared() {
+            Some(ReadGuard { rwlock: self })
+        } else {
+            None
+        }
+    }
+
+    // リードロックかライトロックされていればNoneを返す
+    // 待機しているライタがいても、アンロックされていればwrite()と同じく取得する
+    pub fn try_write(&self) -> Option<WriteGuard<'_, T, P>> {
+        if self.raw.try_lock_exclusive() {
+            Some(WriteGuard { rwlock: self })
+        } else {
+            None
+        }
+    }
+
+    // アップグレード可能なリードロックは同時に1つだけ取得でき、通常のリーダとは共存する
+    // ほかのライタが割り込めないので、読んだ内容を元に書き込むかどうかを決めてからアップグレードできる
+    pub fn upgradable_read(&self) -> UpgradableReadGuard<'_, T, P> {
+        self.raw.lock_upgradable();
+        UpgradableReadGuard { rwlock: self }
+    }
+
+    pub fn try_upgradable_read(&self) -> Option<UpgradableReadGuard<'_, T, P>> {
+        if self.raw.try_lock_upgradable() {
+            Some(UpgradableReadGuard { rwlock: self })
+        } else {
+            None
+        }
+    }
+
+    // Arcを保持する'staticなガードを返す。アンロックはガードのドロップで行う
+    pub fn read_arc(self: &Arc<Self>) -> ArcReadGuard<T, P> {
+        std::mem::forget(self.read());
+        ArcReadGuard {
+            rwlock: self.clone(),
+        }
+    }
+
+    pub fn write_arc(self: &Arc<Self>) -> ArcWriteGuard<T, P> {
+        std::mem::forget(self.write());
+        ArcWriteGuard {
+            rwlock: self.clone(),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T, WriterPreferred> {
+    // ReaderPreferredは待機中のライタを記録しないので、WriterPreferredのみ
+    pub fn has_waiting_writer(&self) -> bool {
+        self.raw.has_waiting_writer()
+    }
+
+    // すでにリードロックを持っているスレッドがもう一度リードロックする場合に使う
+    // read()はライタが待機していると待つので、そのライタが自分のリードロックを待っているとデッドロックする
+    // こちらは待機中のライタを追い越すので、新しいリーダが続くとライタが待たされる
+    // ReaderPreferredのread()はもともと待機中のライタを待たない
+    pub fn read_recursive(&self) -> ReadGuard<'_, T, WriterPreferred> {
+        self.raw.lock_shared_recursive();
+        ReadGuard { rwlock: self }
+    }
+}
+
+pub struct ReadGuard<'a, T: ?Sized, P: FairnessPolicy> {
+    rwlock: &'a RwLock<T, P>,
+}
+
+impl<T: ?Sized, P: FairnessPolicy> Deref for ReadGuard<'_, T, P> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T: ?Sized, P: FairnessPolicy> Drop for ReadGuard<'_, T, P> {
+    fn drop(&mut self) {
+        unsafe { self.rwlock.raw.unlock_shared() };
+    }
+}
+
+pub struct UpgradableReadGuard<'a, T: ?Sized, P: FairnessPolicy> {
+    rwlock: &'a RwLock<T, P>,
+}
+
+impl<'a, T: ?Sized, P: FairnessPolicy> UpgradableReadGuard<'a, T, P> {
+    // 通常のリーダがいなくなるまで待ってライトロックにする
+    pub fn upgrade(self) -> WriteGuard<'a, T, P> {
+        let rwlock = self.rwlock;
+        // アンロックせずにライトロックのガードに引き継ぐ
+        std::mem::forget(self);
+        unsafe { rwlock.raw.upgrade() };
+        WriteGuard { rwlock }
+    }
+
+    // 通常のリーダがいれば、アップグレード可能なリードロックのまま返す
+    pub fn try_upgrade(self) -> Result<WriteGuard<'a, T, P>, Self> {
+        if unsafe { self.rwlock.raw.try_upgrade() } {
+            let rwlock = self.rwlock;
+            std::mem::forget(self);
+            Ok(WriteGuard { rwlock })
+        } else {
+            Err(self)
+        }
+    }
+
+    // timeoutまでにアップグレードできなければ、アップグレード可能なリードロックのまま返す
+    // ロックを外していないので、ほかのライタに割り込まれずに再び試したり読み続けたりできる
+    pub fn try_upgrade_for(self, timeout: Duration) -> Result<WriteGuard<'a, T, P>, Self> {
+        let upgraded = match Instant::now().checked_add(timeout) {
+            Some(deadline) => unsafe { self.rwlock.raw.try_upgrade_until(deadline) },
+            // 期限を表せないほど長ければupgrade()と同じく待ち続ける
+            None => {
+                unsafe { self.rwlock.raw.upgrade() };
+                true
+            }
+        };
+        if upgraded {
+            let rwlock = self.rwlock;
+            std::mem::forget(self);
+            Ok(WriteGuard { rwlock })
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl<T: ?Sized, P: FairnessPolicy> Deref for UpgradableReadGuard<'_, T, P> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T: ?Sized, P: FairnessPolicy> Drop for UpgradableReadGuard<'_, T, P> {
+    fn drop(&mut self) {
+        unsafe { self.rwlock.raw.unlock_upgradable() };
+    }
+}
+
+pub struct WriteGuard<'a, T: ?Sized, P: FairnessPolicy> {
+    rwlock: &'a RwLock<T, P>,
+}
+
+impl<T: ?Sized, P: FairnessPolicy> Deref for WriteGuard<'_, T, P> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T: ?Sized, P: FairnessPolicy> DerefMut for WriteGuard<'_, T, P> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+impl<T: ?Sized, P: FairnessPolicy> Drop for WriteGuard<'_, T, P> {
+    fn drop(&mut self) {
+        unsafe { self.rwlock.raw.unlock_exclusive() };
+    }
+}
+
+pub struct ArcReadGuard<T: ?Sized, P: FairnessPolicy> {
+    rwlock: Arc<RwLock<T, P>>,
+}
+
+impl<T: ?Sized, P: FairnessPolicy> Deref for ArcReadGuard<T, P> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T: ?Sized, P: FairnessPolicy> Drop for ArcReadGuard<T, P> {
+    fn drop(&mut self) {
+        // ReadGuardを作って即座にドロップすることでアンロックする
+        drop(ReadGuard {
+            rwlock: &self.rwlock,
+        })
+    }
+}
+
+pub struct ArcWriteGuard<T: ?Sized, P: FairnessPolicy> {
+    rwlock: Arc<RwLock<T, P>>,
+}
+
+impl<T: ?Sized, P: FairnessPolicy> Deref for ArcWriteGuard<T, P> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T: ?Sized, P: FairnessPolicy> DerefMut for ArcWriteGuard<T, P> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+impl<T: ?Sized, P: FairnessPolicy> Drop for ArcWriteGuard<T, P> {
+    fn drop(&mut self) {
+        drop(WriteGuard {
+            rwlock: &self.rwlock,
+        })
+    }
+}
+
+#[test]
+fn test_rwlock() {
+    use crate::raw_rwlock::ReaderPreferred;
+    use std::thread;
+
+    fn count<P: FairnessPolicy>() {
+        let rwlock = RwLock::<_, P>::new((0, 0));
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let mut w = rwlock.write();
+                        w.0 += 1;
+                        w.1 += 1;
+                    }
+                });
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let r = rwlock.read();
+                        assert_eq!(r.0, r.1);
+                    }
+                });
+            }
+        });
+        assert_eq!(rwlock.into_inner(), (2000, 2000));
+    }
+
+    count::<ReaderPreferred>();
+    count::<WriterPreferred>();
+}
+
+#[test]
+fn test_try_read_write() {
+    use crate::raw_rwlock::ReaderPreferred;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread;
+    use std::time::Duration;
+
+    fn check<P: FairnessPolicy>() {
+        let rwlock = RwLock::<_, P>::new(0);
+        let r = rwlock.try_read().unwrap();
+        assert!(rwlock.try_write().is_none());
+        drop(r);
+        let w = rwlock.try_write().unwrap();
+        assert!(rwlock.try_read().is_none());
+        assert!(rwlock.is_write_locked());
+        drop(w);
+    }
+    check::<ReaderPreferred>();
+    check::<WriterPreferred>();
+
+    // ReaderPreferredは待機中のライタがいても新しいリーダを通す
+    // 待機中のライタはstateに何も残さないので、ライタがwrite()を呼んだ後であれば
+    // 待機に入る前でも後でもリーダから見た状態は同じになる
+    let rwlock = RwLock::<_, ReaderPreferred>::new(0);
+    let writing = AtomicBool::new(false);
+    thread::scope(|s| {
+        let r = rwlock.read();
+        s.spawn(|| {
+            writing.store(true, Relaxed);
+            *rwlock.write() += 1;
+        });
+        while !writing.load(Relaxed) {
+            thread::yield_now();
+        }
+        assert_eq!(rwlock.reader_count(), 1);
+        assert_eq!(*rwlock.try_read().unwrap(), 0);
+        drop(r);
+    });
+    assert_eq!(*rwlock.try_read().unwrap(), 1);
+
+    // WriterPreferredはライタが待機している間は新しいリーダを通さない
+    let rwlock = RwLock::<_, WriterPreferred>::new(0);
+    thread::scope(|s| {
+        let r = rwlock.read();
+        s.spawn(|| *rwlock.write() += 1);
+        while !rwlock.has_waiting_writer() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(rwlock.reader_count(), 1);
+        assert!(rwlock.try_read().is_none());
+        drop(r);
+    });
+    assert_eq!(*rwlock.try_read().unwrap(), 1);
+}
+
+#[test]
+fn test_read_recursive() {
+    use std::thread;
+    use std::time::Duration;
+
+    let rwlock = RwLock::<_, WriterPreferred>::new(0);
+    thread::scope(|s| {
+        let r1 = rwlock.read();
+        s.spawn(|| *rwlock.write() += 1);
+        while !rwlock.has_waiting_writer() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        // read()だとライタを待ってデッドロックする
+        let r2 = rwlock.read_recursive();
+        assert_eq!(*r2, 0);
+        assert_eq!(rwlock.reader_count(), 2);
+        assert!(rwlock.has_waiting_writer());
+        drop(r1);
+        drop(r2);
+    });
+    assert_eq!(rwlock.into_inner(), 1);
+}
+
+#[test]
+fn test_arc_guards() {
+    use std::thread;
+
+    let rwlock = Arc::new(RwLock::<_, WriterPreferred>::new(0));
+
+    let mut w = rwlock.write_arc();
+    thread::spawn(move || *w += 1).join().unwrap();
+
+    let r1 = rwlock.read_arc();
+    let r2 = rwlock.read_arc();
+    let t = thread::spawn(move || *r1 + *r2);
+    assert_eq!(t.join().unwrap(), 2);
+
+    assert_eq!(*rwlock.write(), 1);
+}
+
+#[test]
+fn test_unsized() {
+    use crate::raw_rwlock::ReaderPreferred;
+
+    let rwlock: Arc<RwLock<[u8], ReaderPreferred>> = Arc::new(RwLock::new([1, 2, 3]));
+    rwlock.write()[0] = 10;
+    assert_eq!(*rwlock.read(), [10, 2, 3]);
+}
+
+#[test]
+fn test_upgradable_read() {
+    use crate::raw_rwlock::ReaderPreferred;
+    use std::thread;
+
+    fn check<P: FairnessPolicy>() {
+        let rwlock = RwLock::<_, P>::new(0);
+
+        // 通常のリーダとは共存するが、ほかのアップグレード可能なリーダやライタとは共存しない
+        let u = rwlock.upgradable_read();
+        assert!(rwlock.try_upgradable_read().is_none());
+        assert!(rwlock.try_write().is_none());
+        let r = rwlock.try_read().unwrap();
+        let Err(u) = u.try_upgrade() else {
+            panic!("a reader is still holding the lock");
+        };
+        drop(r);
+        let mut w = u.try_upgrade().ok().unwrap();
+        *w += 1;
+        assert!(rwlock.try_read().is_none());
+        assert!(rwlock.try_upgradable_read().is_none());
+        drop(w);
+        assert_eq!(rwlock.reader_count(), 0);
+
+        // 読んだ値を元に書き込んでも、その間にほかのライタが割り込まない
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..500 {
+                        let u = rwlock.upgradable_read();
+                        let v = *u;
+                        *u.upgrade() = v + 1;
+                    }
+                });
+                s.spawn(|| {
+                    for _ in 0..500 {
+                        *rwlock.write() += 1;
+                        drop(rwlock.read());
+                    }
+                });
+            }
+        });
+        assert_eq!(rwlock.into_inner(), 4001);
+    }
+
+    check::<ReaderPreferred>();
+    check::<WriterPreferred>();
+}
+
+#[test]
+fn test_try_upgrade_for() {
+    use crate::raw_rwlock::ReaderPreferred;
+
+    fn check<P: FairnessPolicy>() {
+        let rwlock = RwLock::<_, P>::new(0);
+        let u = rwlock.upgradable_read();
+        let r = rwlock.read();
+
+        // リーダがいるのでタイムアウトして、アップグレード可能なリードロックのまま返ってくる
+        let Err(u) = u.try_upgrade_for(Duration::from_millis(20)) else {
+            panic!("upgrade should time out while a reader holds the lock");
+        };
+        assert_eq!(*u, 0);
+        // WriterPreferredでも、あきらめたアップグレードは新しいリーダを止めない
+        drop(rwlock.try_read().unwrap());
+        drop(r);
+        let Ok(mut w) = u.try_upgrade_for(Duration::from_millis(20)) else {
+            panic!("upgrade should succeed once the reader leaves");
+        };
+        *w += 1;
+        drop(w);
+        assert!(!rwlock.is_write_locked());
+        assert_eq!(*rwlock.read(), 1);
+    }
+
+    check::<ReaderPreferred>();
+    check::<WriterPreferred>();
+}
@@ -1,114 +1,6 @@
-use atomic_wait::{wait, wake_all, wake_one};
-use std::cell::UnsafeCell;
-use std::ops::{Deref, DerefMut};
-use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use crate::raw_rwlock::ReaderPreferred;
 
-pub struct RwLock<T> {
-    // リードロックの数。ライタロックの場合はu32:MAX
-    state: AtomicU32,
-    // ライタを起こす際にインクリメントする
-    writer_wake_counter: AtomicU32,
-    value: UnsafeCell<T>,
-}
-
-// 複数リーダが同時にデータにアクセスするため Sync が必要
-unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
-
-impl<T> RwLock<T> {
-    pub const fn new(value: T) -> Self {
-        Self {
-            state: AtomicU32::new(0),
-            writer_wake_counter: AtomicU32::new(0),
-            value: UnsafeCell::new(value),
-        }
-    }
-
-    pub fn read(&self) -> ReadGuard<T> {
-        let mut s = self.state.load(Relaxed);
-        loop {
-            if s < u32::MAX {
-                assert!(s != u32::MAX - 1, "too many readers");
-                match self.state.compare_exchange_weak(s, s + 1, Acquire, Relaxed) {
-                    Ok(_) => return ReadGuard { rwlock: self },
-                    Err(e) => s = e,
-                }
-            }
-            // RwLockがライトロックされている場合は wait() して後で再度試みる
-            if s == u32::MAX {
-                wait(&self.state, u32::MAX);
-                s = self.state.load(Relaxed);
-            }
-        }
-    }
-    pub fn write(&self) -> WriteGuard<T> {
-        while self
-            .state
-            .compare_exchange(0, u32::MAX, Acquire, Relaxed)
-            .is_err()
-        {
-            let w = self.writer_wake_counter.load(Acquire);
-            if self.state.load(Relaxed) != 0 {
-                // RwLockがまだロックされていたら待機する
-                // ただし、チェック後にwake通知が来ていない場合のみ
-                wait(&self.writer_wake_counter, w);
-            }
-        }
-        WriteGuard { rwlock: self }
-    }
-}
-
-pub struct ReadGuard<'a, T> {
-    rwlock: &'a RwLock<T>,
-}
-
-impl<T> Deref for ReadGuard<'_, T> {
-    type Target = T;
-
-    fn deref(&self) -> &T {
-        unsafe { &*self.rwlock.value.get() }
-    }
-}
-
-impl<T> Drop for ReadGuard<'_, T> {
-    fn drop(&mut self) {
-        if self.rwlock.state.fetch_sub(1, Release) == 1 {
-            // writer_wake_counterに対するAcquireロード操作が
-            // stateをアンロックした直後で待機しているライタを起こす前に行われる
-            // Releaseインクリメント操作との間に先行発生関係を形成する
-            //
-            // これによりwriteがwriter_wake_counterの値がインクリメントされた値を観測することなく、
-            // まだデクリメントさ荒れていないstateを観測することを保証する
-            self.rwlock.writer_wake_counter.fetch_add(1, Release);
-            wake_one(&self.rwlock.writer_wake_counter);
-        }
-    }
-}
-
-pub struct WriteGuard<'a, T> {
-    rwlock: &'a RwLock<T>,
-}
-
-impl<T> Deref for WriteGuard<'_, T> {
-    type Target = T;
-
-    fn deref(&self) -> &T {
-        unsafe { &*self.rwlock.value.get() }
-    }
-}
-
-impl<T> DerefMut for WriteGuard<'_, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.rwlock.value.get() }
-    }
-}
-
-impl<T> Drop for WriteGuard<'_, T> {
-    fn drop(&mut self) {
-        self.rwlock.state.store(0, Release);
-        self.rwlock.writer_wake_counter.fetch_add(1, Release);
-        wake_one(&self.rwlock.writer_wake_counter);
-        // 待機しているすべてのリーダまたは1つのライタをすべて起こす
-        wake_all(&self.rwlock.state);
-    }
-}
+// 待機中のリーダもライタもwait()で眠るRwLock
+// リーダはRwLockがライトロックされていなければ、ライタが待機していてもロックを取得する
+// 実装はrwlock_fairness.rsとraw_rwlock.rsでrwlock_avoid_writer_starvation.rsと共有している
+pub type RwLock<T> = crate::rwlock_fairness::RwLock<T, ReaderPreferred>;
@@ -0,0 +1,199 @@
+use atomic_wait::{wait, wake_all, wake_one};
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+
+// 下位2ビットは待機中または実行中のライタの情報、それより上はリーダの数
+const READER: u32 = 1 << 8;
+const WRITER_BITS: u32 = 0b11;
+// ライタがいる
+const PRESENT: u32 = 0b10;
+// ライタのフェーズ。連続するライタで交互に変わる
+const PHASE_ID: u32 = 0b01;
+
+// フェーズフェアなRwLock(Brandenburg, Andersonのphase-fair ticket lock)
+// リーダのフェーズとライタのフェーズを交互に繰り返す
+// - ライタが来たら、それ以降のリーダは現在のリーダのフェーズが終わるまで待つ
+// - ライタのフェーズが終わったら、その間に来たリーダは次のライタより先にすべて入る
+// rwlock_avoid_writer_starvation.rsはライタを優先するので、ライタが次々に来るとリーダは待ち続ける
+// こちらはリーダもライタも、最大で1回ずつ相手のフェーズを待てば入れる
+pub struct RwLock<T> {
+    // 来たリーダの数 * READER | ライタのビット
+    reader_in: AtomicU32,
+    // 出ていったリーダの数 * READER
+    reader_out: AtomicU32,
+    // ライタ同士はチケットロックで順番に並ぶ
+    writer_in: AtomicU32,
+    writer_out: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+// 複数リーダが同時にデータにアクセスするため Sync が必要
+unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            reader_in: AtomicU32::new(0),
+            reader_out: AtomicU32::new(0),
+            writer_in: AtomicU32::new(0),
+            writer_out: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        // SeqCst: 後からライタがreader_outを読む前に、この到着が見えるようにする
+        let w = self.reader_in.fetch_add(READER, SeqCst) & WRITER_BITS;
+        if w != 0 {
+            // ライタのフェーズが終わるまで待つ
+            // ライタのビットが消えるか、次のライタのフェーズに変われば入れる
+            // 次のライタは自分が入るまでreader_outを待つので、自分を追い越すことはない
+            loop {
+                let s = self.reader_in.load(Acquire);
+                if s & WRITER_BITS != w {
+                    break;
+                }
+                wait(&self.reader_in, s);
+            }
+        }
+        ReadGuard { rwlock: self }
+    }
+
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        // ほかのライタの後ろに並ぶ
+        let ticket = self.writer_in.fetch_add(1, Relaxed);
+        loop {
+            let s = self.writer_out.load(Acquire);
+            if s == ticket {
+                break;
+            }
+            wait(&self.writer_out, s);
+        }
+        // ライタが来たことを知らせて、新しいリーダを止める
+        // 戻り値は、それまでに来たリーダの数
+        let w = PRESENT | (ticket & PHASE_ID);
+        let readers = self.reader_in.fetch_add(w, SeqCst) & !WRITER_BITS;
+        // すでに来ているリーダがすべて出ていくまで待つ
+        loop {
+            let s = self.reader_out.load(SeqCst);
+            if s == readers {
+                break;
+            }
+            wait(&self.reader_out, s);
+        }
+        WriteGuard { rwlock: self }
+    }
+}
+
+pub struct ReadGuard<'a, T> {
+    rwlock: &'a RwLock<T>,
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.rwlock.reader_out.fetch_add(READER, SeqCst);
+        // ライタがいれば、reader_outを待っているかもしれないので起こす
+        // ライタはreader_inを書き換えてからreader_outを読むので、
+        // ここでビットが見えなければ、ライタはこのインクリメントを見ている
+        if self.rwlock.reader_in.load(SeqCst) & PRESENT != 0 {
+            wake_one(&self.rwlock.reader_out);
+        }
+    }
+}
+
+pub struct WriteGuard<'a, T> {
+    rwlock: &'a RwLock<T>,
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        // ライタのフェーズを終えて、待っているリーダをすべて入れる
+        self.rwlock.reader_in.fetch_and(!WRITER_BITS, Release);
+        wake_all(&self.rwlock.reader_in);
+        // 次のライタの番にする
+        // 次のライタはリーダが出ていくまで待つので、ここで入れたリーダが先に入る
+        self.rwlock.writer_out.fetch_add(1, Release);
+        wake_all(&self.rwlock.writer_out);
+    }
+}
+
+#[test]
+fn test_phase_order() {
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    let rwlock = RwLock::new(());
+    let order = Mutex::new(Vec::new());
+    thread::scope(|s| {
+        let w = rwlock.write();
+        s.spawn(|| {
+            let _w = rwlock.write();
+            order.lock().unwrap().push("writer");
+        });
+        thread::sleep(Duration::from_millis(50));
+        s.spawn(|| {
+            let _r = rwlock.read();
+            order.lock().unwrap().push("reader");
+        });
+        thread::sleep(Duration::from_millis(50));
+        // ライタが先に並んでいても、ライタのフェーズの後はリーダのフェーズになる
+        drop(w);
+    });
+    assert_eq!(order.into_inner().unwrap(), ["reader", "writer"]);
+}
+
+#[test]
+fn test_stress() {
+    use std::thread;
+
+    // リーダもライタも休まずにロックし続けても、どちらも決まった回数を終えられる
+    // ライタ優先やリーダ優先のRwLockでは、片方がもう片方を待ち続けることがある
+    let rwlock = RwLock::new((0u32, 0u32));
+    thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    let mut w = rwlock.write();
+                    w.0 += 1;
+                    w.1 += 1;
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    let r = rwlock.read();
+                    assert_eq!(r.0, r.1);
+                }
+            });
+        }
+    });
+    assert_eq!(rwlock.into_inner(), (3000, 3000));
+}
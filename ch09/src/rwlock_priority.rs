@@ -0,0 +1,208 @@
+use atomic_wait::{wait, wake_all};
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Normal,
+    // チェックポイントなど、ほかのライタより先に書き込みたいライタ
+    High,
+}
+
+// ライトロックに優先度をつけられるRwLock
+// Highのライタが待っている間は、Normalのライタも新しいリーダもロックを取得しない
+// Highのライタ同士、Normalのライタ同士の順番は決まらない
+pub struct RwLock<T> {
+    // リードロックの数。ライタロックの場合はu32:MAX
+    state: AtomicU32,
+    // ロックを待っているHighのライタの数
+    // Normalのライタとリーダは0になるまでこのアドレスで待つ
+    high_writers: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+// 複数リーダが同時にデータにアクセスするため Sync が必要
+unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            high_writers: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    // Highのライタがいなくなるまで待つ
+    fn wait_for_high_writers(&self) {
+        loop {
+            let h = self.high_writers.load(Acquire);
+            if h == 0 {
+                return;
+            }
+            wait(&self.high_writers, h);
+        }
+    }
+
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        loop {
+            self.wait_for_high_writers();
+            let s = self.state.load(Relaxed);
+            if s < u32::MAX - 1 {
+                if self
+                    .state
+                    .compare_exchange_weak(s, s + 1, Acquire, Relaxed)
+                    .is_ok()
+                {
+                    return ReadGuard { rwlock: self };
+                }
+                continue;
+            }
+            // ライトロックされているか、リーダの数が上限に達している
+            wait(&self.state, s);
+        }
+    }
+
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        self.write_with_priority(Priority::Normal)
+    }
+
+    pub fn write_with_priority(&self, priority: Priority) -> WriteGuard<'_, T> {
+        match priority {
+            Priority::High => {
+                // これ以降、Normalのライタと新しいリーダはこのライタを待つ
+                self.high_writers.fetch_add(1, Relaxed);
+                while let Err(s) = self.state.compare_exchange(0, u32::MAX, Acquire, Relaxed) {
+                    wait(&self.state, s);
+                }
+                if self.high_writers.fetch_sub(1, Release) == 1 {
+                    wake_all(&self.high_writers);
+                }
+            }
+            Priority::Normal => loop {
+                self.wait_for_high_writers();
+                match self.state.compare_exchange(0, u32::MAX, Acquire, Relaxed) {
+                    Ok(_) => break,
+                    Err(s) => wait(&self.state, s),
+                }
+            },
+        }
+        WriteGuard { rwlock: self }
+    }
+}
+
+pub struct ReadGuard<'a, T> {
+    rwlock: &'a RwLock<T>,
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // 最後のリーダ、または上限から減らしたリーダが待機中のスレッドを起こす
+        // 1つだけ起こすと、Highのライタではなく、Highのライタを待つだけのスレッドを起こすかもしれないので、
+        // すべて起こしてHighのライタを確実に起こす
+        let s = self.rwlock.state.fetch_sub(1, Release);
+        if s == 1 || s == u32::MAX - 1 {
+            wake_all(&self.rwlock.state);
+        }
+    }
+}
+
+pub struct WriteGuard<'a, T> {
+    rwlock: &'a RwLock<T>,
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.rwlock.state.store(0, Release);
+        // 待機しているすべてのリーダとライタを起こす
+        wake_all(&self.rwlock.state);
+    }
+}
+
+#[test]
+fn test_priority() {
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    let rwlock = RwLock::new(());
+    let order = Mutex::new(Vec::new());
+    thread::scope(|s| {
+        let r = rwlock.read();
+        s.spawn(|| {
+            let _w = rwlock.write();
+            order.lock().unwrap().push("normal");
+        });
+        thread::sleep(Duration::from_millis(50));
+        s.spawn(|| {
+            let _w = rwlock.write_with_priority(Priority::High);
+            order.lock().unwrap().push("high");
+        });
+        thread::sleep(Duration::from_millis(50));
+        // Highのライタが待っている間は、新しいリーダも入れない
+        s.spawn(|| {
+            let _r = rwlock.read();
+            order.lock().unwrap().push("reader");
+        });
+        thread::sleep(Duration::from_millis(50));
+        // Normalのライタが先に待っていても、Highのライタが先に入る
+        drop(r);
+    });
+    let order = order.into_inner().unwrap();
+    assert_eq!(order[0], "high");
+    assert_eq!(order.len(), 3);
+}
+
+#[test]
+fn test_rwlock_priority() {
+    use std::thread;
+
+    let rwlock = RwLock::new((0, 0));
+    thread::scope(|s| {
+        for priority in [Priority::Normal, Priority::High, Priority::Normal] {
+            let rwlock = &rwlock;
+            s.spawn(move || {
+                for _ in 0..1000 {
+                    let mut w = rwlock.write_with_priority(priority);
+                    w.0 += 1;
+                    w.1 += 1;
+                }
+            });
+        }
+        s.spawn(|| {
+            for _ in 0..1000 {
+                let r = rwlock.read();
+                assert_eq!(r.0, r.1);
+            }
+        });
+    });
+    assert_eq!(rwlock.into_inner(), (3000, 3000));
+}
@@ -0,0 +1,189 @@
+use crate::condvar::Condvar;
+use crate::mutex::Mutex;
+
+pub struct Semaphore {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    permits: usize,
+    // 次に待ち行列に並ぶスレッドに渡すチケット
+    next_ticket: u64,
+    // 許可を取得できる順番のチケット
+    // 先頭のスレッドが取得するまで後ろのスレッドは許可を取得できないので、
+    // 大きなacquire_manyが小さなacquireに追い越され続けることはない
+    now_serving: u64,
+    closed: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AcquireError;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryAcquireError {
+    Closed,
+    NoPermits,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                permits,
+                next_ticket: 0,
+                now_serving: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn acquire(&self) -> Result<SemaphorePermit<'_>, AcquireError> {
+        self.acquire_many(1)
+    }
+
+    pub fn acquire_many(&self, n: usize) -> Result<SemaphorePermit<'_>, AcquireError> {
+        let mut s = self.state.lock();
+        if s.closed {
+            return Err(AcquireError);
+        }
+        let ticket = s.next_ticket;
+        s.next_ticket += 1;
+        // 自分の順番が来て、かつ許可が足りるまで待機する
+        while !s.closed && (s.now_serving != ticket || s.permits < n) {
+            s = self.changed.wait(s);
+        }
+        // close()されたら順番を待っているすべてのスレッドがエラーを返す
+        if s.closed {
+            return Err(AcquireError);
+        }
+        s.permits -= n;
+        s.now_serving += 1;
+        drop(s);
+        // 次の順番のスレッドも取得できるかもしれない
+        self.changed.notify_all();
+        Ok(SemaphorePermit {
+            semaphore: self,
+            permits: n,
+        })
+    }
+
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    pub fn try_acquire_many(&self, n: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        let mut s = self.state.lock();
+        if s.closed {
+            return Err(TryAcquireError::Closed);
+        }
+        // 待機中のスレッドがいれば追い越さない
+        if s.now_serving != s.next_ticket || s.permits < n {
+            return Err(TryAcquireError::NoPermits);
+        }
+        s.permits -= n;
+        Ok(SemaphorePermit {
+            semaphore: self,
+            permits: n,
+        })
+    }
+
+    pub fn add_permits(&self, n: usize) {
+        self.state.lock().permits += n;
+        self.changed.notify_all();
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    // 待機中のすべてのスレッドを起こしてエラーを返させる
+    // 以降のacquireもすべて失敗する
+    pub fn close(&self) {
+        self.state.lock().closed = true;
+        self.changed.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+}
+
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    // 許可を返却せずに手放す
+    pub fn forget(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(self.permits);
+    }
+}
+
+#[test]
+fn test_semaphore_fifo() {
+    use std::thread;
+    use std::time::Duration;
+
+    let semaphore = Semaphore::new(0);
+    let order = Mutex::new(Vec::new());
+
+    thread::scope(|s| {
+        // 先に並んだacquire_many(2)が後から来たacquire(1)に追い越されない
+        s.spawn(|| {
+            let _p = semaphore.acquire_many(2).unwrap();
+            order.lock().push("many");
+        });
+        thread::sleep(Duration::from_millis(100));
+        s.spawn(|| {
+            let _p = semaphore.acquire().unwrap();
+            order.lock().push("one");
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        semaphore.add_permits(1);
+        assert_eq!(
+            semaphore.try_acquire().err(),
+            Some(TryAcquireError::NoPermits)
+        );
+        thread::sleep(Duration::from_millis(100));
+        assert!(order.lock().is_empty());
+
+        semaphore.add_permits(1);
+    });
+
+    assert_eq!(*order.lock(), ["many", "one"]);
+    assert_eq!(semaphore.available_permits(), 2);
+    assert!(semaphore.try_acquire_many(2).is_ok());
+    assert_eq!(
+        semaphore.try_acquire_many(3).err(),
+        Some(TryAcquireError::NoPermits)
+    );
+}
+
+#[test]
+fn test_semaphore_close() {
+    use std::thread;
+    use std::time::Duration;
+
+    let semaphore = Semaphore::new(1);
+    let _p = semaphore.acquire().unwrap();
+
+    thread::scope(|s| {
+        let waiter = s.spawn(|| semaphore.acquire_many(1).map(|_| ()));
+        thread::sleep(Duration::from_millis(100));
+        semaphore.close();
+        assert_eq!(waiter.join().unwrap(), Err(AcquireError));
+    });
+
+    assert!(semaphore.is_closed());
+    assert_eq!(semaphore.try_acquire().err(), Some(TryAcquireError::Closed));
+}
@@ -0,0 +1,155 @@
+use crate::raw_mutex::RawMutex;
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{fence, AtomicU32};
+
+// シーケンスロック
+// ライタは書き込みの前後でseqをインクリメントし、リーダは読む前後でseqが変わっていないかを確かめる
+// リーダは何も書き込まないので、リーダが多くてもキャッシュラインを取り合わない
+// 代わりにリーダは書き込み途中の値を読むことがあるので、コピーして確かめてから使えるT: Copyに限る
+pub struct SeqLock<T> {
+    // 偶数: 書き込み中のライタなし
+    // 奇数: 書き込み中
+    seq: AtomicU32,
+    // ライタ同士の排他制御。リーダは使わない
+    writer: RawMutex,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+            writer: RawMutex::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> T {
+        loop {
+            // Acquire: 直前のライタの書き込みを見る
+            let s1 = self.seq.load(Acquire);
+            if s1 % 2 == 1 {
+                // 書き込み中なので終わるまで待つ
+                std::hint::spin_loop();
+                continue;
+            }
+            // 書き込み途中の値を読むかもしれないので、確かめるまではMaybeUninitのまま扱う
+            // 壊れた値がboolやenumとして不正なビットパターンでも、初期化済みとみなさなければ問題ない
+            let value = unsafe { ptr::read_volatile(self.value.get() as *const MaybeUninit<T>) };
+            // Acquireフェンス: 上の読み込みがseqの2回目のロードより後に並べ替えられないようにする
+            // 読んだ値が途中でライタに書き換えられていれば、2回目のロードでそのライタのインクリメントが見える
+            fence(Acquire);
+            let s2 = self.seq.load(Relaxed);
+            if s1 == s2 {
+                return unsafe { value.assume_init() };
+            }
+        }
+    }
+
+    pub fn write(&self, value: T) {
+        self.update(|_| value);
+    }
+
+    // 現在の値を元に書き込む。fを呼んでから書き込むまでの間にほかのライタは書き込まない
+    // fがパニックしたら何も書き込まない。seqは奇数にする前なので、リーダも次のライタも待たされない
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        self.writer.lock();
+        let _unlock = WriterGuard(&self.writer);
+        // ライタはwriterで排他されているので、値を書き換えたりseqを変更するのはこのスレッドだけ
+        let new = f(unsafe { *self.value.get() });
+        let s = self.seq.load(Relaxed);
+        self.seq.store(s.wrapping_add(1), Relaxed);
+        // Releaseフェンス: 奇数にしたことが値の書き込みより先に見えるようにする
+        // 書き換え中の値を読んだリーダは、2回目のロードで少なくとも奇数の値を見る
+        fence(Release);
+        unsafe { ptr::write_volatile(self.value.get(), new) };
+        // Release: 書き込んだ値を次のリーダに見せる
+        self.seq.store(s.wrapping_add(2), Release);
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+// ドロップされるとwriterをアンロックする
+// update()のfがパニックしても、ほかのライタがロックできなくなることはない
+struct WriterGuard<'a>(&'a RawMutex);
+
+impl Drop for WriterGuard<'_> {
+    fn drop(&mut self) {
+        // update()でロックしたものだけを渡す
+        unsafe { self.0.unlock() };
+    }
+}
+
+#[test]
+fn test_seqlock() {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    // 要素がすべて一致していれば、書き込み途中の値を返していない
+    let lock = SeqLock::new([0u64; 8]);
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        for _ in 0..2 {
+            s.spawn(|| {
+                for _ in 0..10000 {
+                    lock.update(|v| [v[0] + 1; 8]);
+                }
+            });
+        }
+        for _ in 0..2 {
+            s.spawn(|| {
+                let mut last = 0;
+                while !done.load(Relaxed) {
+                    let v = lock.read();
+                    assert!(v.iter().all(|&x| x == v[0]), "torn read: {v:?}");
+                    // ライタの書き込みはseqの順に見える
+                    assert!(v[0] >= last);
+                    last = v[0];
+                }
+            });
+        }
+        while lock.read()[0] < 20000 {
+            thread::yield_now();
+        }
+        done.store(true, Relaxed);
+    });
+    assert_eq!(lock.into_inner(), [20000; 8]);
+}
+
+#[test]
+fn test_seqlock_write() {
+    let mut lock = SeqLock::new((1, true));
+    lock.write((2, false));
+    assert_eq!(lock.read(), (2, false));
+    lock.get_mut().0 = 3;
+    assert_eq!(lock.read(), (3, false));
+    assert_eq!(lock.seq.load(Relaxed), 2);
+}
+
+#[test]
+fn test_update_panic() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let lock = SeqLock::new(1);
+    let r = catch_unwind(AssertUnwindSafe(|| {
+        lock.update(|_| panic!("update failed"))
+    }));
+    assert!(r.is_err());
+    // 書き込まれず、ロックも手放されているので次のライタが書き込める
+    assert_eq!(lock.read(), 1);
+    lock.update(|v| v + 1);
+    assert_eq!(lock.read(), 2);
+    assert_eq!(lock.seq.load(Relaxed), 2);
+}
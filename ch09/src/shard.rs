@@ -0,0 +1,40 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+// rwlock_big_reader.rsとsharded_lock.rsで、リーダをスレッドごとのシャードに振り分ける
+
+// 隣のシャードと同じキャッシュラインに載らないようにする
+#[repr(align(128))]
+pub(crate) struct Shard<T>(pub(crate) T);
+
+// スレッドごとに順番に割り当てる番号。シャード数で割った余りをシャードとして使う
+static NEXT_THREAD_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static THREAD_INDEX: usize = NEXT_THREAD_INDEX.fetch_add(1, Relaxed);
+}
+
+// シャード数を指定しない場合はCPUの数だけ作る
+pub(crate) fn default_count() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+// 現在のスレッドが使うシャードの番号
+pub(crate) fn current(shards: usize) -> usize {
+    THREAD_INDEX.with(|i| *i) % shards
+}
+
+#[test]
+fn test_current() {
+    use std::thread;
+
+    // 同じスレッドは常に同じシャードを使う
+    let shard = current(4);
+    assert!(shard < 4);
+    assert_eq!(current(4), shard);
+    assert_eq!(current(1), 0);
+    // 続けて作ったスレッドは別の番号を割り当てられる
+    let a = thread::spawn(|| THREAD_INDEX.with(|i| *i)).join().unwrap();
+    let b = thread::spawn(|| THREAD_INDEX.with(|i| *i)).join().unwrap();
+    assert_ne!(a, b);
+}
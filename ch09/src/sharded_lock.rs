@@ -0,0 +1,162 @@
+use crate::rwlock::{self, RwLock};
+use crate::shard::{self, Shard};
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+
+// crossbeamのShardedLockと同じく、このクレートのRwLockを並べたもの
+// リーダは自分のシャードのRwLockだけをリードロックするので、ほかのシャードのリーダと競合しない
+// ライタはすべてのシャードを先頭から順にライトロックする
+// rwlock_big_reader.rsはシャードのカウンタを自分で管理するが、こちらはRwLockを組み合わせるだけで作れる
+pub struct ShardedLock<T> {
+    shards: Box<[Shard<RwLock<()>>]>,
+    value: UnsafeCell<T>,
+}
+
+// 複数リーダが同時にデータにアクセスするため Sync が必要
+unsafe impl<T> Sync for ShardedLock<T> where T: Send + Sync {}
+
+impl<T> ShardedLock<T> {
+    // CPUの数だけシャードを作る
+    pub fn new(value: T) -> Self {
+        Self::with_shards(value, shard::default_count())
+    }
+
+    pub fn with_shards(value: T, n: usize) -> Self {
+        assert!(n > 0, "at least one shard is required");
+        Self {
+            shards: (0..n).map(|_| Shard(RwLock::new(()))).collect(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        let shard = shard::current(self.shards.len());
+        ReadGuard {
+            rwlock: self,
+            _guard: self.shards[shard].0.read(),
+        }
+    }
+
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        // ライタ同士が逆順にロックしてデッドロックしないように、常に先頭から順にロックする
+        WriteGuard {
+            rwlock: self,
+            _guards: self.shards.iter().map(|shard| shard.0.write()).collect(),
+        }
+    }
+
+    pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+        let shard = shard::current(self.shards.len());
+        let guard = self.shards[shard].0.try_read()?;
+        Some(ReadGuard {
+            rwlock: self,
+            _guard: guard,
+        })
+    }
+
+    // 途中のシャードで失敗したら、それまでにロックしたシャードはガードのドロップでアンロックされる
+    pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+        let guards = self
+            .shards
+            .iter()
+            .map(|shard| shard.0.try_write())
+            .collect::<Option<Vec<_>>>()?;
+        Some(WriteGuard {
+            rwlock: self,
+            _guards: guards,
+        })
+    }
+}
+
+pub struct ReadGuard<'a, T> {
+    rwlock: &'a ShardedLock<T>,
+    // ドロップするとシャードのリードロックを外す
+    _guard: rwlock::ReadGuard<'a, ()>,
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+pub struct WriteGuard<'a, T> {
+    rwlock: &'a ShardedLock<T>,
+    // ドロップするとすべてのシャードのライトロックを外す
+    _guards: Vec<rwlock::WriteGuard<'a, ()>>,
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+#[test]
+fn test_sharded_lock() {
+    use std::sync::Barrier;
+    use std::thread;
+
+    // シャードより多いスレッドでリードロックするので、同じシャードを共有するリーダもいる
+    // どのシャードにリーダが残っていても、ライタはすべてのシャードをロックできない
+    let lock = ShardedLock::with_shards(0, 3);
+    let barrier = Barrier::new(9);
+    thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| {
+                let r = lock.read();
+                barrier.wait();
+                assert_eq!(*r, 0);
+                barrier.wait();
+            });
+        }
+        barrier.wait();
+        assert!(lock.try_write().is_none());
+        barrier.wait();
+    });
+    *lock.write() += 1;
+
+    // ライタはすべてのシャードをロックしているので、どのスレッドのリーダも入れない
+    let w = lock.write();
+    thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| assert!(lock.try_read().is_none()));
+        }
+    });
+    drop(w);
+    assert_eq!(lock.into_inner(), 1);
+}
+
+#[test]
+fn test_try_read_write() {
+    let lock = ShardedLock::with_shards(0, 4);
+    let r = lock.read();
+    // 1つのシャードがリードロックされていれば、ライタはすべてをロックできない
+    assert!(lock.try_write().is_none());
+    assert!(lock.try_read().is_some());
+    drop(r);
+
+    let mut w = lock.try_write().unwrap();
+    *w += 1;
+    assert!(lock.try_read().is_none());
+    drop(w);
+    assert_eq!(*lock.read(), 1);
+}
@@ -0,0 +1,171 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::{Duration, Instant};
+
+// ロックの取得状況を数える
+// ロックの状態とは独立した統計なので、すべてRelaxedでよい
+pub struct LockStats {
+    uncontended: AtomicU64,
+    contended: AtomicU64,
+    waits: AtomicU64,
+    wait_nanos: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stats {
+    // 待機せずに取得できた回数
+    pub uncontended: u64,
+    // 待機してから取得できた回数
+    pub contended: u64,
+    // wait()を呼んだ回数。誤って起こされた場合も含む
+    pub waits: u64,
+    // 最初のwait()から取得までにかかった時間の合計
+    pub wait_time: Duration,
+}
+
+impl LockStats {
+    pub const fn new() -> Self {
+        Self {
+            uncontended: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            waits: AtomicU64::new(0),
+            wait_nanos: AtomicU64::new(0),
+        }
+    }
+
+    // wait()の直前に呼ぶ
+    // 競合していない場合は時刻を取得しないように、最初の待機で開始時刻を記録する
+    pub fn record_wait(&self, wait_start: &mut Option<Instant>) {
+        wait_start.get_or_insert_with(Instant::now);
+        self.waits.fetch_add(1, Relaxed);
+    }
+
+    // ロックを取得したときに呼ぶ
+    pub fn record_acquire(&self, wait_start: Option<Instant>) {
+        match wait_start {
+            None => {
+                self.uncontended.fetch_add(1, Relaxed);
+            }
+            Some(start) => {
+                self.contended.fetch_add(1, Relaxed);
+                add_nanos(&self.wait_nanos, start.elapsed());
+            }
+        }
+    }
+
+    pub fn get(&self) -> Stats {
+        Stats {
+            uncontended: self.uncontended.load(Relaxed),
+            contended: self.contended.load(Relaxed),
+            waits: self.waits.load(Relaxed),
+            wait_time: Duration::from_nanos(self.wait_nanos.load(Relaxed)),
+        }
+    }
+}
+
+// condvar_opt.rsのCondvarで待機した回数と通知した回数を数える
+// notify_one()とnotify_all()の使い分けを調整するために使う
+pub struct CondvarWaitStats {
+    waits: AtomicU64,
+    notifications: AtomicU64,
+    spurious_wakeups: AtomicU64,
+    wait_nanos: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CondvarStats {
+    // wait()を呼んだ回数
+    pub waits: u64,
+    // notify_one()とnotify_all()を呼んだ回数。待機スレッドがいなかった場合も含む
+    pub notifications: u64,
+    // 待機が終わらないのに起こされた回数
+    // wait()では通知されずに戻った回数、wait_while()では条件を満たしておらずもう一度待機した回数
+    pub spurious_wakeups: u64,
+    // wait()で待機していた時間の合計
+    pub wait_time: Duration,
+}
+
+// 待機時間をナノ秒で足す。u64に収まらなければ上限で止める
+// 回数と違って長い待機が続くと溢れうるので、巻き戻って小さな値にならないようにする
+fn add_nanos(total: &AtomicU64, d: Duration) {
+    let nanos = d.as_nanos().try_into().unwrap_or(u64::MAX);
+    let _ = total.fetch_update(Relaxed, Relaxed, |t| Some(t.saturating_add(nanos)));
+}
+
+impl CondvarWaitStats {
+    pub const fn new() -> Self {
+        Self {
+            waits: AtomicU64::new(0),
+            notifications: AtomicU64::new(0),
+            spurious_wakeups: AtomicU64::new(0),
+            wait_nanos: AtomicU64::new(0),
+        }
+    }
+
+    // wait()から戻ったときに、待機を始めた時刻を渡して呼ぶ
+    pub fn record_wait(&self, start: Instant) {
+        self.waits.fetch_add(1, Relaxed);
+        add_nanos(&self.wait_nanos, start.elapsed());
+    }
+
+    pub fn record_notify(&self) {
+        self.notifications.fetch_add(1, Relaxed);
+    }
+
+    pub fn record_spurious_wakeup(&self) {
+        self.spurious_wakeups.fetch_add(1, Relaxed);
+    }
+
+    pub fn get(&self) -> CondvarStats {
+        CondvarStats {
+            waits: self.waits.load(Relaxed),
+            notifications: self.notifications.load(Relaxed),
+            spurious_wakeups: self.spurious_wakeups.load(Relaxed),
+            wait_time: Duration::from_nanos(self.wait_nanos.load(Relaxed)),
+        }
+    }
+}
+
+#[test]
+fn test_stats() {
+    use crate::mutex::Mutex;
+    use crate::rwlock::RwLock;
+    use std::thread;
+
+    let mutex = Mutex::new(0);
+    let rwlock = RwLock::new(0);
+    *mutex.lock() += 1;
+    *rwlock.write() += 1;
+    assert_eq!(
+        mutex.stats(),
+        Stats {
+            uncontended: 1,
+            ..Stats::default()
+        }
+    );
+
+    thread::scope(|s| {
+        let m = mutex.lock();
+        let w = rwlock.write();
+        s.spawn(|| *mutex.lock() += 1);
+        s.spawn(|| *rwlock.read());
+        thread::sleep(Duration::from_millis(100));
+        drop(m);
+        drop(w);
+    });
+
+    for stats in [mutex.stats(), rwlock.stats()] {
+        assert_eq!(stats.uncontended, 2);
+        assert_eq!(stats.contended, 1);
+        assert!(stats.waits >= 1);
+        assert!(stats.wait_time >= Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_wait_time_saturates() {
+    let stats = LockStats::new();
+    add_nanos(&stats.wait_nanos, Duration::MAX);
+    add_nanos(&stats.wait_nanos, Duration::from_secs(1));
+    assert_eq!(stats.get().wait_time, Duration::from_nanos(u64::MAX));
+}
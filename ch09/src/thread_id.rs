@@ -0,0 +1,20 @@
+// スレッドごとに異なる0以外の値
+// スレッドローカル変数のアドレスは生存しているスレッドの間で重複しない
+// 終了したスレッドの値は新しいスレッドで使い回されることがある
+pub(crate) fn current_thread_id() -> usize {
+    thread_local! {
+        static KEY: u8 = const { 0 };
+    }
+    KEY.with(|k| k as *const u8 as usize)
+}
+
+#[test]
+fn test_current_thread_id() {
+    let id = current_thread_id();
+    assert_ne!(id, 0);
+    assert_eq!(id, current_thread_id());
+    // 同時に生存しているスレッドとは重ならない
+    std::thread::scope(|s| {
+        s.spawn(|| assert_ne!(current_thread_id(), id));
+    });
+}
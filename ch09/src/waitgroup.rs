@@ -0,0 +1,144 @@
+use crate::futex::{wait_timeout, wake_all};
+use atomic_wait::wait;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::time::{Duration, Instant};
+
+pub struct WaitGroup {
+    // 完了していないタスクの数
+    count: AtomicU32,
+    // wait()でブロックしているスレッドの数
+    // 0からのadd()が待機中のwait()と重なっていないかの検出に使う
+    waiters: AtomicU32,
+}
+
+impl WaitGroup {
+    pub const fn new() -> Self {
+        Self {
+            count: AtomicU32::new(0),
+            waiters: AtomicU32::new(0),
+        }
+    }
+
+    pub fn add(&self, n: u32) {
+        // fetch_addだとパニックする前に数があふれてしまい、ほかのスレッドのwait()が戻ってしまう
+        // あふれる場合は書き換えずにパニックする
+        let old = self
+            .count
+            .fetch_update(Relaxed, Relaxed, |c| c.checked_add(n))
+            .unwrap_or_else(|_| panic!("too many tasks"));
+        // 0からのインクリメントは前回のwait()がすべて戻ってから行う必要がある
+        // そうでないと起こされたwait()が新しいタスクの完了を待たずに戻ってしまう
+        debug_assert!(
+            old != 0 || n == 0 || self.waiters.load(Relaxed) == 0,
+            "WaitGroup is reused before previous wait has returned"
+        );
+    }
+
+    pub fn done(&self) {
+        let old = self.count.fetch_sub(1, Release);
+        assert!(old != 0, "negative WaitGroup counter");
+        if old == 1 {
+            wake_all(&self.count);
+        }
+    }
+
+    pub fn wait(&self) {
+        self.waiters.fetch_add(1, Relaxed);
+        loop {
+            let c = self.count.load(Acquire);
+            if c == 0 {
+                break;
+            }
+            wait(&self.count, c);
+        }
+        self.waiters.fetch_sub(1, Relaxed);
+    }
+
+    // すべてのタスクが完了すればtrue、タイムアウトすればfalse
+    // Instantで表せないほど長いtimeoutは、wait()と同じく期限なしで待つ
+    #[must_use]
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let Some(deadline) = Instant::now().checked_add(timeout) else {
+            self.wait();
+            return true;
+        };
+        self.waiters.fetch_add(1, Relaxed);
+        let completed = loop {
+            let c = self.count.load(Acquire);
+            if c == 0 {
+                break true;
+            }
+            match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => wait_timeout(&self.count, c, remaining),
+                _ => break false,
+            }
+        };
+        self.waiters.fetch_sub(1, Relaxed);
+        completed
+    }
+}
+
+#[test]
+fn test_waitgroup() {
+    use std::thread;
+
+    let wg = WaitGroup::new();
+    wg.add(3);
+
+    thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(200));
+                wg.done();
+            });
+        }
+        assert!(!wg.wait_timeout(Duration::from_millis(10)));
+        assert!(wg.wait_timeout(Duration::from_secs(10)));
+    });
+    // 期限が表せなくてもパニックしない
+    assert!(wg.wait_timeout(Duration::MAX));
+
+    // 再利用できる
+    wg.add(1);
+    wg.done();
+    wg.wait();
+}
+
+#[test]
+fn test_too_many_tasks() {
+    use std::panic;
+
+    let wg = WaitGroup::new();
+    wg.add(u32::MAX);
+    assert!(panic::catch_unwind(|| wg.add(1)).is_err());
+    // パニックしても数はあふれずに残っている
+    assert_eq!(wg.count.load(Relaxed), u32::MAX);
+    assert!(!wg.wait_timeout(Duration::from_millis(10)));
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "WaitGroup is reused before previous wait has returned")]
+fn test_waitgroup_add_after_wait() {
+    use std::sync::Arc;
+    use std::thread;
+
+    // done()で起こされたwait()が戻る前にadd()する
+    // 起こされたスレッドがいつ戻るかは決められないので、検出されるまで繰り返す
+    // 検出されたときは待っているスレッドが残るので、scopeではなくspawnして置いていく
+    let wg = Arc::new(WaitGroup::new());
+    for _ in 0..1000 {
+        wg.add(1);
+        let waiter = {
+            let wg = wg.clone();
+            thread::spawn(move || wg.wait())
+        };
+        // wait()でブロックするまで待つ
+        thread::sleep(Duration::from_millis(1));
+        wg.done();
+        wg.add(1);
+        wg.done();
+        waiter.join().unwrap();
+    }
+}